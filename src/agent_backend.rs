@@ -0,0 +1,940 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+
+use crate::claude_cli::{
+    build_args, feed_stream, finish_run, ClaudeResult, ExecClaudeOptions, ExecClaudeResult,
+    ReplyUpdate, StreamJsonParser,
+};
+use crate::docker_manager::{
+    dir_size, ContainerInfo, ContainerStats, DockerManager, Permission, WorkspaceEntry,
+};
+
+/// Status info for a user's execution environment.
+#[derive(Debug)]
+pub struct ContainerStatus {
+    pub name: String,
+    pub running: bool,
+    pub stats: Option<ContainerStats>,
+    /// Bytes used by the workspace.
+    pub disk: Option<u64>,
+}
+
+/// Where and how Claude runs for each user.
+///
+/// `ClaudeExecutor` owns session bookkeeping and prompt construction; a backend
+/// only provides the per-user environment and runs the CLI in it.
+#[async_trait]
+pub trait AgentBackend: Send + Sync {
+    /// Make sure the user's environment exists and is ready to run Claude.
+    async fn prepare(&self, wxid: &str, permission: Permission) -> Result<()>;
+
+    /// Run one Claude turn for the user.
+    async fn execute(
+        &self,
+        wxid: &str,
+        system_prompt: &str,
+        message: &str,
+        options: ExecClaudeOptions,
+    ) -> ExecClaudeResult;
+
+    /// Reset the environment after the user's session was cleared,
+    /// optionally restarting it from scratch.
+    async fn clear_session(&self, wxid: &str, restart: bool) -> Result<()>;
+
+    /// Kill any Claude run in progress for the user. Returns whether one was running.
+    async fn kill(&self, wxid: &str) -> Result<bool>;
+
+    /// Kill every Claude run in progress (on shutdown). Returns how many were killed.
+    async fn kill_all(&self) -> usize;
+
+    /// Write a file into the user's workspace, creating parent directories.
+    /// `path` is relative to the workspace root.
+    async fn write_file(&self, wxid: &str, path: &str, data: &[u8]) -> Result<()>;
+
+    /// List a workspace directory (`""` for the root), sorted by name.
+    async fn list_files(&self, wxid: &str, dir: &str) -> Result<Vec<WorkspaceEntry>>;
+
+    /// Read a file from the user's workspace; fails without reading it if
+    /// it is larger than `max_bytes`.
+    async fn read_file(&self, wxid: &str, path: &str, max_bytes: u64) -> Result<Vec<u8>>;
+
+    /// Delete a file or directory (recursively) from the user's workspace.
+    async fn remove_file(&self, wxid: &str, path: &str) -> Result<()>;
+
+    /// Bytes used by the user's workspace.
+    async fn workspace_usage(&self, wxid: &str) -> Result<u64>;
+
+    /// Where the user's workspace lives on this machine, if it does
+    /// (needed for snapshots).
+    fn host_workspace(&self, wxid: &str) -> Option<PathBuf>;
+
+    /// Status of the user's environment.
+    async fn status(&self, wxid: &str) -> ContainerStatus;
+
+    /// List all environments managed by this backend.
+    async fn list(&self) -> Result<Vec<ContainerInfo>>;
+
+    /// Stop the user's environment (it is restarted on demand).
+    async fn stop(&self, wxid: &str) -> Result<bool>;
+
+    /// Remove the user's environment; persistent data is kept.
+    async fn destroy(&self, wxid: &str) -> Result<bool>;
+
+    /// Destroy and recreate the user's environment.
+    async fn rebuild(&self, wxid: &str, permission: Permission) -> Result<()>;
+}
+
+/// Check that `path` stays inside the workspace: relative, without `..`.
+fn workspace_path(path: &str) -> Result<&Path> {
+    let p = Path::new(path);
+    if path.is_empty() || !p.components().all(|c| matches!(c, Component::Normal(_))) {
+        anyhow::bail!("Invalid workspace path: {}", path);
+    }
+    Ok(p)
+}
+
+// ============================================
+// Docker backend
+// ============================================
+
+/// Runs Claude inside a per-user Docker container (the default).
+pub struct DockerBackend {
+    docker: Arc<DockerManager>,
+}
+
+impl DockerBackend {
+    pub fn new(docker: Arc<DockerManager>) -> Self {
+        Self { docker }
+    }
+}
+
+#[async_trait]
+impl AgentBackend for DockerBackend {
+    async fn prepare(&self, wxid: &str, permission: Permission) -> Result<()> {
+        self.docker.ensure_container(wxid, permission).await?;
+        Ok(())
+    }
+
+    async fn execute(
+        &self,
+        wxid: &str,
+        system_prompt: &str,
+        message: &str,
+        options: ExecClaudeOptions,
+    ) -> ExecClaudeResult {
+        self.docker
+            .exec_claude(wxid, system_prompt, message, options)
+            .await
+    }
+
+    async fn clear_session(&self, wxid: &str, restart: bool) -> Result<()> {
+        if restart {
+            let _ = self.docker.stop_container(wxid).await;
+            self.docker
+                .ensure_container(wxid, Permission::Normal)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn kill(&self, wxid: &str) -> Result<bool> {
+        self.docker.kill_claude(wxid).await
+    }
+
+    async fn kill_all(&self) -> usize {
+        self.docker.kill_all_claude().await
+    }
+
+    async fn write_file(&self, wxid: &str, path: &str, data: &[u8]) -> Result<()> {
+        self.docker.write_workspace_file(wxid, path, data).await
+    }
+
+    async fn list_files(&self, wxid: &str, dir: &str) -> Result<Vec<WorkspaceEntry>> {
+        self.docker.list_workspace_dir(wxid, dir).await
+    }
+
+    async fn read_file(&self, wxid: &str, path: &str, max_bytes: u64) -> Result<Vec<u8>> {
+        self.docker.read_workspace_file(wxid, path, max_bytes).await
+    }
+
+    async fn remove_file(&self, wxid: &str, path: &str) -> Result<()> {
+        self.docker.remove_workspace_path(wxid, path).await
+    }
+
+    async fn workspace_usage(&self, wxid: &str) -> Result<u64> {
+        self.docker.workspace_usage(wxid).await
+    }
+
+    fn host_workspace(&self, wxid: &str) -> Option<PathBuf> {
+        Some(self.docker.workspace_host_dir(wxid))
+    }
+
+    async fn status(&self, wxid: &str) -> ContainerStatus {
+        let name = self.docker.container_name(wxid);
+        let running = self.docker.is_running(&name).await;
+        let stats = if running {
+            self.docker.get_stats(wxid).await.ok().flatten()
+        } else {
+            None
+        };
+        let disk = self.docker.workspace_usage(wxid).await.ok();
+
+        ContainerStatus {
+            name,
+            running,
+            stats,
+            disk,
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<ContainerInfo>> {
+        self.docker.list_containers().await
+    }
+
+    async fn stop(&self, wxid: &str) -> Result<bool> {
+        self.docker.stop_container(wxid).await
+    }
+
+    async fn destroy(&self, wxid: &str) -> Result<bool> {
+        self.docker.destroy_container(wxid).await
+    }
+
+    async fn rebuild(&self, wxid: &str, permission: Permission) -> Result<()> {
+        self.docker.rebuild(wxid, permission).await
+    }
+}
+
+// ============================================
+// Host-process backend
+// ============================================
+
+/// How long to wait for the rest of the CLI's stderr once it has exited or
+/// been killed.
+const STDERR_GRACE: Duration = Duration::from_secs(2);
+
+/// How long a stopped run gets to exit on SIGTERM before SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// Runs the Claude CLI directly on the host, one workspace directory per user.
+///
+/// There is no isolation between users or from the host: only use this for
+/// trusted single-user setups or machines without Docker.
+pub struct HostBackend {
+    cli_path: String,
+    data_dir: PathBuf,
+    /// wxid -> cancel handle of the run in progress.
+    running: Mutex<HashMap<String, oneshot::Sender<()>>>,
+}
+
+impl HostBackend {
+    pub fn new(cli_path: String, data_dir: PathBuf) -> Self {
+        Self {
+            cli_path,
+            data_dir,
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn workspace_dir(&self, wxid: &str) -> PathBuf {
+        self.data_dir.join(wxid).join("workspace")
+    }
+
+    /// Spawn the CLI and collect its output, feeding stdout to the stream
+    /// parser as it arrives. Returns early if `cancel` fires.
+    async fn run_cli(
+        &self,
+        wxid: &str,
+        args: Vec<String>,
+        options: &ExecClaudeOptions,
+        cancel: oneshot::Receiver<()>,
+    ) -> ExecClaudeResult {
+        let mut child = match Command::new(&self.cli_path)
+            .args(&args)
+            .current_dir(self.workspace_dir(wxid))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Its own process group, so tools it starts are stopped with it
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to spawn {}: {}", self.cli_path, e);
+                return ExecClaudeResult::failed("Claude execution failed", e.to_string());
+            }
+        };
+
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let mut stderr_task = tokio::spawn(read_to_string(stderr));
+
+        let mut parser = options.stream.as_ref().map(|_| StreamJsonParser::new());
+        let collect = async {
+            let mut out = Vec::new();
+            let mut buf = [0u8; 8192];
+            loop {
+                let n = stdout.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                feed_stream(&mut parser, options.stream.as_ref(), &buf[..n]);
+                out.extend_from_slice(&buf[..n]);
+            }
+            child.wait().await?;
+            Ok::<_, std::io::Error>(String::from_utf8_lossy(&out).into_owned())
+        };
+
+        let timeout_secs = options.timeout.unwrap_or(120);
+        let result = tokio::select! {
+            r = tokio::time::timeout(Duration::from_secs(timeout_secs), collect) => Some(r),
+            _ = cancel => None,
+        };
+        let Some(result) = result else {
+            info!("Claude run killed on host: {}", wxid);
+            kill_run_group(&mut child).await;
+            stderr_task.abort();
+            return ExecClaudeResult::failed("Request was cancelled", String::new());
+        };
+        if result.is_err() {
+            // `collect` only borrowed the child: stop it before going on
+            kill_run_group(&mut child).await;
+        }
+        // Processes the CLI started may still hold stderr open
+        let stderr = match tokio::time::timeout(STDERR_GRACE, &mut stderr_task).await {
+            Ok(r) => r.unwrap_or_default(),
+            Err(_) => {
+                stderr_task.abort();
+                String::new()
+            }
+        };
+
+        match result {
+            Ok(Ok(stdout)) => finish_run(&stdout, stderr, parser),
+            Ok(Err(e)) => {
+                error!("Host claude run failed [{}]: {}", wxid, e);
+                ExecClaudeResult::failed("Processing error, please try again later", e.to_string())
+            }
+            Err(_) => {
+                warn!("Claude run timed out on host for {} after {}s", wxid, timeout_secs);
+                ExecClaudeResult::failed("Request timed out", stderr)
+            }
+        }
+    }
+}
+
+/// Stop a host run: SIGTERM to the CLI's process group, then SIGKILL to
+/// whatever is left of it once the CLI exits or `KILL_GRACE` passes.
+async fn kill_run_group(child: &mut Child) {
+    if let Some(pid) = child.id() {
+        let pgid = pid as libc::pid_t;
+        // SAFETY: killpg has no memory-safety preconditions
+        unsafe { libc::killpg(pgid, libc::SIGTERM) };
+        let _ = tokio::time::timeout(KILL_GRACE, child.wait()).await;
+        unsafe { libc::killpg(pgid, libc::SIGKILL) };
+    }
+    let _ = child.start_kill();
+    let _ = child.wait().await;
+}
+
+async fn read_to_string(mut reader: impl AsyncRead + Unpin) -> String {
+    let mut buf = Vec::new();
+    let _ = reader.read_to_end(&mut buf).await;
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+#[async_trait]
+impl AgentBackend for HostBackend {
+    async fn prepare(&self, wxid: &str, _permission: Permission) -> Result<()> {
+        let dir = self.workspace_dir(wxid);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create workspace: {:?}", dir))
+    }
+
+    async fn execute(
+        &self,
+        wxid: &str,
+        system_prompt: &str,
+        message: &str,
+        options: ExecClaudeOptions,
+    ) -> ExecClaudeResult {
+        let args = build_args(system_prompt, message, &options);
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        self.running
+            .lock()
+            .unwrap()
+            .insert(wxid.to_string(), cancel_tx);
+
+        // The child is killed on drop, so a timeout or cancel never leaks it
+        let result = self.run_cli(wxid, args, &options, cancel_rx).await;
+
+        self.running.lock().unwrap().remove(wxid);
+        result
+    }
+
+    async fn clear_session(&self, _wxid: &str, _restart: bool) -> Result<()> {
+        Ok(())
+    }
+
+    async fn kill(&self, wxid: &str) -> Result<bool> {
+        let cancel = self.running.lock().unwrap().remove(wxid);
+        Ok(cancel.is_some_and(|tx| tx.send(()).is_ok()))
+    }
+
+    async fn kill_all(&self) -> usize {
+        let running: Vec<_> = self.running.lock().unwrap().drain().collect();
+        let mut killed = 0;
+        for (_, tx) in running {
+            if tx.send(()).is_ok() {
+                killed += 1;
+            }
+        }
+        killed
+    }
+
+    async fn write_file(&self, wxid: &str, path: &str, data: &[u8]) -> Result<()> {
+        let file = self.workspace_dir(wxid).join(workspace_path(path)?);
+        if let Some(parent) = file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&file, data)
+            .await
+            .with_context(|| format!("Failed to write {:?}", file))
+    }
+
+    async fn list_files(&self, wxid: &str, dir: &str) -> Result<Vec<WorkspaceEntry>> {
+        let mut root = self.workspace_dir(wxid);
+        if !dir.is_empty() {
+            root.push(workspace_path(dir)?);
+        }
+        let mut entries = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(&root)
+            .await
+            .with_context(|| format!("Failed to list {:?}", root))?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let meta = entry.metadata().await?;
+            let modified = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_millis() as i64);
+            entries.push(WorkspaceEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                is_dir: meta.is_dir(),
+                size: meta.len(),
+                modified,
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    async fn read_file(&self, wxid: &str, path: &str, max_bytes: u64) -> Result<Vec<u8>> {
+        let file = self.workspace_dir(wxid).join(workspace_path(path)?);
+        let handle = tokio::fs::File::open(&file)
+            .await
+            .with_context(|| format!("Failed to read {:?}", file))?;
+        let size = handle.metadata().await?.len();
+        if size > max_bytes {
+            anyhow::bail!("{:?} is larger than {} bytes", file, max_bytes);
+        }
+        // The file may grow after the check; never read past the limit
+        let mut data = Vec::with_capacity(size as usize);
+        handle.take(max_bytes + 1).read_to_end(&mut data).await?;
+        if data.len() as u64 > max_bytes {
+            anyhow::bail!("{:?} is larger than {} bytes", file, max_bytes);
+        }
+        Ok(data)
+    }
+
+    async fn remove_file(&self, wxid: &str, path: &str) -> Result<()> {
+        let target = self.workspace_dir(wxid).join(workspace_path(path)?);
+        let meta = tokio::fs::symlink_metadata(&target)
+            .await
+            .with_context(|| format!("Failed to remove {:?}", target))?;
+        if meta.is_dir() {
+            tokio::fs::remove_dir_all(&target).await?;
+        } else {
+            tokio::fs::remove_file(&target).await?;
+        }
+        Ok(())
+    }
+
+    async fn workspace_usage(&self, wxid: &str) -> Result<u64> {
+        let dir = self.workspace_dir(wxid);
+        Ok(tokio::task::spawn_blocking(move || dir_size(&dir)).await?)
+    }
+
+    fn host_workspace(&self, wxid: &str) -> Option<PathBuf> {
+        Some(self.workspace_dir(wxid))
+    }
+
+    async fn status(&self, wxid: &str) -> ContainerStatus {
+        let running = self.running.lock().unwrap().contains_key(wxid);
+        ContainerStatus {
+            name: format!("host:{}", self.workspace_dir(wxid).display()),
+            running,
+            stats: None,
+            disk: self.workspace_usage(wxid).await.ok(),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<ContainerInfo>> {
+        let running = self.running.lock().unwrap();
+        Ok(running
+            .keys()
+            .map(|wxid| ContainerInfo {
+                name: format!("host:{}", wxid),
+                status: "Up (running claude)".to_string(),
+                wxid: Some(wxid.clone()),
+                permission: None,
+            })
+            .collect())
+    }
+
+    async fn stop(&self, wxid: &str) -> Result<bool> {
+        self.kill(wxid).await
+    }
+
+    async fn destroy(&self, wxid: &str) -> Result<bool> {
+        self.kill(wxid).await
+    }
+
+    async fn rebuild(&self, wxid: &str, permission: Permission) -> Result<()> {
+        self.kill(wxid).await?;
+        self.prepare(wxid, permission).await
+    }
+}
+
+// ============================================
+// Mock backend
+// ============================================
+
+/// Contents and modification time (Unix ms) of a mock workspace file.
+type MockFile = (Vec<u8>, i64);
+
+/// Deterministic in-process backend that never runs Claude.
+///
+/// Replies with `[mock] <message>` and a stable fake session ID per user, so
+/// the whole routing/session/audit path can run without Docker or the CLI.
+#[derive(Default)]
+pub struct MockBackend {
+    /// wxid -> permission of each prepared "container".
+    envs: Mutex<HashMap<String, Permission>>,
+    /// wxids whose environment is currently stopped.
+    stopped: Mutex<HashSet<String>>,
+    /// Workspace files by `(wxid, path)`, with their modification time.
+    files: Mutex<HashMap<(String, String), MockFile>>,
+    /// Files the next `execute` writes, as if Claude had created them.
+    run_writes: Mutex<Vec<(String, Vec<u8>)>>,
+    /// wxid -> permission the last run was given.
+    run_permissions: Mutex<HashMap<String, Permission>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Session ID the mock reports for a user.
+    pub fn session_id(wxid: &str) -> String {
+        format!("mock-session-{}", wxid)
+    }
+
+    /// Contents of a file written to the user's workspace.
+    #[cfg(test)]
+    pub fn file(&self, wxid: &str, path: &str) -> Option<Vec<u8>> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(&(wxid.to_string(), path.to_string()))
+            .map(|(data, _)| data.clone())
+    }
+
+    /// Permission the user's last run was given, if they had one.
+    #[cfg(test)]
+    pub fn run_permission(&self, wxid: &str) -> Option<Permission> {
+        self.run_permissions.lock().unwrap().get(wxid).copied()
+    }
+
+    /// Have the next run write `path` into the user's workspace.
+    #[cfg(test)]
+    pub fn write_on_next_run(&self, path: &str, data: &[u8]) {
+        self.run_writes
+            .lock()
+            .unwrap()
+            .push((path.to_string(), data.to_vec()));
+    }
+}
+
+#[async_trait]
+impl AgentBackend for MockBackend {
+    async fn prepare(&self, wxid: &str, permission: Permission) -> Result<()> {
+        self.envs
+            .lock()
+            .unwrap()
+            .entry(wxid.to_string())
+            .or_insert(permission);
+        self.stopped.lock().unwrap().remove(wxid);
+        Ok(())
+    }
+
+    async fn execute(
+        &self,
+        wxid: &str,
+        _system_prompt: &str,
+        message: &str,
+        options: ExecClaudeOptions,
+    ) -> ExecClaudeResult {
+        if let Some(permission) = options.permission {
+            self.run_permissions
+                .lock()
+                .unwrap()
+                .insert(wxid.to_string(), permission);
+        }
+        let writes: Vec<(String, Vec<u8>)> = self.run_writes.lock().unwrap().drain(..).collect();
+        for (path, data) in writes {
+            let _ = self.write_file(wxid, &path, &data).await;
+        }
+
+        let mut output = format!("[mock] {}", message);
+        if let Some(ref resumed) = options.claude_session {
+            output.push_str(&format!(" (resumed {})", resumed));
+        }
+        if let Some(ref sink) = options.stream {
+            let _ = sink.send(ReplyUpdate::Partial(output.clone()));
+        }
+
+        ExecClaudeResult {
+            ok: true,
+            output: output.clone(),
+            stderr: String::new(),
+            claude: Some(ClaudeResult {
+                session_id: Some(Self::session_id(wxid)),
+                result: Some(output),
+                num_turns: 1,
+                ..Default::default()
+            }),
+        }
+    }
+
+    async fn clear_session(&self, _wxid: &str, _restart: bool) -> Result<()> {
+        Ok(())
+    }
+
+    async fn kill(&self, _wxid: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn kill_all(&self) -> usize {
+        0
+    }
+
+    async fn write_file(&self, wxid: &str, path: &str, data: &[u8]) -> Result<()> {
+        workspace_path(path)?;
+        self.files.lock().unwrap().insert(
+            (wxid.to_string(), path.to_string()),
+            (data.to_vec(), chrono::Utc::now().timestamp_millis()),
+        );
+        Ok(())
+    }
+
+    async fn list_files(&self, wxid: &str, dir: &str) -> Result<Vec<WorkspaceEntry>> {
+        let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+        let files = self.files.lock().unwrap();
+        let mut entries: Vec<WorkspaceEntry> = Vec::new();
+        for ((owner, path), (data, modified)) in files.iter() {
+            let Some(rest) = path.strip_prefix(&prefix).filter(|_| owner == wxid) else {
+                continue;
+            };
+            // Deeper files show up as their top directory under `dir`
+            let entry = match rest.split_once('/') {
+                Some((sub, _)) => WorkspaceEntry {
+                    name: sub.to_string(),
+                    is_dir: true,
+                    size: 0,
+                    modified: *modified,
+                },
+                None => WorkspaceEntry {
+                    name: rest.to_string(),
+                    is_dir: false,
+                    size: data.len() as u64,
+                    modified: *modified,
+                },
+            };
+            if !entries.iter().any(|e| e.name == entry.name) {
+                entries.push(entry);
+            }
+        }
+        if entries.is_empty() && !dir.is_empty() {
+            anyhow::bail!("No such directory: {}", dir);
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    async fn read_file(&self, wxid: &str, path: &str, max_bytes: u64) -> Result<Vec<u8>> {
+        let data = self
+            .files
+            .lock()
+            .unwrap()
+            .get(&(wxid.to_string(), path.to_string()))
+            .map(|(data, _)| data.clone())
+            .with_context(|| format!("No such file: {}", path))?;
+        if data.len() as u64 > max_bytes {
+            anyhow::bail!("{} is larger than {} bytes", path, max_bytes);
+        }
+        Ok(data)
+    }
+
+    async fn remove_file(&self, wxid: &str, path: &str) -> Result<()> {
+        workspace_path(path)?;
+        let dir_prefix = format!("{}/", path);
+        let mut files = self.files.lock().unwrap();
+        let before = files.len();
+        files.retain(|(owner, p), _| {
+            owner != wxid || (p != path && !p.starts_with(&dir_prefix))
+        });
+        if files.len() == before {
+            anyhow::bail!("No such file or directory: {}", path);
+        }
+        Ok(())
+    }
+
+    async fn workspace_usage(&self, wxid: &str) -> Result<u64> {
+        let files = self.files.lock().unwrap();
+        Ok(files
+            .iter()
+            .filter(|((owner, _), _)| owner == wxid)
+            .map(|(_, (data, _))| data.len() as u64)
+            .sum())
+    }
+
+    fn host_workspace(&self, _wxid: &str) -> Option<PathBuf> {
+        None
+    }
+
+    async fn status(&self, wxid: &str) -> ContainerStatus {
+        let exists = self.envs.lock().unwrap().contains_key(wxid);
+        let stopped = self.stopped.lock().unwrap().contains(wxid);
+        ContainerStatus {
+            name: format!("mock-{}", wxid),
+            running: exists && !stopped,
+            stats: None,
+            disk: self.workspace_usage(wxid).await.ok(),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<ContainerInfo>> {
+        let envs = self.envs.lock().unwrap();
+        let stopped = self.stopped.lock().unwrap();
+        let mut list: Vec<ContainerInfo> = envs
+            .iter()
+            .map(|(wxid, perm)| ContainerInfo {
+                name: format!("mock-{}", wxid),
+                status: if stopped.contains(wxid) { "Exited".into() } else { "Up".into() },
+                wxid: Some(wxid.clone()),
+                permission: Some(perm.as_str().to_string()),
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    async fn stop(&self, wxid: &str) -> Result<bool> {
+        if !self.envs.lock().unwrap().contains_key(wxid) {
+            return Ok(false);
+        }
+        Ok(self.stopped.lock().unwrap().insert(wxid.to_string()))
+    }
+
+    async fn destroy(&self, wxid: &str) -> Result<bool> {
+        self.stopped.lock().unwrap().remove(wxid);
+        Ok(self.envs.lock().unwrap().remove(wxid).is_some())
+    }
+
+    async fn rebuild(&self, wxid: &str, permission: Permission) -> Result<()> {
+        self.destroy(wxid).await?;
+        self.prepare(wxid, permission).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mock_backend_is_deterministic() {
+        let backend = MockBackend::new();
+        backend.prepare("wx_m", Permission::Trusted).await.unwrap();
+
+        let r = backend
+            .execute("wx_m", "sys", "hello", ExecClaudeOptions::default())
+            .await;
+        assert!(r.ok);
+        assert_eq!(r.output, "[mock] hello");
+        let claude = r.claude.unwrap();
+        assert_eq!(claude.session_id.as_deref(), Some("mock-session-wx_m"));
+
+        let resumed = ExecClaudeOptions {
+            claude_session: Some("cs".into()),
+            ..Default::default()
+        };
+        let r = backend.execute("wx_m", "sys", "again", resumed).await;
+        assert_eq!(r.output, "[mock] again (resumed cs)");
+    }
+
+    #[tokio::test]
+    async fn mock_backend_lifecycle() {
+        let backend = MockBackend::new();
+        assert!(!backend.status("wx_l").await.running);
+
+        backend.prepare("wx_l", Permission::Normal).await.unwrap();
+        assert!(backend.status("wx_l").await.running);
+        assert_eq!(backend.list().await.unwrap()[0].permission.as_deref(), Some("normal"));
+
+        assert!(backend.stop("wx_l").await.unwrap());
+        assert!(!backend.status("wx_l").await.running);
+        assert_eq!(backend.list().await.unwrap()[0].status, "Exited");
+
+        assert!(backend.destroy("wx_l").await.unwrap());
+        assert!(backend.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn host_backend_reports_spawn_failure() {
+        let dir = std::env::temp_dir().join(format!("wcb-host-{}", std::process::id()));
+        let backend = HostBackend::new("/nonexistent/claude-cli".into(), dir.clone());
+        backend.prepare("wx_h", Permission::Admin).await.unwrap();
+        assert!(dir.join("wx_h").join("workspace").is_dir());
+        backend.write_file("wx_h", "inbox/a.txt", b"hi").await.unwrap();
+        assert_eq!(std::fs::read(dir.join("wx_h/workspace/inbox/a.txt")).unwrap(), b"hi");
+        assert!(backend.write_file("wx_h", "../escape.txt", b"x").await.is_err());
+        assert_eq!(backend.read_file("wx_h", "inbox/a.txt", 2).await.unwrap(), b"hi");
+        assert!(backend.read_file("wx_h", "inbox/a.txt", 1).await.is_err());
+
+        let r = backend
+            .execute("wx_h", "sys", "hi", ExecClaudeOptions::default())
+            .await;
+        assert!(!r.ok);
+        assert!(!backend.status("wx_h").await.running);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn host_backend_runs_cli_and_parses_json() {
+        // A stand-in CLI: ignores its arguments and prints a JSON result
+        let dir = std::env::temp_dir().join(format!("wcb-host-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("fake-claude.sh");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\necho '{\"type\":\"result\",\"result\":\"pong\",\"session_id\":\"host-1\"}'\n",
+        )
+        .unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let backend = HostBackend::new(script.to_string_lossy().into_owned(), dir.clone());
+        backend.prepare("wx_cli", Permission::Admin).await.unwrap();
+        let r = backend
+            .execute("wx_cli", "sys", "ping", ExecClaudeOptions::default())
+            .await;
+        assert!(r.ok);
+        assert_eq!(r.output, "pong");
+        assert_eq!(r.claude.unwrap().session_id.as_deref(), Some("host-1"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn host_backend_timeout_stops_the_cli() {
+        // A CLI that hangs, with a child of its own holding stdout and stderr
+        let dir = std::env::temp_dir().join(format!("wcb-host-slow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("slow-claude.sh");
+        std::fs::write(&script, "#!/bin/sh\nsleep 8\n").unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let backend = HostBackend::new(script.to_string_lossy().into_owned(), dir.clone());
+        backend.prepare("wx_slow", Permission::Admin).await.unwrap();
+        let options = ExecClaudeOptions {
+            timeout: Some(1),
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        let r = backend.execute("wx_slow", "sys", "ping", options).await;
+        let elapsed = started.elapsed();
+        assert!(!r.ok);
+        assert_eq!(r.output, "Request timed out");
+        assert!(elapsed < Duration::from_secs(4), "took {:?}", elapsed);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// Whether `pid` is still running (zombies waiting for init don't count).
+    fn process_alive(pid: &str) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat.rsplit(')').next().unwrap_or("").trim_start().starts_with('Z'),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn host_backend_kill_stops_the_whole_run() {
+        // A CLI that starts a background tool and then hangs
+        let dir = std::env::temp_dir().join(format!("wcb-host-tree-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("tree-claude.sh");
+        let pid_file = dir.join("tool.pid");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\nsleep 30 >/dev/null 2>&1 &\necho $! > {}\nsleep 30\n", pid_file.display()),
+        )
+        .unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let backend = Arc::new(HostBackend::new(script.to_string_lossy().into_owned(), dir.clone()));
+        backend.prepare("wx_tree", Permission::Admin).await.unwrap();
+
+        for (round, timeout) in [(1, Some(1)), (2, Some(60))] {
+            let _ = std::fs::remove_file(&pid_file);
+            let run = {
+                let backend = Arc::clone(&backend);
+                let options = ExecClaudeOptions { timeout, ..Default::default() };
+                tokio::spawn(async move { backend.execute("wx_tree", "sys", "ping", options).await })
+            };
+            let tool = loop {
+                match std::fs::read_to_string(&pid_file) {
+                    Ok(pid) if pid.ends_with('\n') => break pid.trim().to_string(),
+                    _ => tokio::time::sleep(Duration::from_millis(20)).await,
+                }
+            };
+            assert!(process_alive(&tool));
+            // Round 1 times out, round 2 is stopped with /kill
+            if round == 2 {
+                assert!(backend.kill("wx_tree").await.unwrap());
+            }
+            assert!(!run.await.unwrap().ok);
+            for _ in 0..50 {
+                if !process_alive(&tool) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            assert!(!process_alive(&tool), "round {}: tool {} survived", round, tool);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,412 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::database::Database;
+
+// ============================================
+// Hash chain
+// ============================================
+
+/// `prev_hash` of the first audit entry.
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Digest of a message, stored next to it so the text can later be
+/// redacted without breaking the chain.
+pub fn message_hash(message: Option<&str>) -> Option<String> {
+    message.map(|m| sha256_hex(m.as_bytes()))
+}
+
+/// The parts of an audit entry its hash covers.
+pub struct ChainFields<'a> {
+    pub id: i64,
+    pub wxid: &'a str,
+    /// Group member who wrote; only set for group chats.
+    pub sender: Option<&'a str>,
+    pub nickname: Option<&'a str>,
+    pub direction: &'a str,
+    pub message_hash: Option<&'a str>,
+    pub claude_session: Option<&'a str>,
+    pub timestamp: Option<&'a str>,
+}
+
+/// Hash of an entry: SHA-256 over the previous entry's hash and the entry's
+/// fields as a JSON array. The sender is appended only when set, so entries
+/// from before it was recorded keep their hashes.
+pub fn entry_hash(prev_hash: &str, fields: &ChainFields) -> String {
+    let mut body = serde_json::json!([
+        fields.id,
+        fields.wxid,
+        fields.nickname,
+        fields.direction,
+        fields.message_hash,
+        fields.claude_session,
+        fields.timestamp,
+    ]);
+    if let (Some(sender), Some(body)) = (fields.sender, body.as_array_mut()) {
+        body.push(sender.into());
+    }
+    sha256_hex(format!("{}\n{}", prev_hash, body).as_bytes())
+}
+
+/// Hash every unhashed audit entry in order, chaining on from the entry
+/// before it. Used to bring existing rows into the chain when it's added.
+pub fn seal_unhashed(conn: &Connection) -> rusqlite::Result<()> {
+    type Row = (i64, String, Option<String>, String, Option<String>, Option<String>, Option<String>);
+    let rows: Vec<Row> = conn
+        .prepare(
+            "SELECT id, wxid, nickname, direction, message, claude_session, timestamp
+             FROM audit_log WHERE hash IS NULL ORDER BY id",
+        )?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?, r.get(6)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let Some(first) = rows.first() else {
+        return Ok(());
+    };
+    let mut prev: String = conn
+        .query_row(
+            "SELECT hash FROM audit_log WHERE id < ? AND hash IS NOT NULL ORDER BY id DESC LIMIT 1",
+            params![first.0],
+            |r| r.get(0),
+        )
+        .optional()?
+        .unwrap_or_else(|| GENESIS.to_string());
+
+    let mut update = conn.prepare("UPDATE audit_log SET message_hash = ?, prev_hash = ?, hash = ? WHERE id = ?")?;
+    for (id, wxid, nickname, direction, message, claude_session, timestamp) in rows {
+        let digest = message_hash(message.as_deref());
+        let hash = entry_hash(
+            &prev,
+            &ChainFields {
+                id,
+                wxid: &wxid,
+                sender: None,
+                nickname: nickname.as_deref(),
+                direction: &direction,
+                message_hash: digest.as_deref(),
+                claude_session: claude_session.as_deref(),
+                timestamp: timestamp.as_deref(),
+            },
+        );
+        update.execute(params![digest, prev, hash, id])?;
+        prev = hash;
+    }
+    Ok(())
+}
+
+/// Why `Database::audit_verify` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakKind {
+    /// The entry has no hash.
+    Unsealed,
+    /// `prev_hash` doesn't match the entry before it, which was changed,
+    /// removed or inserted.
+    Link,
+    /// The entry doesn't match its own hash.
+    Content,
+}
+
+/// Result of walking the audit log's hash chain.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChainReport {
+    /// Entries checked before stopping.
+    pub entries: usize,
+    /// Pruned entries whose links were checked.
+    pub pruned: usize,
+    /// Entries whose text was redacted.
+    pub redacted: usize,
+    /// Last link checked: id and hash.
+    pub head: Option<(i64, String)>,
+    /// First broken link: id and why.
+    pub broken: Option<(i64, BreakKind)>,
+}
+
+// ============================================
+// Signed checkpoints
+// ============================================
+
+/// The chain head at some point in time, signed with the checkpoint key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub time: String,
+    pub id: i64,
+    pub hash: String,
+    pub mac: String,
+}
+
+/// What a checkpoint found wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointProblem {
+    /// The line is unreadable or its signature doesn't match.
+    BadSignature,
+    /// The entry at the checkpoint's id has a different hash now.
+    Mismatch,
+    /// The checkpoint is past the end of the log.
+    Truncated,
+}
+
+/// Result of checking the checkpoint file against the log.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CheckpointReport {
+    /// Checkpoints that matched.
+    pub matched: usize,
+    /// Checkpoints of entries pruned with no trace left.
+    pub skipped: usize,
+    /// First problem: position in the file (from 1) and what's wrong.
+    pub problem: Option<(usize, CheckpointProblem)>,
+}
+
+/// An append-only file of signed chain heads, kept apart from the database
+/// so that rewriting the log (even with valid hashes) shows up as a
+/// mismatch. Signed with HMAC-SHA256 and a key generated on first use.
+///
+/// All methods do blocking file I/O; call them from `spawn_blocking`.
+pub struct CheckpointLog {
+    path: PathBuf,
+    key: Vec<u8>,
+}
+
+impl CheckpointLog {
+    /// Open the checkpoint file at `path`, creating the key in `key_file`
+    /// if there isn't one yet.
+    pub fn open(path: &Path, key_file: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            key: load_or_create_key(key_file)?,
+        })
+    }
+
+    /// All checkpoints, oldest first. Unreadable lines come back as `None`.
+    fn read(&self) -> Result<Vec<Option<Checkpoint>>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut checkpoints = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                checkpoints.push(serde_json::from_str(&line).ok());
+            }
+        }
+        Ok(checkpoints)
+    }
+
+    fn mac(&self, time: &str, id: i64, hash: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(format!("{}\n{}\n{}", time, id, hash).as_bytes());
+        to_hex(&mac.finalize().into_bytes())
+    }
+
+    /// Append the current chain head, unless it's already the last
+    /// checkpoint or the log is empty. Returns the new checkpoint.
+    pub fn checkpoint(&self, db: &Database) -> Result<Option<Checkpoint>> {
+        let Some((id, hash)) = db.audit_head()? else {
+            return Ok(None);
+        };
+        if let Some(Some(last)) = self.read()?.last() {
+            if last.id == id && last.hash == hash {
+                return Ok(None);
+            }
+        }
+
+        let time = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        let mac = self.mac(&time, id, &hash);
+        let checkpoint = Checkpoint { time, id, hash, mac };
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {:?}", self.path))?;
+        writeln!(file, "{}", serde_json::to_string(&checkpoint)?)?;
+        file.sync_all()?;
+        Ok(Some(checkpoint))
+    }
+
+    /// Check every checkpoint's signature and that the log still has the
+    /// hash it recorded. Stops at the first problem.
+    pub fn verify(&self, db: &Database) -> Result<CheckpointReport> {
+        let mut report = CheckpointReport::default();
+        let head = db.audit_head()?.map_or(0, |(id, _)| id);
+        for (i, checkpoint) in self.read()?.into_iter().enumerate() {
+            let line = i + 1;
+            let Some(cp) = checkpoint.filter(|cp| cp.mac == self.mac(&cp.time, cp.id, &cp.hash)) else {
+                report.problem = Some((line, CheckpointProblem::BadSignature));
+                break;
+            };
+            match db.audit_chain_hash(cp.id)? {
+                Some(hash) if hash == cp.hash => report.matched += 1,
+                Some(_) => {
+                    report.problem = Some((line, CheckpointProblem::Mismatch));
+                    break;
+                }
+                None if cp.id > head => {
+                    report.problem = Some((line, CheckpointProblem::Truncated));
+                    break;
+                }
+                None => report.skipped += 1,
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Read the hex key in `path`, or write a new random 256-bit one there.
+fn load_or_create_key(path: &Path) -> Result<Vec<u8>> {
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            return from_hex(text.trim()).with_context(|| format!("Invalid checkpoint key in {:?}", path));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(anyhow::Error::new(e).context(format!("Failed to read {:?}", path))),
+    }
+
+    let mut key = uuid::Uuid::new_v4().as_bytes().to_vec();
+    key.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).with_context(|| format!("Failed to create {:?}", path))?;
+    writeln!(file, "{}", to_hex(&key))?;
+    Ok(key)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.is_empty() || !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wcb-chain-{}-{}", tag, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn test_db() -> Database {
+        Database::new(Some(Path::new(":memory:"))).unwrap()
+    }
+
+    #[test]
+    fn entry_hash_covers_prev_hash_and_fields() {
+        let digest = message_hash(Some("hello"));
+        let fields = ChainFields {
+            id: 1,
+            wxid: "wx_a",
+            sender: None,
+            nickname: Some("Alice"),
+            direction: "in",
+            message_hash: digest.as_deref(),
+            claude_session: None,
+            timestamp: Some("2026-10-17 12:00:00"),
+        };
+        let hash = entry_hash(GENESIS, &fields);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, entry_hash(GENESIS, &fields));
+        assert_ne!(hash, entry_hash(&hash, &fields));
+        assert_ne!(hash, entry_hash(GENESIS, &ChainFields { direction: "out", ..fields }));
+        assert_ne!(hash, entry_hash(GENESIS, &ChainFields { sender: Some("wx_b"), ..fields }));
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn checkpoints_catch_rewritten_and_truncated_logs() {
+        let dir = temp_dir("checkpoints");
+        let (file, key) = (dir.join("checkpoints.jsonl"), dir.join("key"));
+        let db = test_db();
+        let log = CheckpointLog::open(&file, &key).unwrap();
+        assert_eq!(log.checkpoint(&db).unwrap(), None);
+        assert_eq!(log.verify(&db).unwrap(), CheckpointReport::default());
+
+        db.audit_log("wx_a", None, "in", Some("one"), None).unwrap();
+        let first = log.checkpoint(&db).unwrap().unwrap();
+        assert_eq!(first.id, 1);
+        // Nothing new, nothing written
+        assert_eq!(log.checkpoint(&db).unwrap(), None);
+        db.audit_log("wx_a", None, "in", Some("two"), None).unwrap();
+        db.audit_log("wx_a", None, "in", Some("three"), None).unwrap();
+        log.checkpoint(&db).unwrap().unwrap();
+        assert_eq!(log.verify(&db).unwrap().matched, 2);
+
+        // The key is kept, so a reopened log still verifies
+        let log = CheckpointLog::open(&file, &key).unwrap();
+        assert_eq!(log.verify(&db).unwrap().problem, None);
+
+        // A log rewritten with valid hashes still differs from the checkpoints
+        let forged = test_db();
+        forged.audit_log("wx_a", None, "in", Some("one"), None).unwrap();
+        forged.audit_log("wx_a", None, "in", Some("2"), None).unwrap();
+        forged.audit_log("wx_a", None, "in", Some("three"), None).unwrap();
+        assert_eq!(forged.audit_verify().unwrap().broken, None);
+        let report = log.verify(&forged).unwrap();
+        assert_eq!(report.matched, 1);
+        assert_eq!(report.problem, Some((2, CheckpointProblem::Mismatch)));
+
+        // ... as does one missing its latest entries
+        let truncated = test_db();
+        truncated.audit_log("wx_a", None, "in", Some("one"), None).unwrap();
+        assert_eq!(log.verify(&truncated).unwrap().problem, Some((2, CheckpointProblem::Truncated)));
+
+        // Checkpoints signed with another key don't count
+        let other = CheckpointLog::open(&file, &dir.join("other-key")).unwrap();
+        assert_eq!(other.verify(&db).unwrap().problem, Some((1, CheckpointProblem::BadSignature)));
+        let mut text = fs::read_to_string(&file).unwrap();
+        text.push_str("not json\n");
+        fs::write(&file, text).unwrap();
+        assert_eq!(log.verify(&db).unwrap().problem, Some((3, CheckpointProblem::BadSignature)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keys_are_hex_and_private() {
+        let dir = temp_dir("key");
+        let path = dir.join("key");
+        let key = load_or_create_key(&path).unwrap();
+        assert_eq!(key.len(), 32);
+        assert_eq!(load_or_create_key(&path).unwrap(), key);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::write(&path, "zz").unwrap();
+        assert!(load_or_create_key(&path).is_err());
+        assert_eq!(from_hex("00ff10"), Some(vec![0, 255, 16]));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
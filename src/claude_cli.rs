@@ -0,0 +1,508 @@
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::docker_manager::Permission;
+use crate::wechat_bot::Attachment;
+
+// ============================================
+// Streaming reply plumbing
+// ============================================
+
+/// Incremental update for a reply that is still being generated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplyUpdate {
+    /// Full reply text produced so far (not a delta).
+    Partial(String),
+    /// The request is waiting for a free slot at this 1-based position.
+    Queued(usize),
+    /// A file from the workspace outbox, to send after the reply.
+    File(Attachment),
+}
+
+/// Channel used to push `ReplyUpdate`s from the executor to the bot frontend.
+pub type ReplySink = mpsc::UnboundedSender<ReplyUpdate>;
+
+// ============================================
+// CLI invocation
+// ============================================
+
+/// Options for executing Claude.
+#[derive(Debug, Default)]
+pub struct ExecClaudeOptions {
+    pub timeout: Option<u64>,
+    pub claude_session: Option<String>,
+    pub permission: Option<Permission>,
+    /// When set, Claude runs with `--output-format stream-json` and partial
+    /// text is pushed here as it arrives.
+    pub stream: Option<ReplySink>,
+}
+
+/// Result from executing Claude.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct ExecClaudeResult {
+    pub ok: bool,
+    pub output: String,
+    pub stderr: String,
+    /// Structured result reported by the CLI, if its output could be parsed.
+    pub claude: Option<ClaudeResult>,
+}
+
+impl ExecClaudeResult {
+    /// A failed run with a user-facing message and no structured result.
+    pub fn failed(output: &str, stderr: String) -> Self {
+        Self {
+            ok: false,
+            output: output.to_string(),
+            stderr,
+            claude: None,
+        }
+    }
+}
+
+/// Arguments for one `claude --print` run, excluding the binary itself.
+pub fn build_args(system_prompt: &str, message: &str, options: &ExecClaudeOptions) -> Vec<String> {
+    let mut args = vec!["--print".to_string()];
+    if options.stream.is_some() {
+        // stream-json requires --verbose in --print mode
+        args.extend(["--output-format", "stream-json", "--verbose"].map(String::from));
+    } else {
+        args.extend(["--output-format", "json"].map(String::from));
+    }
+    args.push("--system-prompt".to_string());
+    args.push(system_prompt.to_string());
+
+    // Session resume
+    if let Some(ref session) = options.claude_session {
+        args.push("--resume".to_string());
+        args.push(session.clone());
+    }
+
+    // Permission-based tool restrictions
+    if let Some(Permission::Normal) = options.permission {
+        args.push("--allowedTools".to_string());
+        args.push(String::new());
+    }
+
+    // User message
+    args.push(message.to_string());
+    args
+}
+
+/// `KEY=value` auth variables to forward to Claude:
+/// CLAUDE_CODE_OAUTH_TOKEN and/or ANTHROPIC_API_KEY, if set on the host.
+pub fn auth_env() -> Vec<String> {
+    ["CLAUDE_CODE_OAUTH_TOKEN", "ANTHROPIC_API_KEY"]
+        .iter()
+        .filter_map(|key| {
+            std::env::var(key)
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| format!("{}={}", key, v))
+        })
+        .collect()
+}
+
+/// Feed a stdout chunk to the stream parser (if streaming) and push the
+/// updated partial text to the sink.
+pub fn feed_stream(parser: &mut Option<StreamJsonParser>, sink: Option<&ReplySink>, chunk: &[u8]) {
+    if let (Some(p), Some(sink)) = (parser.as_mut(), sink) {
+        if p.feed(chunk) {
+            let _ = sink.send(ReplyUpdate::Partial(p.text().to_string()));
+        }
+    }
+}
+
+/// Turn the output of a completed run into an `ExecClaudeResult`.
+/// `parser` is the stream parser if the run used stream-json.
+pub fn finish_run(stdout: &str, stderr: String, parser: Option<StreamJsonParser>) -> ExecClaudeResult {
+    let (output, claude) = match parser {
+        Some(mut p) => {
+            p.finish();
+            let claude = p.result().cloned();
+            (p.into_output(), claude)
+        }
+        None => match parse_json_output(stdout) {
+            Some(r) => (r.result.clone().unwrap_or_default().trim().to_string(), Some(r)),
+            // Not JSON (e.g. a CLI startup error): pass the raw text through
+            None => (stdout.trim().to_string(), None),
+        },
+    };
+
+    let ok = !claude.as_ref().is_some_and(|r| r.is_error);
+    let output = if output.is_empty() {
+        "(Claude returned no content)".to_string()
+    } else {
+        output
+    };
+    ExecClaudeResult {
+        ok,
+        output,
+        stderr,
+        claude,
+    }
+}
+
+// ============================================
+// Result object
+// ============================================
+
+/// Final result object printed by `claude --print --output-format json`,
+/// and as the last event of `stream-json` output.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ClaudeResult {
+    pub session_id: Option<String>,
+    pub result: Option<String>,
+    pub is_error: bool,
+    pub num_turns: u32,
+    pub total_cost_usd: f64,
+    pub usage: ClaudeUsage,
+}
+
+/// Token usage reported in a `ClaudeResult`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ClaudeUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+}
+
+/// Parse the stdout of `--output-format json`.
+///
+/// Recent CLI versions print a single result object; some print the whole
+/// event list as a JSON array, in which case the last `result` event is used.
+pub fn parse_json_output(stdout: &str) -> Option<ClaudeResult> {
+    let value: serde_json::Value = serde_json::from_str(stdout.trim()).ok()?;
+    let result = match value {
+        serde_json::Value::Array(events) => events
+            .into_iter()
+            .rev()
+            .find(|e| e.get("type").and_then(|t| t.as_str()) == Some("result"))?,
+        obj => obj,
+    };
+    serde_json::from_value(result).ok()
+}
+
+// ============================================
+// stream-json event types
+// ============================================
+
+/// One line of `claude --print --output-format stream-json --verbose` output.
+/// Only the fields the bridge cares about are modelled; everything else is ignored.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    Assistant {
+        message: AssistantMessage,
+    },
+    Result(ClaudeResult),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct AssistantMessage {
+    #[serde(default)]
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+// ============================================
+// StreamJsonParser
+// ============================================
+
+/// Incremental parser for `stream-json` output.
+///
+/// Docker exec output arrives in arbitrary chunks, so bytes are buffered until a
+/// full line is available. Text blocks from each assistant message are appended
+/// to the running reply, which is what partial updates show.
+#[derive(Debug, Default)]
+pub struct StreamJsonParser {
+    line_buf: Vec<u8>,
+    text: String,
+    result: Option<ClaudeResult>,
+}
+
+impl StreamJsonParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a raw stdout chunk. Returns true if the accumulated text changed.
+    pub fn feed(&mut self, chunk: &[u8]) -> bool {
+        self.line_buf.extend_from_slice(chunk);
+
+        let mut changed = false;
+        while let Some(pos) = self.line_buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.line_buf.drain(..=pos).collect();
+            changed |= self.handle_line(&line);
+        }
+        changed
+    }
+
+    /// Flush a trailing line that was not newline-terminated.
+    /// Returns true if the accumulated text changed.
+    pub fn finish(&mut self) -> bool {
+        if self.line_buf.is_empty() {
+            return false;
+        }
+        let line = std::mem::take(&mut self.line_buf);
+        self.handle_line(&line)
+    }
+
+    /// Text accumulated from assistant messages so far.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The final `result` event, once it has been seen.
+    pub fn result(&self) -> Option<&ClaudeResult> {
+        self.result.as_ref()
+    }
+
+    /// The final reply: the accumulated assistant text, which partial
+    /// updates already showed. The `result` event only holds the last turn's
+    /// text, so it is used instead just when it says something else (an
+    /// error, or more than was streamed).
+    pub fn into_output(self) -> String {
+        let text = self.text.trim();
+        match self.result.and_then(|r| r.result) {
+            Some(r) if !r.trim().is_empty() && !text.ends_with(r.trim()) => r.trim().to_string(),
+            _ => text.to_string(),
+        }
+    }
+
+    fn handle_line(&mut self, line: &[u8]) -> bool {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.is_empty() {
+            return false;
+        }
+
+        let event: StreamEvent = match serde_json::from_str(line) {
+            Ok(e) => e,
+            Err(e) => {
+                debug!("Skipping unparseable stream-json line: {}", e);
+                return false;
+            }
+        };
+
+        match event {
+            StreamEvent::Assistant { message } => {
+                let mut changed = false;
+                for block in message.content {
+                    if let ContentBlock::Text { text } = block {
+                        if text.is_empty() {
+                            continue;
+                        }
+                        if !self.text.is_empty() {
+                            self.text.push_str("\n\n");
+                        }
+                        self.text.push_str(&text);
+                        changed = true;
+                    }
+                }
+                changed
+            }
+            StreamEvent::Result(result) => {
+                self.result = Some(result);
+                false
+            }
+            StreamEvent::Other => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trimmed-down transcript of a real `claude -p --output-format stream-json --verbose` run.
+    const TRANSCRIPT: &str = concat!(
+        r#"{"type":"system","subtype":"init","session_id":"0f6c2c4e-1b7a-4d3e-9a51-2d0c7c1f9b11","tools":["Bash","Read"],"model":"claude-sonnet-4"}"#,
+        "\n",
+        r#"{"type":"assistant","message":{"id":"msg_01","role":"assistant","content":[{"type":"text","text":"Let me check the files."},{"type":"tool_use","id":"tu_1","name":"Bash","input":{"command":"ls"}}]},"session_id":"0f6c2c4e-1b7a-4d3e-9a51-2d0c7c1f9b11"}"#,
+        "\n",
+        r#"{"type":"user","message":{"role":"user","content":[{"type":"tool_result","tool_use_id":"tu_1","content":"main.py\n"}]},"session_id":"0f6c2c4e-1b7a-4d3e-9a51-2d0c7c1f9b11"}"#,
+        "\n",
+        r#"{"type":"assistant","message":{"id":"msg_02","role":"assistant","content":[{"type":"text","text":"There is one file: main.py"}]},"session_id":"0f6c2c4e-1b7a-4d3e-9a51-2d0c7c1f9b11"}"#,
+        "\n",
+        r#"{"type":"result","subtype":"success","is_error":false,"duration_ms":5321,"num_turns":2,"result":"There is one file: main.py","session_id":"0f6c2c4e-1b7a-4d3e-9a51-2d0c7c1f9b11","total_cost_usd":0.0123,"usage":{"input_tokens":12,"cache_creation_input_tokens":0,"cache_read_input_tokens":3400,"output_tokens":42}}"#,
+        "\n",
+    );
+
+    #[test]
+    fn parses_transcript_in_one_chunk() {
+        let mut p = StreamJsonParser::new();
+        assert!(p.feed(TRANSCRIPT.as_bytes()));
+        assert_eq!(p.text(), "Let me check the files.\n\nThere is one file: main.py");
+
+        let result = p.result().cloned().unwrap();
+        assert_eq!(
+            result.session_id.as_deref(),
+            Some("0f6c2c4e-1b7a-4d3e-9a51-2d0c7c1f9b11")
+        );
+        assert_eq!(result.num_turns, 2);
+        assert_eq!(result.usage.cache_read_input_tokens, 3400);
+        // Ends with what was streamed, not just the last turn
+        assert_eq!(p.into_output(), "Let me check the files.\n\nThere is one file: main.py");
+    }
+
+    #[test]
+    fn result_replaces_streamed_text_only_when_it_differs() {
+        let turn = b"{\"type\":\"assistant\",\"message\":{\"content\":[{\"type\":\"text\",\"text\":\"Working\"}]}}\n";
+        let mut p = StreamJsonParser::new();
+        p.feed(turn);
+        p.feed(b"{\"type\":\"result\",\"is_error\":true,\"result\":\"Max turns reached\"}\n");
+        assert_eq!(p.into_output(), "Max turns reached");
+
+        let mut p = StreamJsonParser::new();
+        p.feed(turn);
+        p.feed(b"{\"type\":\"result\",\"result\":\"Working. Done.\"}\n");
+        assert_eq!(p.into_output(), "Working. Done.");
+    }
+
+    #[test]
+    fn parses_transcript_in_small_chunks() {
+        let mut p = StreamJsonParser::new();
+        let mut snapshots = Vec::new();
+        for chunk in TRANSCRIPT.as_bytes().chunks(7) {
+            if p.feed(chunk) {
+                snapshots.push(p.text().to_string());
+            }
+        }
+        assert!(!p.finish());
+        assert_eq!(
+            snapshots,
+            vec![
+                "Let me check the files.".to_string(),
+                "Let me check the files.\n\nThere is one file: main.py".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn multibyte_text_split_across_chunks() {
+        let line = r#"{"type":"assistant","message":{"content":[{"type":"text","text":"你好，世界"}]}}"#;
+        let bytes = line.as_bytes();
+        let mut p = StreamJsonParser::new();
+        // Split in the middle of a 3-byte character
+        let mid = line.find('好').unwrap() + 1;
+        assert!(!p.feed(&bytes[..mid]));
+        assert!(!p.feed(&bytes[mid..]));
+        // No trailing newline: only flushed by finish()
+        assert!(p.finish());
+        assert_eq!(p.text(), "你好，世界");
+    }
+
+    #[test]
+    fn falls_back_to_text_without_result_event() {
+        let mut p = StreamJsonParser::new();
+        p.feed(b"{\"type\":\"assistant\",\"message\":{\"content\":[{\"type\":\"text\",\"text\":\"partial\"}]}}\n");
+        assert_eq!(p.into_output(), "partial");
+    }
+
+    #[test]
+    fn parse_json_output_single_object() {
+        let stdout = r#"{"type":"result","subtype":"success","is_error":false,"num_turns":1,"result":"Hi!","session_id":"abc-123","total_cost_usd":0.0042,"usage":{"input_tokens":5,"cache_creation_input_tokens":120,"cache_read_input_tokens":0,"output_tokens":7}}"#;
+        let r = parse_json_output(stdout).unwrap();
+        assert_eq!(
+            r,
+            ClaudeResult {
+                session_id: Some("abc-123".into()),
+                result: Some("Hi!".into()),
+                is_error: false,
+                num_turns: 1,
+                total_cost_usd: 0.0042,
+                usage: ClaudeUsage {
+                    input_tokens: 5,
+                    output_tokens: 7,
+                    cache_creation_input_tokens: 120,
+                    cache_read_input_tokens: 0,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn parse_json_output_event_array() {
+        let stdout = r#"[{"type":"system","subtype":"init","session_id":"s1"},{"type":"result","is_error":true,"result":"Credit balance is too low","session_id":"s1"}]"#;
+        let r = parse_json_output(stdout).unwrap();
+        assert!(r.is_error);
+        assert_eq!(r.session_id.as_deref(), Some("s1"));
+        assert_eq!(r.result.as_deref(), Some("Credit balance is too low"));
+    }
+
+    #[test]
+    fn parse_json_output_rejects_plain_text() {
+        assert!(parse_json_output("Error: not logged in").is_none());
+        assert!(parse_json_output("").is_none());
+    }
+
+    #[test]
+    fn build_args_json_with_resume() {
+        let options = ExecClaudeOptions {
+            claude_session: Some("cs-1".into()),
+            permission: Some(Permission::Trusted),
+            ..Default::default()
+        };
+        let args = build_args("sys", "hello", &options);
+        assert_eq!(
+            args,
+            vec!["--print", "--output-format", "json", "--system-prompt", "sys", "--resume", "cs-1", "hello"]
+        );
+    }
+
+    #[test]
+    fn build_args_stream_normal_user() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let options = ExecClaudeOptions {
+            permission: Some(Permission::Normal),
+            stream: Some(tx),
+            ..Default::default()
+        };
+        let args = build_args("sys", "hi", &options);
+        assert_eq!(&args[..4], ["--print", "--output-format", "stream-json", "--verbose"]);
+        // Normal users get an empty tool allowlist, right before the message
+        assert_eq!(&args[args.len() - 3..], ["--allowedTools", "", "hi"]);
+    }
+
+    #[test]
+    fn finish_run_marks_cli_errors() {
+        let stdout = r#"{"type":"result","is_error":true,"result":"Invalid API key","session_id":"s"}"#;
+        let r = finish_run(stdout, String::new(), None);
+        assert!(!r.ok);
+        assert_eq!(r.output, "Invalid API key");
+    }
+
+    #[test]
+    fn finish_run_passes_plain_text_through() {
+        let r = finish_run("  command not found  \n", "err".into(), None);
+        assert!(r.ok);
+        assert!(r.claude.is_none());
+        assert_eq!(r.output, "command not found");
+
+        let r = finish_run("", String::new(), None);
+        assert_eq!(r.output, "(Claude returned no content)");
+    }
+
+    #[test]
+    fn ignores_garbage_lines() {
+        let mut p = StreamJsonParser::new();
+        assert!(!p.feed(b"not json\n\n{\"type\":\"unknown_event\"}\n"));
+        assert_eq!(p.into_output(), "");
+    }
+}
@@ -1,20 +1,113 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use regex::Regex;
+use anyhow::{Context, Result};
 use tokio::sync::Mutex;
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use crate::database::{Database, Friend, Session};
-use crate::docker_manager::{
-    ContainerInfo, ContainerStats, DockerManager, ExecClaudeOptions, Permission,
-};
+use crate::agent_backend::{AgentBackend, ContainerStatus};
+use crate::claude_cli::{ClaudeResult, ExecClaudeOptions, ReplySink, ReplyUpdate};
+use crate::config::OutboxConfig;
+use crate::database::{Database, Friend, Session, TokenCounts};
+use crate::docker_manager::{ContainerInfo, Permission, WorkspaceEntry};
+use crate::scheduler::{QueueDepth, Scheduler};
+use crate::snapshots::{SnapshotDir, SnapshotInfo, SnapshotPolicy, AUTO_PREFIX};
+use crate::wechat_bot::{Attachment, AttachmentKind};
 
 /// Maximum response length before truncation (WeChat message friendly).
 const MAX_RESPONSE_LEN: usize = 4000;
 
+/// Workspace directory that received attachments are saved to.
+const INBOX_DIR: &str = "inbox";
+
+/// Workspace directory whose new files are sent to the user after each run.
+const OUTBOX_DIR: &str = "outbox";
+
+/// How long a workspace usage scan is trusted for a user under quota.
+const USAGE_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Workspace disk quota per permission level, in bytes. 0 = unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WorkspaceQuota {
+    pub admin: u64,
+    pub trusted: u64,
+    pub normal: u64,
+}
+
+impl WorkspaceQuota {
+    pub fn for_level(&self, permission: Permission) -> u64 {
+        match permission {
+            Permission::Admin => self.admin,
+            Permission::Trusted => self.trusted,
+            Permission::Normal => self.normal,
+        }
+    }
+}
+
+/// Which outbox files may be sent to users.
+#[derive(Debug, Clone)]
+pub struct OutboxPolicy {
+    /// Largest file sent, in bytes.
+    pub max_bytes: u64,
+    /// Most files sent after one run.
+    pub max_files: usize,
+    /// Lowercase file extensions each level may receive; "*" allows any.
+    pub admin: Vec<String>,
+    pub trusted: Vec<String>,
+    pub normal: Vec<String>,
+}
+
+impl OutboxPolicy {
+    /// Map the `outbox` config section onto a policy.
+    pub fn from_config(cfg: &OutboxConfig) -> Self {
+        Self {
+            max_bytes: cfg.max_file_size_mb * 1024 * 1024,
+            max_files: cfg.max_files,
+            admin: cfg.allowed_types.admin.clone(),
+            trusted: cfg.allowed_types.trusted.clone(),
+            normal: cfg.allowed_types.normal.clone(),
+        }
+    }
+
+    /// Whether a user with `permission` may receive `file_name`.
+    pub fn allows(&self, permission: Permission, file_name: &str) -> bool {
+        let allowed = match permission {
+            Permission::Admin => &self.admin,
+            Permission::Trusted => &self.trusted,
+            Permission::Normal => &self.normal,
+        };
+        let ext = file_extension(file_name);
+        allowed
+            .iter()
+            .any(|t| t == "*" || ext.as_deref() == Some(t.to_lowercase().as_str()))
+    }
+}
+
+/// Lowercase extension of a file name, if it has one.
+fn file_extension(file_name: &str) -> Option<String> {
+    Path::new(file_name)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+}
+
+/// Make a received file name safe to use as a single path component.
+pub fn sanitize_file_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let clean: String = base
+        .chars()
+        .map(|c| if c.is_control() || ":*?\"<>|".contains(c) { '_' } else { c })
+        .collect();
+    let clean = truncate_str(clean.trim().trim_start_matches('.'), 100);
+    if clean.is_empty() {
+        "file".to_string()
+    } else {
+        clean.to_string()
+    }
+}
+
 /// Truncate a string to at most `max_bytes` bytes at a valid UTF-8 char boundary.
 fn truncate_str(s: &str, max_bytes: usize) -> &str {
     if s.len() <= max_bytes {
@@ -27,40 +120,94 @@ fn truncate_str(s: &str, max_bytes: usize) -> &str {
     &s[..end]
 }
 
-/// Claude Code executor backed by Docker containers.
+/// Claude Code executor on top of a pluggable [`AgentBackend`].
 ///
-/// Each user's Claude runs in an isolated container. The executor manages:
+/// Each user's Claude runs in an environment provided by the backend
+/// (normally an isolated Docker container). The executor manages:
 /// - Session lifecycle (create, expire, resume)
 /// - Concurrency guard (one request per user at a time)
+/// - Global run limit with weighted fair queueing across users
 /// - System prompt construction
 /// - Response truncation for WeChat
 pub struct ClaudeExecutor {
-    docker: Arc<DockerManager>,
+    backend: Arc<dyn AgentBackend>,
     db: Arc<Database>,
     /// Set of wxids currently being processed (concurrency guard).
     active_tasks: Mutex<HashSet<String>>,
+    /// wxid -> lock held while a snapshot is restored; requests wait on it.
+    restore_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
     /// Session expiry in minutes.
     session_expire_minutes: u64,
     /// Claude execution timeout in seconds.
     timeout: u64,
+    /// Global limit on concurrent Claude runs.
+    scheduler: Arc<Scheduler>,
+    /// Whether partial reply text is passed on to the reply sink.
+    partial_replies: bool,
+    /// Send new outbox files after each run; off when `None`.
+    outbox: Option<OutboxPolicy>,
+    workspace_quota: WorkspaceQuota,
+    /// wxid -> last scanned workspace usage in bytes, and when it was scanned.
+    workspace_usage: Mutex<HashMap<String, (u64, Instant)>>,
+    /// Workspace snapshots; off when `None`.
+    snapshots: Option<SnapshotPolicy>,
 }
 
 impl ClaudeExecutor {
     pub fn new(
-        docker: Arc<DockerManager>,
+        backend: Arc<dyn AgentBackend>,
         db: Arc<Database>,
         session_expire_minutes: u64,
         timeout: u64,
     ) -> Self {
         Self {
-            docker,
+            backend,
             db,
             active_tasks: Mutex::new(HashSet::new()),
+            restore_locks: Mutex::new(HashMap::new()),
             session_expire_minutes,
             timeout,
+            scheduler: Arc::new(Scheduler::default()),
+            partial_replies: true,
+            outbox: None,
+            workspace_quota: WorkspaceQuota::default(),
+            workspace_usage: Mutex::new(HashMap::new()),
+            snapshots: None,
         }
     }
 
+    /// Enable `/snapshot` and `/restore`, and automatic snapshots before
+    /// runs where the policy asks for them.
+    pub fn with_snapshots(mut self, policy: SnapshotPolicy) -> Self {
+        self.snapshots = Some(policy);
+        self
+    }
+
+    /// Limit how much each permission level may store in its workspace.
+    pub fn with_workspace_quota(mut self, quota: WorkspaceQuota) -> Self {
+        self.workspace_quota = quota;
+        self
+    }
+
+    /// Send files Claude writes to `outbox/` during a run to the reply sink.
+    pub fn with_outbox(mut self, policy: OutboxPolicy) -> Self {
+        self.outbox = Some(policy);
+        self
+    }
+
+    /// Use `scheduler` to limit concurrent Claude runs.
+    pub fn with_scheduler(mut self, scheduler: Arc<Scheduler>) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Whether to stream partial replies to the sink given to `execute`.
+    /// Queue notices are sent either way.
+    pub fn with_partial_replies(mut self, enabled: bool) -> Self {
+        self.partial_replies = enabled;
+        self
+    }
+
     // ============================================
     // Session management
     // ============================================
@@ -118,6 +265,10 @@ impl ClaudeExecutor {
 
         let tool_note = if friend.permission == "normal" {
             "- WARNING: This user is limited to Q&A only. Do not execute any code, shell commands, or file operations"
+        } else if self.outbox.is_some() {
+            "- This user can request code execution and file operations\n\
+             - Files the user sends are saved in inbox/. To send the user a file, write it to outbox/; \
+             files created or changed there during your run are delivered after your reply"
         } else {
             "- This user can request code execution and file operations"
         };
@@ -149,29 +300,42 @@ impl ClaudeExecutor {
     /// Execute a user's message through Claude in their Docker container.
     ///
     /// Steps:
-    /// 1. Concurrency guard (one request per user)
+    /// 1. Concurrency guard (one request per user), then wait for a global slot
     /// 2. Ensure container is running
     /// 3. Get/create session
     /// 4. Build system prompt
     /// 5. Execute Claude in container
-    /// 6. Extract session ID from stderr
+    /// 6. Record the Claude session ID from the structured result
     /// 7. Truncate response if needed
+    ///
+    /// If `stream` is set, it is told the queue position when the request has
+    /// to wait, partial reply text is pushed to it while Claude runs and new
+    /// outbox files are pushed to it afterwards.
     pub async fn execute(
         &self,
         wxid: &str,
         friend: &Friend,
         message: &str,
+        stream: Option<ReplySink>,
     ) -> String {
-        // Concurrency guard
+        // Concurrency guard. The dispatcher sends one request per user at a
+        // time; a snapshot being restored is waited out.
         {
-            let mut tasks = self.active_tasks.lock().await;
-            if tasks.contains(wxid) {
-                return "Previous message is still being processed, please wait...".to_string();
-            }
-            tasks.insert(wxid.to_string());
+            let restore = self.restore_lock(wxid).await;
+            let _restore = restore.lock().await;
+            self.active_tasks.lock().await.insert(wxid.to_string());
         }
 
-        let result = self.execute_inner(wxid, friend, message).await;
+        let permit = self
+            .scheduler
+            .acquire(wxid, parse_permission(&friend.permission), |position| {
+                if let Some(ref sink) = stream {
+                    let _ = sink.send(ReplyUpdate::Queued(position));
+                }
+            })
+            .await;
+        let result = self.execute_inner(wxid, friend, message, stream).await;
+        drop(permit);
 
         // Release concurrency guard
         {
@@ -187,15 +351,29 @@ impl ClaudeExecutor {
         wxid: &str,
         friend: &Friend,
         message: &str,
+        stream: Option<ReplySink>,
     ) -> String {
         let permission = parse_permission(&friend.permission);
 
         // 1. Ensure container
-        if let Err(e) = self.docker.ensure_container(wxid, permission).await {
+        if let Err(e) = self.backend.prepare(wxid, permission).await {
             error!("Failed to ensure container for {}: {}", wxid, e);
             return "Container setup failed, please try again later".to_string();
         }
 
+        // Keep an undo point for levels that want one before every run
+        if self.snapshots.as_ref().is_some_and(|p| p.auto_for(permission)) {
+            let name = format!("{}{}", AUTO_PREFIX, chrono::Local::now().format("%Y%m%d-%H%M%S"));
+            if let Some((needed, quota)) = self.snapshot_over_quota(wxid, permission).await {
+                info!(
+                    "Skipping automatic snapshot for {}: {} > {} bytes",
+                    wxid, needed, quota
+                );
+            } else if let Err(e) = self.create_snapshot(wxid, &name).await {
+                warn!("Automatic snapshot failed for {}: {:#}", wxid, e);
+            }
+        }
+
         // 2. Get/create session
         let session = match self.get_or_create_session(wxid) {
             Ok(s) => s,
@@ -224,16 +402,20 @@ impl ClaudeExecutor {
             timeout: Some(self.timeout),
             claude_session: session.claude_session.clone(),
             permission: Some(permission),
+            stream: stream.clone().filter(|_| self.partial_replies),
         };
 
+        let started = chrono::Utc::now().timestamp_millis();
         let result = self
-            .docker
-            .exec_claude(wxid, &system_prompt, message, options)
+            .backend
+            .execute(wxid, &system_prompt, message, options)
             .await;
 
-        // 5. Try to extract Claude session ID from stderr
-        if !result.stderr.is_empty() {
-            self.try_extract_session_id(&session.id, &result.stderr);
+        // 5. Remember the Claude session ID so the next message can --resume it
+        if let Some(ref claude) = result.claude {
+            self.record_result(&session, claude);
+        } else if !result.stderr.is_empty() {
+            debug!("Claude stderr [{}]: {}", wxid, truncate_str(&result.stderr, 200));
         }
 
         // 6. Truncate if needed
@@ -248,22 +430,109 @@ impl ClaudeExecutor {
             response.push_str("\n\n... (response truncated)");
         }
 
+        // 7. Send the files Claude left in the outbox
+        if let (Some(policy), Some(sink)) = (&self.outbox, &stream) {
+            let held_back = self.deliver_outbox(wxid, permission, policy, started, sink).await;
+            if !held_back.is_empty() {
+                response.push_str("\n\nFiles not sent:");
+                for note in held_back {
+                    response.push_str("\n- ");
+                    response.push_str(&note);
+                }
+            }
+        }
+
         response
     }
 
-    /// Try to extract a Claude session ID from stderr output.
-    fn try_extract_session_id(&self, session_id: &str, stderr: &str) {
-        let re = Regex::new(r"(?i)session[:\s]+([a-f0-9-]+)").unwrap();
-        if let Some(captures) = re.captures(stderr) {
-            if let Some(claude_session) = captures.get(1) {
-                let cs = claude_session.as_str();
-                if let Err(e) = self.db.session_set_claude_session(session_id, cs) {
+    /// Push the files written to `outbox/` since `since` (Unix ms) to
+    /// `sink`. Returns a note for each file the policy held back.
+    async fn deliver_outbox(
+        &self,
+        wxid: &str,
+        permission: Permission,
+        policy: &OutboxPolicy,
+        since: i64,
+        sink: &ReplySink,
+    ) -> Vec<String> {
+        // No outbox directory means nothing to send
+        let Ok(entries) = self.backend.list_files(wxid, OUTBOX_DIR).await else {
+            return Vec::new();
+        };
+
+        let mut held_back = Vec::new();
+        let mut sent = 0;
+        for entry in entries.into_iter().filter(|e| !e.is_dir && e.modified >= since) {
+            let name = entry.name;
+            if sent >= policy.max_files {
+                held_back.push(format!("{} (more than {} files)", name, policy.max_files));
+                continue;
+            }
+            if !policy.allows(permission, &name) {
+                held_back.push(format!("{} (file type not allowed)", name));
+                continue;
+            }
+            if entry.size > policy.max_bytes {
+                let limit_mb = policy.max_bytes / (1024 * 1024);
+                held_back.push(format!("{} (larger than {} MB)", name, limit_mb));
+                continue;
+            }
+
+            let path = format!("{}/{}", OUTBOX_DIR, name);
+            match self.backend.read_file(wxid, &path, policy.max_bytes).await {
+                Ok(data) => {
+                    info!("Sending {} to {} ({} bytes)", path, wxid, data.len());
+                    let _ = sink.send(ReplyUpdate::File(Attachment {
+                        kind: AttachmentKind::for_file_name(&name),
+                        file_name: name,
+                        data,
+                    }));
+                    sent += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to read {} of {}: {}", path, wxid, e);
+                    held_back.push(format!("{} (could not be read)", name));
+                }
+            }
+        }
+        held_back
+    }
+
+    /// Persist what the CLI reported about a finished run.
+    fn record_result(&self, session: &Session, claude: &ClaudeResult) {
+        if let Some(ref cs) = claude.session_id {
+            if session.claude_session.as_deref() != Some(cs.as_str()) {
+                if let Err(e) = self.db.session_set_claude_session(&session.id, cs) {
                     warn!("Failed to save Claude session ID: {}", e);
                 } else {
                     debug!("Captured Claude session ID: {}", cs);
                 }
             }
         }
+
+        let tokens = TokenCounts {
+            input: claude.usage.input_tokens as i64,
+            output: claude.usage.output_tokens as i64,
+            cache_creation: claude.usage.cache_creation_input_tokens as i64,
+            cache_read: claude.usage.cache_read_input_tokens as i64,
+        };
+        if let Err(e) = self.db.usage_record(
+            &session.wxid,
+            Some(&session.id),
+            claude.session_id.as_deref(),
+            &tokens,
+            claude.total_cost_usd,
+        ) {
+            warn!("Failed to record usage for {}: {}", session.wxid, e);
+        }
+        debug!(
+            "Claude run finished: turns={} cost=${:.4} in={} out={} cache_read={}",
+            claude.num_turns,
+            claude.total_cost_usd,
+            tokens.input,
+            tokens.output,
+            tokens.cache_read,
+        );
     }
 
     // ============================================
@@ -273,12 +542,7 @@ impl ClaudeExecutor {
     /// Clear a user's session, optionally restarting their container.
     pub async fn clear_session(&self, wxid: &str, restart_container: bool) -> Result<()> {
         self.db.session_clear_user(wxid)?;
-        if restart_container {
-            let _ = self.docker.stop_container(wxid).await;
-            self.docker
-                .ensure_container(wxid, Permission::Normal)
-                .await?;
-        }
+        self.backend.clear_session(wxid, restart_container).await?;
         info!(
             "Cleared session: {}{}",
             wxid,
@@ -291,51 +555,287 @@ impl ClaudeExecutor {
         Ok(())
     }
 
-    /// Kill any running Claude process in a user's container.
-    pub async fn kill_process(&self, wxid: &str) -> bool {
-        match self
-            .docker
-            .exec_command(wxid, "pkill -f claude || true", true)
-            .await
-        {
-            Ok(_) => {
-                let mut tasks = self.active_tasks.lock().await;
-                tasks.remove(wxid);
-                true
+    /// Save received attachments into the user's workspace `inbox/`, preparing
+    /// the environment first. Returns their paths relative to the workspace.
+    pub async fn save_attachments(
+        &self,
+        wxid: &str,
+        permission: Permission,
+        attachments: &[Attachment],
+    ) -> Result<Vec<String>> {
+        self.backend.prepare(wxid, permission).await?;
+
+        // Timestamped names keep earlier uploads; the counter separates
+        // same-named files within one message
+        let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+        let mut paths: Vec<String> = Vec::new();
+        for attachment in attachments {
+            let name = sanitize_file_name(&attachment.file_name);
+            let mut path = format!("{}/{}-{}", INBOX_DIR, stamp, name);
+            let mut n = 1;
+            while paths.contains(&path) {
+                n += 1;
+                path = format!("{}/{}-{}-{}", INBOX_DIR, stamp, n, name);
             }
-            Err(_) => false,
+            self.backend.write_file(wxid, &path, &attachment.data).await?;
+            self.note_written(wxid, attachment.data.len()).await;
+            info!("Saved {} for {}: {}", attachment.kind.as_str(), wxid, path);
+            paths.push(path);
         }
+        Ok(paths)
     }
 
-    /// Get status info for a user's container.
-    pub async fn get_container_status(&self, wxid: &str) -> ContainerStatus {
-        let name = self.docker.container_name(wxid);
-        let running = self.docker.is_running(&name).await;
-        let stats = if running {
-            self.docker.get_stats(wxid).await.ok().flatten()
+    // ============================================
+    // Snapshots
+    // ============================================
+
+    /// Whether workspace snapshots are available.
+    pub fn snapshots_enabled(&self) -> bool {
+        self.snapshots.is_some()
+    }
+
+    fn snapshot_dir(&self, wxid: &str) -> Result<(SnapshotDir, &SnapshotPolicy)> {
+        let policy = self.snapshots.as_ref().context("Snapshots are disabled")?;
+        let workspace = self
+            .backend
+            .host_workspace(wxid)
+            .context("This backend has no workspace on the host to snapshot")?;
+        Ok((SnapshotDir::for_workspace(&workspace), policy))
+    }
+
+    /// Archive the user's workspace as snapshot `name`. Names starting with
+    /// `auto-` count against the automatic snapshot limit.
+    pub async fn create_snapshot(&self, wxid: &str, name: &str) -> Result<SnapshotInfo> {
+        let (dir, policy) = self.snapshot_dir(wxid)?;
+        let keep = if name.starts_with(AUTO_PREFIX) {
+            policy.keep_auto
         } else {
-            None
+            policy.keep_manual
         };
-        let disk = if running {
-            self.docker
-                .exec_command(wxid, "du -sh /home/sandbox/workspace", false)
-                .await
-                .ok()
+        let name = name.to_string();
+        let info = tokio::task::spawn_blocking(move || dir.create(&name, keep)).await??;
+        info!("Snapshot {} of {}: {} bytes", info.name, wxid, info.size);
+        Ok(info)
+    }
+
+    /// The user's snapshots, newest first.
+    pub async fn list_snapshots(&self, wxid: &str) -> Result<Vec<SnapshotInfo>> {
+        let (dir, _) = self.snapshot_dir(wxid)?;
+        tokio::task::spawn_blocking(move || dir.list()).await?
+    }
+
+    async fn restore_lock(&self, wxid: &str) -> Arc<Mutex<()>> {
+        let mut locks = self.restore_locks.lock().await;
+        Arc::clone(locks.entry(wxid.to_string()).or_default())
+    }
+
+    /// Put snapshot `name` back in place of the user's workspace. The user's
+    /// environment is removed first so nothing writes to the workspace while
+    /// it is swapped; it is recreated (with fresh ownership) on next use.
+    pub async fn restore_snapshot(&self, wxid: &str, name: &str) -> Result<()> {
+        let (dir, _) = self.snapshot_dir(wxid)?;
+
+        // Requests arriving meanwhile wait for this lock
+        let restore = self.restore_lock(wxid).await;
+        let _restore = restore.lock().await;
+        {
+            let mut tasks = self.active_tasks.lock().await;
+            if !tasks.insert(wxid.to_string()) {
+                anyhow::bail!("A request for {} is still being processed", wxid);
+            }
+        }
+        let result = async {
+            self.backend.destroy(wxid).await?;
+            let name = name.to_string();
+            tokio::task::spawn_blocking(move || dir.restore(&name)).await?
+        }
+        .await;
+        self.active_tasks.lock().await.remove(wxid);
+        self.workspace_usage.lock().await.remove(wxid);
+
+        if result.is_ok() {
+            info!("Restored snapshot {} for {}", name, wxid);
+        }
+        result
+    }
+
+    // ============================================
+    // Workspace quota
+    // ============================================
+
+    /// Workspace quota for a permission level in bytes (0 = unlimited).
+    pub fn workspace_quota(&self, permission: Permission) -> u64 {
+        self.workspace_quota.for_level(permission)
+    }
+
+    /// If `extra` more bytes would put the user's workspace over quota,
+    /// returns `(used, quota)`. A recent scan is reused while it leaves room;
+    /// otherwise the workspace is rescanned, so files deleted by a user at
+    /// the limit count right away.
+    pub async fn workspace_over_quota(
+        &self,
+        wxid: &str,
+        permission: Permission,
+        extra: u64,
+    ) -> Option<(u64, u64)> {
+        let quota = self.workspace_quota.for_level(permission);
+        if quota == 0 {
+            return None;
+        }
+        if let Some(&(used, scanned)) = self.workspace_usage.lock().await.get(wxid) {
+            if scanned.elapsed() < USAGE_SCAN_INTERVAL && used + extra <= quota {
+                return None;
+            }
+        }
+
+        let used = match self.backend.workspace_usage(wxid).await {
+            Ok(used) => used,
+            Err(e) => {
+                warn!("Failed to measure workspace of {}: {}", wxid, e);
+                return None;
+            }
+        };
+        self.workspace_usage
+            .lock()
+            .await
+            .insert(wxid.to_string(), (used, Instant::now()));
+        if used + extra > quota {
+            info!("Workspace of {} is over quota: {} + {} > {} bytes", wxid, used, extra, quota);
+            Some((used, quota))
         } else {
             None
+        }
+    }
+
+    /// Snapshots live outside the workspace but share its quota. If the
+    /// workspace, the user's snapshots and a new archive of the workspace
+    /// would not fit, returns `(needed, quota)`.
+    pub async fn snapshot_over_quota(&self, wxid: &str, permission: Permission) -> Option<(u64, u64)> {
+        let quota = self.workspace_quota.for_level(permission);
+        if quota == 0 {
+            return None;
+        }
+        let used = match self.backend.workspace_usage(wxid).await {
+            Ok(used) => used,
+            Err(e) => {
+                warn!("Failed to measure workspace of {}: {}", wxid, e);
+                return None;
+            }
         };
+        let stored: u64 = match self.list_snapshots(wxid).await {
+            Ok(list) => list.iter().map(|s| s.size).sum(),
+            Err(e) => {
+                warn!("Failed to list snapshots of {}: {:#}", wxid, e);
+                return None;
+            }
+        };
+        // The archive holds the whole workspace
+        let needed = used * 2 + stored;
+        (needed > quota).then_some((needed, quota))
+    }
+
+    /// Count bytes written on the user's behalf against their cached usage.
+    async fn note_written(&self, wxid: &str, bytes: usize) {
+        if let Some(entry) = self.workspace_usage.lock().await.get_mut(wxid) {
+            entry.0 += bytes as u64;
+        }
+    }
+
+    /// Outbox policy, if sending files to users is enabled.
+    pub fn outbox_policy(&self) -> Option<&OutboxPolicy> {
+        self.outbox.as_ref()
+    }
+
+    /// List a directory of the user's workspace (`""` for the root).
+    pub async fn list_files(
+        &self,
+        wxid: &str,
+        permission: Permission,
+        dir: &str,
+    ) -> Result<Vec<WorkspaceEntry>> {
+        self.backend.prepare(wxid, permission).await?;
+        self.backend.list_files(wxid, dir).await
+    }
+
+    /// Read a file of at most `max_bytes` from the user's workspace.
+    pub async fn read_file(
+        &self,
+        wxid: &str,
+        permission: Permission,
+        path: &str,
+        max_bytes: u64,
+    ) -> Result<Vec<u8>> {
+        self.backend.prepare(wxid, permission).await?;
+        self.backend.read_file(wxid, path, max_bytes).await
+    }
+
+    /// Write a file into the user's workspace.
+    pub async fn write_file(
+        &self,
+        wxid: &str,
+        permission: Permission,
+        path: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        self.backend.prepare(wxid, permission).await?;
+        self.backend.write_file(wxid, path, data).await?;
+        self.note_written(wxid, data.len()).await;
+        Ok(())
+    }
 
-        ContainerStatus {
-            name,
-            running,
-            stats,
-            disk,
+    /// Delete a file or directory from the user's workspace.
+    pub async fn remove_file(&self, wxid: &str, permission: Permission, path: &str) -> Result<()> {
+        self.backend.prepare(wxid, permission).await?;
+        self.backend.remove_file(wxid, path).await?;
+        self.workspace_usage.lock().await.remove(wxid);
+        Ok(())
+    }
+
+    /// Whether a request for this user is currently being processed.
+    pub async fn is_busy(&self, wxid: &str) -> bool {
+        self.active_tasks.lock().await.contains(wxid)
+    }
+
+    /// Running and waiting Claude requests across all users.
+    pub fn queue_depth(&self) -> QueueDepth {
+        self.scheduler.depth()
+    }
+
+    /// 1-based queue position of the user's waiting request, if any.
+    pub fn queue_position(&self, wxid: &str) -> Option<usize> {
+        self.scheduler.position(wxid)
+    }
+
+    /// Kill the Claude run in progress for a user, if any.
+    pub async fn kill_process(&self, wxid: &str) -> bool {
+        match self.backend.kill(wxid).await {
+            Ok(killed) => {
+                if killed {
+                    let mut tasks = self.active_tasks.lock().await;
+                    tasks.remove(wxid);
+                }
+                killed
+            }
+            Err(e) => {
+                warn!("Failed to kill claude run of {}: {}", wxid, e);
+                false
+            }
         }
     }
 
+    /// Kill every Claude run in progress (on shutdown).
+    pub async fn kill_all(&self) -> usize {
+        self.backend.kill_all().await
+    }
+
+    /// Get status info for a user's container.
+    pub async fn get_container_status(&self, wxid: &str) -> ContainerStatus {
+        self.backend.status(wxid).await
+    }
+
     /// Stop a user's container.
     pub async fn stop_container(&self, wxid: &str) -> Result<bool> {
-        self.docker.stop_container(wxid).await
+        self.backend.stop(wxid).await
     }
 
     /// Destroy a user's container (data volumes are preserved).
@@ -345,7 +845,7 @@ impl ClaudeExecutor {
             let mut tasks = self.active_tasks.lock().await;
             tasks.remove(wxid);
         }
-        self.docker.destroy_container(wxid).await
+        self.backend.destroy(wxid).await
     }
 
     /// Rebuild a user's container.
@@ -355,24 +855,15 @@ impl ClaudeExecutor {
             let mut tasks = self.active_tasks.lock().await;
             tasks.remove(wxid);
         }
-        self.docker.rebuild(wxid, permission).await
+        self.backend.rebuild(wxid, permission).await
     }
 
     /// List all bridge containers.
     pub async fn list_containers(&self) -> Result<Vec<ContainerInfo>> {
-        self.docker.list_containers().await
+        self.backend.list().await
     }
 }
 
-/// Container status info.
-#[derive(Debug)]
-pub struct ContainerStatus {
-    pub name: String,
-    pub running: bool,
-    pub stats: Option<ContainerStats>,
-    pub disk: Option<String>,
-}
-
 /// Parse a permission string to the Permission enum.
 pub fn parse_permission(s: &str) -> Permission {
     match s {
@@ -411,6 +902,33 @@ fn is_session_expired(last_active: &str, expire_minutes: u64) -> bool {
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn requests_wait_for_a_restore() {
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let executor = Arc::new(ClaudeExecutor::new(
+            Arc::new(crate::agent_backend::MockBackend::new()),
+            Arc::clone(&db),
+            60,
+            120,
+        ));
+        db.friend_upsert("wx_r", Some("R"), None, Some("trusted"), None, None).unwrap();
+        let friend = db.friend_get("wx_r").unwrap().unwrap();
+
+        // As if a restore were in progress
+        let restore = executor.restore_lock("wx_r").await;
+        let guard = restore.lock().await;
+        let run = tokio::spawn({
+            let executor = Arc::clone(&executor);
+            async move { executor.execute("wx_r", &friend, "hello", None).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!run.is_finished());
+        assert!(!executor.is_busy("wx_r").await);
+
+        drop(guard);
+        assert_eq!(run.await.unwrap(), "[mock] hello");
+    }
+
     // ============================================
     // parse_permission tests
     // ============================================
@@ -493,4 +1011,40 @@ mod tests {
         // A future timestamp should not be expired
         assert!(!is_session_expired("2099-01-01 00:00:00", 60));
     }
+
+    // ============================================
+    // OutboxPolicy tests
+    // ============================================
+
+    #[test]
+    fn outbox_policy_matches_extensions_per_level() {
+        let policy = OutboxPolicy {
+            max_bytes: 1024,
+            max_files: 10,
+            admin: vec!["*".into()],
+            trusted: vec!["png".into(), "CSV".into()],
+            normal: Vec::new(),
+        };
+        assert!(policy.allows(Permission::Admin, "anything"));
+        assert!(policy.allows(Permission::Trusted, "chart.PNG"));
+        assert!(policy.allows(Permission::Trusted, "data.csv"));
+        assert!(!policy.allows(Permission::Trusted, "archive.tar.gz"));
+        assert!(!policy.allows(Permission::Trusted, "png"));
+        assert!(!policy.allows(Permission::Normal, "chart.png"));
+    }
+
+    // ============================================
+    // sanitize_file_name tests
+    // ============================================
+
+    #[test]
+    fn sanitize_file_name_strips_directories_and_hidden_prefix() {
+        assert_eq!(sanitize_file_name("report.pdf"), "report.pdf");
+        assert_eq!(sanitize_file_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_file_name("C:\\Users\\me\\a.txt"), "a.txt");
+        assert_eq!(sanitize_file_name(".bashrc"), "bashrc");
+        assert_eq!(sanitize_file_name("what?.txt"), "what_.txt");
+        assert_eq!(sanitize_file_name(".."), "file");
+        assert_eq!(sanitize_file_name(""), "file");
+    }
 }
@@ -6,7 +6,7 @@ use std::sync::OnceLock;
 static CONFIG: OnceLock<Config> = OnceLock::new();
 
 /// Top-level configuration, deserialized from config.yaml.
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 #[serde(default)]
 pub struct Config {
     pub admin_wxid: String,
@@ -16,8 +16,15 @@ pub struct Config {
     pub permissions: PermissionsConfig,
     pub session: SessionConfig,
     pub rate_limit: RateLimitConfig,
+    pub quota: QuotaConfig,
+    pub queue: QueueConfig,
+    pub outbox: OutboxConfig,
+    pub snapshots: SnapshotsConfig,
+    pub egress: EgressConfig,
+    pub reaper: ReaperConfig,
     pub security: SecurityConfig,
     pub logging: LoggingConfig,
+    pub database: DatabaseConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -25,6 +32,10 @@ pub struct Config {
 pub struct ClaudeConfig {
     pub cli_path: String,
     pub timeout: u64,
+    /// Stream partial replies to the chat while Claude is still running.
+    pub stream: bool,
+    /// Where Claude runs: "docker" (default), "host" or "mock".
+    pub backend: String,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -33,8 +44,12 @@ pub struct DockerConfig {
     pub image: String,
     pub container_prefix: String,
     pub data_dir: String,
+    /// Engine API socket, e.g. a rootless Podman socket. Empty = DOCKER_HOST or the default socket.
+    pub socket: String,
     pub limits: DockerLimits,
     pub network: DockerNetwork,
+    pub runtime: DockerRuntime,
+    pub pool: DockerPool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -46,6 +61,16 @@ pub struct DockerLimits {
     pub admin_cpus: u32,
     pub pids: u32,
     pub tmp_size: String,
+    pub workspace_size: WorkspaceSize,
+}
+
+/// Workspace disk quota per permission level, e.g. "1g". Empty or "0" = unlimited.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WorkspaceSize {
+    pub admin: String,
+    pub trusted: String,
+    pub normal: String,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -56,6 +81,27 @@ pub struct DockerNetwork {
     pub normal: String,
 }
 
+/// OCI runtime per permission level (e.g. "runsc" for gVisor). Empty = engine default.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct DockerRuntime {
+    pub admin: String,
+    pub trusted: String,
+    pub normal: String,
+}
+
+/// Pre-warmed containers per permission level, so a new friend's first
+/// message doesn't wait for container setup. 0 disables the pool for a level.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DockerPool {
+    pub admin: usize,
+    pub trusted: usize,
+    pub normal: usize,
+    /// Delay after a container is taken from the pool before it is refilled.
+    pub refill_delay_secs: u64,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(default)]
 pub struct TelegramConfig {
@@ -63,6 +109,26 @@ pub struct TelegramConfig {
     pub enabled: bool,
     /// Bot token from @BotFather.
     pub bot_token: String,
+    /// Bot API server, e.g. a self-hosted `telegram-bot-api`.
+    pub api_base: String,
+    /// Receive updates on a local HTTP listener instead of long-polling.
+    pub webhook: TelegramWebhook,
+}
+
+/// Webhook mode. The listener speaks plain HTTP; Telegram only calls HTTPS
+/// URLs, so put a TLS-terminating reverse proxy in front of it.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TelegramWebhook {
+    pub enabled: bool,
+    /// Address the listener binds to.
+    pub listen: String,
+    /// Request path updates are posted to.
+    pub path: String,
+    /// Public HTTPS URL registered with `setWebhook`.
+    pub url: String,
+    /// Expected `X-Telegram-Bot-Api-Secret-Token`; random per start when empty.
+    pub secret_token: String,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -71,6 +137,9 @@ pub struct PermissionsConfig {
     pub notify_unauthorized: bool,
     pub unauthorized_message: String,
     pub default_level: String,
+    /// Level of a newly seen group chat. `blocked` ignores the group until
+    /// the admin `/allow`s it.
+    pub group_default_level: String,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -87,6 +156,114 @@ pub struct RateLimitConfig {
     pub max_per_day: u32,
 }
 
+/// Spend caps per permission level, checked before every Claude run.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub admin: SpendCap,
+    pub trusted: SpendCap,
+    pub normal: SpendCap,
+}
+
+/// Daily / monthly spend limits in USD. 0 means unlimited.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SpendCap {
+    pub daily_usd: f64,
+    pub monthly_usd: f64,
+}
+
+/// Per-user message inboxes in front of Claude.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// Messages a user can have waiting while Claude answers them; more are rejected.
+    pub max_pending: usize,
+    /// Answer everything that arrived while Claude was busy in one turn.
+    pub merge: bool,
+    /// Claude runs in flight across all users.
+    pub max_concurrent: usize,
+    /// Share of freed slots each permission level gets while others wait too.
+    pub weights: QueueWeights,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct QueueWeights {
+    pub admin: u32,
+    pub trusted: u32,
+    pub normal: u32,
+}
+
+/// Files Claude writes to `workspace/outbox/` during a run are sent to the user.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub enabled: bool,
+    /// Larger files are not sent.
+    pub max_file_size_mb: u64,
+    /// Most files sent after one run.
+    pub max_files: usize,
+    /// File extensions each permission level may receive; "*" allows any.
+    pub allowed_types: OutboxAllowedTypes,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct OutboxAllowedTypes {
+    pub admin: Vec<String>,
+    pub trusted: Vec<String>,
+    pub normal: Vec<String>,
+}
+
+/// Workspace snapshots (`/snapshot`, `/restore`), stored next to each workspace.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SnapshotsConfig {
+    pub enabled: bool,
+    /// Manual snapshots kept per user; the oldest are deleted first.
+    pub max_manual: usize,
+    /// Automatic snapshots kept per user.
+    pub max_auto: usize,
+    /// Snapshot the workspace before every Claude run, per permission level.
+    pub auto_before_run: SnapshotAuto,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SnapshotAuto {
+    pub admin: bool,
+    pub trusted: bool,
+    pub normal: bool,
+}
+
+/// Egress proxy for the trusted `claude-limited` network.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct EgressConfig {
+    /// Make `claude-limited` internal and route it through the allowlist proxy.
+    pub enabled: bool,
+    /// Proxy port on the network gateway.
+    pub port: u16,
+    /// Allowed destinations: "host", "*.domain" or "host:port" (default port 443).
+    pub allowlist: Vec<String>,
+}
+
+/// Idle container reaper: stops, then removes, containers of inactive friends.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReaperConfig {
+    pub enabled: bool,
+    /// How often to look for idle containers.
+    pub interval_minutes: u64,
+    /// Stop a container after this long without activity.
+    pub stop_after_minutes: u64,
+    /// Remove a container (data dir is kept) after this long without activity.
+    pub remove_after_minutes: u64,
+    /// Send the admin a summary whenever containers were stopped or removed.
+    pub notify_admin: bool,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(default)]
 pub struct SecurityConfig {
@@ -100,31 +277,65 @@ pub struct LoggingConfig {
     pub level: String,
     pub file: String,
     pub log_message_content: bool,
+    pub retention: LoggingRetention,
+    pub checkpoints: LoggingCheckpoints,
 }
 
-// --- Default implementations matching the JS version ---
+/// Audit log retention, applied by the hourly cleanup task. 0 disables a
+/// limit; all are off by default.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LoggingRetention {
+    /// Delete entries older than this.
+    pub max_age_days: u64,
+    /// Keep only the newest entries of each friend or group.
+    pub max_rows_per_user: u64,
+    /// Hide message text older than this, keeping the entry itself.
+    pub redact_after_days: u64,
+    /// Deleted entries are first written here as gzipped JSONL; empty
+    /// deletes them without an archive.
+    pub archive_dir: String,
+}
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            admin_wxid: String::new(),
-            claude: ClaudeConfig::default(),
-            docker: DockerConfig::default(),
-            telegram: TelegramConfig::default(),
-            permissions: PermissionsConfig::default(),
-            session: SessionConfig::default(),
-            rate_limit: RateLimitConfig::default(),
-            security: SecurityConfig::default(),
-            logging: LoggingConfig::default(),
-        }
-    }
+/// Signed checkpoints of the audit log's hash chain, written hourly to a
+/// file outside the database (see `/audit verify`).
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LoggingCheckpoints {
+    pub enabled: bool,
+    pub file: String,
+    /// HMAC key; generated on first use. Keep it away from the database.
+    pub key_file: String,
+}
+
+/// The SQLite database and its backups (`/backup`, `backup` subcommand).
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub path: String,
+    /// How long a query waits on a lock held by another connection (e.g. a
+    /// backup in progress) before failing.
+    pub busy_timeout_ms: u64,
+    pub backup: DatabaseBackup,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DatabaseBackup {
+    pub dir: String,
+    /// Backups kept; the oldest are deleted first.
+    pub keep: usize,
 }
 
+// --- Default implementations matching the JS version ---
+
 impl Default for ClaudeConfig {
     fn default() -> Self {
         Self {
             cli_path: "claude".into(),
             timeout: 120,
+            stream: false,
+            backend: "docker".into(),
         }
     }
 }
@@ -135,8 +346,11 @@ impl Default for DockerConfig {
             image: "claude-sandbox:latest".into(),
             container_prefix: "claude-friend-".into(),
             data_dir: "~/claude-bridge-data".into(),
+            socket: String::new(),
             limits: DockerLimits::default(),
             network: DockerNetwork::default(),
+            runtime: DockerRuntime::default(),
+            pool: DockerPool::default(),
         }
     }
 }
@@ -150,6 +364,17 @@ impl Default for DockerLimits {
             admin_cpus: 2,
             pids: 100,
             tmp_size: "100m".into(),
+            workspace_size: WorkspaceSize::default(),
+        }
+    }
+}
+
+impl Default for WorkspaceSize {
+    fn default() -> Self {
+        Self {
+            admin: String::new(),
+            trusted: "5g".into(),
+            normal: "1g".into(),
         }
     }
 }
@@ -164,11 +389,36 @@ impl Default for DockerNetwork {
     }
 }
 
+impl Default for DockerPool {
+    fn default() -> Self {
+        Self {
+            admin: 0,
+            trusted: 0,
+            normal: 0,
+            refill_delay_secs: 5,
+        }
+    }
+}
+
 impl Default for TelegramConfig {
     fn default() -> Self {
         Self {
             enabled: false,
             bot_token: String::new(),
+            api_base: "https://api.telegram.org".into(),
+            webhook: TelegramWebhook::default(),
+        }
+    }
+}
+
+impl Default for TelegramWebhook {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:8081".into(),
+            path: "/telegram/webhook".into(),
+            url: String::new(),
+            secret_token: String::new(),
         }
     }
 }
@@ -179,6 +429,7 @@ impl Default for PermissionsConfig {
             notify_unauthorized: true,
             unauthorized_message: "抱歉，你还没有被授权使用此服务。".into(),
             default_level: "normal".into(),
+            group_default_level: "blocked".into(),
         }
     }
 }
@@ -201,6 +452,90 @@ impl Default for RateLimitConfig {
     }
 }
 
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_pending: 5,
+            merge: true,
+            max_concurrent: 4,
+            weights: QueueWeights::default(),
+        }
+    }
+}
+
+impl Default for QueueWeights {
+    fn default() -> Self {
+        Self {
+            admin: 4,
+            trusted: 2,
+            normal: 1,
+        }
+    }
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_file_size_mb: 20,
+            max_files: 10,
+            allowed_types: OutboxAllowedTypes::default(),
+        }
+    }
+}
+
+impl Default for OutboxAllowedTypes {
+    fn default() -> Self {
+        let types = |list: &[&str]| list.iter().map(|t| t.to_string()).collect();
+        Self {
+            admin: types(&["*"]),
+            trusted: types(&[
+                "png", "jpg", "jpeg", "gif", "webp", "svg", "pdf", "txt", "md", "csv", "json",
+                "html", "xlsx", "docx", "pptx", "zip",
+            ]),
+            normal: types(&["png", "jpg", "jpeg", "gif", "webp", "pdf", "txt", "md", "csv"]),
+        }
+    }
+}
+
+impl Default for SnapshotsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_manual: 5,
+            max_auto: 3,
+            auto_before_run: SnapshotAuto::default(),
+        }
+    }
+}
+
+impl Default for EgressConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            port: 3128,
+            allowlist: vec![
+                "api.anthropic.com".into(),
+                "statsig.anthropic.com".into(),
+                "pypi.org".into(),
+                "files.pythonhosted.org".into(),
+            ],
+        }
+    }
+}
+
+impl Default for ReaperConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_minutes: 15,
+            stop_after_minutes: 120,
+            remove_after_minutes: 7 * 24 * 60,
+            notify_admin: true,
+        }
+    }
+}
+
 impl Default for SecurityConfig {
     fn default() -> Self {
         Self {
@@ -216,6 +551,59 @@ impl Default for LoggingConfig {
             level: "info".into(),
             file: "logs/bridge.log".into(),
             log_message_content: true,
+            retention: LoggingRetention::default(),
+            checkpoints: LoggingCheckpoints::default(),
+        }
+    }
+}
+
+impl Default for LoggingCheckpoints {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            file: "data/audit-checkpoints.jsonl".into(),
+            key_file: "data/audit-checkpoint.key".into(),
+        }
+    }
+}
+
+impl Default for LoggingRetention {
+    fn default() -> Self {
+        Self {
+            max_age_days: 0,
+            max_rows_per_user: 0,
+            redact_after_days: 0,
+            archive_dir: "data/audit-archive".into(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: "data/bridge.db".into(),
+            busy_timeout_ms: 5000,
+            backup: DatabaseBackup::default(),
+        }
+    }
+}
+
+impl Default for DatabaseBackup {
+    fn default() -> Self {
+        Self {
+            dir: "data/backups".into(),
+            keep: 7,
+        }
+    }
+}
+
+impl QuotaConfig {
+    /// Spend cap for a permission level; unknown levels get the `normal` cap.
+    pub fn for_permission(&self, permission: &str) -> &SpendCap {
+        match permission {
+            "admin" => &self.admin,
+            "trusted" => &self.trusted,
+            _ => &self.normal,
         }
     }
 }
@@ -223,15 +611,32 @@ impl Default for LoggingConfig {
 impl DockerConfig {
     /// Returns data_dir with ~ expanded to the user's home directory.
     pub fn expanded_data_dir(&self) -> PathBuf {
-        if self.data_dir.starts_with('~') {
-            if let Some(home) = dirs::home_dir() {
-                return home.join(self.data_dir.strip_prefix("~/").unwrap_or(&self.data_dir[1..]));
-            }
+        expand_home(&self.data_dir)
+    }
+
+    /// Returns the socket path with ~ expanded, or None to use the engine defaults.
+    pub fn expanded_socket(&self) -> Option<String> {
+        if self.socket.is_empty() {
+            return None;
+        }
+        match self.socket.strip_prefix("unix://") {
+            Some(path) => Some(format!("unix://{}", expand_home(path).display())),
+            None if self.socket.contains("://") => Some(self.socket.clone()),
+            None => Some(expand_home(&self.socket).to_string_lossy().into_owned()),
         }
-        PathBuf::from(&self.data_dir)
     }
 }
 
+/// Expand a leading ~ to the user's home directory.
+fn expand_home(path: &str) -> PathBuf {
+    if let Some(rest) = path.strip_prefix('~') {
+        if let Some(home) = dirs::home_dir() {
+            return home.join(rest.strip_prefix('/').unwrap_or(rest));
+        }
+    }
+    PathBuf::from(path)
+}
+
 /// Load configuration from config.yaml at the project root.
 /// Panics if config.yaml is missing or malformed. Call once at startup.
 pub fn load_config() -> Result<Config> {
@@ -257,6 +662,12 @@ pub fn init_config() -> Result<()> {
     Ok(())
 }
 
+/// Initialize the global config with defaults for tests that go through `get_config()`.
+#[cfg(test)]
+pub fn init_test_config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
 /// Get a reference to the global config. Panics if `init_config()` was not called.
 pub fn get_config() -> &'static Config {
     CONFIG.get().expect("Config not initialized. Call init_config() first.")
@@ -288,6 +699,171 @@ mod tests {
         assert_eq!(config.timeout, 120);
     }
 
+    #[test]
+    fn config_default_claude_stream_disabled() {
+        let config = ClaudeConfig::default();
+        assert!(!config.stream);
+    }
+
+    #[test]
+    fn config_default_claude_backend_docker() {
+        let config = ClaudeConfig::default();
+        assert_eq!(config.backend, "docker");
+    }
+
+    #[test]
+    fn config_default_docker_runtime_and_socket_unset() {
+        let config = DockerConfig::default();
+        assert!(config.socket.is_empty());
+        assert!(config.expanded_socket().is_none());
+        assert!(config.runtime.admin.is_empty());
+        assert!(config.runtime.trusted.is_empty());
+        assert!(config.runtime.normal.is_empty());
+    }
+
+    #[test]
+    fn config_default_queue() {
+        let config = QueueConfig::default();
+        assert_eq!(config.max_pending, 5);
+        assert!(config.merge);
+        assert_eq!(config.max_concurrent, 4);
+        assert_eq!(
+            (config.weights.admin, config.weights.trusted, config.weights.normal),
+            (4, 2, 1)
+        );
+
+        let yaml = "queue:\n  merge: false\n  weights:\n    normal: 2\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert!(!config.queue.merge);
+        assert_eq!(config.queue.max_pending, 5);
+        assert_eq!(config.queue.weights.normal, 2);
+        assert_eq!(config.queue.weights.admin, 4);
+    }
+
+    #[test]
+    fn config_default_outbox() {
+        let config = OutboxConfig::default();
+        assert!(config.enabled);
+        assert_eq!(config.max_file_size_mb, 20);
+        assert_eq!(config.allowed_types.admin, vec!["*"]);
+        assert!(config.allowed_types.trusted.contains(&"zip".to_string()));
+        assert!(!config.allowed_types.normal.contains(&"zip".to_string()));
+
+        let yaml = "outbox:\n  max_files: 3\n  allowed_types:\n    normal: [png]\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.outbox.max_files, 3);
+        assert_eq!(config.outbox.allowed_types.normal, vec!["png"]);
+        assert_eq!(config.outbox.allowed_types.admin, vec!["*"]);
+    }
+
+    #[test]
+    fn config_default_telegram() {
+        let config = TelegramConfig::default();
+        assert!(!config.enabled);
+        assert_eq!(config.api_base, "https://api.telegram.org");
+        assert!(!config.webhook.enabled);
+        assert_eq!(config.webhook.listen, "127.0.0.1:8081");
+        assert_eq!(config.webhook.path, "/telegram/webhook");
+
+        let yaml = "telegram:\n  webhook:\n    enabled: true\n    url: https://bot.example.com/tg\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert!(config.telegram.webhook.enabled);
+        assert_eq!(config.telegram.webhook.url, "https://bot.example.com/tg");
+        assert_eq!(config.telegram.webhook.path, "/telegram/webhook");
+        assert_eq!(config.telegram.api_base, "https://api.telegram.org");
+    }
+
+    #[test]
+    fn config_default_database() {
+        let config = DatabaseConfig::default();
+        assert_eq!(config.path, "data/bridge.db");
+        assert_eq!(config.busy_timeout_ms, 5000);
+        assert_eq!(config.backup.dir, "data/backups");
+        assert_eq!(config.backup.keep, 7);
+
+        let yaml = "database:\n  path: /var/lib/bridge/bridge.db\n  backup:\n    keep: 30\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.database.path, "/var/lib/bridge/bridge.db");
+        assert_eq!(config.database.backup.keep, 30);
+        assert_eq!(config.database.backup.dir, "data/backups");
+    }
+
+    #[test]
+    fn config_default_snapshots() {
+        let config = SnapshotsConfig::default();
+        assert!(config.enabled);
+        assert_eq!(config.max_manual, 5);
+        assert_eq!(config.max_auto, 3);
+        assert!(!config.auto_before_run.trusted);
+
+        let yaml = "snapshots:\n  auto_before_run:\n    trusted: true\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert!(config.snapshots.auto_before_run.trusted);
+        assert!(!config.snapshots.auto_before_run.admin);
+        assert_eq!(config.snapshots.max_manual, 5);
+    }
+
+    #[test]
+    fn config_docker_pool_disabled_by_default_and_from_yaml() {
+        let config = DockerPool::default();
+        assert_eq!(config.admin + config.trusted + config.normal, 0);
+        assert_eq!(config.refill_delay_secs, 5);
+
+        let yaml = "docker:\n  pool:\n    normal: 3\n    refill_delay_secs: 1\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.docker.pool.normal, 3);
+        assert_eq!(config.docker.pool.trusted, 0);
+        assert_eq!(config.docker.pool.refill_delay_secs, 1);
+    }
+
+    #[test]
+    fn config_docker_runtime_per_level_from_yaml() {
+        let yaml = "docker:\n  socket: /run/user/1000/podman/podman.sock\n  runtime:\n    normal: runsc\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.docker.runtime.normal, "runsc");
+        assert!(config.docker.runtime.admin.is_empty());
+        assert_eq!(
+            config.docker.expanded_socket().as_deref(),
+            Some("/run/user/1000/podman/podman.sock")
+        );
+        // Defaults for unrelated docker fields are kept
+        assert_eq!(config.docker.network.normal, "none");
+    }
+
+    #[test]
+    fn config_docker_socket_expands_home() {
+        let home = dirs::home_dir().unwrap();
+        let config = DockerConfig {
+            socket: "unix://~/podman.sock".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.expanded_socket().unwrap(),
+            format!("unix://{}", home.join("podman.sock").display())
+        );
+        let config = DockerConfig {
+            socket: "tcp://127.0.0.1:2375".into(),
+            ..Default::default()
+        };
+        assert_eq!(config.expanded_socket().unwrap(), "tcp://127.0.0.1:2375");
+    }
+
+    #[test]
+    fn config_default_egress_allows_anthropic_api() {
+        let config = EgressConfig::default();
+        assert!(config.enabled);
+        assert_eq!(config.port, 3128);
+        assert!(config.allowlist.iter().any(|d| d == "api.anthropic.com"));
+    }
+
+    #[test]
+    fn config_default_reaper_stops_before_removing() {
+        let config = ReaperConfig::default();
+        assert!(config.enabled);
+        assert_eq!(config.stop_after_minutes, 120);
+        assert!(config.remove_after_minutes > config.stop_after_minutes);
+    }
+
     #[test]
     fn config_default_session_expire_minutes() {
         let config = SessionConfig::default();
@@ -307,6 +883,35 @@ mod tests {
         assert_eq!(config.max_per_day, 200);
     }
 
+    #[test]
+    fn config_default_quota_unlimited() {
+        let config = QuotaConfig::default();
+        for perm in ["admin", "trusted", "normal"] {
+            let cap = config.for_permission(perm);
+            assert_eq!(cap.daily_usd, 0.0);
+            assert_eq!(cap.monthly_usd, 0.0);
+        }
+    }
+
+    #[test]
+    fn config_deserialize_quota() {
+        let yaml = r#"
+quota:
+  normal:
+    daily_usd: 0.5
+    monthly_usd: 5
+  trusted:
+    daily_usd: 2
+"#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.quota.for_permission("normal").daily_usd, 0.5);
+        assert_eq!(config.quota.for_permission("normal").monthly_usd, 5.0);
+        assert_eq!(config.quota.for_permission("trusted").daily_usd, 2.0);
+        assert_eq!(config.quota.for_permission("trusted").monthly_usd, 0.0);
+        // Unknown levels fall back to the normal cap
+        assert_eq!(config.quota.for_permission("blocked").daily_usd, 0.5);
+    }
+
     #[test]
     fn config_default_security_no_blocked_patterns() {
         let config = SecurityConfig::default();
@@ -319,6 +924,7 @@ mod tests {
         let config = PermissionsConfig::default();
         assert!(config.notify_unauthorized);
         assert_eq!(config.default_level, "normal");
+        assert_eq!(config.group_default_level, "blocked");
         // Unauthorized message should be non-empty Chinese text
         assert!(!config.unauthorized_message.is_empty());
     }
@@ -329,6 +935,19 @@ mod tests {
         assert_eq!(config.level, "info");
         assert_eq!(config.file, "logs/bridge.log");
         assert!(config.log_message_content);
+        assert_eq!(config.retention.max_age_days, 0);
+        assert_eq!(config.retention.max_rows_per_user, 0);
+        assert_eq!(config.retention.redact_after_days, 0);
+        assert_eq!(config.retention.archive_dir, "data/audit-archive");
+        assert!(config.checkpoints.enabled);
+        assert_eq!(config.checkpoints.file, "data/audit-checkpoints.jsonl");
+        assert_eq!(config.checkpoints.key_file, "data/audit-checkpoint.key");
+
+        let yaml = "logging:\n  retention:\n    max_age_days: 180\n    archive_dir: \"\"\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.logging.retention.max_age_days, 180);
+        assert_eq!(config.logging.retention.archive_dir, "");
+        assert!(config.logging.log_message_content);
     }
 
     #[test]
@@ -347,6 +966,9 @@ mod tests {
         assert_eq!(config.admin_cpus, 2);
         assert_eq!(config.pids, 100);
         assert_eq!(config.tmp_size, "100m");
+        assert_eq!(config.workspace_size.admin, "");
+        assert_eq!(config.workspace_size.trusted, "5g");
+        assert_eq!(config.workspace_size.normal, "1g");
     }
 
     #[test]
@@ -0,0 +1,920 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bollard::container::{
+    Config, CreateContainerOptions, InspectContainerOptions, ListContainersOptions,
+    RemoveContainerOptions, RenameContainerOptions, StartContainerOptions, Stats, StatsOptions,
+    DownloadFromContainerOptions, StopContainerOptions, UploadToContainerOptions,
+};
+use bollard::exec::{CreateExecOptions, StartExecResults};
+use bollard::image::BuildImageOptions;
+use bollard::models::ContainerStateStatusEnum;
+use bollard::network::{CreateNetworkOptions, InspectNetworkOptions};
+use bollard::{Docker, API_DEFAULT_VERSION};
+use futures_util::StreamExt;
+use tracing::debug;
+
+use crate::docker_manager::ContainerStats;
+
+/// A command to run inside a container.
+#[derive(Debug, Clone, Default)]
+pub struct ExecSpec {
+    pub cmd: Vec<String>,
+    pub user: String,
+    pub working_dir: Option<String>,
+    pub env: Vec<String>,
+}
+
+/// State of a single container as reported by inspect.
+#[derive(Debug, Clone)]
+pub struct ContainerState {
+    pub running: bool,
+}
+
+/// A network as reported by inspect.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkInfo {
+    /// Internal networks have no route to the outside world.
+    pub internal: bool,
+    /// Gateway address of the network's subnet (the host side of the bridge).
+    pub gateway: Option<String>,
+    /// Attached containers as `(container name, IPv4 address)`.
+    pub containers: Vec<(String, String)>,
+}
+
+/// One entry of a container listing.
+#[derive(Debug, Clone)]
+pub struct ListedContainer {
+    pub name: String,
+    /// Human-readable status, e.g. "Up 2 hours" or "Exited (0) 5 minutes ago".
+    pub status: String,
+    pub labels: HashMap<String, String>,
+}
+
+/// The container engine calls `DockerManager` relies on.
+///
+/// `BollardRuntime` talks to a real Docker daemon; tests use the in-memory
+/// `FakeRuntime` so lifecycle logic can run without one.
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+    /// Engine version string; errors if the daemon is unreachable.
+    async fn version(&self) -> Result<String>;
+
+    /// Names of the OCI runtimes registered with the engine (e.g. "runc", "runsc").
+    async fn runtimes(&self) -> Result<Vec<String>>;
+
+    async fn create_container(&self, name: &str, config: Config<String>) -> Result<()>;
+    async fn start_container(&self, name: &str) -> Result<()>;
+    async fn stop_container(&self, name: &str, timeout_secs: i64) -> Result<()>;
+    async fn remove_container(&self, name: &str, force: bool) -> Result<()>;
+    async fn rename_container(&self, name: &str, new_name: &str) -> Result<()>;
+
+    /// Inspect a container; `Ok(None)` if it does not exist.
+    async fn inspect_container(&self, name: &str) -> Result<Option<ContainerState>>;
+
+    /// List all containers (running or not) carrying `label` ("key=value").
+    async fn list_containers(&self, label: &str) -> Result<Vec<ListedContainer>>;
+
+    /// Create (but don't start) an exec; returns its ID.
+    async fn create_exec(&self, container: &str, spec: ExecSpec) -> Result<String>;
+
+    /// Start an exec and collect `(stdout, stderr)`, passing each raw stdout
+    /// chunk to `on_stdout` as it arrives. Dropping the future stops reading,
+    /// not the process.
+    async fn start_exec(
+        &self,
+        exec_id: &str,
+        on_stdout: &mut (dyn for<'c> FnMut(&'c [u8]) + Send),
+    ) -> Result<(String, String)>;
+
+    /// Whether an exec's process is still running; false for unknown IDs.
+    async fn exec_running(&self, exec_id: &str) -> Result<bool>;
+
+    /// Run a command to completion: `create_exec` + `start_exec`.
+    async fn exec(
+        &self,
+        container: &str,
+        spec: ExecSpec,
+        on_stdout: &mut (dyn for<'c> FnMut(&'c [u8]) + Send),
+    ) -> Result<(String, String)> {
+        let exec_id = self.create_exec(container, spec).await?;
+        self.start_exec(&exec_id, on_stdout).await
+    }
+
+    /// Extract a tar archive into the directory `path` inside the container.
+    async fn upload_archive(&self, container: &str, path: &str, archive: Vec<u8>) -> Result<()>;
+
+    /// Tar archive of the file or directory at `path` inside the container.
+    /// Fails once the archive grows past `max_bytes`.
+    async fn download_archive(&self, container: &str, path: &str, max_bytes: u64) -> Result<Vec<u8>>;
+
+    /// One-shot resource usage snapshot; `Ok(None)` if unavailable.
+    async fn stats(&self, name: &str) -> Result<Option<ContainerStats>>;
+
+    /// Inspect a network; `Ok(None)` if it does not exist.
+    async fn inspect_network(&self, name: &str) -> Result<Option<NetworkInfo>>;
+    async fn create_network(&self, name: &str, driver: &str, internal: bool) -> Result<()>;
+
+    async fn image_exists(&self, image: &str) -> Result<bool>;
+
+    /// Build `tag` from a tar build context containing `dockerfile`.
+    async fn build_image(&self, tag: &str, dockerfile: &str, context: Vec<u8>) -> Result<()>;
+}
+
+// ============================================
+// Bollard (Docker Engine API)
+// ============================================
+
+/// `ContainerRuntime` backed by the Docker Engine API via bollard.
+pub struct BollardRuntime {
+    docker: Docker,
+}
+
+/// Request timeout for engine API calls, in seconds (bollard's default).
+const API_TIMEOUT_SECS: u64 = 120;
+
+impl BollardRuntime {
+    /// Connect to `socket` if given, otherwise use the platform defaults
+    /// (DOCKER_HOST or the local socket).
+    ///
+    /// `socket` may be a Unix socket path (`unix://` optional), e.g. rootless
+    /// Podman's `/run/user/1000/podman/podman.sock`, or a `tcp://` address.
+    pub fn connect(socket: Option<&str>) -> Result<Self> {
+        let docker = match socket {
+            None => Docker::connect_with_local_defaults(),
+            Some(addr) if addr.starts_with("tcp://") || addr.starts_with("http://") => {
+                Docker::connect_with_http(addr, API_TIMEOUT_SECS, API_DEFAULT_VERSION)
+            }
+            Some(path) => Docker::connect_with_socket(path, API_TIMEOUT_SECS, API_DEFAULT_VERSION),
+        };
+        let docker = match socket {
+            Some(addr) => docker.with_context(|| format!("Failed to connect to container engine at {}", addr))?,
+            None => docker.context("Failed to connect to Docker daemon")?,
+        };
+        Ok(Self { docker })
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for BollardRuntime {
+    async fn version(&self) -> Result<String> {
+        let version = self.docker.version().await?;
+        Ok(version.version.unwrap_or_else(|| "unknown".to_string()))
+    }
+
+    async fn runtimes(&self) -> Result<Vec<String>> {
+        let info = self.docker.info().await?;
+        let mut names: Vec<String> = info.runtimes.unwrap_or_default().into_keys().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn create_container(&self, name: &str, config: Config<String>) -> Result<()> {
+        self.docker
+            .create_container(
+                Some(CreateContainerOptions {
+                    name,
+                    platform: None,
+                }),
+                config,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn start_container(&self, name: &str) -> Result<()> {
+        self.docker
+            .start_container(name, None::<StartContainerOptions<String>>)
+            .await?;
+        Ok(())
+    }
+
+    async fn stop_container(&self, name: &str, timeout_secs: i64) -> Result<()> {
+        self.docker
+            .stop_container(name, Some(StopContainerOptions { t: timeout_secs }))
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_container(&self, name: &str, force: bool) -> Result<()> {
+        self.docker
+            .remove_container(
+                name,
+                Some(RemoveContainerOptions {
+                    force,
+                    ..Default::default()
+                }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn rename_container(&self, name: &str, new_name: &str) -> Result<()> {
+        self.docker
+            .rename_container(name, RenameContainerOptions { name: new_name })
+            .await?;
+        Ok(())
+    }
+
+    async fn inspect_container(&self, name: &str) -> Result<Option<ContainerState>> {
+        match self
+            .docker
+            .inspect_container(name, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(info) => {
+                let running = info
+                    .state
+                    .and_then(|s| s.status)
+                    .map(|s| s == ContainerStateStatusEnum::RUNNING)
+                    .unwrap_or(false);
+                Ok(Some(ContainerState { running }))
+            }
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list_containers(&self, label: &str) -> Result<Vec<ListedContainer>> {
+        let mut filters = HashMap::new();
+        filters.insert("label", vec![label]);
+
+        let options = ListContainersOptions {
+            all: true,
+            filters,
+            ..Default::default()
+        };
+
+        let containers = self.docker.list_containers(Some(options)).await?;
+
+        Ok(containers
+            .into_iter()
+            .map(|c| ListedContainer {
+                name: c
+                    .names
+                    .as_ref()
+                    .and_then(|n| n.first())
+                    .map(|n| n.trim_start_matches('/').to_string())
+                    .unwrap_or_default(),
+                status: c.status.unwrap_or_default(),
+                labels: c.labels.unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn create_exec(&self, container: &str, spec: ExecSpec) -> Result<String> {
+        let exec_opts = CreateExecOptions {
+            cmd: Some(spec.cmd),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            user: Some(spec.user),
+            working_dir: spec.working_dir,
+            env: if spec.env.is_empty() { None } else { Some(spec.env) },
+            ..Default::default()
+        };
+
+        let exec = self
+            .docker
+            .create_exec(container, exec_opts)
+            .await
+            .with_context(|| format!("Failed to create exec in {}", container))?;
+        Ok(exec.id)
+    }
+
+    async fn start_exec(
+        &self,
+        exec_id: &str,
+        on_stdout: &mut (dyn for<'c> FnMut(&'c [u8]) + Send),
+    ) -> Result<(String, String)> {
+        let start_result = self
+            .docker
+            .start_exec(exec_id, None)
+            .await
+            .context("Failed to start exec")?;
+
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+
+        match start_result {
+            StartExecResults::Attached { mut output, .. } => {
+                while let Some(chunk) = output.next().await {
+                    match chunk {
+                        Ok(bollard::container::LogOutput::StdOut { message }) => {
+                            on_stdout(&message);
+                            stdout.push_str(&String::from_utf8_lossy(&message));
+                        }
+                        Ok(bollard::container::LogOutput::StdErr { message }) => {
+                            stderr.push_str(&String::from_utf8_lossy(&message));
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            return Err(anyhow::anyhow!("Error reading exec output: {}", e));
+                        }
+                    }
+                }
+            }
+            StartExecResults::Detached => {
+                // Nothing to collect
+            }
+        }
+
+        Ok((stdout, stderr))
+    }
+
+    async fn exec_running(&self, exec_id: &str) -> Result<bool> {
+        match self.docker.inspect_exec(exec_id).await {
+            Ok(info) => Ok(info.running.unwrap_or(false)),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn upload_archive(&self, container: &str, path: &str, archive: Vec<u8>) -> Result<()> {
+        self.docker
+            .upload_to_container(
+                container,
+                Some(UploadToContainerOptions {
+                    path,
+                    ..Default::default()
+                }),
+                archive.into(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn download_archive(&self, container: &str, path: &str, max_bytes: u64) -> Result<Vec<u8>> {
+        let mut stream = self
+            .docker
+            .download_from_container(container, Some(DownloadFromContainerOptions { path }));
+        let mut archive = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            archive.extend_from_slice(&chunk?);
+            if archive.len() as u64 > max_bytes {
+                anyhow::bail!("Archive of {} is larger than {} bytes", path, max_bytes);
+            }
+        }
+        Ok(archive)
+    }
+
+    async fn stats(&self, name: &str) -> Result<Option<ContainerStats>> {
+        let mut stream = self.docker.stats(
+            name,
+            Some(StatsOptions {
+                stream: false,
+                one_shot: true,
+            }),
+        );
+
+        if let Some(Ok(stats)) = stream.next().await {
+            Ok(Some(ContainerStats {
+                cpu_percent: calculate_cpu_percent(&stats),
+                memory_usage: stats.memory_stats.usage.unwrap_or(0),
+                memory_limit: stats.memory_stats.limit.unwrap_or(0),
+                pids: stats.pids_stats.current.unwrap_or(0),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn inspect_network(&self, name: &str) -> Result<Option<NetworkInfo>> {
+        let network = match self
+            .docker
+            .inspect_network(name, None::<InspectNetworkOptions<String>>)
+            .await
+        {
+            Ok(n) => n,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let gateway = network
+            .ipam
+            .and_then(|ipam| ipam.config)
+            .and_then(|configs| configs.into_iter().find_map(|c| c.gateway));
+        let containers = network
+            .containers
+            .unwrap_or_default()
+            .into_values()
+            .filter_map(|c| {
+                // ipv4_address is in CIDR form, e.g. "172.18.0.2/16"
+                let ip = c.ipv4_address?.split('/').next()?.to_string();
+                Some((c.name?, ip))
+            })
+            .collect();
+
+        Ok(Some(NetworkInfo {
+            internal: network.internal.unwrap_or(false),
+            gateway,
+            containers,
+        }))
+    }
+
+    async fn create_network(&self, name: &str, driver: &str, internal: bool) -> Result<()> {
+        let options = CreateNetworkOptions {
+            name,
+            driver,
+            internal,
+            ..Default::default()
+        };
+        self.docker.create_network(options).await?;
+        Ok(())
+    }
+
+    async fn image_exists(&self, image: &str) -> Result<bool> {
+        Ok(self.docker.inspect_image(image).await.is_ok())
+    }
+
+    async fn build_image(&self, tag: &str, dockerfile: &str, context: Vec<u8>) -> Result<()> {
+        let build_options = BuildImageOptions {
+            t: tag,
+            dockerfile,
+            rm: true,
+            ..Default::default()
+        };
+
+        let mut stream = self
+            .docker
+            .build_image(build_options, None, Some(context.into()));
+
+        while let Some(result) = stream.next().await {
+            match result {
+                Ok(output) => {
+                    if let Some(stream_str) = output.stream {
+                        debug!("build: {}", stream_str.trim());
+                    }
+                    if let Some(err) = output.error {
+                        return Err(anyhow::anyhow!("Image build error: {}", err));
+                    }
+                }
+                Err(e) => {
+                    return Err(anyhow::anyhow!("Image build failed: {}", e));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Calculate CPU usage percentage from Docker stats.
+fn calculate_cpu_percent(stats: &Stats) -> f64 {
+    let cpu_delta = stats.cpu_stats.cpu_usage.total_usage as f64
+        - stats.precpu_stats.cpu_usage.total_usage as f64;
+    let system_delta = stats.cpu_stats.system_cpu_usage.unwrap_or(0) as f64
+        - stats.precpu_stats.system_cpu_usage.unwrap_or(0) as f64;
+    let num_cpus = stats
+        .cpu_stats
+        .online_cpus
+        .unwrap_or(1) as f64;
+
+    if system_delta > 0.0 && cpu_delta >= 0.0 {
+        (cpu_delta / system_delta) * num_cpus * 100.0
+    } else {
+        0.0
+    }
+}
+
+// ============================================
+// In-memory fake (tests)
+// ============================================
+
+/// Scripted result of one exec in `FakeRuntime`.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct FakeExec {
+    /// Stdout, delivered to `on_stdout` one chunk at a time.
+    pub stdout: Vec<String>,
+    pub stderr: String,
+    /// Sleep this long before returning, to exercise timeouts.
+    pub delay: Option<std::time::Duration>,
+}
+
+#[cfg(test)]
+impl FakeExec {
+    pub fn stdout(text: &str) -> Self {
+        Self {
+            stdout: vec![text.to_string()],
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct FakeContainer {
+    pub config: Config<String>,
+    pub running: bool,
+    /// Regular files uploaded into the container, by absolute path.
+    pub files: HashMap<String, Vec<u8>>,
+}
+
+#[cfg(test)]
+type FakeExecHandler = Box<dyn Fn(&str, &ExecSpec) -> FakeExec + Send + Sync>;
+
+/// In-memory `ContainerRuntime` that tracks container state and answers
+/// execs from a handler. Errors mirror Docker's: execs and stops fail on
+/// containers that are not running, creates fail on duplicate names.
+#[cfg(test)]
+pub struct FakeRuntime {
+    pub containers: std::sync::Mutex<HashMap<String, FakeContainer>>,
+    pub networks: std::sync::Mutex<HashMap<String, NetworkInfo>>,
+    pub images: std::sync::Mutex<std::collections::HashSet<String>>,
+    /// Every exec run, as `(container, spec)`.
+    pub execs: std::sync::Mutex<Vec<(String, ExecSpec)>>,
+    /// Created but not yet started execs by ID.
+    pending_execs: std::sync::Mutex<HashMap<String, (String, ExecSpec)>>,
+    next_exec_id: std::sync::atomic::AtomicUsize,
+    /// IDs of started execs that haven't finished. An exec whose caller
+    /// gave up stays here, like a process Docker keeps running.
+    pub running_execs: std::sync::Mutex<std::collections::HashSet<String>>,
+    /// When false, `version` fails as if the daemon were down.
+    pub available: std::sync::atomic::AtomicBool,
+    /// OCI runtimes reported by `runtimes`.
+    pub runtimes: std::sync::Mutex<Vec<String>>,
+    handler: std::sync::Mutex<FakeExecHandler>,
+}
+
+#[cfg(test)]
+impl Default for FakeRuntime {
+    fn default() -> Self {
+        Self {
+            containers: Default::default(),
+            networks: Default::default(),
+            images: Default::default(),
+            execs: Default::default(),
+            pending_execs: Default::default(),
+            next_exec_id: Default::default(),
+            running_execs: Default::default(),
+            available: std::sync::atomic::AtomicBool::new(true),
+            runtimes: std::sync::Mutex::new(vec!["runc".to_string()]),
+            handler: std::sync::Mutex::new(Box::new(|_, _| FakeExec::default())),
+        }
+    }
+}
+
+#[cfg(test)]
+impl FakeRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer subsequent execs with `handler(container, spec)`.
+    pub fn on_exec(&self, handler: impl Fn(&str, &ExecSpec) -> FakeExec + Send + Sync + 'static) {
+        *self.handler.lock().unwrap() = Box::new(handler);
+    }
+
+    pub fn is_running(&self, name: &str) -> bool {
+        self.containers
+            .lock()
+            .unwrap()
+            .get(name)
+            .is_some_and(|c| c.running)
+    }
+
+    /// Mark a container as exited, as if its main process died.
+    pub fn kill(&self, name: &str) {
+        if let Some(c) = self.containers.lock().unwrap().get_mut(name) {
+            c.running = false;
+        }
+    }
+
+    fn with_container<T>(&self, name: &str, f: impl FnOnce(&mut FakeContainer) -> Result<T>) -> Result<T> {
+        let mut containers = self.containers.lock().unwrap();
+        match containers.get_mut(name) {
+            Some(c) => f(c),
+            None => anyhow::bail!("No such container: {}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ContainerRuntime for FakeRuntime {
+    async fn version(&self) -> Result<String> {
+        if self.available.load(std::sync::atomic::Ordering::SeqCst) {
+            Ok("fake".to_string())
+        } else {
+            anyhow::bail!("Cannot connect to the Docker daemon")
+        }
+    }
+
+    async fn runtimes(&self) -> Result<Vec<String>> {
+        Ok(self.runtimes.lock().unwrap().clone())
+    }
+
+    async fn create_container(&self, name: &str, config: Config<String>) -> Result<()> {
+        let mut containers = self.containers.lock().unwrap();
+        if containers.contains_key(name) {
+            anyhow::bail!("Conflict. The container name \"/{}\" is already in use", name);
+        }
+        // Attach to a known network with the next free address
+        if let Some(net) = config.host_config.as_ref().and_then(|h| h.network_mode.as_ref()) {
+            if let Some(info) = self.networks.lock().unwrap().get_mut(net) {
+                let ip = format!("172.30.0.{}", info.containers.len() + 2);
+                info.containers.push((name.to_string(), ip));
+            }
+        }
+        containers.insert(
+            name.to_string(),
+            FakeContainer {
+                config,
+                running: false,
+                files: HashMap::new(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn start_container(&self, name: &str) -> Result<()> {
+        self.with_container(name, |c| {
+            c.running = true;
+            Ok(())
+        })
+    }
+
+    async fn stop_container(&self, name: &str, _timeout_secs: i64) -> Result<()> {
+        self.with_container(name, |c| {
+            if !c.running {
+                anyhow::bail!("Container {} is not running", name);
+            }
+            c.running = false;
+            Ok(())
+        })
+    }
+
+    async fn remove_container(&self, name: &str, force: bool) -> Result<()> {
+        let mut containers = self.containers.lock().unwrap();
+        match containers.get(name) {
+            None => anyhow::bail!("No such container: {}", name),
+            Some(c) if c.running && !force => {
+                anyhow::bail!("You cannot remove a running container {}", name)
+            }
+            Some(_) => {
+                containers.remove(name);
+                for info in self.networks.lock().unwrap().values_mut() {
+                    info.containers.retain(|(n, _)| n != name);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn rename_container(&self, name: &str, new_name: &str) -> Result<()> {
+        let mut containers = self.containers.lock().unwrap();
+        if containers.contains_key(new_name) {
+            anyhow::bail!("Conflict. The container name \"/{}\" is already in use", new_name);
+        }
+        let container = containers
+            .remove(name)
+            .ok_or_else(|| anyhow::anyhow!("No such container: {}", name))?;
+        containers.insert(new_name.to_string(), container);
+        for info in self.networks.lock().unwrap().values_mut() {
+            for (n, _) in info.containers.iter_mut().filter(|(n, _)| n == name) {
+                *n = new_name.to_string();
+            }
+        }
+        Ok(())
+    }
+
+    async fn inspect_container(&self, name: &str) -> Result<Option<ContainerState>> {
+        Ok(self
+            .containers
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|c| ContainerState { running: c.running }))
+    }
+
+    async fn list_containers(&self, label: &str) -> Result<Vec<ListedContainer>> {
+        let (key, value) = label.split_once('=').unwrap_or((label, ""));
+        let containers = self.containers.lock().unwrap();
+        let mut list: Vec<ListedContainer> = containers
+            .iter()
+            .filter_map(|(name, c)| {
+                let labels = c.config.labels.clone().unwrap_or_default();
+                if labels.get(key).map(String::as_str) != Some(value) {
+                    return None;
+                }
+                Some(ListedContainer {
+                    name: name.clone(),
+                    status: if c.running { "Up 1 minute" } else { "Exited (0) 1 minute ago" }
+                        .to_string(),
+                    labels,
+                })
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    async fn create_exec(&self, container: &str, spec: ExecSpec) -> Result<String> {
+        if !self.is_running(container) {
+            anyhow::bail!("Container {} is not running", container);
+        }
+        let n = self.next_exec_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let exec_id = format!("exec-{}", n + 1);
+        self.pending_execs
+            .lock()
+            .unwrap()
+            .insert(exec_id.clone(), (container.to_string(), spec));
+        Ok(exec_id)
+    }
+
+    async fn start_exec(
+        &self,
+        exec_id: &str,
+        on_stdout: &mut (dyn for<'c> FnMut(&'c [u8]) + Send),
+    ) -> Result<(String, String)> {
+        let (container, spec) = self
+            .pending_execs
+            .lock()
+            .unwrap()
+            .remove(exec_id)
+            .ok_or_else(|| anyhow::anyhow!("No such exec instance: {}", exec_id))?;
+        let reply = (self.handler.lock().unwrap())(&container, &spec);
+        self.execs.lock().unwrap().push((container, spec));
+        self.running_execs.lock().unwrap().insert(exec_id.to_string());
+
+        if let Some(delay) = reply.delay {
+            tokio::time::sleep(delay).await;
+        }
+        for chunk in &reply.stdout {
+            on_stdout(chunk.as_bytes());
+        }
+        self.running_execs.lock().unwrap().remove(exec_id);
+        Ok((reply.stdout.concat(), reply.stderr))
+    }
+
+    async fn exec_running(&self, exec_id: &str) -> Result<bool> {
+        Ok(self.running_execs.lock().unwrap().contains(exec_id))
+    }
+
+    async fn upload_archive(&self, container: &str, path: &str, archive: Vec<u8>) -> Result<()> {
+        let mut files = Vec::new();
+        for entry in tar::Archive::new(archive.as_slice()).entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type().is_file() {
+                let dest = std::path::Path::new(path).join(entry.path()?);
+                let mut data = Vec::new();
+                std::io::Read::read_to_end(&mut entry, &mut data)?;
+                files.push((dest.to_string_lossy().into_owned(), data));
+            }
+        }
+        self.with_container(container, |c| {
+            c.files.extend(files);
+            Ok(())
+        })
+    }
+
+    async fn download_archive(&self, container: &str, path: &str, max_bytes: u64) -> Result<Vec<u8>> {
+        let data = self.with_container(container, |c| {
+            c.files
+                .get(path)
+                .cloned()
+                .with_context(|| format!("Could not find the file {} in container {}", path, container))
+        })?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        let name = std::path::Path::new(path).file_name().context("not a file path")?;
+        let mut archive = tar::Builder::new(Vec::new());
+        archive.append_data(&mut header, name, data.as_slice())?;
+        let archive = archive.into_inner()?;
+        if archive.len() as u64 > max_bytes {
+            anyhow::bail!("Archive of {} is larger than {} bytes", path, max_bytes);
+        }
+        Ok(archive)
+    }
+
+    async fn stats(&self, name: &str) -> Result<Option<ContainerStats>> {
+        if !self.is_running(name) {
+            return Ok(None);
+        }
+        Ok(Some(ContainerStats {
+            cpu_percent: 1.5,
+            memory_usage: 64 * 1024 * 1024,
+            memory_limit: 512 * 1024 * 1024,
+            pids: 3,
+        }))
+    }
+
+    async fn inspect_network(&self, name: &str) -> Result<Option<NetworkInfo>> {
+        Ok(self.networks.lock().unwrap().get(name).cloned())
+    }
+
+    async fn create_network(&self, name: &str, _driver: &str, internal: bool) -> Result<()> {
+        let mut networks = self.networks.lock().unwrap();
+        if networks.contains_key(name) {
+            anyhow::bail!("network with name {} already exists", name);
+        }
+        networks.insert(
+            name.to_string(),
+            NetworkInfo {
+                internal,
+                gateway: Some("172.30.0.1".to_string()),
+                containers: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn image_exists(&self, image: &str) -> Result<bool> {
+        Ok(self.images.lock().unwrap().contains(image))
+    }
+
+    async fn build_image(&self, tag: &str, _dockerfile: &str, context: Vec<u8>) -> Result<()> {
+        if context.is_empty() {
+            anyhow::bail!("Image build error: empty build context");
+        }
+        self.images.lock().unwrap().insert(tag.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calculate_cpu_percent_no_delta() {
+        // When system_delta is 0, should return 0
+        let stats: Stats = serde_json::from_str(
+            r#"{
+                "read": "2024-01-01T00:00:00Z",
+                "preread": "2024-01-01T00:00:00Z",
+                "cpu_stats": {
+                    "cpu_usage": {"total_usage": 100, "usage_in_usermode": 50, "usage_in_kernelmode": 50, "percpu_usage": [100]},
+                    "system_cpu_usage": 1000,
+                    "online_cpus": 1,
+                    "throttling_data": {"periods": 0, "throttled_periods": 0, "throttled_time": 0}
+                },
+                "precpu_stats": {
+                    "cpu_usage": {"total_usage": 100, "usage_in_usermode": 50, "usage_in_kernelmode": 50, "percpu_usage": [100]},
+                    "system_cpu_usage": 1000,
+                    "online_cpus": 1,
+                    "throttling_data": {"periods": 0, "throttled_periods": 0, "throttled_time": 0}
+                },
+                "memory_stats": {},
+                "blkio_stats": {"io_service_bytes_recursive": [], "io_serviced_recursive": [], "io_queue_recursive": [], "io_service_time_recursive": [], "io_wait_time_recursive": [], "io_merged_recursive": [], "io_time_recursive": [], "sectors_recursive": []},
+                "pids_stats": {},
+                "networks": {},
+                "storage_stats": {},
+                "num_procs": 0
+            }"#,
+        )
+        .unwrap();
+
+        let result = calculate_cpu_percent(&stats);
+        assert_eq!(result, 0.0);
+    }
+
+    #[tokio::test]
+    async fn fake_runtime_mirrors_docker_errors() {
+        let rt = FakeRuntime::new();
+        rt.create_container("c1", Config::default()).await.unwrap();
+        assert!(rt.create_container("c1", Config::default()).await.is_err());
+
+        // Not running yet: exec and stop fail, removal without force is fine
+        let mut sink = |_: &[u8]| {};
+        assert!(rt.exec("c1", ExecSpec::default(), &mut sink).await.is_err());
+        assert!(rt.stop_container("c1", 10).await.is_err());
+
+        rt.start_container("c1").await.unwrap();
+        assert!(rt.remove_container("c1", false).await.is_err());
+        rt.remove_container("c1", true).await.unwrap();
+        assert!(rt.inspect_container("c1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn fake_runtime_exec_streams_chunks() {
+        let rt = FakeRuntime::new();
+        rt.create_container("c1", Config::default()).await.unwrap();
+        rt.start_container("c1").await.unwrap();
+        rt.on_exec(|_, spec| FakeExec {
+            stdout: vec!["a".into(), spec.cmd.join(" ")],
+            stderr: "warn".into(),
+            delay: None,
+        });
+
+        let mut chunks = Vec::new();
+        let mut sink = |c: &[u8]| chunks.push(String::from_utf8_lossy(c).into_owned());
+        let spec = ExecSpec {
+            cmd: vec!["echo".into(), "hi".into()],
+            ..Default::default()
+        };
+        let (out, err) = rt.exec("c1", spec, &mut sink).await.unwrap();
+        assert_eq!(out, "aecho hi");
+        assert_eq!(err, "warn");
+        assert_eq!(chunks, vec!["a", "echo hi"]);
+        assert_eq!(rt.execs.lock().unwrap().len(), 1);
+    }
+}
@@ -1,9 +1,16 @@
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::Duration;
 
+use anyhow::Context;
 use chrono::Utc;
+use rusqlite::backup::Backup;
 use rusqlite::{params, Connection, OptionalExtension};
+use tracing::{info, warn};
+
+use crate::audit_chain::{self, BreakKind, ChainFields, ChainReport};
+use crate::migrations::{self, Migration};
 
 // ============================================
 // Data structs
@@ -19,6 +26,8 @@ pub struct Friend {
     pub added_at: Option<String>,
     pub added_by: Option<String>,
     pub notes: Option<String>,
+    /// `user`, or `group` for a group chat.
+    pub kind: String,
 }
 
 #[derive(Debug, Clone)]
@@ -36,7 +45,10 @@ pub struct Session {
 #[allow(dead_code)]
 pub struct AuditEntry {
     pub id: i64,
+    /// The conversation: the user, or the group for group chats.
     pub wxid: String,
+    /// Group member who wrote (or was replied to) in a group chat.
+    pub sender: Option<String>,
     pub nickname: Option<String>,
     pub direction: String,
     pub message: Option<String>,
@@ -44,6 +56,88 @@ pub struct AuditEntry {
     pub timestamp: Option<String>,
 }
 
+/// An audit entry matching a search, with the matching part of its message.
+#[derive(Debug, Clone)]
+pub struct AuditHit {
+    pub entry: AuditEntry,
+    /// Excerpt around the first match, matches wrapped in 【】.
+    pub snippet: String,
+}
+
+/// Stands in for message text that isn't kept: not logged at all
+/// (`logging.log_message_content: false`) or redacted by `audit_prune`.
+pub const REDACTED_MESSAGE: &str = "[已隐藏]";
+
+/// Audit log retention for `Database::audit_prune`. 0 disables a limit.
+#[derive(Debug, Clone, Default)]
+pub struct AuditRetention {
+    /// Delete entries older than this many days.
+    pub max_age_days: u64,
+    /// Keep only this many of each user's (or group's) newest entries.
+    pub max_rows_per_user: u64,
+    /// Replace message text older than this many days with `REDACTED_MESSAGE`.
+    pub redact_after_days: u64,
+}
+
+impl AuditRetention {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age_days > 0 || self.max_rows_per_user > 0 || self.redact_after_days > 0
+    }
+}
+
+/// What one `Database::audit_prune` run did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PruneReport {
+    pub deleted: usize,
+    pub redacted: usize,
+    /// Where the deleted entries were archived, if anywhere.
+    pub archive: Option<PathBuf>,
+}
+
+/// Token counts for one Claude run, or summed over many.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenCounts {
+    pub input: i64,
+    pub output: i64,
+    pub cache_creation: i64,
+    pub cache_read: i64,
+}
+
+/// Aggregated usage over a set of Claude runs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageSummary {
+    pub requests: i64,
+    pub tokens: TokenCounts,
+    pub cost_usd: f64,
+}
+
+/// Time window for usage aggregation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsagePeriod {
+    /// Since midnight UTC today.
+    Day,
+    /// Since the first day of the current month (UTC).
+    Month,
+}
+
+impl UsagePeriod {
+    /// SQLite datetime() modifier for the start of the period.
+    fn start_modifier(&self) -> &'static str {
+        match self {
+            UsagePeriod::Day => "start of day",
+            UsagePeriod::Month => "start of month",
+        }
+    }
+}
+
+/// A backup file written by `Database::backup`.
+#[derive(Debug, Clone)]
+pub struct BackupInfo {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct RateLimitResult {
     pub allowed: bool,
@@ -54,8 +148,24 @@ pub struct RateLimitResult {
 // Database wrapper
 // ============================================
 
+/// Wait this long for locks by default (see `Database::with_busy_timeout`).
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pages copied per backup step, and the pause between steps that lets
+/// writers in.
+const BACKUP_PAGES_PER_STEP: i32 = 256;
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(5);
+
+/// Backup file names: `bridge-<timestamp>.db`.
+const BACKUP_PREFIX: &str = "bridge-";
+
+/// Audit archive file names: `audit-<timestamp>.jsonl.gz`.
+const ARCHIVE_PREFIX: &str = "audit-";
+
 pub struct Database {
     conn: Mutex<Connection>,
+    /// Database file; `None` for in-memory databases.
+    path: Option<PathBuf>,
 }
 
 impl Database {
@@ -65,77 +175,73 @@ impl Database {
             Some(p) => p.to_path_buf(),
             None => PathBuf::from("data/bridge.db"),
         };
+        let in_memory = db_path == Path::new(":memory:");
 
         // Ensure parent directory exists
         if let Some(parent) = db_path.parent() {
             fs::create_dir_all(parent)?;
         }
 
-        let conn = Connection::open(&db_path)?;
-        conn.pragma_update(None, "journal_mode", "WAL")?;
+        let mut conn = Connection::open(&db_path)
+            .with_context(|| format!("Failed to open {:?}", db_path))?;
+        conn.busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+        let mode: String =
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+        if !in_memory && !mode.eq_ignore_ascii_case("wal") {
+            warn!("SQLite WAL mode unavailable for {:?}, using {}", db_path, mode);
+        }
         conn.pragma_update(None, "foreign_keys", "ON")?;
+        migrations::migrate(&mut conn)
+            .with_context(|| format!("Failed to migrate {:?}", db_path))?;
 
-        let db = Self {
+        Ok(Self {
             conn: Mutex::new(conn),
-        };
-        db.init_tables()?;
-        Ok(db)
+            path: (!in_memory).then_some(db_path),
+        })
     }
 
-    fn init_tables(&self) -> anyhow::Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute_batch(
-            "
-            -- Friends / authorization table
-            CREATE TABLE IF NOT EXISTS friends (
-                wxid           TEXT PRIMARY KEY,
-                nickname       TEXT,
-                remark_name    TEXT,
-                permission     TEXT NOT NULL DEFAULT 'normal'
-                               CHECK(permission IN ('admin','trusted','normal','blocked')),
-                added_at       DATETIME DEFAULT CURRENT_TIMESTAMP,
-                added_by       TEXT,
-                notes          TEXT
-            );
-
-            -- Sessions table (one active session per friend)
-            CREATE TABLE IF NOT EXISTS sessions (
-                id             TEXT PRIMARY KEY,
-                wxid           TEXT NOT NULL,
-                claude_session TEXT,
-                created_at     DATETIME DEFAULT CURRENT_TIMESTAMP,
-                last_active    DATETIME DEFAULT CURRENT_TIMESTAMP,
-                message_count  INTEGER DEFAULT 0,
-                FOREIGN KEY (wxid) REFERENCES friends(wxid)
-            );
-
-            -- Message audit log
-            CREATE TABLE IF NOT EXISTS audit_log (
-                id             INTEGER PRIMARY KEY AUTOINCREMENT,
-                wxid           TEXT NOT NULL,
-                nickname       TEXT,
-                direction      TEXT NOT NULL CHECK(direction IN ('in','out')),
-                message        TEXT,
-                claude_session TEXT,
-                timestamp      DATETIME DEFAULT CURRENT_TIMESTAMP
-            );
-
-            -- Rate limit tracking
-            CREATE TABLE IF NOT EXISTS rate_limits (
-                wxid           TEXT NOT NULL,
-                window_start   DATETIME NOT NULL,
-                request_count  INTEGER DEFAULT 1,
-                PRIMARY KEY (wxid, window_start)
-            );
-
-            -- Indexes
-            CREATE INDEX IF NOT EXISTS idx_audit_wxid ON audit_log(wxid);
-            CREATE INDEX IF NOT EXISTS idx_audit_ts   ON audit_log(timestamp);
-            CREATE INDEX IF NOT EXISTS idx_sessions_wxid ON sessions(wxid);
-            CREATE INDEX IF NOT EXISTS idx_rate_wxid  ON rate_limits(wxid);
-            ",
-        )?;
-        Ok(())
+    /// Replace the default 5 s busy timeout.
+    pub fn with_busy_timeout(self, timeout: Duration) -> anyhow::Result<Self> {
+        self.conn.lock().unwrap().busy_timeout(timeout)?;
+        Ok(self)
+    }
+
+    /// Check which migrations the database at `path` still needs by applying
+    /// them and rolling back. Nothing is written.
+    pub fn migrate_dry_run(path: &Path) -> anyhow::Result<(u32, Vec<&'static Migration>)> {
+        if !path.exists() {
+            anyhow::bail!("No database at {:?}", path);
+        }
+        let mut conn = Connection::open(path)?;
+        let version = migrations::current_version(&conn)?;
+        Ok((version, migrations::dry_run(&mut conn)?))
+    }
+
+    // ============================================
+    // Backups
+    // ============================================
+
+    /// Write a consistent copy of the database to a new timestamped file in
+    /// `dir` with SQLite's online backup API, then delete the oldest backups
+    /// beyond `keep`. File databases are read through a separate connection,
+    /// so the bridge keeps serving meanwhile. Blocking; call it from
+    /// `spawn_blocking`.
+    pub fn backup(&self, dir: &Path, keep: usize) -> anyhow::Result<BackupInfo> {
+        match self.path {
+            Some(ref path) => Self::backup_file(path, dir, keep),
+            None => write_backup(&self.conn.lock().unwrap(), dir, keep),
+        }
+    }
+
+    /// `backup` for the database file at `path`, without opening it as a
+    /// `Database` (no migrations), e.g. while a bridge is running on it.
+    pub fn backup_file(path: &Path, dir: &Path, keep: usize) -> anyhow::Result<BackupInfo> {
+        if !path.exists() {
+            anyhow::bail!("No database at {:?}", path);
+        }
+        let src = Connection::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        src.busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+        write_backup(&src, dir, keep)
     }
 
     // ============================================
@@ -145,7 +251,7 @@ impl Database {
     pub fn friend_get(&self, wxid: &str) -> anyhow::Result<Option<Friend>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT wxid, nickname, remark_name, permission, added_at, added_by, notes FROM friends WHERE wxid = ?",
+            "SELECT wxid, nickname, remark_name, permission, added_at, added_by, notes, kind FROM friends WHERE wxid = ?",
         )?;
         let row = stmt
             .query_row(params![wxid], |row| {
@@ -157,6 +263,7 @@ impl Database {
                     added_at: row.get(4)?,
                     added_by: row.get(5)?,
                     notes: row.get(6)?,
+                    kind: row.get(7)?,
                 })
             })
             .optional()?;
@@ -207,10 +314,17 @@ impl Database {
         Ok(())
     }
 
+    /// Mark a friend row as a `user` or a `group` chat.
+    pub fn friend_set_kind(&self, wxid: &str, kind: &str) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("UPDATE friends SET kind = ? WHERE wxid = ?", params![kind, wxid])?;
+        Ok(())
+    }
+
     pub fn friend_list_all(&self) -> anyhow::Result<Vec<Friend>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT wxid, nickname, remark_name, permission, added_at, added_by, notes FROM friends ORDER BY added_at DESC",
+            "SELECT wxid, nickname, remark_name, permission, added_at, added_by, notes, kind FROM friends ORDER BY added_at DESC",
         )?;
         let rows = stmt.query_map([], |row| {
             Ok(Friend {
@@ -221,6 +335,7 @@ impl Database {
                 added_at: row.get(4)?,
                 added_by: row.get(5)?,
                 notes: row.get(6)?,
+                kind: row.get(7)?,
             })
         })?;
         let mut friends = Vec::new();
@@ -234,7 +349,7 @@ impl Database {
     pub fn friend_list_by_permission(&self, permission: &str) -> anyhow::Result<Vec<Friend>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT wxid, nickname, remark_name, permission, added_at, added_by, notes FROM friends WHERE permission = ?",
+            "SELECT wxid, nickname, remark_name, permission, added_at, added_by, notes, kind FROM friends WHERE permission = ?",
         )?;
         let rows = stmt.query_map(params![permission], |row| {
             Ok(Friend {
@@ -245,6 +360,7 @@ impl Database {
                 added_at: row.get(4)?,
                 added_by: row.get(5)?,
                 notes: row.get(6)?,
+                kind: row.get(7)?,
             })
         })?;
         let mut friends = Vec::new();
@@ -265,7 +381,7 @@ impl Database {
         let conn = self.conn.lock().unwrap();
         let pattern = format!("%{}%", nickname);
         let mut stmt = conn.prepare(
-            "SELECT wxid, nickname, remark_name, permission, added_at, added_by, notes FROM friends WHERE nickname LIKE ? OR remark_name LIKE ?",
+            "SELECT wxid, nickname, remark_name, permission, added_at, added_by, notes, kind FROM friends WHERE nickname LIKE ? OR remark_name LIKE ?",
         )?;
         let rows = stmt.query_map(params![pattern, pattern], |row| {
             Ok(Friend {
@@ -276,6 +392,7 @@ impl Database {
                 added_at: row.get(4)?,
                 added_by: row.get(5)?,
                 notes: row.get(6)?,
+                kind: row.get(7)?,
             })
         })?;
         let mut friends = Vec::new();
@@ -372,18 +489,145 @@ impl Database {
         message: Option<&str>,
         claude_session: Option<&str>,
     ) -> anyhow::Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT INTO audit_log (wxid, nickname, direction, message, claude_session) VALUES (?, ?, ?, ?, ?)",
-            params![wxid, nickname, direction, message, claude_session],
+        self.audit_log_from(wxid, None, nickname, direction, message, claude_session)
+    }
+
+    /// `audit_log` for a conversation with several members: `wxid` is the
+    /// group and `sender` the member the entry belongs to.
+    pub fn audit_log_from(
+        &self,
+        wxid: &str,
+        sender: Option<&str>,
+        nickname: Option<&str>,
+        direction: &str,
+        message: Option<&str>,
+        claude_session: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let prev_hash = chain_head(&tx)?.map_or_else(|| audit_chain::GENESIS.to_string(), |(_, hash)| hash);
+        let digest = audit_chain::message_hash(message);
+        let timestamp = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        tx.execute(
+            "INSERT INTO audit_log (wxid, sender, nickname, direction, message, claude_session, timestamp, message_hash, prev_hash)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![wxid, sender, nickname, direction, message, claude_session, timestamp, digest, prev_hash],
         )?;
+        let id = tx.last_insert_rowid();
+        let hash = audit_chain::entry_hash(
+            &prev_hash,
+            &ChainFields {
+                id,
+                wxid,
+                sender,
+                nickname,
+                direction,
+                message_hash: digest.as_deref(),
+                claude_session,
+                timestamp: Some(&timestamp),
+            },
+        );
+        tx.execute("UPDATE audit_log SET hash = ? WHERE id = ?", params![hash, id])?;
+        tx.commit()?;
         Ok(())
     }
 
+    /// Id and hash of the newest link in the audit chain, pruned or not.
+    pub fn audit_head(&self) -> anyhow::Result<Option<(i64, String)>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(chain_head(&conn)?)
+    }
+
+    /// Hash of audit entry `id`, if it or its pruned stub is still there.
+    pub fn audit_chain_hash(&self, id: i64) -> anyhow::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let hash = conn
+            .query_row(
+                "SELECT hash FROM audit_log WHERE id = ?1 UNION ALL SELECT hash FROM audit_pruned WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(hash.flatten())
+    }
+
+    /// Walk the audit chain from the oldest entry, checking each entry
+    /// against its hash and each link against the entry before it. Stops at
+    /// the first break. Pruned entries are checked by their links only, and
+    /// redacted ones by their stored message digest.
+    pub fn audit_verify(&self) -> anyhow::Result<ChainReport> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, wxid, nickname, direction, message, claude_session, timestamp, message_hash, prev_hash, hash, 0, sender
+             FROM audit_log
+             UNION ALL
+             SELECT id, NULL, NULL, NULL, NULL, NULL, NULL, NULL, prev_hash, hash, 1, NULL FROM audit_pruned
+             ORDER BY id",
+        )?;
+        let mut rows = stmt.query([])?;
+
+        let mut report = ChainReport::default();
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let pruned: bool = row.get(10)?;
+            let (Some(prev_hash), Some(hash)) = (row.get::<_, Option<String>>(8)?, row.get::<_, Option<String>>(9)?) else {
+                report.broken = Some((id, BreakKind::Unsealed));
+                break;
+            };
+            // Only a pruned entry can have lost its predecessor
+            let expected_prev = match report.head {
+                Some((_, ref head)) => Some(head.as_str()),
+                None if pruned => None,
+                None => Some(audit_chain::GENESIS),
+            };
+            if expected_prev.is_some_and(|expected| expected != prev_hash) {
+                report.broken = Some((id, BreakKind::Link));
+                break;
+            }
+
+            if pruned {
+                report.pruned += 1;
+            } else {
+                let wxid: String = row.get(1)?;
+                let nickname: Option<String> = row.get(2)?;
+                let direction: String = row.get(3)?;
+                let message: Option<String> = row.get(4)?;
+                let claude_session: Option<String> = row.get(5)?;
+                let timestamp: Option<String> = row.get(6)?;
+                let digest: Option<String> = row.get(7)?;
+                let sender: Option<String> = row.get(11)?;
+                if audit_chain::message_hash(message.as_deref()) != digest {
+                    if message.as_deref() != Some(REDACTED_MESSAGE) {
+                        report.broken = Some((id, BreakKind::Content));
+                        break;
+                    }
+                    report.redacted += 1;
+                }
+                let fields = ChainFields {
+                    id,
+                    wxid: &wxid,
+                    sender: sender.as_deref(),
+                    nickname: nickname.as_deref(),
+                    direction: &direction,
+                    message_hash: digest.as_deref(),
+                    claude_session: claude_session.as_deref(),
+                    timestamp: timestamp.as_deref(),
+                };
+                if audit_chain::entry_hash(&prev_hash, &fields) != hash {
+                    report.broken = Some((id, BreakKind::Content));
+                    break;
+                }
+                report.entries += 1;
+            }
+            report.head = Some((id, hash));
+        }
+        Ok(report)
+    }
+
     pub fn audit_get_by_user(&self, wxid: &str, limit: i64) -> anyhow::Result<Vec<AuditEntry>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, wxid, nickname, direction, message, claude_session, timestamp FROM audit_log WHERE wxid = ? ORDER BY timestamp DESC LIMIT ?",
+            "SELECT id, wxid, nickname, direction, message, claude_session, timestamp, sender FROM audit_log WHERE wxid = ?1 OR sender = ?1 ORDER BY timestamp DESC LIMIT ?2",
         )?;
         let rows = stmt.query_map(params![wxid, limit], |row| {
             Ok(AuditEntry {
@@ -394,6 +638,7 @@ impl Database {
                 message: row.get(4)?,
                 claude_session: row.get(5)?,
                 timestamp: row.get(6)?,
+                sender: row.get(7)?,
             })
         })?;
         let mut entries = Vec::new();
@@ -406,7 +651,7 @@ impl Database {
     pub fn audit_get_recent(&self, limit: i64) -> anyhow::Result<Vec<AuditEntry>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, wxid, nickname, direction, message, claude_session, timestamp FROM audit_log ORDER BY timestamp DESC LIMIT ?",
+            "SELECT id, wxid, nickname, direction, message, claude_session, timestamp, sender FROM audit_log ORDER BY timestamp DESC LIMIT ?",
         )?;
         let rows = stmt.query_map(params![limit], |row| {
             Ok(AuditEntry {
@@ -417,6 +662,7 @@ impl Database {
                 message: row.get(4)?,
                 claude_session: row.get(5)?,
                 timestamp: row.get(6)?,
+                sender: row.get(7)?,
             })
         })?;
         let mut entries = Vec::new();
@@ -426,6 +672,241 @@ impl Database {
         Ok(entries)
     }
 
+    /// Audit entries whose message contains `keyword` (case-insensitive),
+    /// newest first, optionally for one user (a conversation, or a member's
+    /// entries in group chats) and from `since` (UTC,
+    /// `YYYY-MM-DD HH:MM:SS`) on. Returns one page of hits and the total
+    /// number of matches.
+    ///
+    /// Keywords of 3+ characters use the `audit_fts` trigram index; shorter
+    /// ones fall back to scanning with LIKE. Logged `/search` commands are
+    /// left out so a search never finds itself.
+    pub fn audit_search(
+        &self,
+        keyword: &str,
+        wxid: Option<&str>,
+        since: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> anyhow::Result<(Vec<AuditHit>, i64)> {
+        let keyword = keyword.trim();
+        if keyword.is_empty() {
+            return Ok((Vec::new(), 0));
+        }
+        let (matcher, pattern) = if keyword.chars().count() >= 3 {
+            (
+                "a.id IN (SELECT rowid FROM audit_fts WHERE audit_fts MATCH ?1)",
+                // One quoted phrase: FTS5 query syntax in the keyword is literal
+                format!("\"{}\"", keyword.replace('"', "\"\"")),
+            )
+        } else {
+            let escaped = keyword.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            ("a.message LIKE ?1 ESCAPE '\\'", format!("%{}%", escaped))
+        };
+        let filter = format!(
+            "FROM audit_log a WHERE {} AND a.message NOT LIKE '/search%'
+             AND (?2 IS NULL OR a.wxid = ?2 OR a.sender = ?2) AND (?3 IS NULL OR a.timestamp >= ?3)",
+            matcher
+        );
+
+        let conn = self.conn.lock().unwrap();
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) {}", filter),
+            params![pattern, wxid, since],
+            |row| row.get(0),
+        )?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT a.id, a.wxid, a.nickname, a.direction, a.message, a.claude_session, a.timestamp, a.sender {}
+             ORDER BY a.id DESC LIMIT ?4 OFFSET ?5",
+            filter
+        ))?;
+        let rows = stmt.query_map(params![pattern, wxid, since, limit, offset], |row| {
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                wxid: row.get(1)?,
+                nickname: row.get(2)?,
+                direction: row.get(3)?,
+                message: row.get(4)?,
+                claude_session: row.get(5)?,
+                timestamp: row.get(6)?,
+                sender: row.get(7)?,
+            })
+        })?;
+        let mut hits = Vec::new();
+        for r in rows {
+            let entry = r?;
+            let snippet = snippet(entry.message.as_deref().unwrap_or(""), keyword, SNIPPET_CONTEXT);
+            hits.push(AuditHit { entry, snippet });
+        }
+        Ok((hits, total))
+    }
+
+    /// Apply `retention`: delete entries past the age or per-user limits,
+    /// after writing them to a gzipped JSONL file in `archive_dir` if given,
+    /// then redact the text of old entries that remain. Deleted entries
+    /// leave their hash chain links behind in `audit_pruned`.
+    pub fn audit_prune(
+        &self,
+        retention: &AuditRetention,
+        archive_dir: Option<&Path>,
+    ) -> anyhow::Result<PruneReport> {
+        let mut report = PruneReport::default();
+        // Fixed cutoffs, so what is archived is exactly what gets deleted
+        let age_cutoff = days_ago(retention.max_age_days);
+        let redact_cutoff = days_ago(retention.redact_after_days);
+        let max_rows = (retention.max_rows_per_user > 0).then_some(retention.max_rows_per_user as i64);
+
+        let mut conn = self.conn.lock().unwrap();
+        if age_cutoff.is_some() || max_rows.is_some() {
+            let tx = conn.transaction()?;
+            let expired = {
+                let mut stmt = tx.prepare(
+                    "SELECT id, wxid, nickname, direction, message, claude_session, timestamp, sender FROM audit_log
+                     WHERE timestamp < ?1
+                        OR id IN (SELECT id FROM (
+                               SELECT id, ROW_NUMBER() OVER (PARTITION BY wxid ORDER BY id DESC) AS n
+                               FROM audit_log
+                           ) WHERE n > ?2)
+                     ORDER BY id",
+                )?;
+                let rows = stmt.query_map(params![age_cutoff, max_rows], |row| {
+                    Ok(AuditEntry {
+                        id: row.get(0)?,
+                        wxid: row.get(1)?,
+                        nickname: row.get(2)?,
+                        direction: row.get(3)?,
+                        message: row.get(4)?,
+                        claude_session: row.get(5)?,
+                        timestamp: row.get(6)?,
+                        sender: row.get(7)?,
+                    })
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()?
+            };
+
+            if !expired.is_empty() {
+                report.archive = match archive_dir {
+                    Some(dir) => Some(write_audit_archive(dir, &expired)?),
+                    None => None,
+                };
+                let result = (|| -> rusqlite::Result<()> {
+                    {
+                        // Keep each entry's links so the chain still verifies
+                        let mut keep_links = tx.prepare(
+                            "INSERT INTO audit_pruned (id, prev_hash, hash)
+                             SELECT id, prev_hash, hash FROM audit_log WHERE id = ?",
+                        )?;
+                        let mut delete = tx.prepare("DELETE FROM audit_log WHERE id = ?")?;
+                        for entry in &expired {
+                            keep_links.execute(params![entry.id])?;
+                            delete.execute(params![entry.id])?;
+                        }
+                    }
+                    // Before the oldest remaining entry only the last link matters
+                    tx.execute(
+                        "DELETE FROM audit_pruned WHERE id < (
+                             SELECT MAX(id) FROM audit_pruned
+                             WHERE id < COALESCE((SELECT MIN(id) FROM audit_log), 9223372036854775807)
+                         )",
+                        [],
+                    )?;
+                    tx.commit()
+                })();
+                if let Err(e) = result {
+                    // Rows that are still there must not stay archived too
+                    if let Some(ref path) = report.archive {
+                        let _ = fs::remove_file(path);
+                    }
+                    return Err(e.into());
+                }
+                report.deleted = expired.len();
+            }
+        }
+
+        if redact_cutoff.is_some() {
+            report.redacted = conn.execute(
+                "UPDATE audit_log SET message = ?1
+                 WHERE timestamp < ?2 AND message IS NOT NULL AND message != ?1",
+                params![REDACTED_MESSAGE, redact_cutoff],
+            )?;
+        }
+        Ok(report)
+    }
+
+    /// Most recent time the user was active: the latest session activity or
+    /// incoming message, whichever is newer.
+    pub fn last_activity(&self, wxid: &str) -> anyhow::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let ts = conn.query_row(
+            "SELECT MAX(ts) FROM (
+                SELECT MAX(last_active) AS ts FROM sessions WHERE wxid = ?1
+                UNION ALL
+                SELECT MAX(timestamp) FROM audit_log WHERE wxid = ?1 AND direction = 'in'
+            )",
+            params![wxid],
+            |row| row.get(0),
+        )?;
+        Ok(ts)
+    }
+
+    // ============================================
+    // Usage accounting
+    // ============================================
+
+    pub fn usage_record(
+        &self,
+        wxid: &str,
+        session_id: Option<&str>,
+        claude_session: Option<&str>,
+        tokens: &TokenCounts,
+        cost_usd: f64,
+    ) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO usage (wxid, session_id, claude_session, input_tokens, output_tokens, cache_creation_tokens, cache_read_tokens, cost_usd)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                wxid,
+                session_id,
+                claude_session,
+                tokens.input,
+                tokens.output,
+                tokens.cache_creation,
+                tokens.cache_read,
+                cost_usd
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Sum a friend's usage since the start of the given period.
+    pub fn usage_for_friend(&self, wxid: &str, period: UsagePeriod) -> anyhow::Result<UsageSummary> {
+        let conn = self.conn.lock().unwrap();
+        let summary = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(input_tokens), 0), COALESCE(SUM(output_tokens), 0),
+                    COALESCE(SUM(cache_creation_tokens), 0), COALESCE(SUM(cache_read_tokens), 0),
+                    COALESCE(SUM(cost_usd), 0)
+             FROM usage WHERE wxid = ? AND timestamp >= datetime('now', ?)",
+            params![wxid, period.start_modifier()],
+            usage_summary_from_row,
+        )?;
+        Ok(summary)
+    }
+
+    /// Sum the usage of a single bridge session.
+    pub fn usage_for_session(&self, session_id: &str) -> anyhow::Result<UsageSummary> {
+        let conn = self.conn.lock().unwrap();
+        let summary = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(input_tokens), 0), COALESCE(SUM(output_tokens), 0),
+                    COALESCE(SUM(cache_creation_tokens), 0), COALESCE(SUM(cache_read_tokens), 0),
+                    COALESCE(SUM(cost_usd), 0)
+             FROM usage WHERE session_id = ?",
+            params![session_id],
+            usage_summary_from_row,
+        )?;
+        Ok(summary)
+    }
+
     // ============================================
     // Rate limiting
     // ============================================
@@ -500,6 +981,189 @@ impl Database {
     }
 }
 
+fn usage_summary_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<UsageSummary> {
+    Ok(UsageSummary {
+        requests: row.get(0)?,
+        tokens: TokenCounts {
+            input: row.get(1)?,
+            output: row.get(2)?,
+            cache_creation: row.get(3)?,
+            cache_read: row.get(4)?,
+        },
+        cost_usd: row.get(5)?,
+    })
+}
+
+/// Id and hash of the newest audit chain link, in the log or pruned.
+fn chain_head(conn: &Connection) -> rusqlite::Result<Option<(i64, String)>> {
+    conn.query_row(
+        "SELECT id, hash FROM (
+             SELECT * FROM (SELECT id, hash FROM audit_log ORDER BY id DESC LIMIT 1)
+             UNION ALL
+             SELECT * FROM (SELECT id, hash FROM audit_pruned ORDER BY id DESC LIMIT 1)
+         ) ORDER BY id DESC LIMIT 1",
+        [],
+        |row| Ok((row.get(0)?, row.get::<_, Option<String>>(1)?.unwrap_or_default())),
+    )
+    .optional()
+}
+
+// ============================================
+// Search helpers
+// ============================================
+
+/// Characters of context kept on each side of the first match in a snippet.
+const SNIPPET_CONTEXT: usize = 20;
+
+/// Excerpt of `text` around the first occurrence of `keyword` (ASCII
+/// case-insensitive, like the search itself), with every occurrence inside
+/// the excerpt wrapped in 【】 and cuts marked with `…`.
+fn snippet(text: &str, keyword: &str, context: usize) -> String {
+    // ASCII lowercasing keeps byte offsets valid in `text`
+    let (lower, needle) = (text.to_ascii_lowercase(), keyword.to_ascii_lowercase());
+    let Some(first) = lower.find(&needle) else {
+        return text.chars().take(context * 2).collect();
+    };
+
+    let start = text[..first]
+        .char_indices()
+        .rev()
+        .nth(context.saturating_sub(1))
+        .map_or(0, |(i, _)| i);
+    let after = first + needle.len();
+    let end = text[after..]
+        .char_indices()
+        .nth(context)
+        .map_or(text.len(), |(i, _)| after + i);
+
+    let mut out = String::new();
+    if start > 0 {
+        out.push('…');
+    }
+    let mut pos = start;
+    for (i, _) in lower[start..end].match_indices(&needle) {
+        let i = start + i;
+        if i < pos {
+            continue;
+        }
+        out.push_str(&text[pos..i]);
+        out.push('【');
+        out.push_str(&text[i..i + needle.len()]);
+        out.push('】');
+        pos = i + needle.len();
+    }
+    out.push_str(&text[pos..end]);
+    if end < text.len() {
+        out.push('…');
+    }
+    out
+}
+
+// ============================================
+// Backup helpers
+// ============================================
+
+fn write_backup(src: &Connection, dir: &Path, keep: usize) -> anyhow::Result<BackupInfo> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    let stamp = Utc::now().format("%Y%m%d-%H%M%S%.3f");
+    let path = dir.join(format!("{}{}.db", BACKUP_PREFIX, stamp));
+    // Copy to a temporary name so a half-written file never looks like a backup
+    let tmp = dir.join(format!(".{}{}.db.tmp", BACKUP_PREFIX, stamp));
+
+    let result = (|| -> anyhow::Result<()> {
+        let mut dst = Connection::open(&tmp)?;
+        Backup::new(src, &mut dst)?.run_to_completion(
+            BACKUP_PAGES_PER_STEP,
+            BACKUP_STEP_PAUSE,
+            None,
+        )?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.context("Database backup failed"));
+    }
+    fs::rename(&tmp, &path)?;
+    let size = fs::metadata(&path)?.len();
+    info!("Database backed up to {:?} ({} bytes)", path, size);
+
+    for stale in list_backups(dir)?.into_iter().rev().skip(keep.max(1)) {
+        if let Err(e) = fs::remove_file(&stale) {
+            warn!("Failed to remove old backup {:?}: {}", stale, e);
+        }
+    }
+    Ok(BackupInfo { path, size })
+}
+
+/// Backup files in `dir`, oldest first (their names sort by time).
+fn list_backups(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(BACKUP_PREFIX) && n.ends_with(".db"))
+        })
+        .collect();
+    backups.sort();
+    Ok(backups)
+}
+
+// ============================================
+// Retention helpers
+// ============================================
+
+/// UTC timestamp `days` days ago in the audit log's format; `None` for 0.
+fn days_ago(days: u64) -> Option<String> {
+    if days == 0 {
+        return None;
+    }
+    let cutoff = Utc::now() - chrono::Duration::days(days.min(365_000) as i64);
+    Some(cutoff.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+/// Write `entries` to a new `audit-<timestamp>.jsonl.gz` in `dir`, one JSON
+/// object per line.
+fn write_audit_archive(dir: &Path, entries: &[AuditEntry]) -> anyhow::Result<PathBuf> {
+    use std::io::Write;
+
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    let stamp = Utc::now().format("%Y%m%d-%H%M%S%.3f");
+    let path = dir.join(format!("{}{}.jsonl.gz", ARCHIVE_PREFIX, stamp));
+    let tmp = dir.join(format!(".{}{}.jsonl.gz.tmp", ARCHIVE_PREFIX, stamp));
+
+    let result = (|| -> anyhow::Result<()> {
+        let file = fs::File::create(&tmp)?;
+        let mut out = flate2::write::GzEncoder::new(
+            std::io::BufWriter::new(file),
+            flate2::Compression::default(),
+        );
+        for e in entries {
+            let line = serde_json::json!({
+                "id": e.id,
+                "wxid": e.wxid,
+                "sender": e.sender,
+                "nickname": e.nickname,
+                "direction": e.direction,
+                "message": e.message,
+                "claude_session": e.claude_session,
+                "timestamp": e.timestamp,
+            });
+            serde_json::to_writer(&mut out, &line)?;
+            out.write_all(b"\n")?;
+        }
+        out.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.context("Failed to write audit archive"));
+    }
+    fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -598,6 +1262,76 @@ mod tests {
         assert_eq!(recent.len(), 3);
     }
 
+    #[test]
+    fn audit_search_finds_keywords_and_pages() {
+        let db = test_db();
+        db.audit_log("wx_a1", Some("Alice"), "in", Some("please fix the Docker build"), None)
+            .unwrap();
+        db.audit_log("wx_a1", Some("Alice"), "out", Some("帮你修好了数据库连接"), None)
+            .unwrap();
+        db.audit_log("wx_b1", Some("Bob"), "in", Some("docker compose is slow"), None)
+            .unwrap();
+        db.audit_log("wx_b1", Some("Bob"), "in", None, None).unwrap();
+        db.audit_log("wx_admin", None, "in", Some("/search docker"), None)
+            .unwrap();
+
+        // Trigram index, case-insensitive, newest first
+        let (hits, total) = db.audit_search("DOCKER", None, None, 10, 0).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(hits[0].entry.wxid, "wx_b1");
+        assert_eq!(hits[0].snippet, "【docker】 compose is slow");
+        assert_eq!(hits[1].snippet, "please fix the 【Docker】 build");
+
+        // Chinese keywords, including ones too short for the index
+        let (hits, total) = db.audit_search("数据库", None, None, 10, 0).unwrap();
+        assert_eq!((hits.len(), total), (1, 1));
+        assert!(hits[0].snippet.contains("【数据库】"));
+        let (_, total) = db.audit_search("修好", None, None, 10, 0).unwrap();
+        assert_eq!(total, 1);
+
+        // LIKE wildcards and FTS syntax are literal
+        assert_eq!(db.audit_search("%", None, None, 10, 0).unwrap().1, 0);
+        assert_eq!(db.audit_search("docker OR x", None, None, 10, 0).unwrap().1, 0);
+
+        // Filters and pagination
+        assert_eq!(db.audit_search("docker", Some("wx_a1"), None, 10, 0).unwrap().1, 1);
+        assert_eq!(db.audit_search("docker", None, Some("2999-01-01 00:00:00"), 10, 0).unwrap().1, 0);
+        let (hits, total) = db.audit_search("docker", None, None, 1, 1).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].entry.wxid, "wx_a1");
+    }
+
+    #[test]
+    fn audit_search_index_follows_deletes_and_updates() {
+        let db = test_db();
+        db.audit_log("wx_a1", Some("Alice"), "in", Some("deploy to staging"), None)
+            .unwrap();
+        db.audit_log("wx_a1", Some("Alice"), "in", Some("deploy to prod"), None)
+            .unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            conn.execute("DELETE FROM audit_log WHERE message = 'deploy to prod'", [])
+                .unwrap();
+            conn.execute("UPDATE audit_log SET message = 'rollback staging'", [])
+                .unwrap();
+        }
+        assert_eq!(db.audit_search("deploy", None, None, 10, 0).unwrap().1, 0);
+        assert_eq!(db.audit_search("rollback", None, None, 10, 0).unwrap().1, 1);
+    }
+
+    #[test]
+    fn snippet_trims_and_highlights() {
+        let text = format!("{}needle{}", "a".repeat(30), "b".repeat(30));
+        assert_eq!(
+            snippet(&text, "NEEDLE", 3),
+            "…aaa【needle】bbb…"
+        );
+        assert_eq!(snippet("一二三四五六", "三", 1), "…二【三】四…");
+        assert_eq!(snippet("ab ab", "ab", 20), "【ab】 【ab】");
+        assert_eq!(snippet("no match here", "zz", 2), "no m");
+    }
+
     #[test]
     fn rate_limiting() {
         let db = test_db();
@@ -615,6 +1349,54 @@ mod tests {
         assert!(r.reason.is_some());
     }
 
+    #[test]
+    fn usage_record_and_sum() {
+        let db = test_db();
+        let tokens = TokenCounts {
+            input: 10,
+            output: 20,
+            cache_creation: 100,
+            cache_read: 1000,
+        };
+        db.usage_record("wx_u", Some("sess_u1"), Some("cs_1"), &tokens, 0.25)
+            .unwrap();
+        db.usage_record("wx_u", Some("sess_u2"), None, &tokens, 0.5)
+            .unwrap();
+        db.usage_record("wx_other", Some("sess_o"), None, &tokens, 9.0)
+            .unwrap();
+
+        let day = db.usage_for_friend("wx_u", UsagePeriod::Day).unwrap();
+        assert_eq!(day.requests, 2);
+        assert_eq!(day.tokens.input, 20);
+        assert_eq!(day.tokens.cache_read, 2000);
+        assert!((day.cost_usd - 0.75).abs() < 1e-9);
+        assert_eq!(db.usage_for_friend("wx_u", UsagePeriod::Month).unwrap(), day);
+
+        let session = db.usage_for_session("sess_u1").unwrap();
+        assert_eq!(session.requests, 1);
+        assert!((session.cost_usd - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn usage_empty_is_zero() {
+        let db = test_db();
+        let s = db.usage_for_friend("wx_none", UsagePeriod::Day).unwrap();
+        assert_eq!(s, UsageSummary::default());
+    }
+
+    #[test]
+    fn usage_old_rows_excluded_from_period() {
+        let db = test_db();
+        db.usage_record("wx_old", None, None, &TokenCounts::default(), 1.0)
+            .unwrap();
+        db.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE usage SET timestamp = datetime('now', '-40 days')", [])
+            .unwrap();
+        assert_eq!(db.usage_for_friend("wx_old", UsagePeriod::Month).unwrap().requests, 0);
+    }
+
     // ============================================
     // NEW: Rate limit boundary edge cases
     // ============================================
@@ -942,4 +1724,282 @@ mod tests {
         assert_eq!(matches.len(), 1);
         assert_eq!(matches[0].wxid, "wx_rn");
     }
+
+    #[test]
+    fn last_activity_takes_newest_of_sessions_and_messages() {
+        let db = test_db();
+        db.friend_upsert("wx_act", Some("Act"), None, None, None, None).unwrap();
+        assert!(db.last_activity("wx_act").unwrap().is_none());
+
+        db.audit_log("wx_act", None, "in", Some("hi"), None).unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            conn.execute(
+                "UPDATE audit_log SET timestamp = '2024-01-01 00:00:00' WHERE wxid = 'wx_act'",
+                [],
+            )
+            .unwrap();
+        }
+        assert_eq!(db.last_activity("wx_act").unwrap().as_deref(), Some("2024-01-01 00:00:00"));
+
+        // Outgoing entries (replies, egress denials) don't count as activity
+        db.audit_log("wx_act", None, "out", Some("reply"), None).unwrap();
+        assert_eq!(db.last_activity("wx_act").unwrap().as_deref(), Some("2024-01-01 00:00:00"));
+
+        db.session_create("s-act", "wx_act", None).unwrap();
+        let latest = db.last_activity("wx_act").unwrap().unwrap();
+        assert!(latest.as_str() > "2024-01-01 00:00:00");
+    }
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wcb-db-{}-{}", tag, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn file_database_uses_wal_and_backs_up_while_in_use() {
+        let dir = temp_dir("backup");
+        let db = Database::new(Some(&dir.join("bridge.db")))
+            .unwrap()
+            .with_busy_timeout(Duration::from_millis(200))
+            .unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            let mode: String = conn.pragma_query_value(None, "journal_mode", |r| r.get(0)).unwrap();
+            assert_eq!(mode, "wal");
+        }
+        db.friend_upsert("wx_b", Some("Bob"), None, Some("trusted"), None, None).unwrap();
+
+        // A reader mid-transaction doesn't block the backup
+        let reader = Connection::open(dir.join("bridge.db")).unwrap();
+        reader.execute_batch("BEGIN; SELECT COUNT(*) FROM friends;").unwrap();
+
+        let backups = dir.join("backups");
+        let mut written = Vec::new();
+        for _ in 0..3 {
+            let info = db.backup(&backups, 2).unwrap();
+            assert!(info.size > 0);
+            written.push(info.path);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        reader.execute_batch("COMMIT").unwrap();
+        // The database stays writable
+        db.friend_upsert("wx_c", Some("Carol"), None, None, None, None).unwrap();
+
+        // Only the newest two are kept
+        assert_eq!(list_backups(&backups).unwrap(), written[1..].to_vec());
+        let copy = Database::new(Some(&written[2])).unwrap();
+        assert_eq!(copy.friend_get("wx_b").unwrap().unwrap().permission, "trusted");
+        assert!(copy.friend_get("wx_c").unwrap().is_none());
+
+        // The CLI path reads the file without going through Database::new
+        let info = Database::backup_file(&dir.join("bridge.db"), &backups, 2).unwrap();
+        let copy = Database::new(Some(&info.path)).unwrap();
+        assert!(copy.friend_get("wx_c").unwrap().is_some());
+        assert!(Database::backup_file(&dir.join("missing.db"), &backups, 2).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn in_memory_database_backs_up() {
+        let dir = temp_dir("memory");
+        let db = test_db();
+        db.friend_upsert("wx_m", Some("Mem"), None, None, None, None).unwrap();
+        let info = db.backup(&dir, 3).unwrap();
+        let copy = Database::new(Some(&info.path)).unwrap();
+        assert!(copy.friend_get("wx_m").unwrap().is_some());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Log `message` for `wxid`, `days` days ago.
+    fn audit_log_aged(db: &Database, wxid: &str, message: &str, days: i64) {
+        db.audit_log(wxid, None, "in", Some(message), None).unwrap();
+        db.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE audit_log SET timestamp = datetime('now', ?) WHERE id = last_insert_rowid()",
+                params![format!("-{} days", days)],
+            )
+            .unwrap();
+    }
+
+    fn audit_messages(db: &Database) -> Vec<String> {
+        let mut entries = db.audit_get_recent(100).unwrap();
+        entries.sort_by_key(|e| e.id);
+        entries.into_iter().filter_map(|e| e.message).collect()
+    }
+
+    #[test]
+    fn audit_prune_archives_expired_entries() {
+        use std::io::Read;
+
+        let dir = temp_dir("archive");
+        let db = test_db();
+        audit_log_aged(&db, "wx_a", "ancient history", 400);
+        audit_log_aged(&db, "wx_b", "last year", 200);
+        audit_log_aged(&db, "wx_a", "last week", 7);
+
+        let retention = AuditRetention { max_age_days: 180, ..Default::default() };
+        let report = db.audit_prune(&retention, Some(&dir)).unwrap();
+        assert_eq!(report.deleted, 2);
+        assert_eq!(audit_messages(&db), vec!["last week"]);
+        // The search index follows
+        assert_eq!(db.audit_search("history", None, None, 10, 0).unwrap().1, 0);
+
+        let archive = report.archive.unwrap();
+        assert!(archive.file_name().unwrap().to_str().unwrap().ends_with(".jsonl.gz"));
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(&archive).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        let lines: Vec<serde_json::Value> =
+            text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["wxid"], "wx_a");
+        assert_eq!(lines[0]["message"], "ancient history");
+        assert_eq!(lines[1]["message"], "last year");
+
+        // Nothing left to do: no new archive
+        let report = db.audit_prune(&retention, Some(&dir)).unwrap();
+        assert_eq!(report, PruneReport::default());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn audit_prune_caps_rows_per_user_and_redacts() {
+        let db = test_db();
+        for i in 0..5 {
+            audit_log_aged(&db, "wx_a", &format!("a{}", i), 10 - 2 * i);
+        }
+        audit_log_aged(&db, "wx_b", "b0", 40);
+
+        let retention = AuditRetention {
+            max_rows_per_user: 3,
+            redact_after_days: 5,
+            ..Default::default()
+        };
+        let report = db.audit_prune(&retention, None).unwrap();
+        assert_eq!(report.deleted, 2);
+        assert_eq!(report.redacted, 2);
+        assert_eq!(report.archive, None);
+        assert_eq!(
+            audit_messages(&db),
+            vec![REDACTED_MESSAGE, "a3", "a4", REDACTED_MESSAGE]
+        );
+        assert_eq!(db.audit_search("b0", None, None, 10, 0).unwrap().1, 0);
+
+        // Already redacted entries aren't counted again
+        assert_eq!(db.audit_prune(&retention, None).unwrap(), PruneReport::default());
+        assert!(!AuditRetention::default().is_enabled());
+    }
+
+    fn tamper(db: &Database, sql: &str) {
+        db.conn.lock().unwrap().execute_batch(sql).unwrap();
+    }
+
+    #[test]
+    fn audit_chain_links_every_entry() {
+        let db = test_db();
+        assert_eq!(db.audit_verify().unwrap(), ChainReport::default());
+        assert_eq!(db.audit_head().unwrap(), None);
+
+        for i in 0..4 {
+            db.audit_log("wx_a", Some("Alice"), "in", Some(&format!("m{}", i)), None)
+                .unwrap();
+        }
+        let report = db.audit_verify().unwrap();
+        assert_eq!(report.entries, 4);
+        assert_eq!(report.broken, None);
+        let (id, hash) = report.head.clone().unwrap();
+        assert_eq!(db.audit_head().unwrap(), Some((id, hash.clone())));
+        assert_eq!(db.audit_chain_hash(id).unwrap(), Some(hash));
+        assert_eq!(db.audit_chain_hash(99).unwrap(), None);
+    }
+
+    #[test]
+    fn audit_verify_reports_the_first_broken_link() {
+        let db = test_db();
+        for i in 0..5 {
+            db.audit_log("wx_a", Some("Alice"), "in", Some(&format!("m{}", i)), None)
+                .unwrap();
+        }
+
+        // Edited text
+        tamper(&db, "UPDATE audit_log SET message = 'rm -rf /' WHERE id = 4");
+        assert_eq!(db.audit_verify().unwrap().broken, Some((4, BreakKind::Content)));
+        // Edited field, with a matching message digest
+        tamper(&db, "UPDATE audit_log SET message = 'm3' WHERE id = 4");
+        tamper(&db, "UPDATE audit_log SET direction = 'out' WHERE id = 2");
+        let report = db.audit_verify().unwrap();
+        assert_eq!(report.broken, Some((2, BreakKind::Content)));
+        assert_eq!(report.entries, 1);
+        tamper(&db, "UPDATE audit_log SET direction = 'in' WHERE id = 2");
+        assert_eq!(db.audit_verify().unwrap().broken, None);
+
+        // Removed entry
+        tamper(&db, "DELETE FROM audit_log WHERE id = 3");
+        assert_eq!(db.audit_verify().unwrap().broken, Some((4, BreakKind::Link)));
+
+        // Removing the first entry is a break too
+        let db = test_db();
+        db.audit_log("wx_a", None, "in", Some("first"), None).unwrap();
+        db.audit_log("wx_a", None, "in", Some("second"), None).unwrap();
+        tamper(&db, "DELETE FROM audit_log WHERE id = 1");
+        assert_eq!(db.audit_verify().unwrap().broken, Some((2, BreakKind::Link)));
+
+        // An entry added behind the bridge's back
+        let db = test_db();
+        db.audit_log("wx_a", None, "in", Some("first"), None).unwrap();
+        tamper(&db, "INSERT INTO audit_log (wxid, direction, message) VALUES ('wx_a', 'in', 'forged')");
+        assert_eq!(db.audit_verify().unwrap().broken, Some((2, BreakKind::Unsealed)));
+    }
+
+    #[test]
+    fn audit_chain_survives_pruning_and_redaction() {
+        let db = test_db();
+        for i in 0..4 {
+            audit_log_aged(&db, "wx_a", &format!("a{}", i), 30 - i);
+        }
+        // Backdating edits the entries the chain covers, so reseal them
+        tamper(&db, "UPDATE audit_log SET message_hash = NULL, prev_hash = NULL, hash = NULL");
+        audit_chain::seal_unhashed(&db.conn.lock().unwrap()).unwrap();
+        for i in 0..3 {
+            db.audit_log("wx_b", None, "in", Some(&format!("b{}", i)), None).unwrap();
+        }
+        db.audit_log("wx_a", None, "in", Some("a4"), None).unwrap();
+        assert_eq!(db.audit_verify().unwrap().entries, 8);
+
+        // Age limit drops the oldest two entries, the per-user cap one of
+        // Bob's from the middle; the rest of Alice's get redacted
+        let retention = AuditRetention {
+            max_age_days: 28,
+            max_rows_per_user: 2,
+            redact_after_days: 1,
+        };
+        let report = db.audit_prune(&retention, None).unwrap();
+        assert_eq!(report.deleted, 4);
+        assert_eq!(report.redacted, 1);
+        assert_eq!(audit_messages(&db), vec![REDACTED_MESSAGE, "b1", "b2", "a4"]);
+
+        let chain = db.audit_verify().unwrap();
+        assert_eq!(chain.broken, None);
+        assert_eq!((chain.entries, chain.pruned, chain.redacted), (4, 2, 1));
+        // New entries chain on as before
+        db.audit_log("wx_b", None, "in", Some("b3"), None).unwrap();
+        assert_eq!(db.audit_verify().unwrap().broken, None);
+
+        // Pruning everything leaves just the head behind
+        tamper(&db, "UPDATE audit_log SET timestamp = '2000-01-01 00:00:00'");
+        let head = db.audit_head().unwrap();
+        db.audit_prune(&AuditRetention { max_age_days: 1, ..Default::default() }, None).unwrap();
+        assert_eq!(db.audit_head().unwrap(), head);
+        let chain = db.audit_verify().unwrap();
+        assert_eq!((chain.entries, chain.pruned, chain.broken), (0, 1, None));
+        db.audit_log("wx_a", None, "in", Some("fresh"), None).unwrap();
+        assert_eq!(db.audit_verify().unwrap().broken, None);
+    }
 }
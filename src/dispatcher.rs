@@ -0,0 +1,255 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use futures_util::future::BoxFuture;
+use tracing::debug;
+
+use crate::wechat_bot::{Contact, Message};
+
+/// Handles one message (or merged batch) for a contact, including sending
+/// the reply.
+pub type MessageHandler = Arc<dyn Fn(Contact, Message) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// What `Dispatcher::dispatch` did with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    /// The user was idle; a worker started on it.
+    Started,
+    /// Waiting behind the user's message in flight.
+    Queued,
+    /// The user's inbox is full; the message was dropped.
+    Rejected,
+}
+
+/// Messages waiting in one conversation, each with its sender.
+struct Inbox {
+    pending: VecDeque<(Contact, Message)>,
+}
+
+/// Per-conversation FIFO inboxes in front of the message handler.
+///
+/// Each conversation (a private chat, or a group shared by its members)
+/// with messages has one worker task that handles them in order, so a
+/// conversation never has two Claude runs at once and a slow one doesn't
+/// hold up anyone else. Workers of different conversations run
+/// concurrently; the global Claude limit is enforced by the executor's
+/// scheduler.
+pub struct Dispatcher {
+    handler: MessageHandler,
+    /// Conversation ID -> inbox; present exactly while its worker is alive.
+    inboxes: Mutex<HashMap<String, Inbox>>,
+    max_pending: usize,
+    merge: bool,
+}
+
+impl Dispatcher {
+    pub fn new(handler: MessageHandler, max_pending: usize, merge: bool) -> Self {
+        Self {
+            handler,
+            inboxes: Mutex::new(HashMap::new()),
+            max_pending,
+            merge,
+        }
+    }
+
+    /// Queue a message in its conversation, starting the worker if needed.
+    pub fn dispatch(self: &Arc<Self>, contact: Contact, message: Message) -> Dispatch {
+        let mut inboxes = self.inboxes.lock().unwrap();
+        let conversation = contact.conversation_id().to_string();
+        if let Some(inbox) = inboxes.get_mut(&conversation) {
+            if inbox.pending.len() >= self.max_pending {
+                return Dispatch::Rejected;
+            }
+            inbox.pending.push_back((contact, message));
+            return Dispatch::Queued;
+        }
+
+        inboxes.insert(
+            conversation.clone(),
+            Inbox {
+                pending: VecDeque::from([(contact, message)]),
+            },
+        );
+        tokio::spawn(Arc::clone(self).run_worker(conversation));
+        Dispatch::Started
+    }
+
+    /// Number of conversations with a worker (busy or about to be).
+    #[allow(dead_code)]
+    pub fn active_users(&self) -> usize {
+        self.inboxes.lock().unwrap().len()
+    }
+
+    /// Handle the conversation's messages until its inbox is empty, then
+    /// retire.
+    async fn run_worker(self: Arc<Self>, conversation: String) {
+        while let Some((contact, message)) = self.next_batch(&conversation) {
+            (self.handler)(contact, message).await;
+        }
+        debug!("Inbox drained: {}", conversation);
+    }
+
+    /// Take the next message or, when merging, the run of queued messages
+    /// from the same sender. Removes the inbox when it is empty, under the
+    /// same lock `dispatch` checks, so a new message either lands here or
+    /// starts a new worker.
+    fn next_batch(&self, conversation: &str) -> Option<(Contact, Message)> {
+        let mut inboxes = self.inboxes.lock().unwrap();
+        let inbox = inboxes.get_mut(conversation)?;
+        let Some((contact, first)) = inbox.pending.pop_front() else {
+            inboxes.remove(conversation);
+            return None;
+        };
+        if !self.merge {
+            return Some((contact, first));
+        }
+
+        let mut batch = vec![first];
+        while inbox.pending.front().is_some_and(|(c, _)| c.wxid == contact.wxid) {
+            batch.extend(inbox.pending.pop_front().map(|(_, m)| m));
+        }
+        Message::merge(batch).map(|message| (contact, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn contact(wxid: &str) -> Contact {
+        Contact {
+            wxid: wxid.to_string(),
+            nickname: wxid.to_string(),
+            remark_name: String::new(),
+            group: None,
+        }
+    }
+
+    fn member(wxid: &str, group: &str) -> Contact {
+        Contact {
+            group: Some(crate::wechat_bot::Group {
+                id: group.to_string(),
+                name: group.to_string(),
+            }),
+            ..contact(wxid)
+        }
+    }
+
+    type Seen = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// Handler that records `(wxid, text)` and takes `delay` per message,
+    /// tracking the highest number of handlers in flight.
+    fn recording_handler(delay: Duration) -> (MessageHandler, Seen, Arc<AtomicUsize>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let handler: MessageHandler = {
+            let seen = Arc::clone(&seen);
+            let peak = Arc::clone(&peak);
+            Arc::new(move |contact: Contact, message: Message| {
+                let seen = Arc::clone(&seen);
+                let in_flight = Arc::clone(&in_flight);
+                let peak = Arc::clone(&peak);
+                Box::pin(async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(delay).await;
+                    seen.lock().unwrap().push((contact.wxid, message.text));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+        };
+        (handler, seen, peak)
+    }
+
+    async fn wait_idle(dispatcher: &Dispatcher) {
+        while dispatcher.active_users() > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_while_busy_are_merged_into_one_turn() {
+        let (handler, seen, _) = recording_handler(Duration::from_millis(50));
+        let d = Arc::new(Dispatcher::new(handler, 5, true));
+
+        assert_eq!(d.dispatch(contact("wx_a"), Message::plain("first")), Dispatch::Started);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(d.dispatch(contact("wx_a"), Message::plain("second")), Dispatch::Queued);
+        assert_eq!(d.dispatch(contact("wx_a"), Message::plain("third")), Dispatch::Queued);
+        wait_idle(&d).await;
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            *seen,
+            vec![
+                ("wx_a".to_string(), "first".to_string()),
+                ("wx_a".to_string(), "second\nthird".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_run_in_order_without_merging_and_overflow_is_rejected() {
+        let (handler, seen, _) = recording_handler(Duration::from_millis(50));
+        let d = Arc::new(Dispatcher::new(handler, 2, false));
+
+        assert_eq!(d.dispatch(contact("wx_a"), Message::plain("1")), Dispatch::Started);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(d.dispatch(contact("wx_a"), Message::plain("2")), Dispatch::Queued);
+        assert_eq!(d.dispatch(contact("wx_a"), Message::plain("3")), Dispatch::Queued);
+        assert_eq!(d.dispatch(contact("wx_a"), Message::plain("4")), Dispatch::Rejected);
+        wait_idle(&d).await;
+
+        let texts: Vec<String> = seen.lock().unwrap().iter().map(|(_, t)| t.clone()).collect();
+        assert_eq!(texts, vec!["1", "2", "3"]);
+
+        // The worker retired; the next message starts a new one
+        assert_eq!(d.dispatch(contact("wx_a"), Message::plain("5")), Dispatch::Started);
+        wait_idle(&d).await;
+        assert_eq!(seen.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn group_members_share_one_inbox_and_merge_per_sender() {
+        let (handler, seen, peak) = recording_handler(Duration::from_millis(50));
+        let d = Arc::new(Dispatcher::new(handler, 5, true));
+
+        assert_eq!(d.dispatch(member("wx_a", "grp"), Message::plain("a1")), Dispatch::Started);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(d.dispatch(member("wx_b", "grp"), Message::plain("b1")), Dispatch::Queued);
+        assert_eq!(d.dispatch(member("wx_b", "grp"), Message::plain("b2")), Dispatch::Queued);
+        assert_eq!(d.dispatch(member("wx_a", "grp"), Message::plain("a2")), Dispatch::Queued);
+        // A private chat with the same member is a separate conversation
+        assert_eq!(d.dispatch(contact("wx_a"), Message::plain("dm")), Dispatch::Started);
+        wait_idle(&d).await;
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.retain(|(_, text)| text != "dm");
+        assert_eq!(
+            seen,
+            vec![
+                ("wx_a".to_string(), "a1".to_string()),
+                ("wx_b".to_string(), "b1\nb2".to_string()),
+                ("wx_a".to_string(), "a2".to_string()),
+            ]
+        );
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn users_run_concurrently() {
+        let (handler, seen, peak) = recording_handler(Duration::from_millis(50));
+        let d = Arc::new(Dispatcher::new(handler, 5, true));
+
+        for wxid in ["wx_a", "wx_b", "wx_c", "wx_d"] {
+            assert_eq!(d.dispatch(contact(wxid), Message::plain("hi")), Dispatch::Started);
+        }
+        wait_idle(&d).await;
+
+        assert_eq!(seen.lock().unwrap().len(), 4);
+        assert_eq!(peak.load(Ordering::SeqCst), 4);
+    }
+}
@@ -1,31 +1,39 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
-use bollard::container::{
-    Config, CreateContainerOptions, InspectContainerOptions, ListContainersOptions,
-    RemoveContainerOptions, StartContainerOptions, Stats, StatsOptions, StopContainerOptions,
-};
-use bollard::exec::{CreateExecOptions, StartExecResults};
-use bollard::image::BuildImageOptions;
-use bollard::models::{
-    ContainerStateStatusEnum, HostConfig, RestartPolicy, RestartPolicyNameEnum,
-};
-use bollard::network::{CreateNetworkOptions, InspectNetworkOptions};
-use bollard::Docker;
-use futures_util::StreamExt;
+use bollard::container::Config;
+use bollard::models::{HostConfig, RestartPolicy, RestartPolicyNameEnum};
 use tokio::fs;
 use tracing::{debug, error, info, warn};
 
+use crate::claude_cli::{
+    auth_env, build_args, feed_stream, finish_run, ExecClaudeOptions, ExecClaudeResult,
+    StreamJsonParser,
+};
+use crate::container_runtime::{BollardRuntime, ContainerRuntime, ExecSpec};
+
+/// The bridge-managed network for trusted users. With the egress proxy
+/// enabled it is `internal` and only reaches the outside through the proxy.
+pub const LIMITED_NETWORK: &str = "claude-limited";
+
 /// Docker configuration for container limits, network, and naming.
 #[derive(Debug, Clone)]
 pub struct DockerConfig {
     pub image: String,
     pub container_prefix: String,
     pub data_dir: PathBuf,
+    /// Engine API socket; None uses DOCKER_HOST or the default socket.
+    pub socket: Option<String>,
     pub limits: DockerLimits,
     pub network: DockerNetworkConfig,
+    pub runtime: DockerRuntimeConfig,
+    /// Port of the egress proxy on the `claude-limited` gateway; None leaves
+    /// the network as a plain bridge with direct internet access.
+    pub egress_proxy_port: Option<u16>,
+    pub pool: DockerPoolConfig,
 }
 
 #[derive(Debug, Clone)]
@@ -45,6 +53,46 @@ pub struct DockerNetworkConfig {
     pub normal: String,
 }
 
+/// OCI runtime (`HostConfig.runtime`) per permission level; None = engine default.
+#[derive(Debug, Clone, Default)]
+pub struct DockerRuntimeConfig {
+    pub admin: Option<String>,
+    pub trusted: Option<String>,
+    pub normal: Option<String>,
+}
+
+/// Pre-warmed containers kept per permission level (0 = no pool).
+#[derive(Debug, Clone)]
+pub struct DockerPoolConfig {
+    pub admin: usize,
+    pub trusted: usize,
+    pub normal: usize,
+    /// Wait this long after a claim before refilling, so a burst of new
+    /// friends is refilled in one pass.
+    pub refill_delay: Duration,
+}
+
+impl DockerPoolConfig {
+    pub fn size(&self, permission: Permission) -> usize {
+        match permission {
+            Permission::Admin => self.admin,
+            Permission::Trusted => self.trusted,
+            Permission::Normal => self.normal,
+        }
+    }
+}
+
+impl Default for DockerPoolConfig {
+    fn default() -> Self {
+        Self {
+            admin: 0,
+            trusted: 0,
+            normal: 0,
+            refill_delay: Duration::from_secs(5),
+        }
+    }
+}
+
 impl Default for DockerConfig {
     fn default() -> Self {
         let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
@@ -52,8 +100,12 @@ impl Default for DockerConfig {
             image: "claude-sandbox:latest".to_string(),
             container_prefix: "claude-friend-".to_string(),
             data_dir: home.join("claude-bridge-data"),
+            socket: None,
             limits: DockerLimits::default(),
             network: DockerNetworkConfig::default(),
+            runtime: DockerRuntimeConfig::default(),
+            egress_proxy_port: None,
+            pool: DockerPoolConfig::default(),
         }
     }
 }
@@ -75,7 +127,7 @@ impl Default for DockerNetworkConfig {
     fn default() -> Self {
         Self {
             admin: "bridge".to_string(),
-            trusted: "claude-limited".to_string(),
+            trusted: LIMITED_NETWORK.to_string(),
             normal: "none".to_string(),
         }
     }
@@ -105,23 +157,6 @@ impl std::fmt::Display for Permission {
     }
 }
 
-/// Result from executing Claude in a container.
-#[derive(Debug)]
-#[allow(dead_code)]
-pub struct ExecClaudeResult {
-    pub ok: bool,
-    pub output: String,
-    pub stderr: String,
-}
-
-/// Options for executing Claude.
-#[derive(Debug, Default)]
-pub struct ExecClaudeOptions {
-    pub timeout: Option<u64>,
-    pub claude_session: Option<String>,
-    pub permission: Option<Permission>,
-}
-
 /// Container info returned by list_containers.
 #[derive(Debug)]
 pub struct ContainerInfo {
@@ -131,8 +166,54 @@ pub struct ContainerInfo {
     pub permission: Option<String>,
 }
 
+/// One entry of a workspace directory listing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkspaceEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// Last modification, in Unix milliseconds.
+    pub modified: i64,
+}
+
+/// A ready, unassigned container in the pre-warmed pool.
+#[derive(Debug, Clone)]
+struct PoolMember {
+    name: String,
+    id: String,
+    permission: Permission,
+}
+
+/// A Claude run in progress, tracked so exactly its processes can be killed.
+#[derive(Debug, Clone)]
+struct ClaudeRun {
+    container: String,
+    exec_id: String,
+    /// In-container file holding the PID of the run's process group leader.
+    pid_file: String,
+}
+
+/// Runs `"$@"` as a process group leader (via `setsid`), recording its PID
+/// in the file named by `$0` for `KILL_RUN_SCRIPT`.
+const RUN_WRAPPER_SCRIPT: &str = "echo $$ > \"$0\"; \"$@\"; rc=$?; rm -f \"$0\"; exit $rc";
+
+/// Kills the process group recorded in `$0`: TERM, then KILL after a
+/// short grace period. Prints "killed" if there was a group to signal.
+///
+/// The run can write the pid file itself, so this runs as `sandbox` (able
+/// to signal its own processes and nothing else) and ignores anything but
+/// a plain PID above 1.
+const KILL_RUN_SCRIPT: &str = concat!(
+    "pid=$(cat \"$0\" 2>/dev/null) || exit 0; rm -f \"$0\"; ",
+    "case \"$pid\" in ''|*[!0-9]*|0|1) exit 0;; esac; ",
+    // No `--`: dash's kill (the sandbox's /bin/sh) rejects it
+    "kill -TERM \"-$pid\" 2>/dev/null || exit 0; echo killed; ",
+    "for i in 1 2 3 4 5 6 7 8 9 10; do kill -0 \"-$pid\" 2>/dev/null || exit 0; sleep 0.2; done; ",
+    "kill -KILL \"-$pid\" 2>/dev/null; exit 0"
+);
+
 /// Container stats snapshot.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ContainerStats {
     pub cpu_percent: f64,
     pub memory_usage: u64,
@@ -148,32 +229,55 @@ pub struct ContainerStats {
 /// - Resource limits: CPU, memory, PID caps
 /// - Network isolation: configurable per permission level
 /// - Persistence: workspace volumes survive restarts
+/// - Pre-warmed pool: first contact claims a ready container instead of
+///   waiting for create + start + setup
 pub struct DockerManager {
-    docker: Docker,
+    runtime: Arc<dyn ContainerRuntime>,
     container_prefix: String,
     image_name: String,
     data_dir: PathBuf,
     config: DockerConfig,
+    /// Ready pool members. Only this process hands them out, so anything
+    /// not in here after a restart is stale (see `reconcile_pool`).
+    pool: std::sync::Mutex<Vec<PoolMember>>,
+    /// Serializes `fill_pool` runs.
+    pool_fill: tokio::sync::Mutex<()>,
+    /// Woken after a claim; see `run_pool_refill`.
+    pool_refill: tokio::sync::Notify,
+    /// wxid -> Claude run in progress.
+    runs: std::sync::Mutex<HashMap<String, ClaudeRun>>,
+    /// Container name -> owner of claimed pool containers, which keep the
+    /// labels they were created with. Filled on claim and by `reconcile_pool`.
+    claimed: std::sync::Mutex<HashMap<String, String>>,
 }
 
 impl DockerManager {
     /// Create a new DockerManager with the given config.
     pub async fn new(config: DockerConfig) -> Result<Self> {
-        let docker = Docker::connect_with_local_defaults()
-            .context("Failed to connect to Docker daemon")?;
+        let runtime = Arc::new(BollardRuntime::connect(config.socket.as_deref())?);
 
         // Ensure data root directory exists
         fs::create_dir_all(&config.data_dir)
             .await
             .with_context(|| format!("Failed to create data dir: {:?}", config.data_dir))?;
 
-        Ok(Self {
-            docker,
+        Ok(Self::with_runtime(config, runtime))
+    }
+
+    /// Create a DockerManager on top of an existing container runtime.
+    pub fn with_runtime(config: DockerConfig, runtime: Arc<dyn ContainerRuntime>) -> Self {
+        Self {
+            runtime,
             container_prefix: config.container_prefix.clone(),
             image_name: config.image.clone(),
             data_dir: config.data_dir.clone(),
             config,
-        })
+            pool: std::sync::Mutex::new(Vec::new()),
+            pool_fill: tokio::sync::Mutex::new(()),
+            pool_refill: tokio::sync::Notify::new(),
+            runs: std::sync::Mutex::new(HashMap::new()),
+            claimed: std::sync::Mutex::new(HashMap::new()),
+        }
     }
 
     // ============================================
@@ -214,8 +318,12 @@ impl DockerManager {
         let name = self.container_name(wxid);
 
         if !self.container_exists(&name).await {
-            self.create_container(wxid, permission).await?;
-            info!("Created container: {}", name);
+            if self.claim_from_pool(wxid, permission).await {
+                info!("Assigned pooled container: {}", name);
+            } else {
+                self.create_container(wxid, permission).await?;
+                info!("Created container: {}", name);
+            }
         }
 
         if !self.is_running(&name).await {
@@ -230,7 +338,21 @@ impl DockerManager {
     pub async fn create_container(&self, wxid: &str, permission: Permission) -> Result<()> {
         let name = self.container_name(wxid);
         let data_dir = self.user_data_dir(wxid).await?;
+        let labels = HashMap::from([("wxid".to_string(), wxid.to_string())]);
+        self.create_sandbox(&name, &data_dir, permission, labels, vec![format!("WXID={}", wxid)])
+            .await
+    }
 
+    /// Create, start and set up a sandbox container whose workspace is
+    /// `data_dir/workspace`. `labels` and `env` are added to the common ones.
+    async fn create_sandbox(
+        &self,
+        name: &str,
+        data_dir: &Path,
+        permission: Permission,
+        labels: HashMap<String, String>,
+        env: Vec<String>,
+    ) -> Result<()> {
         // Determine resource limits based on permission
         let (memory, nano_cpus) = match permission {
             Permission::Admin => (self.config.limits.admin_memory, self.config.limits.admin_cpus),
@@ -238,6 +360,11 @@ impl DockerManager {
         };
 
         let network = self.get_network(permission);
+        let proxy_env = if network == LIMITED_NETWORK {
+            self.egress_proxy_env().await
+        } else {
+            Vec::new()
+        };
 
         let workspace_bind = format!(
             "{}:/home/sandbox/workspace",
@@ -269,6 +396,9 @@ impl DockerManager {
             // Network
             network_mode: Some(network),
 
+            // OCI runtime (e.g. runsc for a gVisor kernel boundary)
+            runtime: self.get_runtime(permission),
+
             // Volume mounts
             binds: Some(vec![workspace_bind]),
 
@@ -282,80 +412,54 @@ impl DockerManager {
         };
 
         // Labels for batch management
-        let mut labels = HashMap::new();
-        labels.insert("app", "wechat-claude-bridge");
-        labels.insert("wxid", wxid);
-        let perm_str = permission.as_str();
-        labels.insert("permission", perm_str);
-
-        let env_wxid = format!("WXID={}", wxid);
+        let mut all_labels = HashMap::from([
+            ("app".to_string(), "wechat-claude-bridge".to_string()),
+            ("permission".to_string(), permission.as_str().to_string()),
+        ]);
+        all_labels.extend(labels);
 
         // Auth: pass CLAUDE_CODE_OAUTH_TOKEN (from `claude setup-token`)
         // or ANTHROPIC_API_KEY into the container. OAuth token is preferred
         // for Claude Code Max subscribers.
-        let mut env_vars = vec![env_wxid.as_str()];
-        let env_oauth_token = std::env::var("CLAUDE_CODE_OAUTH_TOKEN")
-            .ok()
-            .filter(|k| !k.is_empty())
-            .map(|k| format!("CLAUDE_CODE_OAUTH_TOKEN={}", k));
-        if let Some(ref token_env) = env_oauth_token {
-            env_vars.push(token_env.as_str());
-        }
-        let env_api_key = std::env::var("ANTHROPIC_API_KEY")
-            .ok()
-            .filter(|k| !k.is_empty())
-            .map(|k| format!("ANTHROPIC_API_KEY={}", k));
-        if let Some(ref key_env) = env_api_key {
-            env_vars.push(key_env.as_str());
-        }
+        let mut env_vars = env;
+        env_vars.extend(auth_env());
+        env_vars.extend(proxy_env);
 
         let container_config = Config {
-            image: Some(self.image_name.as_str()),
-            cmd: Some(vec!["tail", "-f", "/dev/null"]),
+            image: Some(self.image_name.clone()),
+            cmd: Some(vec!["tail".into(), "-f".into(), "/dev/null".into()]),
             env: Some(env_vars),
-            labels: Some(labels),
+            labels: Some(all_labels),
             host_config: Some(host_config),
             ..Default::default()
         };
 
-        self.docker
-            .create_container(
-                Some(CreateContainerOptions {
-                    name: name.as_str(),
-                    platform: None,
-                }),
-                container_config,
-            )
+        self.runtime
+            .create_container(name, container_config)
             .await
             .with_context(|| format!("Failed to create container: {}", name))?;
 
         // Start the container so fix_permissions can exec into it
-        self.start_container(&name).await?;
+        self.start_container(name).await?;
 
         // Fix volume directory permissions
-        self.fix_permissions(wxid).await;
+        self.fix_permissions(name, data_dir).await;
 
         // Prepare ~/.claude.json and ~/.claude/ dir for Claude Code auth
-        self.prepare_claude_home(wxid).await;
+        self.prepare_claude_home(name).await;
 
         Ok(())
     }
 
     /// Fix volume directory permissions (host-created dirs may be owned by root).
-    async fn fix_permissions(&self, wxid: &str) {
-        let data_dir = match self.user_data_dir(wxid).await {
-            Ok(d) => d,
-            Err(_) => return,
-        };
-
+    async fn fix_permissions(&self, name: &str, data_dir: &Path) {
         // Ensure workspace subdirectory exists
         let _ = fs::create_dir_all(data_dir.join("workspace")).await;
 
         // Use root exec inside the container to chown
-        let name = self.container_name(wxid);
         if let Err(e) = self
             .exec_in_container(
-                &name,
+                name,
                 vec![
                     "chown",
                     "-R",
@@ -373,16 +477,16 @@ impl DockerManager {
     /// Prepare the sandbox home directory for Claude Code:
     ///  - Write ~/.claude.json with hasCompletedOnboarding to skip onboarding
     ///  - Create ~/.claude/ directory for Claude Code config/cache
+    ///
     /// The /home/sandbox tmpfs is owned by sandbox (uid=1001), so no chown needed.
-    async fn prepare_claude_home(&self, wxid: &str) {
-        let name = self.container_name(wxid);
+    async fn prepare_claude_home(&self, name: &str) {
         let setup_cmd = concat!(
             "mkdir -p /home/sandbox/.claude && ",
             "echo '{\"hasCompletedOnboarding\":true}' > /home/sandbox/.claude.json"
         );
         if let Err(e) = self
             .exec_in_container(
-                &name,
+                name,
                 vec!["sh", "-c", setup_cmd],
                 false, // as sandbox user (tmpfs owned by sandbox)
             )
@@ -401,129 +505,326 @@ impl DockerManager {
         }
     }
 
+    /// Get the OCI runtime for a permission level, if one is configured.
+    fn get_runtime(&self, permission: Permission) -> Option<String> {
+        match permission {
+            Permission::Admin => self.config.runtime.admin.clone(),
+            Permission::Trusted => self.config.runtime.trusted.clone(),
+            Permission::Normal => self.config.runtime.normal.clone(),
+        }
+    }
+
     // ============================================
-    // Execute commands in container
+    // Pre-warmed pool
     // ============================================
+    //
+    // Pool members are full sandboxes (started, chowned, ~/.claude.json
+    // written) labelled `pool=<level>` and `pool_id=<id>`, with their
+    // workspace at `data_dir/.pool/<id>`. Docker can't change a container's
+    // labels or mounts, so claiming one renames the container and moves
+    // the pool dir to `data_dir/<wxid>`, leaving `.pool/<id>` as a symlink
+    // to it: the bind mount still resolves after a restart. The owner is
+    // recorded in `claimed` on claim, and recovered from the symlink by
+    // `reconcile_pool` after a restart.
+
+    /// Host directory holding pool member workspaces and claim symlinks.
+    fn pool_dir(&self) -> PathBuf {
+        self.data_dir.join(".pool")
+    }
 
-    /// Execute Claude Code in a user's container. This is the core method.
-    pub async fn exec_claude(
-        &self,
-        wxid: &str,
-        system_prompt: &str,
-        message: &str,
-        options: ExecClaudeOptions,
-    ) -> ExecClaudeResult {
-        let name = self.container_name(wxid);
-        let timeout_secs = options.timeout.unwrap_or(120);
+    /// The wxid a pool container was claimed by, if any.
+    async fn claimed_wxid(&self, pool_id: &str) -> Option<String> {
+        let target = fs::read_link(self.pool_dir().join(pool_id)).await.ok()?;
+        target.file_name().map(|n| n.to_string_lossy().into_owned())
+    }
 
-        // Build claude command
-        let mut cmd = vec![
-            "claude".to_string(),
-            "--print".to_string(),
-            "--output-format".to_string(),
-            "text".to_string(),
-            "--system-prompt".to_string(),
-            system_prompt.to_string(),
-        ];
+    /// Hand a ready pool container to `wxid`. Returns false (and the caller
+    /// creates a container from scratch) when the pool has none for this
+    /// level, or when the user already has a data dir: an existing workspace
+    /// can't be attached to a container created without it.
+    async fn claim_from_pool(&self, wxid: &str, permission: Permission) -> bool {
+        let user_dir = self.data_dir.join(wxid);
+        if fs::try_exists(&user_dir).await.unwrap_or(true) {
+            return false;
+        }
+
+        loop {
+            let member = {
+                let mut pool = self.pool.lock().unwrap();
+                match pool.iter().position(|m| m.permission == permission) {
+                    Some(i) => pool.remove(i),
+                    None => return false,
+                }
+            };
+            self.pool_refill.notify_one();
 
-        // Session resume
-        if let Some(ref session) = options.claude_session {
-            cmd.push("--resume".to_string());
-            cmd.push(session.clone());
+            if !self.is_running(&member.name).await {
+                warn!("Pool container {} is not running; discarding it", member.name);
+                self.remove_pool_member(&member.name, &member.id).await;
+                continue;
+            }
+
+            let name = self.container_name(wxid);
+            if let Err(e) = self.runtime.rename_container(&member.name, &name).await {
+                warn!("Failed to rename pool container {}: {}", member.name, e);
+                self.remove_pool_member(&member.name, &member.id).await;
+                return false;
+            }
+            if let Err(e) = self.attach_pool_workspace(&member.id, wxid).await {
+                warn!("Failed to attach workspace of {} for {}: {}", member.name, wxid, e);
+                let _ = self.runtime.remove_container(&name, true).await;
+                return false;
+            }
+            self.claimed.lock().unwrap().insert(name, wxid.to_string());
+            return true;
         }
+    }
+
+    /// Move `.pool/<id>` to the user's data dir and leave a relative symlink
+    /// (`../<wxid>`) in its place.
+    async fn attach_pool_workspace(&self, pool_id: &str, wxid: &str) -> Result<()> {
+        let link = self.pool_dir().join(pool_id);
+        fs::rename(&link, self.data_dir.join(wxid)).await?;
+        fs::symlink(Path::new("..").join(wxid), &link).await?;
+        Ok(())
+    }
 
-        // Permission-based tool restrictions
-        if let Some(Permission::Normal) = options.permission {
-            cmd.push("--allowedTools".to_string());
-            cmd.push(String::new());
+    /// Create one pool member for a permission level.
+    async fn create_pool_member(&self, permission: Permission) -> Result<PoolMember> {
+        let id = uuid::Uuid::new_v4().simple().to_string()[..12].to_string();
+        let name = format!("{}pool-{}-{}", self.container_prefix, permission, id);
+        let dir = self.pool_dir().join(&id);
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create pool dir: {:?}", dir))?;
+
+        let labels = HashMap::from([
+            ("pool".to_string(), permission.as_str().to_string()),
+            ("pool_id".to_string(), id.clone()),
+        ]);
+        if let Err(e) = self.create_sandbox(&name, &dir, permission, labels, Vec::new()).await {
+            self.remove_pool_member(&name, &id).await;
+            return Err(e);
         }
+        Ok(PoolMember { name, id, permission })
+    }
 
-        // User message
-        cmd.push(message.to_string());
+    /// Force-remove an unclaimed pool container and its workspace.
+    async fn remove_pool_member(&self, name: &str, pool_id: &str) {
+        let _ = self.runtime.remove_container(name, true).await;
+        let _ = fs::remove_dir_all(self.pool_dir().join(pool_id)).await;
+    }
 
-        let cmd_refs: Vec<&str> = cmd.iter().map(|s| s.as_str()).collect();
+    /// Top every level up to its configured pool size.
+    pub async fn fill_pool(&self) -> Result<()> {
+        let _guard = self.pool_fill.lock().await;
+        for permission in [Permission::Admin, Permission::Trusted, Permission::Normal] {
+            let have = {
+                let pool = self.pool.lock().unwrap();
+                pool.iter().filter(|m| m.permission == permission).count()
+            };
+            for _ in have..self.config.pool.size(permission) {
+                let member = self.create_pool_member(permission).await?;
+                debug!("Pool container ready: {}", member.name);
+                self.pool.lock().unwrap().push(member);
+            }
+        }
+        Ok(())
+    }
+
+    /// Refill the pool whenever a member is claimed. Runs forever.
+    pub async fn run_pool_refill(self: Arc<Self>) {
+        loop {
+            self.pool_refill.notified().await;
+            tokio::time::sleep(self.config.pool.refill_delay).await;
+            if let Err(e) = self.fill_pool().await {
+                warn!("Failed to refill container pool: {}", e);
+            }
+        }
+    }
+
+    /// Startup cleanup: remove unclaimed pool containers left by a previous
+    /// run (their image, limits or auth env may be outdated), claim symlinks
+    /// whose container is gone, and orphaned pool dirs.
+    pub async fn reconcile_pool(&self) -> Result<()> {
+        let containers = self
+            .runtime
+            .list_containers("app=wechat-claude-bridge")
+            .await
+            .context("Failed to list containers")?;
 
-        // Pass auth env vars into exec: CLAUDE_CODE_OAUTH_TOKEN or ANTHROPIC_API_KEY
-        let mut env_strings: Vec<String> = Vec::new();
-        if let Ok(token) = std::env::var("CLAUDE_CODE_OAUTH_TOKEN") {
-            if !token.is_empty() {
-                env_strings.push(format!("CLAUDE_CODE_OAUTH_TOKEN={}", token));
+        let ready: Vec<String> = self.pool.lock().unwrap().iter().map(|m| m.id.clone()).collect();
+        let mut live = Vec::new();
+        let mut removed = 0;
+        for c in containers {
+            let Some(id) = c.labels.get("pool_id") else { continue };
+            if ready.contains(id) {
+                live.push(id.clone());
+            } else if let Some(wxid) = self.claimed_wxid(id).await {
+                self.claimed.lock().unwrap().insert(c.name.clone(), wxid);
+                live.push(id.clone());
+            } else {
+                self.remove_pool_member(&c.name, id).await;
+                removed += 1;
             }
         }
-        if let Ok(key) = std::env::var("ANTHROPIC_API_KEY") {
-            if !key.is_empty() {
-                env_strings.push(format!("ANTHROPIC_API_KEY={}", key));
+
+        let mut entries = match fs::read_dir(self.pool_dir()).await {
+            Ok(entries) => entries,
+            Err(_) => return Ok(()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let id = entry.file_name().to_string_lossy().into_owned();
+            if live.contains(&id) {
+                continue;
+            }
+            let path = entry.path();
+            if entry.file_type().await?.is_symlink() {
+                let _ = fs::remove_file(&path).await;
+            } else {
+                let _ = fs::remove_dir_all(&path).await;
             }
         }
-        let env_refs: Vec<&str> = env_strings.iter().map(|s| s.as_str()).collect();
-
-        let exec_opts = CreateExecOptions {
-            cmd: Some(cmd_refs),
-            attach_stdout: Some(true),
-            attach_stderr: Some(true),
-            user: Some("sandbox"),
-            working_dir: Some("/home/sandbox/workspace"),
-            env: if env_refs.is_empty() { None } else { Some(env_refs) },
-            ..Default::default()
+
+        if removed > 0 {
+            info!("Removed {} stale pool containers", removed);
+        }
+        Ok(())
+    }
+
+    // ============================================
+    // Execute commands in container
+    // ============================================
+
+    /// Execute Claude Code in a user's container. This is the core method.
+    ///
+    /// The run is tracked per wxid (exec ID + PID file) so `kill_claude` can
+    /// end exactly its process tree; that also happens here on timeout,
+    /// since Docker keeps an exec running after its caller gives up.
+    pub async fn exec_claude(
+        &self,
+        wxid: &str,
+        system_prompt: &str,
+        message: &str,
+        options: ExecClaudeOptions,
+    ) -> ExecClaudeResult {
+        let name = self.container_name(wxid);
+        let timeout_secs = options.timeout.unwrap_or(120);
+        let run_id = uuid::Uuid::new_v4().simple().to_string();
+        let pid_file = format!("/tmp/claude-run-{}.pid", &run_id[..12]);
+
+        // Build claude command, passing auth env vars into the exec:
+        // CLAUDE_CODE_OAUTH_TOKEN or ANTHROPIC_API_KEY
+        let mut cmd: Vec<String> = ["setsid", "-w", "sh", "-c", RUN_WRAPPER_SCRIPT, &pid_file, "claude"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        cmd.extend(build_args(system_prompt, message, &options));
+        // Claimed pool containers were created without the owner's WXID
+        let mut env = auth_env();
+        env.push(format!("WXID={}", wxid));
+        let spec = ExecSpec {
+            cmd,
+            user: "sandbox".to_string(),
+            working_dir: Some("/home/sandbox/workspace".to_string()),
+            env,
         };
 
-        let exec = match self.docker.create_exec(&name, exec_opts).await {
-            Ok(e) => e,
+        let exec_id = match self.runtime.create_exec(&name, spec).await {
+            Ok(id) => id,
             Err(e) => {
-                error!("Failed to create exec in container {}: {}", name, e);
-                return ExecClaudeResult {
-                    ok: false,
-                    output: "Container execution failed".to_string(),
-                    stderr: e.to_string(),
-                };
+                error!("Container exec failed [{}]: {}", name, e);
+                return ExecClaudeResult::failed("Container execution failed", e.to_string());
             }
         };
+        self.runs.lock().unwrap().insert(
+            wxid.to_string(),
+            ClaudeRun {
+                container: name.clone(),
+                exec_id: exec_id.clone(),
+                pid_file,
+            },
+        );
 
-        // Start exec with timeout
-        let result = tokio::time::timeout(
+        // Run with timeout, feeding stdout to the stream parser as it arrives
+        let mut parser = options.stream.as_ref().map(|_| StreamJsonParser::new());
+        let mut on_stdout = |chunk: &[u8]| {
+            feed_stream(&mut parser, options.stream.as_ref(), chunk);
+        };
+        let result = match tokio::time::timeout(
             Duration::from_secs(timeout_secs),
-            self.collect_exec_output(&exec.id),
+            self.runtime.start_exec(&exec_id, &mut on_stdout),
         )
-        .await;
-
-        match result {
-            Ok(Ok((stdout, stderr))) => {
-                let trimmed = stdout.trim().to_string();
-                if trimmed.is_empty() {
-                    ExecClaudeResult {
-                        ok: true,
-                        output: "(Claude returned no content)".to_string(),
-                        stderr,
-                    }
-                } else {
-                    ExecClaudeResult {
-                        ok: true,
-                        output: trimmed,
-                        stderr,
-                    }
-                }
-            }
-            Ok(Err(e)) => {
-                error!("Container exec failed [{}]: {}", name, e);
-                ExecClaudeResult {
-                    ok: false,
-                    output: "Processing error, please try again later".to_string(),
-                    stderr: e.to_string(),
-                }
-            }
+        .await
+        {
+            Ok(result) => result,
             Err(_) => {
                 // Timeout
                 warn!("Claude exec timed out in container {} after {}s", name, timeout_secs);
-                ExecClaudeResult {
-                    ok: false,
-                    output: "Request timed out".to_string(),
-                    stderr: String::new(),
+                if let Err(e) = self.kill_claude(wxid).await {
+                    warn!("Failed to kill timed-out claude in {}: {}", name, e);
                 }
+                return ExecClaudeResult::failed("Request timed out", String::new());
+            }
+        };
+
+        {
+            let mut runs = self.runs.lock().unwrap();
+            if runs.get(wxid).is_some_and(|r| r.exec_id == exec_id) {
+                runs.remove(wxid);
+            }
+        }
+        match result {
+            Ok((stdout, stderr)) => finish_run(&stdout, stderr, parser),
+            Err(e) => {
+                error!("Container exec failed [{}]: {}", name, e);
+                ExecClaudeResult::failed("Container execution failed", e.to_string())
+            }
+        }
+    }
+
+    /// Kill the user's Claude run in progress, if any: its whole process
+    /// group, and nothing else in the container. Returns whether there was
+    /// a live run to kill.
+    pub async fn kill_claude(&self, wxid: &str) -> Result<bool> {
+        let Some(run) = self.runs.lock().unwrap().remove(wxid) else {
+            return Ok(false);
+        };
+        if !self.runtime.exec_running(&run.exec_id).await.unwrap_or(true) {
+            return Ok(false);
+        }
+
+        let out = self
+            .exec_in_container(
+                &run.container,
+                vec!["sh", "-c", KILL_RUN_SCRIPT, &run.pid_file],
+                // Root has no CAP_KILL in the sandbox (cap_drop ALL)
+                false,
+            )
+            .await?;
+        let killed = out == "killed";
+        if killed {
+            info!("Killed claude run in {} (exec {})", run.container, run.exec_id);
+        }
+        Ok(killed)
+    }
+
+    /// Kill every tracked Claude run (on shutdown). Returns how many were killed.
+    pub async fn kill_all_claude(&self) -> usize {
+        let wxids: Vec<String> = self.runs.lock().unwrap().keys().cloned().collect();
+        let mut killed = 0;
+        for wxid in wxids {
+            match self.kill_claude(&wxid).await {
+                Ok(true) => killed += 1,
+                Ok(false) => {}
+                Err(e) => warn!("Failed to kill claude run of {}: {}", wxid, e),
             }
         }
+        killed
     }
 
     /// Execute an arbitrary command in a user's container.
+    #[allow(dead_code)]
     pub async fn exec_command(
         &self,
         wxid: &str,
@@ -542,23 +843,13 @@ impl DockerManager {
         cmd: Vec<&str>,
         as_root: bool,
     ) -> Result<String> {
-        let user = if as_root { "root" } else { "sandbox" };
-
-        let exec_opts = CreateExecOptions {
-            cmd: Some(cmd),
-            attach_stdout: Some(true),
-            attach_stderr: Some(true),
-            user: Some(user),
+        let spec = ExecSpec {
+            cmd: cmd.into_iter().map(String::from).collect(),
+            user: if as_root { "root" } else { "sandbox" }.to_string(),
             ..Default::default()
         };
 
-        let exec = self
-            .docker
-            .create_exec(container_name, exec_opts)
-            .await
-            .with_context(|| format!("Failed to create exec in {}", container_name))?;
-
-        let (stdout, stderr) = self.collect_exec_output(&exec.id).await?;
+        let (stdout, stderr) = self.runtime.exec(container_name, spec, &mut |_| {}).await?;
 
         if !stderr.is_empty() {
             debug!("exec stderr in {}: {}", container_name, stderr);
@@ -567,40 +858,120 @@ impl DockerManager {
         Ok(stdout.trim().to_string())
     }
 
-    /// Collect stdout/stderr from a docker exec.
-    async fn collect_exec_output(&self, exec_id: &str) -> Result<(String, String)> {
-        let start_result = self
-            .docker
-            .start_exec(exec_id, None)
+    // ============================================
+    // Workspace files
+    // ============================================
+
+    /// Write a file into the user's workspace, creating its parent
+    /// directories. `path` is relative to the workspace; everything written
+    /// is owned by the sandbox user.
+    pub async fn write_workspace_file(&self, wxid: &str, path: &str, data: &[u8]) -> Result<()> {
+        let name = self.container_name(wxid);
+        let archive = file_archive(Path::new(path), data)?;
+        self.runtime
+            .upload_archive(&name, "/home/sandbox/workspace", archive)
             .await
-            .context("Failed to start exec")?;
-
-        let mut stdout = String::new();
-        let mut stderr = String::new();
-
-        match start_result {
-            StartExecResults::Attached { mut output, .. } => {
-                while let Some(chunk) = output.next().await {
-                    match chunk {
-                        Ok(bollard::container::LogOutput::StdOut { message }) => {
-                            stdout.push_str(&String::from_utf8_lossy(&message));
-                        }
-                        Ok(bollard::container::LogOutput::StdErr { message }) => {
-                            stderr.push_str(&String::from_utf8_lossy(&message));
-                        }
-                        Ok(_) => {}
-                        Err(e) => {
-                            return Err(anyhow::anyhow!("Error reading exec output: {}", e));
-                        }
-                    }
-                }
-            }
-            StartExecResults::Detached => {
-                // Nothing to collect
-            }
+            .with_context(|| format!("Failed to write {} in {}", path, name))
+    }
+
+    /// List a workspace directory (`""` for the workspace itself), sorted
+    /// by name. Errors if it doesn't exist.
+    pub async fn list_workspace_dir(&self, wxid: &str, dir: &str) -> Result<Vec<WorkspaceEntry>> {
+        let name = self.container_name(wxid);
+        let target = workspace_target(dir)?;
+        let spec = ExecSpec {
+            // type, mtime, size and name of each entry, NUL-terminated
+            cmd: vec![
+                "find", &target, "-mindepth", "1", "-maxdepth", "1",
+                "-printf", "%y %T@ %s %P\\0",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+            user: "sandbox".to_string(),
+            ..Default::default()
+        };
+        let (stdout, stderr) = self.runtime.exec(&name, spec, &mut |_| {}).await?;
+        if stdout.is_empty() && !stderr.trim().is_empty() {
+            anyhow::bail!("{}", stderr.trim());
+        }
+
+        let mut entries: Vec<WorkspaceEntry> = stdout
+            .split('\0')
+            .filter_map(|line| {
+                let mut fields = line.trim_start_matches('\n').splitn(4, ' ');
+                let kind = fields.next()?;
+                let modified: f64 = fields.next()?.parse().ok()?;
+                let size = fields.next()?.parse().ok()?;
+                Some(WorkspaceEntry {
+                    name: fields.next()?.to_string(),
+                    is_dir: kind == "d",
+                    size,
+                    modified: (modified * 1000.0) as i64,
+                })
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Host side of the user's workspace bind mount.
+    pub fn workspace_host_dir(&self, wxid: &str) -> PathBuf {
+        self.data_dir.join(wxid).join("workspace")
+    }
+
+    /// Bytes used by the user's workspace, measured on the host side of the
+    /// bind mount so the container doesn't have to be running.
+    pub async fn workspace_usage(&self, wxid: &str) -> Result<u64> {
+        let dir = self.workspace_host_dir(wxid);
+        Ok(tokio::task::spawn_blocking(move || dir_size(&dir)).await?)
+    }
+
+    /// Delete a file or directory (recursively) from the user's workspace.
+    pub async fn remove_workspace_path(&self, wxid: &str, path: &str) -> Result<()> {
+        if path.is_empty() {
+            anyhow::bail!("Refusing to remove the workspace itself");
         }
+        let name = self.container_name(wxid);
+        let target = workspace_target(path)?;
+        let spec = ExecSpec {
+            cmd: vec!["rm".into(), "-r".into(), "--".into(), target],
+            user: "sandbox".to_string(),
+            ..Default::default()
+        };
+        let (_, stderr) = self.runtime.exec(&name, spec, &mut |_| {}).await?;
+        if !stderr.trim().is_empty() {
+            anyhow::bail!("{}", stderr.trim());
+        }
+        Ok(())
+    }
 
-        Ok((stdout, stderr))
+    /// Read a regular file of at most `max_bytes` from the user's workspace.
+    pub async fn read_workspace_file(&self, wxid: &str, path: &str, max_bytes: u64) -> Result<Vec<u8>> {
+        let name = self.container_name(wxid);
+        let target = workspace_target(path)?;
+        // Room for the tar headers (long names add a few blocks) and trailer
+        let archive = self
+            .runtime
+            .download_archive(&name, &target, max_bytes.saturating_add(64 * 1024))
+            .await
+            .with_context(|| format!("Failed to read {} in {}", path, name))?;
+
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut entry = archive
+            .entries()?
+            .next()
+            .with_context(|| format!("{} not found", path))??;
+        if !entry.header().entry_type().is_file() {
+            anyhow::bail!("{} is not a regular file", path);
+        }
+        let size = entry.header().size()?;
+        if size > max_bytes {
+            anyhow::bail!("{} is larger than {} bytes", path, max_bytes);
+        }
+        let mut data = Vec::with_capacity(size as usize);
+        std::io::Read::read_to_end(&mut entry, &mut data)?;
+        Ok(data)
     }
 
     // ============================================
@@ -609,32 +980,21 @@ impl DockerManager {
 
     /// Check if a container exists.
     pub async fn container_exists(&self, name: &str) -> bool {
-        self.docker
-            .inspect_container(name, None::<InspectContainerOptions>)
-            .await
-            .is_ok()
+        matches!(self.runtime.inspect_container(name).await, Ok(Some(_)))
     }
 
     /// Check if a container is running.
     pub async fn is_running(&self, name: &str) -> bool {
-        match self
-            .docker
-            .inspect_container(name, None::<InspectContainerOptions>)
-            .await
-        {
-            Ok(info) => info
-                .state
-                .and_then(|s| s.status)
-                .map(|s| s == ContainerStateStatusEnum::RUNNING)
-                .unwrap_or(false),
-            Err(_) => false,
+        match self.runtime.inspect_container(name).await {
+            Ok(Some(state)) => state.running,
+            _ => false,
         }
     }
 
     /// Start a container by name.
     pub async fn start_container(&self, name: &str) -> Result<()> {
-        self.docker
-            .start_container(name, None::<StartContainerOptions<String>>)
+        self.runtime
+            .start_container(name)
             .await
             .with_context(|| format!("Failed to start container: {}", name))
     }
@@ -642,14 +1002,7 @@ impl DockerManager {
     /// Stop a user's container gracefully (10s timeout).
     pub async fn stop_container(&self, wxid: &str) -> Result<bool> {
         let name = self.container_name(wxid);
-        match self
-            .docker
-            .stop_container(
-                &name,
-                Some(StopContainerOptions { t: 10 }),
-            )
-            .await
-        {
+        match self.runtime.stop_container(&name, 10).await {
             Ok(_) => {
                 info!("Stopped container: {}", name);
                 Ok(true)
@@ -664,18 +1017,9 @@ impl DockerManager {
     /// Force-remove a user's container.
     pub async fn destroy_container(&self, wxid: &str) -> Result<bool> {
         let name = self.container_name(wxid);
-        match self
-            .docker
-            .remove_container(
-                &name,
-                Some(RemoveContainerOptions {
-                    force: true,
-                    ..Default::default()
-                }),
-            )
-            .await
-        {
+        match self.runtime.remove_container(&name, true).await {
             Ok(_) => {
+                self.claimed.lock().unwrap().remove(&name);
                 info!("Destroyed container: {}", name);
                 Ok(true)
             }
@@ -689,75 +1033,37 @@ impl DockerManager {
     /// Get resource usage stats for a container.
     pub async fn get_stats(&self, wxid: &str) -> Result<Option<ContainerStats>> {
         let name = self.container_name(wxid);
-
-        let mut stream = self.docker.stats(
-            &name,
-            Some(StatsOptions {
-                stream: false,
-                one_shot: true,
-            }),
-        );
-
-        if let Some(Ok(stats)) = stream.next().await {
-            let cpu_percent = calculate_cpu_percent(&stats);
-            let memory_usage = stats.memory_stats.usage.unwrap_or(0);
-            let memory_limit = stats.memory_stats.limit.unwrap_or(0);
-            let pids = stats
-                .pids_stats
-                .current
-                .unwrap_or(0);
-
-            Ok(Some(ContainerStats {
-                cpu_percent,
-                memory_usage,
-                memory_limit,
-                pids,
-            }))
-        } else {
-            Ok(None)
-        }
+        self.runtime.stats(&name).await
     }
 
     /// List all containers managed by this bridge (label=app=wechat-claude-bridge).
+    /// Unclaimed pool containers are left out.
     pub async fn list_containers(&self) -> Result<Vec<ContainerInfo>> {
-        let mut filters = HashMap::new();
-        filters.insert("label", vec!["app=wechat-claude-bridge"]);
-
-        let options = ListContainersOptions {
-            all: true,
-            filters,
-            ..Default::default()
-        };
-
         let containers = self
-            .docker
-            .list_containers(Some(options))
+            .runtime
+            .list_containers("app=wechat-claude-bridge")
             .await
             .context("Failed to list containers")?;
 
-        let mut result = Vec::new();
+        let claimed = self.claimed.lock().unwrap().clone();
+        let mut list = Vec::with_capacity(containers.len());
         for c in containers {
-            let name = c
-                .names
-                .as_ref()
-                .and_then(|n| n.first())
-                .map(|n| n.trim_start_matches('/').to_string())
-                .unwrap_or_default();
-
-            let status = c.status.unwrap_or_default();
-            let labels = c.labels.unwrap_or_default();
-            let wxid = labels.get("wxid").cloned();
-            let permission = labels.get("permission").cloned();
-
-            result.push(ContainerInfo {
-                name,
-                status,
+            let wxid = match (c.labels.get("wxid"), c.labels.get("pool_id")) {
+                (Some(wxid), _) => Some(wxid.clone()),
+                (None, Some(_)) => match claimed.get(&c.name) {
+                    Some(wxid) => Some(wxid.clone()),
+                    None => continue,
+                },
+                (None, None) => None,
+            };
+            list.push(ContainerInfo {
                 wxid,
-                permission,
+                permission: c.labels.get("permission").cloned(),
+                name: c.name,
+                status: c.status,
             });
         }
-
-        Ok(result)
+        Ok(list)
     }
 
     // ============================================
@@ -807,24 +1113,26 @@ impl DockerManager {
     // ============================================
 
     /// Create the claude-limited network if it doesn't exist.
+    ///
+    /// With the egress proxy enabled the network is created `internal`, so
+    /// containers on it can only reach the proxy on the gateway address.
     pub async fn init_networks(&self) -> Result<()> {
-        let network_name = "claude-limited";
+        let network_name = LIMITED_NETWORK;
+        let internal = self.config.egress_proxy_port.is_some();
 
-        match self
-            .docker
-            .inspect_network(network_name, None::<InspectNetworkOptions<String>>)
-            .await
-        {
-            Ok(_) => {
+        match self.runtime.inspect_network(network_name).await {
+            Ok(Some(info)) => {
                 debug!("Network {} already exists", network_name);
+                if internal && !info.internal {
+                    warn!(
+                        "Network {} was created without `internal`, so trusted containers bypass the egress proxy. \
+                         Stop those containers and run `docker network rm {}`, then restart the bridge",
+                        network_name, network_name
+                    );
+                }
             }
-            Err(_) => {
-                let options = CreateNetworkOptions {
-                    name: network_name,
-                    driver: "bridge",
-                    ..Default::default()
-                };
-                match self.docker.create_network(options).await {
+            _ => {
+                match self.runtime.create_network(network_name, "bridge", internal).await {
                     Ok(_) => {
                         info!("Created network: {}", network_name);
                     }
@@ -838,31 +1146,100 @@ impl DockerManager {
         Ok(())
     }
 
+    /// Gateway address of the claude-limited network (the host side of the
+    /// bridge), where the egress proxy listens.
+    pub async fn limited_network_gateway(&self) -> Result<Option<String>> {
+        Ok(self
+            .runtime
+            .inspect_network(LIMITED_NETWORK)
+            .await?
+            .and_then(|info| info.gateway))
+    }
+
+    /// Proxy variables for containers on the claude-limited network.
+    async fn egress_proxy_env(&self) -> Vec<String> {
+        let Some(port) = self.config.egress_proxy_port else {
+            return Vec::new();
+        };
+        match self.limited_network_gateway().await {
+            Ok(Some(gateway)) => {
+                let url = format!("http://{}:{}", gateway, port);
+                vec![
+                    format!("HTTPS_PROXY={}", url),
+                    format!("https_proxy={}", url),
+                    "NO_PROXY=localhost,127.0.0.1".to_string(),
+                    "no_proxy=localhost,127.0.0.1".to_string(),
+                ]
+            }
+            _ => {
+                warn!("No gateway for network {}; egress proxy not configured", LIMITED_NETWORK);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Find the user whose container has `ip` on the claude-limited network.
+    pub async fn wxid_for_ip(&self, ip: &str) -> Option<String> {
+        let info = self.runtime.inspect_network(LIMITED_NETWORK).await.ok()??;
+        let (name, _) = info.containers.into_iter().find(|(_, addr)| addr == ip)?;
+        self.list_containers()
+            .await
+            .ok()?
+            .into_iter()
+            .find(|c| c.name == name)
+            .and_then(|c| c.wxid)
+    }
+
     // ============================================
     // Health & image management
     // ============================================
 
-    /// Check if Docker is available and responding.
+    /// Check if Docker is available and responding, and that every OCI
+    /// runtime named in the config is registered with it.
     pub async fn health_check(&self) -> Result<bool> {
-        match self.docker.version().await {
-            Ok(version) => {
-                let ver = version.version.unwrap_or_else(|| "unknown".to_string());
+        match self.runtime.version().await {
+            Ok(ver) => {
                 info!("Docker version: {}", ver);
-                Ok(true)
             }
             Err(e) => {
                 error!("Docker is not available: {}", e);
-                Ok(false)
+                return Ok(false);
+            }
+        }
+
+        let wanted = [
+            &self.config.runtime.admin,
+            &self.config.runtime.trusted,
+            &self.config.runtime.normal,
+        ];
+        if wanted.iter().all(|r| r.is_none()) {
+            return Ok(true);
+        }
+
+        let registered = match self.runtime.runtimes().await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to query container runtimes: {}", e);
+                return Ok(false);
+            }
+        };
+        let mut healthy = true;
+        for name in wanted.into_iter().flatten() {
+            if !registered.contains(name) {
+                error!(
+                    "Container runtime '{}' is not registered with the engine (available: {})",
+                    name,
+                    registered.join(", ")
+                );
+                healthy = false;
             }
         }
+        Ok(healthy)
     }
 
     /// Check if the sandbox image exists locally.
     pub async fn image_exists(&self) -> Result<bool> {
-        match self.docker.inspect_image(&self.image_name).await {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false),
-        }
+        Ok(self.runtime.image_exists(&self.image_name).await.unwrap_or(false))
     }
 
     /// Build the sandbox image from the project's docker directory.
@@ -880,52 +1257,71 @@ impl DockerManager {
         // Read the Dockerfile and create a tar archive for the build context
         let tar_bytes = create_build_context(docker_dir).await?;
 
-        let build_options = BuildImageOptions {
-            t: self.image_name.as_str(),
-            dockerfile: "Dockerfile.sandbox",
-            rm: true,
-            ..Default::default()
-        };
-
-        let mut stream = self.docker.build_image(build_options, None, Some(tar_bytes.into()));
-
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(output) => {
-                    if let Some(stream_str) = output.stream {
-                        debug!("build: {}", stream_str.trim());
-                    }
-                    if let Some(err) = output.error {
-                        return Err(anyhow::anyhow!("Image build error: {}", err));
-                    }
-                }
-                Err(e) => {
-                    return Err(anyhow::anyhow!("Image build failed: {}", e));
-                }
-            }
-        }
+        self.runtime
+            .build_image(&self.image_name, "Dockerfile.sandbox", tar_bytes)
+            .await?;
 
         info!("Image build complete: {}", self.image_name);
         Ok(())
     }
 }
 
-/// Calculate CPU usage percentage from Docker stats.
-fn calculate_cpu_percent(stats: &Stats) -> f64 {
-    let cpu_delta = stats.cpu_stats.cpu_usage.total_usage as f64
-        - stats.precpu_stats.cpu_usage.total_usage as f64;
-    let system_delta = stats.cpu_stats.system_cpu_usage.unwrap_or(0) as f64
-        - stats.precpu_stats.system_cpu_usage.unwrap_or(0) as f64;
-    let num_cpus = stats
-        .cpu_stats
-        .online_cpus
-        .unwrap_or(1) as f64;
-
-    if system_delta > 0.0 && cpu_delta >= 0.0 {
-        (cpu_delta / system_delta) * num_cpus * 100.0
-    } else {
-        0.0
+/// Total size of the regular files under `dir`, without following symlinks.
+/// Entries that can't be read (e.g. private to the sandbox user) are skipped.
+pub fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) if meta.is_file() => meta.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Absolute container path of a workspace-relative `path`, which may not
+/// leave the workspace. `""` is the workspace itself.
+fn workspace_target(path: &str) -> Result<String> {
+    let p = Path::new(path);
+    if !p.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+        anyhow::bail!("Invalid workspace path: {}", path);
+    }
+    Ok(Path::new("/home/sandbox/workspace").join(p).display().to_string())
+}
+
+/// Tar archive with `data` at the relative `path`, plus entries for its
+/// parent directories, all owned by the sandbox user (uid=1001).
+fn file_archive(path: &Path, data: &[u8]) -> Result<Vec<u8>> {
+    if path.as_os_str().is_empty()
+        || !path.components().all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        anyhow::bail!("Invalid workspace path: {}", path.display());
     }
+
+    let header = |entry_type: tar::EntryType, mode: u32, size: u64| {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_uid(1001);
+        header.set_gid(1001);
+        header.set_size(size);
+        header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+        header
+    };
+
+    let mut archive = tar::Builder::new(Vec::new());
+    let mut dir = PathBuf::new();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        dir.push(component);
+        let mut h = header(tar::EntryType::Directory, 0o755, 0);
+        archive.append_data(&mut h, &dir, std::io::empty())?;
+    }
+    let mut h = header(tar::EntryType::Regular, 0o644, data.len() as u64);
+    archive.append_data(&mut h, path, data)?;
+    Ok(archive.into_inner()?)
 }
 
 /// Create a tar archive of the docker build context directory.
@@ -961,6 +1357,8 @@ async fn create_build_context(dir: &Path) -> Result<Vec<u8>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::claude_cli::ReplyUpdate;
+    use crate::container_runtime::{FakeExec, FakeRuntime};
 
     #[test]
     fn test_container_name_sanitization() {
@@ -982,6 +1380,41 @@ mod tests {
         assert_eq!(name, "claude-friend-wxid_abc123");
     }
 
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn kill_script_kills_the_run_group_with_sh() {
+        let pid_file = std::env::temp_dir().join(format!("wcb-kill-{}.pid", std::process::id()));
+        let pid_file = pid_file.to_string_lossy().into_owned();
+        let mut run = tokio::process::Command::new("setsid")
+            .args(["-w", "sh", "-c", RUN_WRAPPER_SCRIPT, &pid_file, "sh", "-c", "sleep 30 & sleep 30"])
+            .spawn()
+            .unwrap();
+        for _ in 0..50 {
+            if std::path::Path::new(&pid_file).exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let kill = tokio::process::Command::new("sh")
+            .args(["-c", KILL_RUN_SCRIPT, &pid_file])
+            .output();
+        let (out, status) = tokio::join!(kill, run.wait());
+        assert_eq!(String::from_utf8_lossy(&out.unwrap().stdout).trim(), "killed");
+        assert!(!status.unwrap().success());
+        assert!(!std::path::Path::new(&pid_file).exists());
+
+        // A PID file naming init (or garbage) signals nothing
+        for pid in ["1", "-1", "x"] {
+            std::fs::write(&pid_file, pid).unwrap();
+            let out = std::process::Command::new("sh")
+                .args(["-c", KILL_RUN_SCRIPT, &pid_file])
+                .output()
+                .unwrap();
+            assert!(out.stdout.is_empty(), "{}", pid);
+        }
+    }
+
     #[test]
     fn test_container_name_special_chars() {
         let prefix = "claude-friend-";
@@ -1178,41 +1611,556 @@ mod tests {
     }
 
     // ============================================
-    // NEW: calculate_cpu_percent edge cases
+    // Lifecycle tests (fake runtime)
     // ============================================
 
-    #[test]
-    fn test_calculate_cpu_percent_no_delta() {
-        use bollard::container::Stats;
-
-        // When system_delta is 0, should return 0
-        let stats: Stats = serde_json::from_str(
-            r#"{
-                "read": "2024-01-01T00:00:00Z",
-                "preread": "2024-01-01T00:00:00Z",
-                "cpu_stats": {
-                    "cpu_usage": {"total_usage": 100, "usage_in_usermode": 50, "usage_in_kernelmode": 50, "percpu_usage": [100]},
-                    "system_cpu_usage": 1000,
-                    "online_cpus": 1,
-                    "throttling_data": {"periods": 0, "throttled_periods": 0, "throttled_time": 0}
-                },
-                "precpu_stats": {
-                    "cpu_usage": {"total_usage": 100, "usage_in_usermode": 50, "usage_in_kernelmode": 50, "percpu_usage": [100]},
-                    "system_cpu_usage": 1000,
-                    "online_cpus": 1,
-                    "throttling_data": {"periods": 0, "throttled_periods": 0, "throttled_time": 0}
-                },
-                "memory_stats": {},
-                "blkio_stats": {"io_service_bytes_recursive": [], "io_serviced_recursive": [], "io_queue_recursive": [], "io_service_time_recursive": [], "io_wait_time_recursive": [], "io_merged_recursive": [], "io_time_recursive": [], "sectors_recursive": []},
-                "pids_stats": {},
-                "networks": {},
-                "storage_stats": {},
-                "num_procs": 0
-            }"#,
-        )
-        .unwrap();
+    fn fake_manager(tag: &str) -> (DockerManager, Arc<FakeRuntime>) {
+        let runtime = Arc::new(FakeRuntime::new());
+        let config = DockerConfig {
+            data_dir: std::env::temp_dir()
+                .join(format!("wcb-docker-{}-{}", tag, std::process::id())),
+            ..Default::default()
+        };
+        (DockerManager::with_runtime(config, runtime.clone()), runtime)
+    }
+
+    #[tokio::test]
+    async fn test_ensure_container_creates_and_restarts() {
+        let (dm, rt) = fake_manager("ensure");
+        let name = dm.ensure_container("wx_a", Permission::Trusted).await.unwrap();
+        assert_eq!(name, "claude-friend-wx_a");
+        assert!(rt.is_running(&name));
+
+        {
+            let containers = rt.containers.lock().unwrap();
+            let config = &containers[&name].config;
+            let host = config.host_config.as_ref().unwrap();
+            assert_eq!(host.network_mode.as_deref(), Some("claude-limited"));
+            assert_eq!(host.memory, Some(512 * 1024 * 1024));
+            assert_eq!(host.readonly_rootfs, Some(true));
+            let labels = config.labels.as_ref().unwrap();
+            assert_eq!(labels["permission"], "trusted");
+        }
+        // chown + claude home setup ran on creation
+        assert_eq!(rt.execs.lock().unwrap().len(), 2);
+
+        // A dead container is started again, not recreated
+        rt.kill(&name);
+        dm.ensure_container("wx_a", Permission::Trusted).await.unwrap();
+        assert!(rt.is_running(&name));
+        assert_eq!(rt.execs.lock().unwrap().len(), 2);
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_recreates_with_new_permission() {
+        let (dm, rt) = fake_manager("rebuild");
+        dm.ensure_container("wx_b", Permission::Normal).await.unwrap();
+        dm.rebuild("wx_b", Permission::Admin).await.unwrap();
+
+        let containers = rt.containers.lock().unwrap();
+        let config = &containers["claude-friend-wx_b"].config;
+        let host = config.host_config.as_ref().unwrap();
+        assert_eq!(host.network_mode.as_deref(), Some("bridge"));
+        assert_eq!(host.memory, Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(config.labels.as_ref().unwrap()["permission"], "admin");
+        drop(containers);
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_stop_all_and_cleanup() {
+        let (dm, rt) = fake_manager("batch");
+        dm.ensure_container("wx_1", Permission::Normal).await.unwrap();
+        dm.ensure_container("wx_2", Permission::Normal).await.unwrap();
+        dm.ensure_container("wx_3", Permission::Normal).await.unwrap();
+
+        // Unrelated containers are never touched
+        rt.create_container("other", Config::default()).await.unwrap();
+        rt.start_container("other").await.unwrap();
+
+        assert!(dm.stop_container("wx_1").await.unwrap());
+        assert!(!dm.stop_container("wx_1").await.unwrap());
+
+        // cleanup only removes stopped bridge containers
+        dm.cleanup().await.unwrap();
+        let names: Vec<String> = dm.list_containers().await.unwrap().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, vec!["claude-friend-wx_2", "claude-friend-wx_3"]);
+
+        dm.stop_all().await.unwrap();
+        assert!(!rt.is_running("claude-friend-wx_2"));
+        assert!(!rt.is_running("claude-friend-wx_3"));
+        assert!(rt.is_running("other"));
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_get_stats_only_when_running() {
+        let (dm, rt) = fake_manager("stats");
+        assert!(dm.get_stats("wx_s").await.unwrap().is_none());
+
+        dm.ensure_container("wx_s", Permission::Normal).await.unwrap();
+        let stats = dm.get_stats("wx_s").await.unwrap().unwrap();
+        assert_eq!(stats.pids, 3);
+        assert_eq!(stats.memory_limit, 512 * 1024 * 1024);
+
+        rt.kill("claude-friend-wx_s");
+        assert!(dm.get_stats("wx_s").await.unwrap().is_none());
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_list_containers_reads_labels() {
+        let (dm, _rt) = fake_manager("list");
+        dm.ensure_container("wx_l", Permission::Admin).await.unwrap();
+        dm.stop_container("wx_l").await.unwrap();
+
+        let list = dm.list_containers().await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].wxid.as_deref(), Some("wx_l"));
+        assert_eq!(list[0].permission.as_deref(), Some("admin"));
+        assert!(list[0].status.starts_with("Exited"));
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_exec_claude_runs_as_sandbox_and_parses_json() {
+        let (dm, rt) = fake_manager("exec");
+        dm.ensure_container("wx_e", Permission::Trusted).await.unwrap();
+        rt.on_exec(|_, _| {
+            FakeExec::stdout(r#"{"type":"result","result":"hi there","session_id":"s-1"}"#)
+        });
+
+        let options = ExecClaudeOptions {
+            claude_session: Some("s-0".into()),
+            ..Default::default()
+        };
+        let result = dm.exec_claude("wx_e", "sys", "hello", options).await;
+        assert!(result.ok);
+        assert_eq!(result.output, "hi there");
+        assert_eq!(result.claude.unwrap().session_id.as_deref(), Some("s-1"));
+
+        let execs = rt.execs.lock().unwrap();
+        let (container, spec) = execs.last().unwrap();
+        assert_eq!(container, "claude-friend-wx_e");
+        assert_eq!(spec.user, "sandbox");
+        assert_eq!(spec.working_dir.as_deref(), Some("/home/sandbox/workspace"));
+        // claude runs as a process group leader that records its PID
+        assert_eq!(&spec.cmd[..3], ["setsid", "-w", "sh"]);
+        assert!(spec.cmd[5].starts_with("/tmp/claude-run-"));
+        assert_eq!(spec.cmd[6], "claude");
+        assert!(spec.cmd.windows(2).any(|w| w == ["--resume", "s-0"]));
+        drop(execs);
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_exec_claude_streams_chunks() {
+        let (dm, rt) = fake_manager("stream");
+        dm.ensure_container("wx_st", Permission::Admin).await.unwrap();
+        rt.on_exec(|_, _| FakeExec {
+            stdout: vec![
+                "{\"type\":\"assistant\",\"message\":{\"content\":[{\"type\":\"text\",".into(),
+                "\"text\":\"Hel\"}]}}\n".into(),
+                "{\"type\":\"result\",\"result\":\"Hello\",\"session_id\":\"s-2\"}\n".into(),
+            ],
+            ..Default::default()
+        });
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let options = ExecClaudeOptions {
+            stream: Some(tx),
+            ..Default::default()
+        };
+        let result = dm.exec_claude("wx_st", "sys", "hi", options).await;
+        assert!(result.ok);
+        assert_eq!(result.output, "Hello");
+        match rx.recv().await {
+            Some(ReplyUpdate::Partial(text)) => assert_eq!(text, "Hel"),
+            other => panic!("unexpected update: {:?}", other),
+        }
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_exec_claude_timeout_and_stopped_container() {
+        let (dm, rt) = fake_manager("timeout");
+        dm.ensure_container("wx_t", Permission::Normal).await.unwrap();
+        rt.on_exec(|_, spec| {
+            if spec.cmd[0] == "setsid" {
+                FakeExec {
+                    delay: Some(Duration::from_secs(30)),
+                    ..FakeExec::stdout("late")
+                }
+            } else {
+                FakeExec::stdout("killed")
+            }
+        });
+
+        let options = ExecClaudeOptions {
+            timeout: Some(1),
+            ..Default::default()
+        };
+        let result = dm.exec_claude("wx_t", "sys", "slow", options).await;
+        assert!(!result.ok);
+        assert_eq!(result.output, "Request timed out");
+
+        // The abandoned run's process group was killed, as its own user
+        {
+            let execs = rt.execs.lock().unwrap();
+            let pid_file = execs[execs.len() - 2].1.cmd[5].clone();
+            let (container, kill) = execs.last().unwrap();
+            assert_eq!(container, "claude-friend-wx_t");
+            assert_eq!(kill.user, "sandbox");
+            assert_eq!(kill.cmd, ["sh", "-c", KILL_RUN_SCRIPT, &pid_file]);
+        }
+        assert!(!dm.kill_claude("wx_t").await.unwrap());
+
+        rt.kill("claude-friend-wx_t");
+        let result = dm.exec_claude("wx_t", "sys", "hi", ExecClaudeOptions::default()).await;
+        assert!(!result.ok);
+        assert!(result.stderr.contains("not running"));
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_kill_claude_targets_only_live_runs() {
+        let (dm, rt) = fake_manager("kill");
+        let dm = Arc::new(dm);
+        dm.ensure_container("wx_k", Permission::Trusted).await.unwrap();
+        let execs_before = rt.execs.lock().unwrap().len();
+
+        // Nothing tracked: no kill exec at all
+        assert!(!dm.kill_claude("wx_k").await.unwrap());
+        // A finished run is forgotten
+        dm.exec_claude("wx_k", "sys", "quick", ExecClaudeOptions::default()).await;
+        assert!(!dm.kill_claude("wx_k").await.unwrap());
+        assert_eq!(rt.execs.lock().unwrap().len(), execs_before + 1);
+
+        rt.on_exec(|_, spec| {
+            if spec.cmd[0] == "setsid" {
+                FakeExec {
+                    delay: Some(Duration::from_secs(30)),
+                    ..Default::default()
+                }
+            } else {
+                FakeExec::stdout("killed")
+            }
+        });
+        let run = tokio::spawn({
+            let dm = Arc::clone(&dm);
+            async move { dm.exec_claude("wx_k", "sys", "slow", ExecClaudeOptions::default()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(dm.kill_all_claude().await, 1);
+        assert!(!dm.kill_claude("wx_k").await.unwrap());
+        run.abort();
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_write_workspace_file_uploads_owned_archive() {
+        let (dm, rt) = fake_manager("files");
+        dm.ensure_container("wx_f", Permission::Trusted).await.unwrap();
+        dm.write_workspace_file("wx_f", "inbox/photo.jpg", b"jpeg").await.unwrap();
+        {
+            let containers = rt.containers.lock().unwrap();
+            let files = &containers["claude-friend-wx_f"].files;
+            assert_eq!(files["/home/sandbox/workspace/inbox/photo.jpg"], b"jpeg");
+        }
+
+        let archive = file_archive(Path::new("inbox/photo.jpg"), b"jpeg").unwrap();
+        let mut tar = tar::Archive::new(archive.as_slice());
+        let entries: Vec<(String, u64, bool)> = tar
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let h = e.header();
+                (
+                    e.path().unwrap().display().to_string(),
+                    h.uid().unwrap(),
+                    h.entry_type().is_dir(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![("inbox".into(), 1001, true), ("inbox/photo.jpg".into(), 1001, false)]
+        );
+
+        for bad in ["", "../escape", "/etc/passwd", "inbox/../../x"] {
+            assert!(dm.write_workspace_file("wx_f", bad, b"x").await.is_err(), "{}", bad);
+        }
+
+        assert_eq!(dm.read_workspace_file("wx_f", "inbox/photo.jpg", 4).await.unwrap(), b"jpeg");
+        assert!(dm.read_workspace_file("wx_f", "inbox/photo.jpg", 3).await.is_err());
+        assert!(dm.read_workspace_file("wx_f", "inbox/missing", 4).await.is_err());
+        assert!(dm.read_workspace_file("wx_f", "../etc/passwd", 4).await.is_err());
+
+        rt.on_exec(|_, spec| {
+            assert_eq!(spec.cmd[..2], ["find", "/home/sandbox/workspace/outbox"]);
+            FakeExec::stdout("f 1700000000.5 42 chart.png\0d 1700000001.0 4096 old\0")
+        });
+        let entries = dm.list_workspace_dir("wx_f", "outbox").await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                WorkspaceEntry { name: "chart.png".into(), is_dir: false, size: 42, modified: 1700000000500 },
+                WorkspaceEntry { name: "old".into(), is_dir: true, size: 4096, modified: 1700000001000 },
+            ]
+        );
+        rt.on_exec(|_, _| FakeExec {
+            stderr: "find: '/home/sandbox/workspace/nope': No such file or directory".into(),
+            ..Default::default()
+        });
+        assert!(dm.list_workspace_dir("wx_f", "nope").await.is_err());
+        assert!(dm.remove_workspace_path("wx_f", "nope").await.is_err());
+
+        rt.on_exec(|_, spec| {
+            assert_eq!(spec.cmd, ["rm", "-r", "--", "/home/sandbox/workspace/outbox/old"]);
+            assert_eq!(spec.user, "sandbox");
+            FakeExec::default()
+        });
+        dm.remove_workspace_path("wx_f", "outbox/old").await.unwrap();
+        assert!(dm.remove_workspace_path("wx_f", "").await.is_err());
+        assert!(dm.remove_workspace_path("wx_f", "../home").await.is_err());
+
+        let workspace = dm.data_dir.join("wx_f").join("workspace");
+        std::fs::create_dir_all(workspace.join("a/b")).unwrap();
+        std::fs::write(workspace.join("top.txt"), b"12345").unwrap();
+        std::fs::write(workspace.join("a/b/deep.bin"), vec![0u8; 1000]).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("/usr", workspace.join("link")).unwrap();
+        assert_eq!(dm.workspace_usage("wx_f").await.unwrap(), 1005);
+        assert_eq!(dm.workspace_usage("wx_nobody").await.unwrap(), 0);
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_health_networks_and_image_build() {
+        let (dm, rt) = fake_manager("health");
+        assert!(dm.health_check().await.unwrap());
+        rt.available.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert!(!dm.health_check().await.unwrap());
+
+        dm.init_networks().await.unwrap();
+        let network = rt.inspect_network("claude-limited").await.unwrap().unwrap();
+        assert!(!network.internal);
+
+        assert!(!dm.image_exists().await.unwrap());
+        let ctx = std::env::temp_dir().join(format!("wcb-ctx-{}", std::process::id()));
+        std::fs::create_dir_all(&ctx).unwrap();
+        assert!(dm.build_image(&ctx).await.is_err()); // no Dockerfile.sandbox
+        std::fs::write(ctx.join("Dockerfile.sandbox"), "FROM scratch\n").unwrap();
+        dm.build_image(&ctx).await.unwrap();
+        assert!(dm.image_exists().await.unwrap());
+        let _ = std::fs::remove_dir_all(ctx);
+    }
+
+    #[tokio::test]
+    async fn test_runtime_applied_per_permission() {
+        let (mut dm, rt) = fake_manager("runtime");
+        dm.config.runtime = DockerRuntimeConfig {
+            normal: Some("runsc".into()),
+            ..Default::default()
+        };
+        dm.ensure_container("wx_n", Permission::Normal).await.unwrap();
+        dm.ensure_container("wx_a", Permission::Admin).await.unwrap();
+
+        let containers = rt.containers.lock().unwrap();
+        let runtime_of = |name: &str| {
+            containers[name].config.host_config.as_ref().unwrap().runtime.clone()
+        };
+        assert_eq!(runtime_of("claude-friend-wx_n").as_deref(), Some("runsc"));
+        assert_eq!(runtime_of("claude-friend-wx_a"), None);
+        drop(containers);
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_health_check_requires_configured_runtime() {
+        let (mut dm, rt) = fake_manager("hc-runtime");
+        dm.config.runtime.trusted = Some("runsc".into());
+        assert!(!dm.health_check().await.unwrap());
 
-        let result = calculate_cpu_percent(&stats);
-        assert_eq!(result, 0.0);
+        rt.runtimes.lock().unwrap().push("runsc".into());
+        assert!(dm.health_check().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_egress_proxy_env_only_on_limited_network() {
+        let (mut dm, rt) = fake_manager("egress");
+        dm.config.egress_proxy_port = Some(3128);
+        dm.init_networks().await.unwrap();
+        assert!(rt.inspect_network(LIMITED_NETWORK).await.unwrap().unwrap().internal);
+
+        dm.ensure_container("wx_t", Permission::Trusted).await.unwrap();
+        dm.ensure_container("wx_a", Permission::Admin).await.unwrap();
+
+        {
+            let containers = rt.containers.lock().unwrap();
+            let env_of = |name: &str| containers[name].config.env.clone().unwrap_or_default();
+            let trusted = env_of("claude-friend-wx_t");
+            assert!(trusted.contains(&"HTTPS_PROXY=http://172.30.0.1:3128".to_string()));
+            assert!(!env_of("claude-friend-wx_a").iter().any(|e| e.starts_with("HTTPS_PROXY")));
+        }
+
+        // The proxy maps a container address back to its owner
+        assert_eq!(dm.wxid_for_ip("172.30.0.2").await.as_deref(), Some("wx_t"));
+        assert_eq!(dm.wxid_for_ip("10.0.0.9").await, None);
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    fn pool_names(rt: &FakeRuntime) -> Vec<String> {
+        let mut names: Vec<String> = rt
+            .containers
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|n| n.starts_with("claude-friend-pool-"))
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_pool_claim_renames_and_attaches_workspace() {
+        let (mut dm, rt) = fake_manager("pool-claim");
+        dm.config.pool.normal = 2;
+        dm.config.pool.trusted = 1;
+        dm.fill_pool().await.unwrap();
+        assert_eq!(pool_names(&rt).len(), 3);
+        // Pool members are fully set up but invisible to the listing
+        assert_eq!(rt.execs.lock().unwrap().len(), 6);
+        assert!(dm.list_containers().await.unwrap().is_empty());
+
+        let name = dm.ensure_container("wx_new", Permission::Normal).await.unwrap();
+        assert_eq!(name, "claude-friend-wx_new");
+        assert!(rt.is_running(&name));
+        assert_eq!(pool_names(&rt).len(), 2);
+        // No setup on the request path
+        assert_eq!(rt.execs.lock().unwrap().len(), 6);
+
+        // The workspace moved to the user's dir; the pool path links to it
+        let labels = rt.containers.lock().unwrap()[&name].config.labels.clone().unwrap();
+        let link = dm.data_dir.join(".pool").join(&labels["pool_id"]);
+        assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("../wx_new"));
+        assert!(link.join("workspace").is_dir());
+        assert!(dm.data_dir.join("wx_new").join("workspace").is_dir());
+
+        let list = dm.list_containers().await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].wxid.as_deref(), Some("wx_new"));
+        assert_eq!(list[0].permission.as_deref(), Some("normal"));
+
+        // Refill tops the level back up
+        dm.fill_pool().await.unwrap();
+        assert_eq!(pool_names(&rt).len(), 3);
+
+        // No admin pool: created from scratch
+        dm.ensure_container("wx_admin", Permission::Admin).await.unwrap();
+        assert_eq!(pool_names(&rt).len(), 3);
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_claimed_pool_container_is_owned_like_a_fresh_one() {
+        let (mut dm, rt) = fake_manager("pool-owner");
+        dm.config.pool.trusted = 1;
+        dm.init_networks().await.unwrap();
+        dm.fill_pool().await.unwrap();
+        let name = dm.ensure_container("wx_pooled", Permission::Trusted).await.unwrap();
+        // The owner no longer depends on the claim symlink
+        let labels = rt.containers.lock().unwrap()[&name].config.labels.clone().unwrap();
+        std::fs::remove_file(dm.data_dir.join(".pool").join(&labels["pool_id"])).unwrap();
+
+        let ip = {
+            let network = rt.inspect_network(LIMITED_NETWORK).await.unwrap().unwrap();
+            network.containers.into_iter().find(|(n, _)| *n == name).unwrap().1
+        };
+        assert_eq!(dm.wxid_for_ip(&ip).await.as_deref(), Some("wx_pooled"));
+
+        rt.on_exec(|_, _| FakeExec::stdout(r#"{"type":"result","result":"ok"}"#));
+        dm.exec_claude("wx_pooled", "sys", "hi", ExecClaudeOptions::default()).await;
+        let env = rt.execs.lock().unwrap().last().unwrap().1.env.clone();
+        assert!(env.contains(&"WXID=wx_pooled".to_string()), "{:?}", env);
+
+        // The reaper finds it (no activity on record counts as idle)
+        let dm = Arc::new(dm);
+        let db = Arc::new(crate::database::Database::new(Some(Path::new(":memory:"))).unwrap());
+        let executor = Arc::new(crate::claude_executor::ClaudeExecutor::new(
+            Arc::new(crate::agent_backend::DockerBackend::new(Arc::clone(&dm))),
+            Arc::clone(&db),
+            60,
+            120,
+        ));
+        let reaper = crate::reaper::Reaper::new(executor, db, 120, 1440);
+        let report = reaper.run_once().await.unwrap();
+        assert_eq!(report.removed, vec!["wx_pooled"]);
+        assert!(!rt.containers.lock().unwrap().contains_key(&name));
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_pool_skips_returning_users_and_dead_members() {
+        let (mut dm, rt) = fake_manager("pool-skip");
+        dm.config.pool.normal = 2;
+        dm.fill_pool().await.unwrap();
+
+        // An existing workspace can't be attached, so the user gets a fresh container
+        dm.user_data_dir("wx_old").await.unwrap();
+        dm.ensure_container("wx_old", Permission::Normal).await.unwrap();
+        assert_eq!(pool_names(&rt).len(), 2);
+        {
+            let containers = rt.containers.lock().unwrap();
+            let labels = containers["claude-friend-wx_old"].config.labels.as_ref().unwrap();
+            assert_eq!(labels["wxid"], "wx_old");
+        }
+
+        // A dead member is discarded and the next one is used
+        let dead = dm.pool.lock().unwrap()[0].name.clone();
+        rt.kill(&dead);
+        dm.ensure_container("wx_fresh", Permission::Normal).await.unwrap();
+        assert!(rt.is_running("claude-friend-wx_fresh"));
+        assert!(pool_names(&rt).is_empty());
+        assert!(!dm.data_dir.join(".pool").join(dead.rsplit('-').next().unwrap()).exists());
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_pool_removes_stale_members() {
+        let (mut dm, rt) = fake_manager("pool-reconcile");
+        dm.config.pool.trusted = 2;
+        dm.fill_pool().await.unwrap();
+        dm.ensure_container("wx_kept", Permission::Trusted).await.unwrap();
+        dm.ensure_container("wx_gone", Permission::Trusted).await.unwrap();
+        dm.destroy_container("wx_gone").await.unwrap();
+        dm.fill_pool().await.unwrap();
+        std::fs::create_dir_all(dm.data_dir.join(".pool").join("orphan")).unwrap();
+
+        // A restarted bridge doesn't know the old members
+        let dm = DockerManager::with_runtime(dm.config.clone(), rt.clone());
+        dm.reconcile_pool().await.unwrap();
+
+        assert!(pool_names(&rt).is_empty());
+        let mut left: Vec<String> = std::fs::read_dir(dm.data_dir.join(".pool"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter_map(|p| std::fs::read_link(p).ok())
+            .map(|t| t.display().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["../wx_kept"]);
+        assert_eq!(std::fs::read_dir(dm.data_dir.join(".pool")).unwrap().count(), 1);
+        // Data of the removed user is untouched
+        assert!(dm.data_dir.join("wx_gone").join("workspace").is_dir());
+
+        let list = dm.list_containers().await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].wxid.as_deref(), Some("wx_kept"));
+        assert_eq!(dm.claimed.lock().unwrap()["claude-friend-wx_kept"], "wx_kept");
+        let _ = std::fs::remove_dir_all(&dm.data_dir);
     }
 }
@@ -0,0 +1,385 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::database::Database;
+use crate::docker_manager::DockerManager;
+
+/// Maximum size of a CONNECT request head.
+const MAX_HEAD_LEN: usize = 8192;
+
+/// Time allowed for a client to send its request head, and for the upstream
+/// connection to be established.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// ============================================
+// Allowlist
+// ============================================
+
+/// One allowlist entry: a host (optionally "*.domain") and a port.
+#[derive(Debug, Clone, PartialEq)]
+struct AllowEntry {
+    host: String,
+    wildcard: bool,
+    port: u16,
+}
+
+/// Destinations the egress proxy lets through.
+///
+/// Entries are "host" (port 443), "host:port", or "*.domain" which matches
+/// any subdomain of `domain` but not `domain` itself. Matching is
+/// case-insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    entries: Vec<AllowEntry>,
+}
+
+impl Allowlist {
+    pub fn new(entries: &[String]) -> Self {
+        let entries = entries
+            .iter()
+            .filter_map(|e| {
+                let e = e.trim().to_lowercase();
+                if e.is_empty() {
+                    return None;
+                }
+                let (host, port) = match e.rsplit_once(':') {
+                    Some((h, p)) => match p.parse() {
+                        Ok(port) => (h.to_string(), port),
+                        Err(_) => {
+                            warn!("Ignoring invalid egress allowlist entry: {}", e);
+                            return None;
+                        }
+                    },
+                    None => (e, 443),
+                };
+                match host.strip_prefix("*.") {
+                    Some(domain) => Some(AllowEntry {
+                        host: domain.to_string(),
+                        wildcard: true,
+                        port,
+                    }),
+                    None => Some(AllowEntry {
+                        host,
+                        wildcard: false,
+                        port,
+                    }),
+                }
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// Whether a CONNECT to `host:port` is allowed.
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        self.entries.iter().any(|e| {
+            e.port == port
+                && if e.wildcard {
+                    host.strip_suffix(e.host.as_str())
+                        .is_some_and(|rest| rest.ends_with('.') && rest.len() > 1)
+                } else {
+                    host == e.host
+                }
+        })
+    }
+}
+
+/// Parse the request line of a CONNECT head into `(host, port)`.
+///
+/// Returns None for anything but `CONNECT host:port HTTP/1.x`.
+fn parse_connect(head: &str) -> Option<(String, u16)> {
+    let line = head.lines().next()?;
+    let mut parts = line.split_whitespace();
+    if parts.next()? != "CONNECT" {
+        return None;
+    }
+    let target = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let (host, port) = target.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+// ============================================
+// Proxy server
+// ============================================
+
+/// HTTP CONNECT proxy enforcing the egress allowlist for the `claude-limited`
+/// network.
+///
+/// Runs in-process, listening on the network's gateway address. Only CONNECT
+/// tunnels are supported (containers get `HTTPS_PROXY` only). Denied
+/// destinations are written to the audit log under the user whose container
+/// made the request.
+pub struct EgressProxy {
+    allowlist: Allowlist,
+    docker: Arc<DockerManager>,
+    db: Arc<Database>,
+}
+
+impl EgressProxy {
+    pub fn new(allowlist: Allowlist, docker: Arc<DockerManager>, db: Arc<Database>) -> Self {
+        Self {
+            allowlist,
+            docker,
+            db,
+        }
+    }
+
+    /// Bind to `addr` and serve connections in a background task.
+    pub async fn spawn(self, addr: SocketAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind egress proxy on {}", addr))?;
+        let local = listener.local_addr()?;
+        info!("Egress proxy listening on {}", local);
+        tokio::spawn(Arc::new(self).serve(listener));
+        Ok(local)
+    }
+
+    async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (client, peer) = match listener.accept().await {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("Egress proxy accept failed: {}", e);
+                    continue;
+                }
+            };
+            let proxy = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = proxy.handle(client, peer).await {
+                    debug!("Egress proxy connection from {} ended: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut client: TcpStream, peer: SocketAddr) -> Result<()> {
+        let head = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_head(&mut client))
+            .await
+            .context("Timed out reading request")??;
+
+        let Some((host, port)) = parse_connect(&head) else {
+            client
+                .write_all(b"HTTP/1.1 405 Method Not Allowed\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Ok(());
+        };
+
+        if !self.allowlist.allows(&host, port) {
+            self.record_denied(peer, &host, port).await;
+            client
+                .write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Ok(());
+        }
+
+        let mut upstream = match tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            TcpStream::connect((host.as_str(), port)),
+        )
+        .await
+        {
+            Ok(Ok(s)) => s,
+            _ => {
+                client
+                    .write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n")
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        debug!("Egress proxy tunnel {} -> {}:{}", peer, host, port);
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    }
+
+    /// Audit a denied destination under the requesting user.
+    async fn record_denied(&self, peer: SocketAddr, host: &str, port: u16) {
+        let ip = peer.ip().to_string();
+        let wxid = self
+            .docker
+            .wxid_for_ip(&ip)
+            .await
+            .unwrap_or_else(|| format!("ip:{}", ip));
+        warn!("Egress denied for {}: {}:{}", wxid, host, port);
+        let note = format!("[egress denied] {}:{}", host, port);
+        let _ = self.db.audit_log(&wxid, None, "out", Some(&note), None);
+    }
+}
+
+/// Read up to the end of the request head (blank line).
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut buf = Vec::with_capacity(512);
+    let mut byte = [0u8; 1];
+    // Byte-at-a-time so no tunnel payload is consumed past the head
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() >= MAX_HEAD_LEN {
+            anyhow::bail!("Request head too large");
+        }
+        if stream.read(&mut byte).await? == 0 {
+            anyhow::bail!("Connection closed before request head");
+        }
+        buf.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container_runtime::{ContainerRuntime, FakeRuntime, NetworkInfo};
+    use crate::docker_manager::DockerConfig;
+    use std::path::Path;
+
+    fn allowlist(entries: &[&str]) -> Allowlist {
+        Allowlist::new(&entries.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn allowlist_exact_host_defaults_to_443() {
+        let list = allowlist(&["api.anthropic.com", "PyPI.org"]);
+        assert!(list.allows("api.anthropic.com", 443));
+        assert!(list.allows("pypi.org", 443));
+        assert!(list.allows("API.Anthropic.com.", 443));
+        assert!(!list.allows("api.anthropic.com", 80));
+        assert!(!list.allows("evil.api.anthropic.com", 443));
+        assert!(!list.allows("anthropic.com", 443));
+    }
+
+    #[test]
+    fn allowlist_wildcard_and_port() {
+        let list = allowlist(&["*.pythonhosted.org", "example.com:8443", "bad:port", ""]);
+        assert!(list.allows("files.pythonhosted.org", 443));
+        assert!(!list.allows("pythonhosted.org", 443));
+        assert!(!list.allows("evilpythonhosted.org", 443));
+        assert!(list.allows("example.com", 8443));
+        assert!(!list.allows("example.com", 443));
+        assert!(!list.allows("bad", 443));
+    }
+
+    #[test]
+    fn parse_connect_request_line() {
+        assert_eq!(
+            parse_connect("CONNECT api.anthropic.com:443 HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Some(("api.anthropic.com".into(), 443))
+        );
+        assert_eq!(
+            parse_connect("CONNECT [::1]:8443 HTTP/1.1\r\n\r\n"),
+            Some(("::1".into(), 8443))
+        );
+        assert_eq!(parse_connect("GET http://pypi.org/ HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse_connect("CONNECT pypi.org HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse_connect("CONNECT :443 HTTP/1.1\r\n\r\n"), None);
+    }
+
+    async fn start_proxy(entries: &[&str]) -> (SocketAddr, Arc<Database>, Arc<FakeRuntime>) {
+        let runtime = Arc::new(FakeRuntime::new());
+        let docker = Arc::new(DockerManager::with_runtime(
+            DockerConfig::default(),
+            runtime.clone(),
+        ));
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let proxy = EgressProxy::new(allowlist(entries), docker, Arc::clone(&db));
+        let addr = proxy.spawn("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        (addr, db, runtime)
+    }
+
+    async fn send_connect(proxy: SocketAddr, target: &str) -> (TcpStream, String) {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target).as_bytes())
+            .await
+            .unwrap();
+        let head = read_head(&mut client).await.unwrap();
+        (client, head)
+    }
+
+    #[tokio::test]
+    async fn proxy_tunnels_allowed_destination() {
+        // Upstream echo server
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut s, _) = upstream.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            s.read_exact(&mut buf).await.unwrap();
+            s.write_all(&buf).await.unwrap();
+        });
+
+        let (proxy, _db, _rt) = start_proxy(&[&format!("127.0.0.1:{}", port)]).await;
+        let (mut client, head) = send_connect(proxy, &format!("127.0.0.1:{}", port)).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn proxy_denies_and_audits_under_container_owner() {
+        let (proxy, db, rt) = start_proxy(&["api.anthropic.com"]).await;
+
+        // Pretend the client (127.0.0.1) is a bridge container on claude-limited
+        let config = bollard::container::Config::<String> {
+            labels: Some(std::collections::HashMap::from([
+                ("app".to_string(), "wechat-claude-bridge".to_string()),
+                ("wxid".to_string(), "wx_t".to_string()),
+            ])),
+            ..Default::default()
+        };
+        rt.create_container("claude-friend-wx_t", config).await.unwrap();
+        rt.networks.lock().unwrap().insert(
+            "claude-limited".into(),
+            NetworkInfo {
+                internal: true,
+                gateway: Some("127.0.0.1".into()),
+                containers: vec![("claude-friend-wx_t".into(), "127.0.0.1".into())],
+            },
+        );
+
+        let (_client, head) = send_connect(proxy, "evil.example.com:443").await;
+        assert!(head.starts_with("HTTP/1.1 403"), "{}", head);
+
+        let logs = db.audit_get_by_user("wx_t", 10).unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].direction, "out");
+        assert_eq!(
+            logs[0].message.as_deref(),
+            Some("[egress denied] evil.example.com:443")
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_rejects_plain_http_and_audits_unknown_peers_by_ip() {
+        let (proxy, db, _rt) = start_proxy(&["pypi.org"]).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(b"GET http://pypi.org/ HTTP/1.1\r\nHost: pypi.org\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut client).await.unwrap();
+        assert!(head.starts_with("HTTP/1.1 405"), "{}", head);
+
+        let (_client, head) = send_connect(proxy, "pypi.org:22").await;
+        assert!(head.starts_with("HTTP/1.1 403"), "{}", head);
+        assert_eq!(db.audit_get_by_user("ip:127.0.0.1", 10).unwrap().len(), 1);
+    }
+}
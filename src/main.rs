@@ -1,25 +1,49 @@
+mod agent_backend;
+mod audit_chain;
+mod claude_cli;
 mod claude_executor;
 mod config;
+mod container_runtime;
 mod database;
+mod dispatcher;
 mod docker_manager;
+mod egress_proxy;
 mod error;
 mod message_router;
+mod migrations;
+mod reaper;
+mod scheduler;
+mod snapshots;
 mod telegram_bot;
 mod wechat_bot;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 use tracing::{error, info, warn};
 
-use claude_executor::ClaudeExecutor;
-use config::get_config;
-use database::Database;
-use docker_manager::{DockerConfig, DockerLimits, DockerManager, DockerNetworkConfig};
+use agent_backend::{AgentBackend, DockerBackend, HostBackend, MockBackend};
+use audit_chain::CheckpointLog;
+use claude_cli::ReplyUpdate;
+use claude_executor::{ClaudeExecutor, OutboxPolicy, WorkspaceQuota};
+use config::{get_config, DatabaseConfig};
+use database::{AuditRetention, Database};
+use dispatcher::{Dispatch, Dispatcher, MessageHandler};
+use docker_manager::{
+    DockerConfig, DockerLimits, DockerManager, DockerNetworkConfig, DockerPoolConfig,
+    DockerRuntimeConfig,
+};
+use egress_proxy::{Allowlist, EgressProxy};
 use message_router::MessageRouter;
+use reaper::Reaper;
+use scheduler::Scheduler;
+use snapshots::SnapshotPolicy;
 use telegram_bot::TelegramBot;
-use wechat_bot::{StdinBot, WeChatBot};
+use wechat_bot::{Attachment, Contact, Message, ReplyStream, StdinBot, WeChatBot};
 
 // ============================================
 // Memory string parsing
@@ -52,6 +76,7 @@ fn build_docker_config(cfg: &config::Config) -> DockerConfig {
         image: cfg.docker.image.clone(),
         container_prefix: cfg.docker.container_prefix.clone(),
         data_dir,
+        socket: cfg.docker.expanded_socket(),
         limits: DockerLimits {
             memory: parse_memory(&cfg.docker.limits.memory),
             admin_memory: parse_memory(&cfg.docker.limits.admin_memory),
@@ -65,6 +90,26 @@ fn build_docker_config(cfg: &config::Config) -> DockerConfig {
             trusted: cfg.docker.network.trusted.clone(),
             normal: cfg.docker.network.normal.clone(),
         },
+        runtime: DockerRuntimeConfig {
+            admin: non_empty(&cfg.docker.runtime.admin),
+            trusted: non_empty(&cfg.docker.runtime.trusted),
+            normal: non_empty(&cfg.docker.runtime.normal),
+        },
+        egress_proxy_port: cfg.egress.enabled.then_some(cfg.egress.port),
+        pool: DockerPoolConfig {
+            admin: cfg.docker.pool.admin,
+            trusted: cfg.docker.pool.trusted,
+            normal: cfg.docker.pool.normal,
+            refill_delay: std::time::Duration::from_secs(cfg.docker.pool.refill_delay_secs),
+        },
+    }
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_string())
     }
 }
 
@@ -115,35 +160,154 @@ fn split_message(text: &str, max_len: usize) -> Vec<String> {
 }
 
 // ============================================
-// Entry point
+// Streaming replies
 // ============================================
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // 1. Init tracing (console output)
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
+/// Minimum delay between two partial updates pushed to the bot, so that
+/// edit-in-place frontends stay clear of platform rate limits.
+const STREAM_UPDATE_INTERVAL: Duration = Duration::from_millis(1500);
+
+/// Forward updates from `rx` to the bot: queue notices as plain messages and
+/// partial replies throttled to at most one update per
+/// `STREAM_UPDATE_INTERVAL`. Returns once the sender is dropped, with the
+/// outbox files to send after the final reply.
+async fn forward_stream(
+    bot: &dyn WeChatBot,
+    contact: &Contact,
+    mut rx: mpsc::UnboundedReceiver<ReplyUpdate>,
+) -> (ReplyStream, Vec<Attachment>) {
+    let mut stream = ReplyStream::default();
+    let mut files = Vec::new();
+    let mut pending: Option<String> = None;
+    let mut next_push = Instant::now();
+
+    loop {
+        tokio::select! {
+            update = rx.recv() => match update {
+                Some(ReplyUpdate::Partial(text)) => pending = Some(text),
+                Some(ReplyUpdate::Queued(position)) => {
+                    let notice = format!("⏳ 排队中，你是第 {} 位", position);
+                    if let Err(e) = bot.send_message(contact, &notice).await {
+                        warn!("Failed to send queue notice: {}", e);
+                    }
+                }
+                Some(ReplyUpdate::File(file)) => files.push(file),
+                None => break,
+            },
+            _ = tokio::time::sleep_until(next_push), if pending.is_some() => {
+                if let Some(text) = pending.take() {
+                    if let Err(e) = bot.send_stream_update(contact, &mut stream, &text, false).await {
+                        warn!("Failed to push partial reply: {}", e);
+                    }
+                }
+                next_push = Instant::now() + STREAM_UPDATE_INTERVAL;
+            }
+        }
+    }
 
-    info!("Starting WeChat -> Claude Code bridge...");
+    (stream, files)
+}
 
-    // 2. Load config
-    config::init_config().context("Failed to load configuration")?;
-    let cfg = get_config();
+/// Route one message (or merged batch) and send the reply, streaming
+/// partial replies if enabled, followed by any outbox files.
+async fn reply_to(
+    router: &MessageRouter,
+    bot: &dyn WeChatBot,
+    contact: &Contact,
+    message: &Message,
+    stream: bool,
+) {
+    // The executor only sends partial replies when streaming is on, but
+    // queue notices and outbox files always go through the channel
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (response, (mut reply_stream, files)) = tokio::join!(
+        router.handle_message(contact, message, Some(tx)),
+        forward_stream(bot, contact, rx),
+    );
+    let Some(response) = response else {
+        return;
+    };
 
-    if cfg.admin_wxid.is_empty() {
-        warn!("admin_wxid is not set in config.yaml!");
+    if stream {
+        if let Err(e) = bot
+            .send_stream_update(contact, &mut reply_stream, &response, true)
+            .await
+        {
+            error!("Failed to send message: {}", e);
+        }
+    } else {
+        // 11. Split long messages for WeChat
+        let chunks = split_message(&response, 2000);
+        for (i, chunk) in chunks.iter().enumerate() {
+            if let Err(e) = bot.send_message(contact, chunk).await {
+                error!("Failed to send message: {}", e);
+            }
+            // Brief pause between multi-part messages
+            if chunks.len() > 1 && i < chunks.len() - 1 {
+                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            }
+        }
     }
 
-    // 3. Create Database
-    let db = Arc::new(
-        Database::new(None).context("Failed to initialize database")?,
-    );
+    for file in &files {
+        if let Err(e) = bot.send_file(contact, file).await {
+            error!("Failed to send file {}: {}", file.file_name, e);
+        }
+    }
+}
+
+/// Map the `snapshots` config section onto the executor's policy.
+fn build_snapshot_policy(cfg: &config::SnapshotsConfig) -> SnapshotPolicy {
+    SnapshotPolicy {
+        keep_manual: cfg.max_manual,
+        keep_auto: cfg.max_auto,
+        auto_admin: cfg.auto_before_run.admin,
+        auto_trusted: cfg.auto_before_run.trusted,
+        auto_normal: cfg.auto_before_run.normal,
+    }
+}
+
+/// Map `logging.retention` onto the audit log pruner's settings.
+fn build_audit_retention(cfg: &config::LoggingRetention) -> (AuditRetention, Option<PathBuf>) {
+    let retention = AuditRetention {
+        max_age_days: cfg.max_age_days,
+        max_rows_per_user: cfg.max_rows_per_user,
+        redact_after_days: cfg.redact_after_days,
+    };
+    let archive_dir = (!cfg.archive_dir.is_empty()).then(|| PathBuf::from(&cfg.archive_dir));
+    (retention, archive_dir)
+}
+
+/// Map `docker.limits.workspace_size` onto byte quotas (0 = unlimited).
+fn build_workspace_quota(cfg: &config::WorkspaceSize) -> WorkspaceQuota {
+    WorkspaceQuota {
+        admin: parse_memory(&cfg.admin).max(0) as u64,
+        trusted: parse_memory(&cfg.trusted).max(0) as u64,
+        normal: parse_memory(&cfg.normal).max(0) as u64,
+    }
+}
+
+/// Create the agent backend selected by `claude.backend`.
+///
+/// For the docker backend this also runs the health check, builds the sandbox
+/// image if missing and creates the networks; exits if Docker is unavailable.
+async fn build_backend(cfg: &config::Config, db: &Arc<Database>) -> Result<Arc<dyn AgentBackend>> {
+    match cfg.claude.backend.as_str() {
+        "docker" => {}
+        "host" => {
+            warn!("Using host backend: Claude runs directly on this machine without isolation");
+            return Ok(Arc::new(HostBackend::new(
+                cfg.claude.cli_path.clone(),
+                cfg.docker.expanded_data_dir(),
+            )));
+        }
+        "mock" => {
+            warn!("Using mock backend: replies are canned, Claude is never called");
+            return Ok(Arc::new(MockBackend::new()));
+        }
+        other => anyhow::bail!("Unknown claude.backend '{}' (expected docker, host or mock)", other),
+    }
 
-    // 4. Create DockerManager
     let docker_cfg = build_docker_config(cfg);
     let docker = Arc::new(
         DockerManager::new(docker_cfg)
@@ -154,7 +318,7 @@ async fn main() -> Result<()> {
     // 5. Docker health check
     let healthy = docker.health_check().await?;
     if !healthy {
-        error!("Docker is not available. Please install and start Docker: https://docs.docker.com/get-docker/");
+        error!("Docker is not available or a configured docker.runtime is missing. Please install and start Docker: https://docs.docker.com/get-docker/");
         std::process::exit(1);
     }
 
@@ -170,23 +334,162 @@ async fn main() -> Result<()> {
         }
     }
 
-    // 7. Init Docker networks
+    // 7. Init Docker networks and the trusted-network egress proxy. Pool
+    // members left by the last run are sorted out first, so the proxy knows
+    // the owners of claimed ones.
     docker.init_networks().await?;
+    if let Err(e) = docker.reconcile_pool().await {
+        warn!("Failed to reconcile container pool: {}", e);
+    }
+    if cfg.egress.enabled {
+        start_egress_proxy(cfg, &docker, db).await;
+    }
+
+    // Pre-warmed pool: fill and keep refilling in the background
+    if cfg.docker.pool.admin + cfg.docker.pool.trusted + cfg.docker.pool.normal > 0 {
+        let pool_docker = Arc::clone(&docker);
+        tokio::spawn(async move {
+            if let Err(e) = pool_docker.fill_pool().await {
+                warn!("Failed to fill container pool: {}", e);
+            }
+            pool_docker.run_pool_refill().await;
+        });
+    }
+
+    Ok(Arc::new(DockerBackend::new(docker)))
+}
+
+/// Start the allowlist proxy on the claude-limited gateway. Failures are
+/// logged, not fatal: the internal network then simply has no egress.
+async fn start_egress_proxy(cfg: &config::Config, docker: &Arc<DockerManager>, db: &Arc<Database>) {
+    let gateway = match docker.limited_network_gateway().await {
+        Ok(Some(gw)) => gw,
+        Ok(None) => {
+            warn!("claude-limited network has no gateway; egress proxy not started");
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to inspect claude-limited network: {}", e);
+            return;
+        }
+    };
+    let addr = match format!("{}:{}", gateway, cfg.egress.port).parse() {
+        Ok(a) => a,
+        Err(e) => {
+            warn!("Invalid egress proxy address {}:{}: {}", gateway, cfg.egress.port, e);
+            return;
+        }
+    };
+
+    let proxy = EgressProxy::new(
+        Allowlist::new(&cfg.egress.allowlist),
+        Arc::clone(docker),
+        Arc::clone(db),
+    );
+    if let Err(e) = proxy.spawn(addr).await {
+        warn!("{:#}", e);
+    }
+}
+
+/// `--migrate-dry-run`: apply pending schema migrations to the database
+/// inside a transaction, roll back, and report what would change.
+fn migrate_dry_run(path: &Path) -> Result<()> {
+    let (version, pending) = Database::migrate_dry_run(path)?;
+    println!(
+        "Database schema version {} (this build: {})",
+        version,
+        migrations::latest_version()
+    );
+    if pending.is_empty() {
+        println!("No pending migrations.");
+    }
+    for migration in pending {
+        println!("Would apply {}: {} (OK)", migration.version, migration.description);
+    }
+    Ok(())
+}
+
+/// `backup`: write a backup of the database file, which may be in use by a
+/// running bridge, and rotate old backups.
+fn backup_database(cfg: &DatabaseConfig) -> Result<()> {
+    let info = Database::backup_file(
+        Path::new(&cfg.path),
+        Path::new(&cfg.backup.dir),
+        cfg.backup.keep,
+    )?;
+    println!("Backed up {} to {} ({} bytes)", cfg.path, info.path.display(), info.size);
+    Ok(())
+}
+
+// ============================================
+// Entry point
+// ============================================
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // 1. Init tracing (console output)
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+
+    info!("Starting WeChat -> Claude Code bridge...");
+
+    // 2. Load config
+    config::init_config().context("Failed to load configuration")?;
+    let cfg = get_config();
+
+    if cfg.admin_wxid.is_empty() {
+        warn!("admin_wxid is not set in config.yaml!");
+    }
+
+    // Maintenance commands that only touch the database, then exit
+    match std::env::args().nth(1).as_deref() {
+        Some("--migrate-dry-run") => return migrate_dry_run(Path::new(&cfg.database.path)),
+        Some("backup") => return backup_database(&cfg.database),
+        _ => {}
+    }
+
+    // 3. Create Database (applies pending schema migrations)
+    let db = Arc::new(
+        Database::new(Some(Path::new(&cfg.database.path)))
+            .and_then(|db| db.with_busy_timeout(Duration::from_millis(cfg.database.busy_timeout_ms)))
+            .context("Failed to initialize database")?,
+    );
+
+    // 4. Create the agent backend (Docker checks only apply to the docker backend)
+    let backend = build_backend(cfg, &db).await?;
 
     // 8. Create ClaudeExecutor
-    let executor = Arc::new(ClaudeExecutor::new(
-        Arc::clone(&docker),
+    let weights = &cfg.queue.weights;
+    let mut executor = ClaudeExecutor::new(
+        backend,
         Arc::clone(&db),
         cfg.session.expire_minutes,
         cfg.claude.timeout,
-    ));
+    )
+    .with_scheduler(Arc::new(Scheduler::new(
+        cfg.queue.max_concurrent,
+        [weights.admin, weights.trusted, weights.normal],
+    )))
+    .with_partial_replies(cfg.claude.stream)
+    .with_workspace_quota(build_workspace_quota(&cfg.docker.limits.workspace_size));
+    if cfg.outbox.enabled {
+        executor = executor.with_outbox(OutboxPolicy::from_config(&cfg.outbox));
+    }
+    if cfg.snapshots.enabled {
+        executor = executor.with_snapshots(build_snapshot_policy(&cfg.snapshots));
+    }
+    let executor = Arc::new(executor);
 
     // 9. Create MessageRouter
-    let router = MessageRouter::new(
+    let router = Arc::new(MessageRouter::new(
         Arc::clone(&db),
         Arc::clone(&executor),
         cfg.admin_wxid.clone(),
-    );
+    ));
 
     // 10. Start bot (Telegram or StdinBot)
     let mut bot: Box<dyn WeChatBot> = if cfg.telegram.enabled {
@@ -197,12 +500,15 @@ async fn main() -> Result<()> {
         Box::new(StdinBot::new())
     };
     bot.start().await?;
+    let bot: Arc<dyn WeChatBot> = Arc::from(bot);
 
-    info!("Docker environment ready. Bot started, waiting for messages...");
+    info!("Backend ready ({}). Bot started, waiting for messages...", cfg.claude.backend);
 
     // 12. Periodic cleanup task
     let cleanup_db = Arc::clone(&db);
     let expire_min = cfg.session.expire_minutes as i64;
+    let (retention, archive_dir) = build_audit_retention(&cfg.logging.retention);
+    let checkpoints = cfg.logging.checkpoints.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
         loop {
@@ -223,32 +529,125 @@ async fn main() -> Result<()> {
                 }
                 Err(e) => warn!("Rate limit cleanup failed: {}", e),
             }
+            if retention.is_enabled() {
+                let db = Arc::clone(&cleanup_db);
+                let (retention, archive_dir) = (retention.clone(), archive_dir.clone());
+                let pruned =
+                    tokio::task::spawn_blocking(move || db.audit_prune(&retention, archive_dir.as_deref()))
+                        .await;
+                match pruned {
+                    Ok(Ok(report)) => {
+                        if report.deleted > 0 || report.redacted > 0 {
+                            info!(
+                                "Pruned audit log: {} deleted, {} redacted{}",
+                                report.deleted,
+                                report.redacted,
+                                report
+                                    .archive
+                                    .map(|p| format!(", archived to {}", p.display()))
+                                    .unwrap_or_default()
+                            );
+                        }
+                    }
+                    Ok(Err(e)) => warn!("Audit log pruning failed: {:#}", e),
+                    Err(e) => warn!("Audit log pruning panicked: {}", e),
+                }
+            }
+            if checkpoints.enabled {
+                let db = Arc::clone(&cleanup_db);
+                let cfg = checkpoints.clone();
+                let written = tokio::task::spawn_blocking(move || {
+                    CheckpointLog::open(Path::new(&cfg.file), Path::new(&cfg.key_file))?.checkpoint(&db)
+                })
+                .await;
+                match written {
+                    Ok(Ok(Some(cp))) => info!("Audit checkpoint at #{} {}", cp.id, cp.hash),
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => warn!("Audit checkpoint failed: {:#}", e),
+                    Err(e) => warn!("Audit checkpoint panicked: {}", e),
+                }
+            }
         }
     });
 
-    // 13. Graceful shutdown via Ctrl+C
+    // 13. Idle container reaper, reporting to the admin
+    if cfg.reaper.enabled {
+        let reaper = Reaper::new(
+            Arc::clone(&executor),
+            Arc::clone(&db),
+            cfg.reaper.stop_after_minutes,
+            cfg.reaper.remove_after_minutes,
+        );
+        let reaper_db = Arc::clone(&db);
+        let reaper_bot = Arc::clone(&bot);
+        let admin = (cfg.reaper.notify_admin && !cfg.admin_wxid.is_empty()).then(|| Contact {
+            wxid: cfg.admin_wxid.clone(),
+            nickname: "admin".to_string(),
+            remark_name: String::new(),
+            group: None,
+        });
+        let period = std::time::Duration::from_secs(cfg.reaper.interval_minutes.max(1) * 60);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let report = match reaper.run_once().await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Idle container reaper failed: {}", e);
+                        continue;
+                    }
+                };
+                if report.is_empty() {
+                    continue;
+                }
+                if let Some(ref admin) = admin {
+                    if let Err(e) = reaper_bot.send_message(admin, &report.format(&reaper_db)).await {
+                        warn!("Failed to send reaper report to admin: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    // 14. Per-user inboxes: one worker per busy user
+    let handler: MessageHandler = {
+        let router = Arc::clone(&router);
+        let bot = Arc::clone(&bot);
+        let stream = cfg.claude.stream;
+        Arc::new(move |contact: Contact, message: Message| {
+            let router = Arc::clone(&router);
+            let bot = Arc::clone(&bot);
+            Box::pin(async move {
+                reply_to(&router, bot.as_ref(), &contact, &message, stream).await;
+            })
+        })
+    };
+    let dispatcher = Arc::new(Dispatcher::new(
+        Arc::clone(&handler),
+        cfg.queue.max_pending,
+        cfg.queue.merge,
+    ));
+
+    // 15. Graceful shutdown via Ctrl+C
     let message_loop = async {
         loop {
             let msg = bot.recv_message().await;
             match msg {
-                Ok(Some((contact, text))) => {
-                    let text = text.trim().to_string();
-                    if text.is_empty() {
+                Ok(Some((contact, mut message))) => {
+                    message.text = message.text.trim().to_string();
+                    if message.is_empty() {
                         continue;
                     }
 
-                    // Route the message
-                    if let Some(response) = router.handle_message(&contact, &text).await {
-                        // 11. Split long messages for WeChat
-                        let chunks = split_message(&response, 2000);
-                        for (i, chunk) in chunks.iter().enumerate() {
-                            if let Err(e) = bot.send_message(&contact, chunk).await {
-                                error!("Failed to send message: {}", e);
-                            }
-                            // Brief pause between multi-part messages
-                            if chunks.len() > 1 && i < chunks.len() - 1 {
-                                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                            }
+                    // Commands skip the inbox so /status, /kill etc. answer
+                    // while the sender's Claude run is still going
+                    if router.is_command(&message.text) {
+                        tokio::spawn(handler(contact, message));
+                    } else if dispatcher.dispatch(contact.clone(), message) == Dispatch::Rejected {
+                        let notice = "⚠️ 消息太多了，请等当前回复完成后再发送";
+                        if let Err(e) = bot.send_message(&contact, notice).await {
+                            error!("Failed to send message: {}", e);
                         }
                     }
                 }
@@ -271,6 +670,15 @@ async fn main() -> Result<()> {
         }
     }
 
+    // Don't leave Claude runs behind in the containers
+    let killed = executor.kill_all().await;
+    if killed > 0 {
+        info!("Killed {} Claude runs in progress", killed);
+    }
+    if let Err(e) = bot.stop().await {
+        warn!("Failed to stop the bot cleanly: {}", e);
+    }
+
     info!("Bridge stopped.");
     Ok(())
 }
@@ -352,7 +760,7 @@ mod tests {
     #[test]
     fn test_parse_memory_negative() {
         // Negative numbers should parse fine as i64
-        assert_eq!(parse_memory("-1m"), -1 * 1024 * 1024);
+        assert_eq!(parse_memory("-1m"), -1024 * 1024);
     }
 
     #[test]
@@ -431,7 +839,7 @@ mod tests {
     fn test_split_message_unicode_chinese() {
         // Chinese characters are multi-byte in UTF-8 (3 bytes each)
         // Create a string of ~700 Chinese chars (2100 bytes)
-        let msg: String = std::iter::repeat('中').take(700).collect();
+        let msg: String = "中".repeat(700);
         // Note: split_message uses byte length (.len()), not char count
         // 700 * 3 = 2100 bytes > 2000, so it should split
         // But the split at byte position 2000 could land mid-character!
@@ -452,7 +860,7 @@ mod tests {
     #[test]
     fn test_split_message_emoji_content() {
         // Emoji are 4 bytes in UTF-8
-        let msg: String = std::iter::repeat("🎉").take(600).collect();
+        let msg: String = "🎉".repeat(600);
         // 600 * 4 = 2400 bytes > 2000
         let result = std::panic::catch_unwind(|| {
             split_message(&msg, 2000)
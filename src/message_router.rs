@@ -1,13 +1,17 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use regex::Regex;
 use tracing::{info, warn};
 
-use crate::claude_executor::{parse_permission, ClaudeExecutor};
+use crate::audit_chain::{BreakKind, ChainReport, CheckpointLog, CheckpointProblem, CheckpointReport};
+use crate::claude_cli::{ReplySink, ReplyUpdate};
+use crate::claude_executor::{parse_permission, sanitize_file_name, ClaudeExecutor, OutboxPolicy};
 use crate::config::get_config;
-use crate::database::{AuditEntry, Database, Friend};
-use crate::wechat_bot::Contact;
+use crate::database::{AuditEntry, Database, Friend, UsagePeriod, UsageSummary, REDACTED_MESSAGE};
+use crate::snapshots;
+use crate::wechat_bot::{Attachment, AttachmentKind, Contact, Group, Message};
 
 // ============================================
 // Helpers
@@ -46,6 +50,49 @@ fn display_name(contact: &Contact) -> &str {
     }
 }
 
+/// Chinese label for an attachment kind, for logs and the audit trail.
+fn attachment_label(kind: AttachmentKind) -> &'static str {
+    match kind {
+        AttachmentKind::Image => "图片",
+        AttachmentKind::File => "文件",
+        AttachmentKind::Voice => "语音",
+    }
+}
+
+/// One-line description of a message: its text plus its attachments.
+fn message_summary(message: &Message) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    if !message.text.is_empty() {
+        parts.push(message.text.clone());
+    }
+    for a in &message.attachments {
+        parts.push(format!("[{}: {}]", attachment_label(a.kind), a.file_name));
+    }
+    parts.join(" ")
+}
+
+/// The text Claude gets for a message: who sent it (in groups), the quoted
+/// message, the text itself and where each attachment was saved in the
+/// workspace.
+fn claude_prompt(message: &Message, saved: &[String], group_sender: Option<&str>) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    if let Some(sender) = group_sender {
+        lines.push(format!("[Group message from {}]", sender));
+    }
+    if let Some(ref quote) = message.reply_to {
+        if !quote.text.is_empty() {
+            lines.push(format!("[Replying to: {}]", truncate_str(&quote.text, 500)));
+        }
+    }
+    if !message.text.is_empty() {
+        lines.push(message.text.clone());
+    }
+    for (attachment, path) in message.attachments.iter().zip(saved) {
+        lines.push(format!("[Attached {}: {}]", attachment.kind.as_str(), path));
+    }
+    lines.join("\n")
+}
+
 // ============================================
 // Command metadata
 // ============================================
@@ -67,6 +114,9 @@ pub struct MessageRouter {
     admin_wxid: String,
     /// Command name -> metadata.  Dispatch is via match in handle_command_dispatch.
     commands: HashMap<&'static str, Command>,
+    /// (conversation, sender) -> workspace path the sender's next attachment
+    /// is saved to (`/put`).
+    pending_puts: Mutex<HashMap<(String, String), String>>,
 }
 
 impl MessageRouter {
@@ -77,53 +127,112 @@ impl MessageRouter {
         commands.insert("/help", Command { permission: "normal", description: "查看帮助" });
         commands.insert("/status", Command { permission: "normal", description: "查看状态（含容器信息）" });
         commands.insert("/clear", Command { permission: "normal", description: "清除会话历史" });
+        commands.insert("/usage", Command { permission: "normal", description: "查看用量（管理员可 /usage 昵称）" });
+
+        // Workspace file commands
+        commands.insert("/ls", Command { permission: "trusted", description: "列出工作区文件: /ls [目录]" });
+        commands.insert("/get", Command { permission: "trusted", description: "获取工作区文件: /get 路径" });
+        commands.insert("/put", Command { permission: "trusted", description: "上传文件到工作区: /put 路径，再发送文件" });
+        commands.insert("/rm", Command { permission: "trusted", description: "删除工作区文件或目录: /rm 路径" });
+        commands.insert("/snapshot", Command { permission: "trusted", description: "保存工作区快照: /snapshot [名称]" });
+        commands.insert("/snapshots", Command { permission: "trusted", description: "列出工作区快照" });
+        commands.insert("/restore", Command { permission: "trusted", description: "恢复工作区快照: /restore 名称" });
 
         // Admin commands
         commands.insert("/allow", Command { permission: "admin", description: "授权好友: /allow 昵称 [trusted|normal]" });
         commands.insert("/block", Command { permission: "admin", description: "拉黑好友: /block 昵称" });
         commands.insert("/list", Command { permission: "admin", description: "列出所有授权好友" });
         commands.insert("/logs", Command { permission: "admin", description: "查看日志: /logs [昵称]" });
+        commands.insert("/search", Command { permission: "admin", description: "搜索日志: /search 关键词 [昵称] [7d|2026-10-01] [p2]" });
         commands.insert("/kill", Command { permission: "admin", description: "终止好友进程: /kill 昵称" });
         commands.insert("/containers", Command { permission: "admin", description: "查看所有容器状态" });
         commands.insert("/restart", Command { permission: "admin", description: "重启容器: /restart 昵称" });
         commands.insert("/destroy", Command { permission: "admin", description: "销毁容器（保留数据）: /destroy 昵称" });
         commands.insert("/rebuild", Command { permission: "admin", description: "重建容器: /rebuild 昵称" });
         commands.insert("/stopall", Command { permission: "admin", description: "停止所有容器" });
-
-        Self { db, executor, admin_wxid, commands }
+        commands.insert("/backup", Command { permission: "admin", description: "备份数据库" });
+        commands.insert("/audit", Command { permission: "admin", description: "校验审计日志: /audit verify" });
+
+        Self {
+            db,
+            executor,
+            admin_wxid,
+            commands,
+            pending_puts: Mutex::new(HashMap::new()),
+        }
     }
 
     // ============================================
     // Core routing
     // ============================================
 
+    /// Whether a message is a known command (answered without Claude).
+    pub fn is_command(&self, message: &str) -> bool {
+        message.starts_with('/')
+            && message
+                .split_whitespace()
+                .next()
+                .is_some_and(|cmd| self.commands.contains_key(cmd.to_lowercase().as_str()))
+    }
+
     /// Handle an incoming message and return an optional reply.
-    pub async fn handle_message(&self, contact: &Contact, message: &str) -> Option<String> {
+    ///
+    /// When `stream` is set, messages forwarded to Claude push their queue
+    /// position and partial reply text to it; the returned string is still
+    /// the complete final reply.
+    pub async fn handle_message(
+        &self,
+        contact: &Contact,
+        incoming: &Message,
+        stream: Option<ReplySink>,
+    ) -> Option<String> {
         let config = get_config();
         let dn = display_name(contact);
+        let message = incoming.text.as_str();
+        let summary = message_summary(incoming);
+        // Container, session, workspace and spend belong to the conversation;
+        // permission and rate limit to the sender
+        let conversation = contact.conversation_id();
+        // Audit entries of group chats also name the member
+        let member = contact.group.as_ref().map(|_| contact.wxid.as_str());
 
         // 1. Log + audit incoming message
-        info!(
-            "收到消息 [{}({})]: {}",
-            dn,
-            contact.wxid,
-            truncate_str(message, 100)
-        );
+        match contact.group {
+            Some(ref group) => info!(
+                "收到群消息 [{}({}) @ {}({})]: {}",
+                dn,
+                contact.wxid,
+                group.name,
+                group.id,
+                truncate_str(&summary, 100)
+            ),
+            None => info!(
+                "收到消息 [{}({})]: {}",
+                dn,
+                contact.wxid,
+                truncate_str(&summary, 100)
+            ),
+        }
         let audit_content = if config.logging.log_message_content {
-            message
+            summary.as_str()
         } else {
-            "[已隐藏]"
+            REDACTED_MESSAGE
         };
-        let _ = self.db.audit_log(&contact.wxid, Some(dn), "in", Some(audit_content), None);
+        let _ = self
+            .db
+            .audit_log_from(conversation, member, Some(dn), "in", Some(audit_content), None);
 
-        // 2. Ensure friend registered
+        // 2. Ensure friend (and group) registered
         self.ensure_friend_registered(contact);
+        if let Some(ref group) = contact.group {
+            self.ensure_group_registered(group);
+        }
 
         // 3. Permission check
-        let permission = self.get_effective_permission(&contact.wxid);
+        let permission = self.conversation_permission(contact);
 
         if permission == "blocked" {
-            warn!("拒绝黑名单用户: {}({})", dn, contact.wxid);
+            warn!("拒绝黑名单用户或群组: {}({}) in {}", dn, contact.wxid, conversation);
             return None;
         }
 
@@ -149,9 +258,13 @@ impl MessageRouter {
 
         // 5. Command handling
         if message.starts_with('/') {
-            if let Some(response) = self.handle_command(&contact.wxid, &permission, message).await {
-                let _ = self.db.audit_log(
-                    &contact.wxid,
+            if let Some(response) = self
+                .handle_command(conversation, &contact.wxid, &permission, incoming, stream.as_ref())
+                .await
+            {
+                let _ = self.db.audit_log_from(
+                    conversation,
+                    member,
                     Some(dn),
                     "out",
                     Some(truncate_str(&response, 200)),
@@ -161,21 +274,65 @@ impl MessageRouter {
             }
         }
 
+        // A file sent after /put goes to the workspace instead of Claude
+        if let Some(file) = incoming.attachments.first() {
+            let key = (conversation.to_string(), contact.wxid.clone());
+            let pending = self.pending_puts.lock().unwrap().remove(&key);
+            if let Some(path) = pending {
+                let response = self.put_file(conversation, &permission, &path, file).await;
+                let _ = self.db.audit_log_from(conversation, member, Some(dn), "out", Some(&response), None);
+                return Some(response);
+            }
+        }
+
         // 6. Security check
         if let Some(reason) = self.security_check(message, &permission) {
             return Some(format!("⚠️ {}", reason));
         }
 
-        // 7. Forward to Claude executor
-        let friend = match self.db.friend_get(&contact.wxid) {
-            Ok(Some(f)) => f,
+        // 7. Spend and workspace quota checks
+        if let Some(reason) = self.quota_check(conversation, &permission) {
+            return Some(format!("⚠️ {}", reason));
+        }
+        let upload: usize = incoming.attachments.iter().map(|a| a.data.len()).sum();
+        if let Some(reason) = self.workspace_check(conversation, &permission, upload).await {
+            return Some(format!("⚠️ {}", reason));
+        }
+
+        // 8. Forward to Claude executor
+        // In groups this is the group's own row; tools, scheduling and the
+        // container follow the sender's effective level, not the group's
+        let friend = match self.db.friend_get(conversation) {
+            Ok(Some(f)) => Friend { permission: permission.clone(), ..f },
             _ => return Some("❌ 处理消息时出错了，请稍后重试".to_string()),
         };
 
-        let response = self.executor.execute(&contact.wxid, &friend, message).await;
+        // 9. Save attachments into the workspace and tell Claude where they are
+        let saved = if incoming.attachments.is_empty() {
+            Vec::new()
+        } else {
+            match self
+                .executor
+                .save_attachments(conversation, parse_permission(&permission), &incoming.attachments)
+                .await
+            {
+                Ok(paths) => paths,
+                Err(e) => {
+                    warn!("保存附件失败 [{}]: {}", conversation, e);
+                    return Some("❌ 附件保存失败，请稍后重试".to_string());
+                }
+            }
+        };
+        let prompt = claude_prompt(incoming, &saved, contact.group.as_ref().map(|_| dn));
+
+        let response = self
+            .executor
+            .execute(conversation, &friend, &prompt, stream)
+            .await;
 
-        let _ = self.db.audit_log(
-            &contact.wxid,
+        let _ = self.db.audit_log_from(
+            conversation,
+            member,
             Some(dn),
             "out",
             Some(truncate_str(&response, 500)),
@@ -201,6 +358,57 @@ impl MessageRouter {
         }
     }
 
+    /// Permission for a message: the sender's level in private chats. In a
+    /// group it is the lower of the group's and the sender's level, so an
+    /// authorized group doesn't lift its members above their own level and a
+    /// blocked member stays blocked.
+    fn conversation_permission(&self, contact: &Contact) -> String {
+        let sender = self.get_effective_permission(&contact.wxid);
+        let Some(ref group) = contact.group else {
+            return sender;
+        };
+        let group = match self.db.friend_get_permission(&group.id) {
+            Ok(Some(perm)) => perm,
+            _ => get_config().permissions.group_default_level.clone(),
+        };
+        if sender == "blocked" || group == "blocked" {
+            "blocked".to_string()
+        } else if perm_level(&group) < perm_level(&sender) {
+            group
+        } else {
+            sender
+        }
+    }
+
+    /// Register a group chat (as a friend row keyed by the group ID, named
+    /// after the group) at `group_default_level`, keeping its name current.
+    fn ensure_group_registered(&self, group: &Group) {
+        let config = get_config();
+        match self.db.friend_get(&group.id) {
+            Ok(Some(existing)) => {
+                if existing.nickname.as_deref() != Some(&group.name) {
+                    let _ = self.db.friend_upsert(&group.id, Some(&group.name), None, None, None, None);
+                }
+            }
+            Ok(None) => {
+                let _ = self.db.friend_upsert(
+                    &group.id,
+                    Some(&group.name),
+                    None,
+                    Some(&config.permissions.group_default_level),
+                    None,
+                    None,
+                );
+                let _ = self.db.friend_set_kind(&group.id, "group");
+                info!(
+                    "新群组注册: {}({}) 权限 {}",
+                    group.name, group.id, config.permissions.group_default_level
+                );
+            }
+            Err(_) => {}
+        }
+    }
+
     fn ensure_friend_registered(&self, contact: &Contact) {
         let config = get_config();
         match self.db.friend_get(&contact.wxid) {
@@ -243,8 +451,17 @@ impl MessageRouter {
     // Command dispatch
     // ============================================
 
-    async fn handle_command(&self, wxid: &str, permission: &str, message: &str) -> Option<String> {
-        let parts: Vec<&str> = message.trim().split_whitespace().collect();
+    /// `wxid` is the conversation and `sender` who wrote the command, the
+    /// same user outside group chats.
+    async fn handle_command(
+        &self,
+        wxid: &str,
+        sender: &str,
+        permission: &str,
+        message: &Message,
+        stream: Option<&ReplySink>,
+    ) -> Option<String> {
+        let parts: Vec<&str> = message.text.split_whitespace().collect();
         let cmd = parts[0].to_lowercase();
         let args = if parts.len() > 1 {
             parts[1..].join(" ")
@@ -263,18 +480,29 @@ impl MessageRouter {
         // Dispatch to handler
         let result = match cmd.as_str() {
             "/help" => self.cmd_help(permission),
-            "/status" => self.cmd_status(wxid).await,
+            "/status" => self.cmd_status(wxid, permission).await,
             "/clear" => self.cmd_clear(wxid).await,
+            "/usage" => self.cmd_usage(wxid, permission, &args),
+            "/ls" => self.cmd_ls(wxid, permission, &args).await,
+            "/get" => self.cmd_get(wxid, permission, &args, stream).await,
+            "/put" => self.cmd_put(wxid, sender, permission, &args, &message.attachments).await,
+            "/rm" => self.cmd_rm(wxid, permission, &args).await,
+            "/snapshot" => self.cmd_snapshot(wxid, permission, &args).await,
+            "/snapshots" => self.cmd_snapshots(wxid).await,
+            "/restore" => self.cmd_restore(wxid, &args).await,
             "/allow" => self.cmd_allow(&args),
             "/block" => self.cmd_block(&args).await,
             "/list" => self.cmd_list(),
             "/logs" => self.cmd_logs(&args),
+            "/search" => self.cmd_search(&args),
             "/kill" => self.cmd_kill(&args).await,
             "/containers" => self.cmd_containers().await,
             "/restart" => self.cmd_restart(&args).await,
             "/destroy" => self.cmd_destroy(&args).await,
             "/rebuild" => self.cmd_rebuild(&args).await,
             "/stopall" => self.cmd_stopall().await,
+            "/backup" => self.cmd_backup().await,
+            "/audit" => self.cmd_audit(&args).await,
             _ => return None,
         };
 
@@ -298,11 +526,11 @@ impl MessageRouter {
             }
         }
 
-        lines.push("\n直接发送文字消息即可与 Claude 对话".to_string());
+        lines.push("\n直接发送文字消息即可与 Claude 对话，图片、文件和语音会存到工作区 inbox/ 目录".to_string());
         lines.join("\n")
     }
 
-    async fn cmd_status(&self, wxid: &str) -> String {
+    async fn cmd_status(&self, wxid: &str, permission: &str) -> String {
         let friend = self.db.friend_get(wxid).ok().flatten();
         let session = self.db.session_get_active(wxid).ok().flatten();
         let container = self.executor.get_container_status(wxid).await;
@@ -340,8 +568,25 @@ impl MessageRouter {
             ));
             lines.push(format!("   进程: {}", stats.pids));
         }
-        if let Some(ref disk) = container.disk {
-            lines.push(format!("   磁盘: {}", disk));
+        if let Some(used) = container.disk {
+            match self.executor.workspace_quota(parse_permission(permission)) {
+                0 => lines.push(format!("   磁盘: {}", format_bytes(used))),
+                quota => lines.push(format!(
+                    "   磁盘: {} / {}",
+                    format_bytes(used),
+                    format_bytes(quota)
+                )),
+            }
+        }
+
+        let queue = self.executor.queue_depth();
+        lines.push(String::new());
+        lines.push(format!(
+            "⏳ 队列: 运行中 {}/{}，等待 {}",
+            queue.running, queue.capacity, queue.waiting
+        ));
+        if let Some(position) = self.executor.queue_position(wxid) {
+            lines.push(format!("   你排在第 {} 位", position));
         }
 
         lines.join("\n")
@@ -352,6 +597,332 @@ impl MessageRouter {
         "✅ 会话已清除，下次对话将开始新的上下文".to_string()
     }
 
+    fn cmd_usage(&self, wxid: &str, permission: &str, args: &str) -> String {
+        // Looking at someone else's usage is admin-only
+        let (target_wxid, title) = if args.is_empty() {
+            (wxid.to_string(), "📈 我的用量".to_string())
+        } else {
+            if perm_level(permission) < perm_level("admin") {
+                return "⚠️ 权限不足".to_string();
+            }
+            let matches = match self.db.friend_find_by_nickname(args.trim()) {
+                Ok(m) => m,
+                Err(_) => return "❌ 查询出错".to_string(),
+            };
+            if matches.is_empty() {
+                return format!("❌ 未找到 \"{}\"", args);
+            }
+            let f = &matches[0];
+            (f.wxid.clone(), format!("📈 {} 的用量", f.nickname.as_deref().unwrap_or("?")))
+        };
+
+        let target_perm = self.get_effective_permission(&target_wxid);
+        let cap = get_config().quota.for_permission(&target_perm);
+        let day = self.db.usage_for_friend(&target_wxid, UsagePeriod::Day).unwrap_or_default();
+        let month = self.db.usage_for_friend(&target_wxid, UsagePeriod::Month).unwrap_or_default();
+
+        let mut lines = vec![
+            format!("{}:\n", title),
+            format_usage_line("今日", &day, cap.daily_usd),
+            format_usage_line("本月", &month, cap.monthly_usd),
+        ];
+        if let Ok(Some(session)) = self.db.session_get_active(&target_wxid) {
+            if let Ok(usage) = self.db.usage_for_session(&session.id) {
+                lines.push(format_usage_line("当前会话", &usage, 0.0));
+            }
+        }
+        lines.join("\n")
+    }
+
+    // ============================================
+    // Command implementations - Workspace files
+    // ============================================
+
+    async fn cmd_ls(&self, wxid: &str, permission: &str, args: &str) -> String {
+        let Some(dir) = workspace_path(args) else {
+            return "⚠️ 路径不能超出工作区".to_string();
+        };
+        let entries = match self
+            .executor
+            .list_files(wxid, parse_permission(permission), &dir)
+            .await
+        {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("列出文件失败 [{}] {}: {}", wxid, dir, e);
+                return format!("❌ 无法列出 {}", display_path(&dir));
+            }
+        };
+        self.audit_file_op(wxid, &format!("ls {}", display_path(&dir)));
+
+        if entries.is_empty() {
+            return format!("📂 {} 是空的", display_path(&dir));
+        }
+        let mut lines = vec![format!("📂 {} ({} 项):\n", display_path(&dir), entries.len())];
+        for entry in entries.iter().take(MAX_LS_ENTRIES) {
+            if entry.is_dir {
+                lines.push(format!("📁 {}/", entry.name));
+            } else {
+                lines.push(format!("📄 {} ({})", entry.name, format_bytes(entry.size)));
+            }
+        }
+        if entries.len() > MAX_LS_ENTRIES {
+            lines.push(format!("... 还有 {} 项", entries.len() - MAX_LS_ENTRIES));
+        }
+        lines.join("\n")
+    }
+
+    async fn cmd_get(
+        &self,
+        wxid: &str,
+        permission: &str,
+        args: &str,
+        stream: Option<&ReplySink>,
+    ) -> String {
+        let Some(path) = workspace_path(args) else {
+            return "⚠️ 路径不能超出工作区".to_string();
+        };
+        if path.is_empty() {
+            return "用法: /get 路径".to_string();
+        }
+        let Some(sink) = stream else {
+            return "⚠️ 当前不支持发送文件".to_string();
+        };
+        // Sending outbox files after runs may be off; /get still follows the
+        // configured size and type limits.
+        let policy = match self.executor.outbox_policy() {
+            Some(policy) => policy.clone(),
+            None => OutboxPolicy::from_config(&get_config().outbox),
+        };
+        let level = parse_permission(permission);
+        let (dir, file_name) = path.rsplit_once('/').unwrap_or(("", &path));
+        let file_name = file_name.to_string();
+        if !policy.allows(level, &file_name) {
+            return format!("⚠️ 不允许发送此类型的文件: {}", file_name);
+        }
+
+        // Check the size before reading anything into memory
+        let entry = match self.executor.list_files(wxid, level, dir).await {
+            Ok(entries) => entries.into_iter().find(|e| e.name == file_name && !e.is_dir),
+            Err(e) => {
+                warn!("读取文件失败 [{}] {}: {}", wxid, path, e);
+                None
+            }
+        };
+        let Some(entry) = entry else {
+            return format!("❌ 无法读取 {}", path);
+        };
+        if entry.size > policy.max_bytes {
+            return format!(
+                "⚠️ 文件太大 ({})，上限 {}",
+                format_bytes(entry.size),
+                format_bytes(policy.max_bytes)
+            );
+        }
+        let data = match self.executor.read_file(wxid, level, &path, policy.max_bytes).await {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("读取文件失败 [{}] {}: {}", wxid, path, e);
+                return format!("❌ 无法读取 {}", path);
+            }
+        };
+        let size = data.len() as u64;
+        self.audit_file_op(wxid, &format!("get {} ({})", path, format_bytes(size)));
+
+        let _ = sink.send(ReplyUpdate::File(Attachment {
+            kind: AttachmentKind::for_file_name(&file_name),
+            file_name,
+            data,
+        }));
+        format!("📤 正在发送 {} ({})", path, format_bytes(size))
+    }
+
+    async fn cmd_put(
+        &self,
+        wxid: &str,
+        sender: &str,
+        permission: &str,
+        args: &str,
+        attachments: &[Attachment],
+    ) -> String {
+        if args.is_empty() {
+            return "用法: /put 路径，再发送文件".to_string();
+        }
+        let Some(path) = workspace_path(args) else {
+            return "⚠️ 路径不能超出工作区".to_string();
+        };
+        // A trailing slash (or the workspace root) keeps the file's own name
+        let path = if path.is_empty() || args.ends_with('/') {
+            format!("{}/", path)
+        } else {
+            path
+        };
+
+        // The file came with the command (e.g. as a caption)
+        if let Some(file) = attachments.first() {
+            return self.put_file(wxid, permission, &path, file).await;
+        }
+        // In groups the file must come from whoever asked for it
+        self.pending_puts
+            .lock()
+            .unwrap()
+            .insert((wxid.to_string(), sender.to_string()), path.clone());
+        format!("📥 请发送要保存到 {} 的文件", display_path(path.trim_end_matches('/')))
+    }
+
+    /// Save an uploaded file for `/put`. A `path` ending in `/` is a
+    /// directory to save into under the file's own name.
+    async fn put_file(&self, wxid: &str, permission: &str, path: &str, file: &Attachment) -> String {
+        let path = match path.strip_suffix('/') {
+            Some("") => sanitize_file_name(&file.file_name),
+            Some(dir) => format!("{}/{}", dir, sanitize_file_name(&file.file_name)),
+            None => path.to_string(),
+        };
+        if let Some(reason) = self.workspace_check(wxid, permission, file.data.len()).await {
+            return format!("⚠️ {}", reason);
+        }
+        if let Err(e) = self
+            .executor
+            .write_file(wxid, parse_permission(permission), &path, &file.data)
+            .await
+        {
+            warn!("保存文件失败 [{}] {}: {}", wxid, path, e);
+            return format!("❌ 无法保存到 {}", path);
+        }
+        let size = format_bytes(file.data.len() as u64);
+        self.audit_file_op(wxid, &format!("put {} ({})", path, size));
+        format!("✅ 已保存到 {} ({})", path, size)
+    }
+
+    async fn cmd_rm(&self, wxid: &str, permission: &str, args: &str) -> String {
+        if args.is_empty() {
+            return "用法: /rm 路径".to_string();
+        }
+        let Some(path) = workspace_path(args) else {
+            return "⚠️ 路径不能超出工作区".to_string();
+        };
+        if path.is_empty() {
+            return "⚠️ 不能删除整个工作区".to_string();
+        }
+        if let Err(e) = self
+            .executor
+            .remove_file(wxid, parse_permission(permission), &path)
+            .await
+        {
+            warn!("删除文件失败 [{}] {}: {}", wxid, path, e);
+            return format!("❌ 无法删除 {}", path);
+        }
+        self.audit_file_op(wxid, &format!("rm {}", path));
+        format!("🗑️ 已删除 {}", path)
+    }
+
+    async fn cmd_snapshot(&self, wxid: &str, permission: &str, args: &str) -> String {
+        if !self.executor.snapshots_enabled() {
+            return "⚠️ 快照功能未启用".to_string();
+        }
+        let name = if args.is_empty() {
+            chrono::Local::now().format("%Y%m%d-%H%M%S").to_string()
+        } else {
+            args.to_string()
+        };
+        if !snapshots::valid_name(&name) {
+            return "⚠️ 快照名称只能包含字母、数字、- 和 _（最长 64 个字符）".to_string();
+        }
+        if name.starts_with(snapshots::AUTO_PREFIX) {
+            return format!("⚠️ {} 开头的名称保留给自动快照", snapshots::AUTO_PREFIX);
+        }
+        let level = parse_permission(permission);
+        if let Some((needed, quota)) = self.executor.snapshot_over_quota(wxid, level).await {
+            return format!(
+                "⚠️ 快照会超出工作区空间 ({} / {})，请先用 /rm 删除文件",
+                format_bytes(needed),
+                format_bytes(quota)
+            );
+        }
+
+        match self.executor.create_snapshot(wxid, &name).await {
+            Ok(info) => {
+                self.audit_file_op(wxid, &format!("snapshot {} ({})", name, format_bytes(info.size)));
+                format!("📸 已保存快照 {} ({})", name, format_bytes(info.size))
+            }
+            Err(e) => {
+                warn!("创建快照失败 [{}] {}: {:#}", wxid, name, e);
+                "❌ 创建快照失败".to_string()
+            }
+        }
+    }
+
+    async fn cmd_snapshots(&self, wxid: &str) -> String {
+        if !self.executor.snapshots_enabled() {
+            return "⚠️ 快照功能未启用".to_string();
+        }
+        let list = match self.executor.list_snapshots(wxid).await {
+            Ok(list) => list,
+            Err(e) => {
+                warn!("列出快照失败 [{}]: {:#}", wxid, e);
+                return "❌ 查询快照失败".to_string();
+            }
+        };
+        if list.is_empty() {
+            return "暂无快照，用 /snapshot [名称] 保存一个".to_string();
+        }
+
+        let mut lines = vec![format!("📸 快照 ({}):\n", list.len())];
+        for snapshot in &list {
+            let time = chrono::DateTime::from_timestamp(snapshot.created, 0)
+                .map(|t| t.with_timezone(&chrono::Local).format("%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            let kind = if snapshot.is_auto() { " (自动)" } else { "" };
+            lines.push(format!(
+                "• {}{} - {} [{}]",
+                snapshot.name,
+                kind,
+                format_bytes(snapshot.size),
+                time
+            ));
+        }
+        lines.push("\n用 /restore 名称 恢复".to_string());
+        lines.join("\n")
+    }
+
+    async fn cmd_restore(&self, wxid: &str, args: &str) -> String {
+        if args.is_empty() {
+            return "用法: /restore 名称".to_string();
+        }
+        if !self.executor.snapshots_enabled() {
+            return "⚠️ 快照功能未启用".to_string();
+        }
+        let exists = match self.executor.list_snapshots(wxid).await {
+            Ok(list) => list.iter().any(|s| s.name == args),
+            Err(_) => false,
+        };
+        if !exists {
+            return format!("❌ 未找到快照 \"{}\"", args);
+        }
+        if self.executor.is_busy(wxid).await {
+            return "⚠️ Claude 正在处理你的消息，请等回复完成后再恢复".to_string();
+        }
+
+        match self.executor.restore_snapshot(wxid, args).await {
+            Ok(()) => {
+                self.audit_file_op(wxid, &format!("restore {}", args));
+                format!("✅ 工作区已恢复到快照 {}", args)
+            }
+            Err(e) => {
+                warn!("恢复快照失败 [{}] {}: {:#}", wxid, args, e);
+                "❌ 恢复快照失败".to_string()
+            }
+        }
+    }
+
+    /// Record a workspace file operation in the audit log, whatever
+    /// `logging.log_message_content` says.
+    fn audit_file_op(&self, wxid: &str, op: &str) {
+        info!("文件操作 [{}]: {}", wxid, op);
+        let entry = format!("[文件操作] {}", op);
+        let _ = self.db.audit_log(wxid, None, "in", Some(&entry), None);
+    }
+
     // ============================================
     // Command implementations - Friend management
     // ============================================
@@ -445,7 +1016,11 @@ impl MessageRouter {
                     lines.push(format!("{} {}:", icon, perm.to_uppercase()));
                     for f in group {
                         let name = f.remark_name.as_deref().or(f.nickname.as_deref()).unwrap_or(&f.wxid);
-                        lines.push(format!("  {}", name));
+                        if f.kind == "group" {
+                            lines.push(format!("  {} [群]", name));
+                        } else {
+                            lines.push(format!("  {}", name));
+                        }
                     }
                     lines.push(String::new());
                 }
@@ -473,6 +1048,60 @@ impl MessageRouter {
         format_logs(&logs)
     }
 
+    fn cmd_search(&self, args: &str) -> String {
+        let Some(query) = SearchArgs::parse(args, chrono::Utc::now()) else {
+            return "用法: /search 关键词 [昵称] [7d|24h|2026-10-01] [p2]".to_string();
+        };
+
+        let wxid = match query.name {
+            Some(ref name) => match self.db.friend_find_by_nickname(name) {
+                Ok(matches) if !matches.is_empty() => Some(matches[0].wxid.clone()),
+                Ok(_) => return format!("❌ 未找到 \"{}\"", name),
+                Err(_) => return "❌ 查询出错".to_string(),
+            },
+            None => None,
+        };
+
+        let offset = (query.page - 1) * SEARCH_PAGE_SIZE;
+        let (hits, total) = match self.db.audit_search(
+            &query.keyword,
+            wxid.as_deref(),
+            query.since.as_deref(),
+            SEARCH_PAGE_SIZE,
+            offset,
+        ) {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("搜索日志失败: {}", e);
+                return "❌ 查询出错".to_string();
+            }
+        };
+        if total == 0 {
+            return format!("🔍 没有找到包含 \"{}\" 的记录", query.keyword);
+        }
+        let pages = (total + SEARCH_PAGE_SIZE - 1) / SEARCH_PAGE_SIZE;
+        if hits.is_empty() {
+            return format!("🔍 \"{}\" 共 {} 条，只有 {} 页", query.keyword, total, pages);
+        }
+
+        let mut lines = vec![format!(
+            "🔍 \"{}\" 共 {} 条（第 {}/{} 页）",
+            query.keyword, total, query.page, pages
+        )];
+        for hit in &hits {
+            let e = &hit.entry;
+            let dir = if e.direction == "in" { "📩" } else { "📤" };
+            // "YYYY-MM-DD HH:MM:SS" -> "MM-DD HH:MM"
+            let time = e.timestamp.as_deref().and_then(|t| t.get(5..16)).unwrap_or("");
+            let name = e.nickname.as_deref().unwrap_or(&e.wxid);
+            lines.push(format!("{} [{}] {}: {}", dir, time, name, hit.snippet));
+        }
+        if query.page < pages {
+            lines.push(format!("下一页: {}", query.command_for_page(query.page + 1)));
+        }
+        lines.join("\n")
+    }
+
     async fn cmd_kill(&self, args: &str) -> String {
         if args.is_empty() {
             return "用法: /kill 昵称".to_string();
@@ -602,6 +1231,94 @@ impl MessageRouter {
         format!("⏹️ 已停止全部 {} 个容器", containers.len())
     }
 
+    async fn cmd_backup(&self) -> String {
+        let backup = &get_config().database.backup;
+        let (db, dir, keep) = (Arc::clone(&self.db), PathBuf::from(&backup.dir), backup.keep);
+        let result = tokio::task::spawn_blocking(move || db.backup(&dir, keep))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r);
+        match result {
+            Ok(info) => {
+                let name = info.path.file_name().unwrap_or_default().to_string_lossy();
+                format!(
+                    "💾 数据库已备份: {} ({})\n保留最近 {} 份",
+                    name,
+                    format_bytes(info.size),
+                    keep.max(1)
+                )
+            }
+            Err(e) => {
+                warn!("数据库备份失败: {:#}", e);
+                "❌ 备份失败，请查看日志".to_string()
+            }
+        }
+    }
+
+    async fn cmd_audit(&self, args: &str) -> String {
+        if !args.eq_ignore_ascii_case("verify") {
+            return "用法: /audit verify".to_string();
+        }
+        let db = Arc::clone(&self.db);
+        let checkpoints = get_config().logging.checkpoints.clone();
+        let result = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+            let chain = db.audit_verify()?;
+            let file = Path::new(&checkpoints.file);
+            let signed = if !checkpoints.enabled {
+                None
+            } else if !file.exists() {
+                // Nothing written yet; don't create a key just to find that out
+                Some(CheckpointReport::default())
+            } else {
+                Some(CheckpointLog::open(file, Path::new(&checkpoints.key_file))?.verify(&db)?)
+            };
+            Ok((chain, signed))
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|r| r);
+        match result {
+            Ok((chain, signed)) => format_audit_report(&chain, signed.as_ref()),
+            Err(e) => {
+                warn!("审计日志校验失败: {:#}", e);
+                "❌ 校验失败，请查看日志".to_string()
+            }
+        }
+    }
+
+    // ============================================
+    // Spend and workspace quota
+    // ============================================
+
+    /// Reject the message if the user's daily or monthly spend cap is reached.
+    fn quota_check(&self, wxid: &str, permission: &str) -> Option<String> {
+        let cap = get_config().quota.for_permission(permission);
+
+        for (period, limit) in [(UsagePeriod::Day, cap.daily_usd), (UsagePeriod::Month, cap.monthly_usd)] {
+            if limit > 0.0 {
+                let usage = self.db.usage_for_friend(wxid, period);
+                if let Some(reply) = spend_rejection(wxid, period, usage, limit) {
+                    return Some(reply);
+                }
+            }
+        }
+        None
+    }
+
+    /// Reject the message if the user's workspace is full, or would be
+    /// after saving `upload` more bytes.
+    async fn workspace_check(&self, wxid: &str, permission: &str, upload: usize) -> Option<String> {
+        let (used, quota) = self
+            .executor
+            .workspace_over_quota(wxid, parse_permission(permission), upload as u64)
+            .await?;
+        Some(format!(
+            "工作区空间不足 ({} / {})，请先用 /rm 删除文件",
+            format_bytes(used),
+            format_bytes(quota)
+        ))
+    }
+
     // ============================================
     // Security check
     // ============================================
@@ -650,6 +1367,204 @@ fn format_logs(logs: &[AuditEntry]) -> String {
         .join("\n")
 }
 
+/// One line of `/usage` output. `cap_usd` of 0 means no cap is shown.
+fn format_usage_line(label: &str, usage: &UsageSummary, cap_usd: f64) -> String {
+    let cap = if cap_usd > 0.0 {
+        format!(" / ${:.2}", cap_usd)
+    } else {
+        String::new()
+    };
+    format!(
+        "{}: {} 次, ${:.4}{}\n   tokens 输入 {} / 输出 {} / 缓存写 {} / 缓存读 {}",
+        label,
+        usage.requests,
+        usage.cost_usd,
+        cap,
+        usage.tokens.input,
+        usage.tokens.output,
+        usage.tokens.cache_creation,
+        usage.tokens.cache_read,
+    )
+}
+
+/// Most entries `/ls` shows.
+const MAX_LS_ENTRIES: usize = 50;
+
+const WORKSPACE_ROOT: &str = "/home/sandbox/workspace";
+
+/// Turn a user-supplied path into one relative to the workspace root (`""`
+/// for the root itself). Paths may be relative or absolute under
+/// `/home/sandbox/workspace`; anything that could leave the workspace
+/// yields `None`.
+fn workspace_path(arg: &str) -> Option<String> {
+    let arg = arg.trim();
+    let relative = match arg.strip_prefix(WORKSPACE_ROOT) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ if arg.starts_with('/') => return None,
+        _ => arg,
+    };
+    let mut parts = Vec::new();
+    for part in relative.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            part if part.contains('\\') => return None,
+            part => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Why a spend cap rejects a message, if it does. A failed usage query
+/// rejects too, so a broken database can't switch the caps off.
+fn spend_rejection(
+    wxid: &str,
+    period: UsagePeriod,
+    usage: anyhow::Result<UsageSummary>,
+    limit: f64,
+) -> Option<String> {
+    let usage = match usage {
+        Ok(usage) => usage,
+        Err(e) => {
+            warn!("额度查询失败 [{}]: {:#}", wxid, e);
+            return Some("额度查询失败，请稍后重试".to_string());
+        }
+    };
+    if usage.cost_usd < limit {
+        return None;
+    }
+    Some(match period {
+        UsagePeriod::Day => format!("今日额度已用完 (${:.2} / ${:.2})，明天再来吧", usage.cost_usd, limit),
+        UsagePeriod::Month => format!("本月额度已用完 (${:.2} / ${:.2})", usage.cost_usd, limit),
+    })
+}
+
+/// `/audit verify` reply: the chain, then the checkpoints if enabled.
+fn format_audit_report(chain: &ChainReport, signed: Option<&CheckpointReport>) -> String {
+    let mut lines = Vec::new();
+    match chain.broken {
+        Some((id, kind)) => {
+            let reason = match kind {
+                BreakKind::Unsealed => "记录没有哈希",
+                BreakKind::Link => "与前一条记录接不上，前面的记录被修改、删除或插入过",
+                BreakKind::Content => "记录内容与哈希不符，已被修改",
+            };
+            lines.push(format!("❌ 审计链在 #{} 处断开: {}", id, reason));
+            lines.push(format!("此前 {} 条记录完好", chain.entries));
+        }
+        None => {
+            lines.push(format!("🔗 审计链完好: {} 条记录", chain.entries));
+            if chain.pruned > 0 || chain.redacted > 0 {
+                lines.push(format!("（含已清理 {} 条，已隐藏内容 {} 条）", chain.pruned, chain.redacted));
+            }
+            if let Some((id, ref hash)) = chain.head {
+                lines.push(format!("最新: #{} {}", id, &hash[..hash.len().min(16)]));
+            }
+        }
+    }
+    match signed {
+        None => {}
+        Some(report) => match report.problem {
+            Some((n, problem)) => {
+                let reason = match problem {
+                    CheckpointProblem::BadSignature => "签名无效",
+                    CheckpointProblem::Mismatch => "与当前日志不符，日志被改写过",
+                    CheckpointProblem::Truncated => "指向的记录已不存在，日志末尾被删除过",
+                };
+                lines.push(format!("❌ 第 {} 个检查点{}", n, reason));
+            }
+            None if report.matched == 0 && report.skipped == 0 => lines.push("🔏 还没有检查点".to_string()),
+            None => {
+                let mut line = format!("🔏 {} 个签名检查点一致", report.matched);
+                if report.skipped > 0 {
+                    line.push_str(&format!("（{} 个已随日志清理）", report.skipped));
+                }
+                lines.push(line);
+            }
+        },
+    }
+    lines.join("\n")
+}
+
+/// Hits per `/search` page.
+const SEARCH_PAGE_SIZE: i64 = 10;
+
+/// Parsed `/search 关键词 [昵称] [since] [pN]` arguments.
+#[derive(Debug, PartialEq)]
+struct SearchArgs {
+    keyword: String,
+    name: Option<String>,
+    /// The `since` argument as typed, and as a UTC timestamp.
+    since_arg: Option<String>,
+    since: Option<String>,
+    page: i64,
+}
+
+impl SearchArgs {
+    /// Parse the arguments. `since` is `<n>h`, `<n>d`, `<n>w` before `now`
+    /// or a `YYYY-MM-DD` date; `pN` picks page N. Any other word after the
+    /// keyword is the nickname.
+    fn parse(args: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Self> {
+        let mut words = args.split_whitespace();
+        let mut query = SearchArgs {
+            keyword: words.next()?.to_string(),
+            name: None,
+            since_arg: None,
+            since: None,
+            page: 1,
+        };
+        for word in words {
+            if let Some(page) = word.strip_prefix('p').and_then(|n| n.parse::<i64>().ok()) {
+                query.page = page.max(1);
+            } else if let Some(since) = parse_since(word, now) {
+                query.since_arg = Some(word.to_string());
+                query.since = Some(since);
+            } else if query.name.is_none() {
+                query.name = Some(word.to_string());
+            } else {
+                return None;
+            }
+        }
+        Some(query)
+    }
+
+    /// The command that shows `page` of the same search.
+    fn command_for_page(&self, page: i64) -> String {
+        let mut words = vec!["/search", self.keyword.as_str()];
+        words.extend(self.name.as_deref());
+        words.extend(self.since_arg.as_deref());
+        let page = format!("p{}", page);
+        words.push(&page);
+        words.join(" ")
+    }
+}
+
+/// A `/search` since argument as a UTC timestamp in the audit log's format.
+fn parse_since(word: &str, now: chrono::DateTime<chrono::Utc>) -> Option<String> {
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(word, "%Y-%m-%d") {
+        return Some(date.and_hms_opt(0, 0, 0)?.format(FORMAT).to_string());
+    }
+    let unit = word.chars().last()?;
+    let n: i64 = word[..word.len() - unit.len_utf8()].parse().ok()?;
+    let span = match unit {
+        'h' => chrono::Duration::try_hours(n)?,
+        'd' => chrono::Duration::try_days(n)?,
+        'w' => chrono::Duration::try_weeks(n)?,
+        _ => return None,
+    };
+    Some((now - span).format(FORMAT).to_string())
+}
+
+/// How a workspace-relative path is shown to users.
+fn display_path(path: &str) -> &str {
+    if path.is_empty() {
+        "工作区"
+    } else {
+        path
+    }
+}
+
 fn format_bytes(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = 1024 * KB;
@@ -669,6 +1584,15 @@ fn format_bytes(bytes: u64) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::path::Path;
+
+    use crate::agent_backend::{AgentBackend, HostBackend, MockBackend};
+    use crate::claude_cli::ReplyUpdate;
+    use crate::docker_manager::Permission;
+    use crate::scheduler::Scheduler;
+    use crate::snapshots::SnapshotPolicy;
+    use crate::claude_executor::WorkspaceQuota;
+    use crate::wechat_bot::{Attachment, Quote};
 
     // ============================================
     // perm_level tests
@@ -707,6 +1631,7 @@ mod tests {
             wxid: "wx_001".into(),
             nickname: "Nick".into(),
             remark_name: "Remark".into(),
+            group: None,
         };
         assert_eq!(display_name(&contact), "Remark");
     }
@@ -717,6 +1642,7 @@ mod tests {
             wxid: "wx_001".into(),
             nickname: "Nick".into(),
             remark_name: String::new(),
+            group: None,
         };
         assert_eq!(display_name(&contact), "Nick");
     }
@@ -727,6 +1653,7 @@ mod tests {
             wxid: "wx_001".into(),
             nickname: String::new(),
             remark_name: String::new(),
+            group: None,
         };
         assert_eq!(display_name(&contact), "wx_001");
     }
@@ -737,6 +1664,7 @@ mod tests {
             wxid: "wx_001".into(),
             nickname: "张三李四".into(),
             remark_name: String::new(),
+            group: None,
         };
         assert_eq!(display_name(&contact), "张三李四");
     }
@@ -747,6 +1675,7 @@ mod tests {
             wxid: "wx_001".into(),
             nickname: "Name".into(),
             remark_name: "🎉 Party Friend".into(),
+            group: None,
         };
         assert_eq!(display_name(&contact), "🎉 Party Friend");
     }
@@ -791,6 +1720,33 @@ mod tests {
         assert_eq!(format_bytes(1024 * 1024 * 1024), "1.0GB");
     }
 
+    // ============================================
+    // format_usage_line tests
+    // ============================================
+
+    #[test]
+    fn format_usage_line_with_cap() {
+        let usage = UsageSummary {
+            requests: 3,
+            tokens: crate::database::TokenCounts {
+                input: 10,
+                output: 20,
+                cache_creation: 30,
+                cache_read: 40,
+            },
+            cost_usd: 0.1234,
+        };
+        let line = format_usage_line("今日", &usage, 1.0);
+        assert!(line.starts_with("今日: 3 次, $0.1234 / $1.00"));
+        assert!(line.contains("输入 10 / 输出 20 / 缓存写 30 / 缓存读 40"));
+    }
+
+    #[test]
+    fn format_usage_line_without_cap() {
+        let line = format_usage_line("本月", &UsageSummary::default(), 0.0);
+        assert!(line.starts_with("本月: 0 次, $0.0000\n"));
+    }
+
     // ============================================
     // format_logs tests
     // ============================================
@@ -806,6 +1762,7 @@ mod tests {
         let logs = vec![AuditEntry {
             id: 1,
             wxid: "wx_001".into(),
+            sender: None,
             nickname: Some("Alice".into()),
             direction: "in".into(),
             message: Some("hello".into()),
@@ -824,6 +1781,7 @@ mod tests {
         let logs = vec![AuditEntry {
             id: 1,
             wxid: "wx_001".into(),
+            sender: None,
             nickname: Some("Bot".into()),
             direction: "out".into(),
             message: Some("response".into()),
@@ -839,6 +1797,7 @@ mod tests {
         let logs = vec![AuditEntry {
             id: 1,
             wxid: "wx_001".into(),
+            sender: None,
             nickname: Some("Alice".into()),
             direction: "in".into(),
             message: Some("hello".into()),
@@ -854,6 +1813,7 @@ mod tests {
         let logs = vec![AuditEntry {
             id: 1,
             wxid: "wx_001".into(),
+            sender: None,
             nickname: None,
             direction: "in".into(),
             message: Some("hello".into()),
@@ -870,6 +1830,7 @@ mod tests {
         let logs = vec![AuditEntry {
             id: 1,
             wxid: "wx_001".into(),
+            sender: None,
             nickname: Some("Alice".into()),
             direction: "in".into(),
             message: Some(long_msg),
@@ -886,6 +1847,7 @@ mod tests {
         let logs = vec![AuditEntry {
             id: 1,
             wxid: "wx_001".into(),
+            sender: None,
             nickname: Some("张三".into()),
             direction: "in".into(),
             message: Some("你好世界！这是一条测试消息。".into()),
@@ -896,4 +1858,773 @@ mod tests {
         assert!(result.contains("张三"));
         assert!(result.contains("你好世界"));
     }
+
+    #[test]
+    fn spend_rejection_fails_closed() {
+        let spent = |cost_usd| Ok(UsageSummary { cost_usd, ..Default::default() });
+        assert_eq!(spend_rejection("wx", UsagePeriod::Day, spent(0.5), 1.0), None);
+        assert!(spend_rejection("wx", UsagePeriod::Day, spent(1.0), 1.0).unwrap().starts_with("今日额度已用完"));
+        assert!(spend_rejection("wx", UsagePeriod::Month, spent(9.0), 5.0).unwrap().starts_with("本月额度已用完"));
+        let failed = spend_rejection("wx", UsagePeriod::Day, Err(anyhow::anyhow!("database is locked")), 1.0);
+        assert_eq!(failed.as_deref(), Some("额度查询失败，请稍后重试"));
+    }
+
+    #[test]
+    fn audit_report_formats() {
+        let chain = ChainReport {
+            entries: 40,
+            pruned: 3,
+            redacted: 2,
+            head: Some((45, "ab".repeat(32))),
+            broken: None,
+        };
+        let signed = CheckpointReport { matched: 5, skipped: 1, problem: None };
+        let reply = format_audit_report(&chain, Some(&signed));
+        assert!(reply.starts_with("🔗 审计链完好: 40 条记录"), "{}", reply);
+        assert!(reply.contains("已清理 3 条，已隐藏内容 2 条"), "{}", reply);
+        assert!(reply.contains("最新: #45 abababababababab\n"), "{}", reply);
+        assert!(reply.ends_with("5 个签名检查点一致（1 个已随日志清理）"), "{}", reply);
+
+        let broken = ChainReport { entries: 7, broken: Some((8, BreakKind::Link)), ..Default::default() };
+        let signed = CheckpointReport { problem: Some((2, CheckpointProblem::Mismatch)), ..Default::default() };
+        let reply = format_audit_report(&broken, Some(&signed));
+        assert!(reply.starts_with("❌ 审计链在 #8 处断开"), "{}", reply);
+        assert!(reply.contains("此前 7 条记录完好"), "{}", reply);
+        assert!(reply.contains("❌ 第 2 个检查点与当前日志不符"), "{}", reply);
+        assert!(!format_audit_report(&broken, None).contains("检查点"));
+    }
+
+    #[test]
+    fn search_args_parse_name_since_and_page() {
+        let now = chrono::NaiveDate::from_ymd_opt(2026, 10, 17)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+            .and_utc();
+        assert_eq!(SearchArgs::parse("", now), None);
+
+        let query = SearchArgs::parse("docker Alice 7d p2", now).unwrap();
+        assert_eq!(query.keyword, "docker");
+        assert_eq!(query.name.as_deref(), Some("Alice"));
+        assert_eq!(query.since.as_deref(), Some("2026-10-10 12:00:00"));
+        assert_eq!(query.page, 2);
+        assert_eq!(query.command_for_page(3), "/search docker Alice 7d p3");
+
+        let query = SearchArgs::parse("数据库 2026-10-01", now).unwrap();
+        assert_eq!(query.name, None);
+        assert_eq!(query.since.as_deref(), Some("2026-10-01 00:00:00"));
+        assert_eq!(query.page, 1);
+        assert_eq!(parse_since("24h", now).as_deref(), Some("2026-10-16 12:00:00"));
+        assert_eq!(parse_since("2w", now).as_deref(), Some("2026-10-03 12:00:00"));
+        assert_eq!(parse_since("7x", now), None);
+
+        // Only one nickname
+        assert_eq!(SearchArgs::parse("docker Alice Bob", now), None);
+    }
+
+    // ============================================
+    // End-to-end routing tests (mock backend)
+    // ============================================
+
+    const ADMIN: &str = "wx_admin";
+
+    fn mock_router() -> (MessageRouter, Arc<Database>) {
+        crate::config::init_test_config();
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let executor = Arc::new(ClaudeExecutor::new(
+            Arc::new(MockBackend::new()),
+            Arc::clone(&db),
+            60,
+            120,
+        ));
+        (MessageRouter::new(Arc::clone(&db), executor, ADMIN.into()), db)
+    }
+
+    fn contact(wxid: &str, nickname: &str) -> Contact {
+        Contact {
+            wxid: wxid.into(),
+            nickname: nickname.into(),
+            remark_name: String::new(),
+            group: None,
+        }
+    }
+
+    #[test]
+    fn is_command_only_for_known_commands() {
+        let (router, _db) = mock_router();
+        assert!(router.is_command("/status"));
+        assert!(router.is_command("/KILL Alice"));
+        assert!(!router.is_command("/usr/bin is missing"));
+        assert!(!router.is_command("hello /status"));
+    }
+
+    #[tokio::test]
+    async fn e2e_message_is_answered_and_session_resumed() {
+        let (router, db) = mock_router();
+        let admin = contact(ADMIN, "Boss");
+
+        let reply = router.handle_message(&admin, &Message::plain("hello"), None).await.unwrap();
+        assert_eq!(reply, "[mock] hello");
+
+        let session = db.session_get_active(ADMIN).unwrap().unwrap();
+        assert_eq!(session.claude_session.as_deref(), Some("mock-session-wx_admin"));
+
+        let reply = router.handle_message(&admin, &Message::plain("again"), None).await.unwrap();
+        assert_eq!(reply, "[mock] again (resumed mock-session-wx_admin)");
+
+        let logs = db.audit_get_by_user(ADMIN, 10).unwrap();
+        assert_eq!(logs.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn e2e_clear_starts_fresh_session() {
+        let (router, db) = mock_router();
+        let admin = contact(ADMIN, "Boss");
+
+        router.handle_message(&admin, &Message::plain("first"), None).await.unwrap();
+        let reply = router.handle_message(&admin, &Message::plain("/clear"), None).await.unwrap();
+        assert!(!reply.is_empty());
+        assert!(db.session_get_active(ADMIN).unwrap().is_none());
+
+        let reply = router.handle_message(&admin, &Message::plain("second"), None).await.unwrap();
+        assert_eq!(reply, "[mock] second");
+    }
+
+    #[tokio::test]
+    async fn e2e_admin_commands_reach_backend() {
+        let (router, db) = mock_router();
+        let admin = contact(ADMIN, "Boss");
+        let bob = contact("wx_bob", "Bob");
+
+        router.handle_message(&bob, &Message::plain("hi"), None).await.unwrap();
+        db.friend_set_permission("wx_bob", "trusted").unwrap();
+
+        let reply = router.handle_message(&admin, &Message::plain("/containers"), None).await.unwrap();
+        assert!(reply.contains("mock-wx_bob"), "{}", reply);
+
+        let reply = router.handle_message(&admin, &Message::plain("/destroy Bob"), None).await.unwrap();
+        assert!(reply.contains("Bob"), "{}", reply);
+        let reply = router.handle_message(&admin, &Message::plain("/containers"), None).await.unwrap();
+        assert!(!reply.contains("mock-wx_bob"), "{}", reply);
+    }
+
+    #[tokio::test]
+    async fn e2e_blocked_and_unprivileged_users() {
+        let (router, db) = mock_router();
+        let eve = contact("wx_eve", "Eve");
+
+        router.handle_message(&eve, &Message::plain("hi"), None).await.unwrap();
+        let reply = router.handle_message(&eve, &Message::plain("/list"), None).await.unwrap();
+        assert_eq!(reply, "⚠️ 权限不足");
+
+        db.friend_set_permission("wx_eve", "blocked").unwrap();
+        assert!(router.handle_message(&eve, &Message::plain("hi"), None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn e2e_stream_receives_partial_reply() {
+        let (router, _db) = mock_router();
+        let admin = contact(ADMIN, "Boss");
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let reply = router.handle_message(&admin, &Message::plain("stream me"), Some(tx)).await.unwrap();
+        assert_eq!(reply, "[mock] stream me");
+        match rx.recv().await {
+            Some(ReplyUpdate::Partial(text)) => assert_eq!(text, "[mock] stream me"),
+            other => panic!("unexpected update: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn e2e_attachments_are_saved_to_inbox() {
+        crate::config::init_test_config();
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(MockBackend::new());
+        let executor = Arc::new(ClaudeExecutor::new(backend.clone(), Arc::clone(&db), 60, 120));
+        let router = MessageRouter::new(Arc::clone(&db), executor, ADMIN.into());
+        let admin = contact(ADMIN, "Boss");
+
+        let message = Message {
+            id: "42".into(),
+            text: "what is this?".into(),
+            attachments: vec![Attachment {
+                kind: AttachmentKind::Image,
+                file_name: "../photo.jpg".into(),
+                data: b"jpeg".to_vec(),
+            }],
+            reply_to: Some(Quote { id: "41".into(), text: "earlier".into() }),
+        };
+        let reply = router.handle_message(&admin, &message, None).await.unwrap();
+        assert!(reply.starts_with("[mock] [Replying to: earlier]\nwhat is this?\n"), "{}", reply);
+
+        let path = reply
+            .lines()
+            .find_map(|l| l.strip_prefix("[Attached image: ")?.strip_suffix(']'))
+            .unwrap();
+        assert!(path.starts_with("inbox/") && path.ends_with("-photo.jpg"), "{}", path);
+        assert_eq!(backend.file(ADMIN, path).unwrap(), b"jpeg");
+
+        let logs = db.audit_get_by_user(ADMIN, 10).unwrap();
+        assert!(logs.iter().any(|l| l.message.as_deref() == Some("what is this? [图片: ../photo.jpg]")));
+    }
+
+    #[tokio::test]
+    async fn e2e_outbox_files_are_sent_by_type() {
+        crate::config::init_test_config();
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(MockBackend::new());
+        let policy = OutboxPolicy {
+            max_bytes: 1024 * 1024,
+            max_files: 10,
+            admin: vec!["*".into()],
+            trusted: vec!["png".into(), "csv".into()],
+            normal: vec!["png".into()],
+        };
+        let executor = Arc::new(
+            ClaudeExecutor::new(backend.clone(), Arc::clone(&db), 60, 120).with_outbox(policy),
+        );
+        let router = MessageRouter::new(Arc::clone(&db), executor, ADMIN.into());
+        let bob = contact("wx_bob", "Bob");
+        router.handle_message(&bob, &Message::plain("hi"), None).await.unwrap();
+        db.friend_set_permission("wx_bob", "trusted").unwrap();
+
+        backend.write_on_next_run("outbox/chart.png", b"png");
+        backend.write_on_next_run("outbox/tool.exe", b"exe");
+        backend.write_on_next_run("outbox/huge.csv", &vec![0; 2 * 1024 * 1024]);
+        backend.write_on_next_run("notes/draft.txt", b"not in the outbox");
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let reply = router
+            .handle_message(&bob, &Message::plain("make a chart"), Some(tx))
+            .await
+            .unwrap();
+
+        let mut files = Vec::new();
+        while let Some(update) = rx.recv().await {
+            if let ReplyUpdate::File(file) = update {
+                files.push(file);
+            }
+        }
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].file_name, "chart.png");
+        assert_eq!(files[0].kind, AttachmentKind::Image);
+        assert_eq!(files[0].data, b"png");
+        assert!(reply.contains("huge.csv (larger than 1 MB)"), "{}", reply);
+        assert!(reply.contains("tool.exe (file type not allowed)"), "{}", reply);
+
+        // Files from earlier runs are not sent again
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let reply = router.handle_message(&bob, &Message::plain("thanks"), Some(tx)).await.unwrap();
+        assert_eq!(reply, "[mock] thanks (resumed mock-session-wx_bob)");
+        while let Some(update) = rx.recv().await {
+            assert!(!matches!(update, ReplyUpdate::File(_)));
+        }
+    }
+
+    #[test]
+    fn workspace_path_stays_inside_the_workspace() {
+        assert_eq!(workspace_path("").as_deref(), Some(""));
+        assert_eq!(workspace_path("./docs//a.txt ").as_deref(), Some("docs/a.txt"));
+        assert_eq!(workspace_path("/home/sandbox/workspace").as_deref(), Some(""));
+        assert_eq!(workspace_path("/home/sandbox/workspace/out/x.png").as_deref(), Some("out/x.png"));
+        assert_eq!(workspace_path("../secret"), None);
+        assert_eq!(workspace_path("docs/../../x"), None);
+        assert_eq!(workspace_path("/etc/passwd"), None);
+        assert_eq!(workspace_path("/home/sandbox/workspace2/x"), None);
+    }
+
+    #[tokio::test]
+    async fn e2e_workspace_file_commands() {
+        crate::config::init_test_config();
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(MockBackend::new());
+        let policy = OutboxPolicy {
+            max_bytes: 1024,
+            max_files: 10,
+            admin: vec!["*".into()],
+            trusted: vec!["txt".into(), "png".into()],
+            normal: vec![],
+        };
+        let executor = Arc::new(
+            ClaudeExecutor::new(backend.clone(), Arc::clone(&db), 60, 120).with_outbox(policy),
+        );
+        let router = MessageRouter::new(Arc::clone(&db), executor, ADMIN.into());
+        let bob = contact("wx_bob", "Bob");
+        router.handle_message(&bob, &Message::plain("hi"), None).await.unwrap();
+        let reply = router.handle_message(&bob, &Message::plain("/ls"), None).await.unwrap();
+        assert_eq!(reply, "⚠️ 权限不足");
+        db.friend_set_permission("wx_bob", "trusted").unwrap();
+
+        // /put then the file
+        let reply = router.handle_message(&bob, &Message::plain("/put docs/"), None).await.unwrap();
+        assert!(reply.contains("docs"), "{}", reply);
+        let upload = Message {
+            attachments: vec![Attachment {
+                kind: AttachmentKind::File,
+                file_name: "notes.txt".into(),
+                data: b"hello".to_vec(),
+            }],
+            ..Message::plain("")
+        };
+        let reply = router.handle_message(&bob, &upload, None).await.unwrap();
+        assert_eq!(reply, "✅ 已保存到 docs/notes.txt (5B)");
+        assert_eq!(backend.file("wx_bob", "docs/notes.txt").unwrap(), b"hello");
+
+        // With the file attached to the command itself, and an explicit name
+        let upload = Message { text: "/put big.bin".into(), ..upload };
+        let reply = router.handle_message(&bob, &upload, None).await.unwrap();
+        assert_eq!(reply, "✅ 已保存到 big.bin (5B)");
+
+        let reply = router.handle_message(&bob, &Message::plain("/ls"), None).await.unwrap();
+        assert!(reply.contains("📄 big.bin (5B)"), "{}", reply);
+        assert!(reply.contains("📁 docs/"), "{}", reply);
+
+        // /get sends the file through the reply stream, within the outbox policy
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let reply = router
+            .handle_message(&bob, &Message::plain("/get /home/sandbox/workspace/docs/notes.txt"), Some(tx))
+            .await
+            .unwrap();
+        assert!(reply.contains("docs/notes.txt"), "{}", reply);
+        match rx.recv().await {
+            Some(ReplyUpdate::File(file)) => {
+                assert_eq!(file.file_name, "notes.txt");
+                assert_eq!(file.data, b"hello");
+            }
+            other => panic!("unexpected update: {:?}", other),
+        }
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let reply = router.handle_message(&bob, &Message::plain("/get big.bin"), Some(tx)).await.unwrap();
+        assert!(reply.contains("不允许"), "{}", reply);
+
+        // Nothing outside the workspace (as admin, who has their own rate limit)
+        let admin = contact(ADMIN, "Boss");
+        for cmd in ["/ls ..", "/get ../../etc/passwd", "/put /etc/cron.d/x", "/rm docs/../.."] {
+            let reply = router.handle_message(&admin, &Message::plain(cmd), None).await.unwrap();
+            assert_eq!(reply, "⚠️ 路径不能超出工作区", "{}", cmd);
+        }
+
+        let reply = router.handle_message(&bob, &Message::plain("/rm docs"), None).await.unwrap();
+        assert_eq!(reply, "🗑️ 已删除 docs");
+        assert!(backend.file("wx_bob", "docs/notes.txt").is_none());
+        let reply = router.handle_message(&bob, &Message::plain("/rm docs"), None).await.unwrap();
+        assert!(reply.starts_with("❌"), "{}", reply);
+
+        let ops: Vec<String> = db
+            .audit_get_by_user("wx_bob", 50)
+            .unwrap()
+            .into_iter()
+            .filter_map(|l| Some(l.message?.strip_prefix("[文件操作] ")?.to_string()))
+            .collect();
+        for op in ["put docs/notes.txt (5B)", "put big.bin (5B)", "ls 工作区", "get docs/notes.txt (5B)", "rm docs"] {
+            assert!(ops.iter().any(|o| o == op), "{} not in {:?}", op, ops);
+        }
+    }
+
+    #[tokio::test]
+    async fn e2e_get_works_without_outbox() {
+        crate::config::init_test_config();
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(MockBackend::new());
+        let executor = Arc::new(ClaudeExecutor::new(backend.clone(), Arc::clone(&db), 60, 120));
+        assert!(executor.outbox_policy().is_none());
+        let router = MessageRouter::new(Arc::clone(&db), executor, ADMIN.into());
+        let bob = contact("wx_bob", "Bob");
+        router.handle_message(&bob, &Message::plain("hi"), None).await.unwrap();
+        db.friend_set_permission("wx_bob", "trusted").unwrap();
+        backend.write_file("wx_bob", "report.pdf", b"%PDF").await.unwrap();
+        backend.write_file("wx_bob", "run.sh", b"echo").await.unwrap();
+
+        // Falls back to the configured outbox limits
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let reply = router.handle_message(&bob, &Message::plain("/get report.pdf"), Some(tx)).await.unwrap();
+        assert!(reply.starts_with("📤"), "{}", reply);
+        match rx.recv().await {
+            Some(ReplyUpdate::File(file)) => assert_eq!(file.file_name, "report.pdf"),
+            other => panic!("unexpected update: {:?}", other),
+        }
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let reply = router.handle_message(&bob, &Message::plain("/get run.sh"), Some(tx)).await.unwrap();
+        assert!(reply.contains("不允许"), "{}", reply);
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let reply = router.handle_message(&bob, &Message::plain("/get missing.pdf"), Some(tx)).await.unwrap();
+        assert_eq!(reply, "❌ 无法读取 missing.pdf");
+
+        // The size is checked before the file is read
+        backend.write_file("wx_bob", "docs/huge.pdf", &vec![0; 21 * 1024 * 1024]).await.unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let reply = router.handle_message(&bob, &Message::plain("/get docs/huge.pdf"), Some(tx)).await.unwrap();
+        assert!(reply.starts_with("⚠️ 文件太大"), "{}", reply);
+        assert!(rx.try_recv().is_err());
+
+        // Still needs somewhere to send the file
+        let reply = router.handle_message(&bob, &Message::plain("/get report.pdf"), None).await.unwrap();
+        assert_eq!(reply, "⚠️ 当前不支持发送文件");
+    }
+
+    #[tokio::test]
+    async fn e2e_workspace_quota_blocks_runs_and_uploads() {
+        crate::config::init_test_config();
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(MockBackend::new());
+        let quota = WorkspaceQuota { admin: 0, trusted: 10, normal: 5 };
+        let executor = Arc::new(
+            ClaudeExecutor::new(backend.clone(), Arc::clone(&db), 60, 120)
+                .with_workspace_quota(quota),
+        );
+        let router = MessageRouter::new(Arc::clone(&db), executor, ADMIN.into());
+        let bob = contact("wx_bob", "Bob");
+        router.handle_message(&bob, &Message::plain("hi"), None).await.unwrap();
+        db.friend_set_permission("wx_bob", "trusted").unwrap();
+
+        let upload = |text: &str, size: usize| Message {
+            attachments: vec![Attachment {
+                kind: AttachmentKind::File,
+                file_name: "data.bin".into(),
+                data: vec![0; size],
+            }],
+            ..Message::plain(text)
+        };
+        let reply = router.handle_message(&bob, &upload("/put a.bin", 8), None).await.unwrap();
+        assert!(reply.starts_with("✅"), "{}", reply);
+        let reply = router.handle_message(&bob, &upload("/put b.bin", 8), None).await.unwrap();
+        assert_eq!(reply, "⚠️ 工作区空间不足 (8B / 10B)，请先用 /rm 删除文件");
+        assert!(backend.file("wx_bob", "b.bin").is_none());
+
+        // Claude's own writes are picked up by the next scan that matters
+        backend.write_on_next_run("out.txt", b"12345");
+        let reply = router.handle_message(&bob, &Message::plain("write"), None).await.unwrap();
+        assert!(reply.starts_with("[mock] write"), "{}", reply);
+        let reply = router.handle_message(&bob, &upload("look", 3), None).await.unwrap();
+        assert_eq!(reply, "⚠️ 工作区空间不足 (13B / 10B)，请先用 /rm 删除文件");
+        let status = router.handle_message(&bob, &Message::plain("/status"), None).await.unwrap();
+        assert!(status.contains("磁盘: 13B / 10B"), "{}", status);
+
+        // Deleting files frees space at once
+        router.handle_message(&bob, &Message::plain("/rm a.bin"), None).await.unwrap();
+        let reply = router.handle_message(&bob, &Message::plain("again"), None).await.unwrap();
+        assert!(reply.starts_with("[mock] again"), "{}", reply);
+    }
+
+    #[tokio::test]
+    async fn e2e_snapshot_and_restore_workspace() {
+        crate::config::init_test_config();
+        let dir = std::env::temp_dir().join(format!("wcb-router-snap-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(HostBackend::new("/nonexistent/claude-cli".into(), dir.clone()));
+        let policy = SnapshotPolicy { keep_manual: 5, keep_auto: 2, auto_trusted: true, ..Default::default() };
+        let executor = Arc::new(
+            ClaudeExecutor::new(backend, Arc::clone(&db), 60, 120).with_snapshots(policy),
+        );
+        let router = MessageRouter::new(Arc::clone(&db), executor, ADMIN.into());
+        let bob = contact("wx_bob", "Bob");
+        let admin = contact(ADMIN, "Boss");
+        let notes = dir.join("wx_bob/workspace/notes.txt");
+        let put = |text: &str, data: &[u8]| Message {
+            attachments: vec![Attachment {
+                kind: AttachmentKind::File,
+                file_name: "notes.txt".into(),
+                data: data.to_vec(),
+            }],
+            ..Message::plain(text)
+        };
+
+        router.handle_message(&bob, &Message::plain("/snapshots"), None).await.unwrap();
+        db.friend_set_permission("wx_bob", "trusted").unwrap();
+        router.handle_message(&bob, &put("/put notes.txt", b"good"), None).await.unwrap();
+        let reply = router.handle_message(&bob, &Message::plain("/snapshot v1"), None).await.unwrap();
+        assert!(reply.starts_with("📸 已保存快照 v1"), "{}", reply);
+
+        router.handle_message(&bob, &put("/put notes.txt", b"wrecked"), None).await.unwrap();
+        assert_eq!(std::fs::read(&notes).unwrap(), b"wrecked");
+        let reply = router.handle_message(&bob, &Message::plain("/restore v1"), None).await.unwrap();
+        assert_eq!(reply, "✅ 工作区已恢复到快照 v1");
+        assert_eq!(std::fs::read(&notes).unwrap(), b"good");
+        let reply = router.handle_message(&bob, &Message::plain("/restore v2"), None).await.unwrap();
+        assert_eq!(reply, "❌ 未找到快照 \"v2\"");
+
+        // Trusted runs take an automatic snapshot first (the CLI itself fails here)
+        router.handle_message(&bob, &Message::plain("do something"), None).await.unwrap();
+        let reply = router.handle_message(&bob, &Message::plain("/snapshots"), None).await.unwrap();
+        assert!(reply.contains("📸 快照 (2)"), "{}", reply);
+        assert!(reply.contains("• v1 - "), "{}", reply);
+        assert!(reply.contains("(自动)"), "{}", reply);
+
+        for name in ["auto-1", "bad/name"] {
+            let reply = router
+                .handle_message(&admin, &Message::plain(format!("/snapshot {}", name)), None)
+                .await
+                .unwrap();
+            assert!(reply.starts_with("⚠️"), "{}", reply);
+        }
+        let logs = db.audit_get_by_user("wx_bob", 50).unwrap();
+        assert!(logs.iter().any(|l| l.message.as_deref() == Some("[文件操作] restore v1")));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn e2e_snapshots_count_against_workspace_quota() {
+        crate::config::init_test_config();
+        let dir = std::env::temp_dir().join(format!("wcb-router-snap-quota-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(HostBackend::new("/nonexistent/claude-cli".into(), dir.clone()));
+        let policy = SnapshotPolicy { keep_manual: 5, keep_auto: 2, auto_trusted: true, ..Default::default() };
+        let executor = Arc::new(
+            ClaudeExecutor::new(backend, Arc::clone(&db), 60, 120)
+                .with_snapshots(policy)
+                .with_workspace_quota(WorkspaceQuota { admin: 0, trusted: 4096, normal: 0 }),
+        );
+        let router = MessageRouter::new(Arc::clone(&db), executor, ADMIN.into());
+        let bob = contact("wx_bob", "Bob");
+        router.handle_message(&bob, &Message::plain("hi"), None).await.unwrap();
+        db.friend_set_permission("wx_bob", "trusted").unwrap();
+        let upload = Message {
+            attachments: vec![Attachment {
+                kind: AttachmentKind::File,
+                file_name: "notes.txt".into(),
+                data: vec![b'x'; 1000],
+            }],
+            ..Message::plain("/put notes.txt")
+        };
+        router.handle_message(&bob, &upload, None).await.unwrap();
+
+        // Room for one archive next to the workspace, not a second
+        let reply = router.handle_message(&bob, &Message::plain("/snapshot v1"), None).await.unwrap();
+        assert!(reply.starts_with("📸 已保存快照 v1"), "{}", reply);
+        let reply = router.handle_message(&bob, &Message::plain("/snapshot v2"), None).await.unwrap();
+        assert!(reply.starts_with("⚠️ 快照会超出工作区空间"), "{}", reply);
+
+        // The automatic snapshot before a run is skipped, the run still happens
+        router.handle_message(&bob, &Message::plain("do something"), None).await.unwrap();
+        let reply = router.handle_message(&bob, &Message::plain("/snapshots"), None).await.unwrap();
+        assert!(reply.contains("📸 快照 (1)"), "{}", reply);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn e2e_waiting_user_is_told_queue_position() {
+        crate::config::init_test_config();
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let scheduler = Arc::new(Scheduler::new(1, [4, 2, 1]));
+        let executor = Arc::new(
+            ClaudeExecutor::new(Arc::new(MockBackend::new()), Arc::clone(&db), 60, 120)
+                .with_scheduler(Arc::clone(&scheduler)),
+        );
+        let router = Arc::new(MessageRouter::new(Arc::clone(&db), executor, ADMIN.into()));
+        let bob = contact("wx_bob", "Bob");
+
+        let busy = scheduler.acquire("wx_busy", Permission::Admin, |_| {}).await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let task = {
+            let router = Arc::clone(&router);
+            let bob = bob.clone();
+            tokio::spawn(async move { router.handle_message(&bob, &Message::plain("hi"), Some(tx)).await })
+        };
+        assert_eq!(rx.recv().await, Some(ReplyUpdate::Queued(1)));
+
+        let status = router.handle_message(&bob, &Message::plain("/status"), None).await.unwrap();
+        assert!(status.contains("运行中 1/1，等待 1"), "{}", status);
+        assert!(status.contains("你排在第 1 位"), "{}", status);
+
+        drop(busy);
+        assert_eq!(task.await.unwrap().as_deref(), Some("[mock] hi"));
+        let status = router.handle_message(&bob, &Message::plain("/status"), None).await.unwrap();
+        assert!(status.contains("运行中 0/1，等待 0"), "{}", status);
+        assert!(!status.contains("你排在"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn e2e_audit_verify() {
+        let (router, db) = mock_router();
+        let admin = contact(ADMIN, "Boss");
+
+        router.handle_message(&admin, &Message::plain("hello"), None).await.unwrap();
+        let reply = router.handle_message(&admin, &Message::plain("/audit verify"), None).await.unwrap();
+        // hello, its reply and the command itself
+        assert!(reply.starts_with("🔗 审计链完好: 3 条记录"), "{}", reply);
+        assert!(reply.ends_with("🔏 还没有检查点"), "{}", reply);
+        // ... and then its reply
+        assert_eq!(db.audit_head().unwrap().map(|(id, _)| id), Some(4));
+
+        let reply = router.handle_message(&admin, &Message::plain("/audit"), None).await.unwrap();
+        assert!(reply.starts_with("用法"), "{}", reply);
+    }
+
+    #[tokio::test]
+    async fn e2e_search_logs() {
+        let (router, db) = mock_router();
+        let admin = contact(ADMIN, "Boss");
+        let bob = contact("wx_bob", "Bob");
+        db.friend_upsert("wx_bob", Some("Bob"), None, None, None, None).unwrap();
+
+        for i in 0..12 {
+            db.audit_log("wx_bob", Some("Bob"), "in", Some(&format!("docker question {}", i)), None)
+                .unwrap();
+        }
+        db.audit_log("wx_carol", Some("Carol"), "in", Some("my docker broke"), None)
+            .unwrap();
+
+        let reply = router.handle_message(&admin, &Message::plain("/search docker"), None).await.unwrap();
+        assert!(reply.starts_with("🔍 \"docker\" 共 13 条（第 1/2 页）"), "{}", reply);
+        assert!(reply.contains("Carol: my 【docker】 broke"), "{}", reply);
+        assert!(reply.contains("下一页: /search docker p2"), "{}", reply);
+
+        let reply = router.handle_message(&admin, &Message::plain("/search docker Bob p2"), None).await.unwrap();
+        assert!(reply.contains("共 12 条（第 2/2 页）"), "{}", reply);
+        assert!(reply.contains("【docker】 question 0"), "{}", reply);
+        assert!(!reply.contains("Carol"), "{}", reply);
+        assert!(!reply.contains("下一页"), "{}", reply);
+
+        let reply = router.handle_message(&admin, &Message::plain("/search kubernetes"), None).await.unwrap();
+        assert!(reply.contains("没有找到"), "{}", reply);
+        let reply = router.handle_message(&admin, &Message::plain("/search docker Nobody"), None).await.unwrap();
+        assert!(reply.contains("未找到"), "{}", reply);
+
+        // Admins only
+        let reply = router.handle_message(&bob, &Message::plain("/search docker"), None).await.unwrap();
+        assert!(!reply.contains("🔍"), "{}", reply);
+    }
+
+    #[tokio::test]
+    async fn e2e_group_chats_need_authorizing_and_use_their_own_session() {
+        let (router, db) = mock_router();
+        let admin = contact(ADMIN, "Boss");
+        let group = Group {
+            id: "-100777".into(),
+            name: "Dev Team".into(),
+        };
+        let member = |wxid: &str, nickname: &str| Contact {
+            group: Some(group.clone()),
+            ..contact(wxid, nickname)
+        };
+        let (alice, mallory) = (member("wx_alice", "Alice"), member("wx_mallory", "Mallory"));
+
+        // New groups are registered blocked and ignored without a reply
+        assert!(router.handle_message(&alice, &Message::plain("hi bot"), None).await.is_none());
+        let row = db.friend_get("-100777").unwrap().unwrap();
+        assert_eq!((row.nickname.as_deref(), row.permission.as_str()), (Some("Dev Team"), "blocked"));
+        let reply = router.handle_message(&admin, &Message::plain("/list"), None).await.unwrap();
+        assert!(reply.contains("Dev Team [群]"), "{}", reply);
+
+        let reply = router
+            .handle_message(&admin, &Message::plain("/allow Dev trusted"), None)
+            .await
+            .unwrap();
+        assert!(reply.contains("Dev Team"), "{}", reply);
+
+        // Claude runs in the group's conversation and knows who is talking
+        let reply = router.handle_message(&alice, &Message::plain("hello"), None).await.unwrap();
+        assert!(reply.contains("[Group message from Alice]"), "{}", reply);
+        assert!(db.session_get_active("-100777").unwrap().is_some());
+        assert!(db.session_get_active("wx_alice").unwrap().is_none());
+        assert_eq!(db.audit_get_by_user("-100777", 10).unwrap().len(), 3);
+
+        // Members keep their own level: Alice is only normal, so no file commands
+        let reply = router.handle_message(&alice, &Message::plain("/ls"), None).await.unwrap();
+        assert_eq!(reply, "⚠️ 权限不足");
+        db.friend_set_permission("wx_alice", "trusted").unwrap();
+        let reply = router.handle_message(&alice, &Message::plain("/ls"), None).await.unwrap();
+        assert_ne!(reply, "⚠️ 权限不足");
+
+        // A blocked member is ignored in an authorized group
+        router.handle_message(&mallory, &Message::plain("hi"), None).await.unwrap();
+        db.friend_set_permission("wx_mallory", "blocked").unwrap();
+        assert!(router.handle_message(&mallory, &Message::plain("hi"), None).await.is_none());
+
+        // Renamed groups are kept up to date
+        let renamed = Contact {
+            group: Some(Group {
+                name: "Platform Team".into(),
+                ..group.clone()
+            }),
+            ..contact("wx_alice", "Alice")
+        };
+        router.handle_message(&renamed, &Message::plain("/help"), None).await.unwrap();
+        let row = db.friend_get("-100777").unwrap().unwrap();
+        assert_eq!((row.nickname.as_deref(), row.permission.as_str()), (Some("Platform Team"), "trusted"));
+    }
+
+    #[tokio::test]
+    async fn e2e_group_runs_use_the_senders_effective_level() {
+        crate::config::init_test_config();
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(MockBackend::new());
+        let executor = Arc::new(ClaudeExecutor::new(backend.clone(), Arc::clone(&db), 60, 120));
+        let router = MessageRouter::new(Arc::clone(&db), executor, ADMIN.into());
+        let group = Group {
+            id: "-100888".into(),
+            name: "Ops".into(),
+        };
+        let member = |wxid: &str, nickname: &str| Contact {
+            group: Some(group.clone()),
+            ..contact(wxid, nickname)
+        };
+        let (alice, bob) = (member("wx_alice", "Alice"), member("wx_bob", "Bob"));
+
+        router.handle_message(&alice, &Message::plain("hi"), None).await;
+        router.handle_message(&bob, &Message::plain("hi"), None).await;
+        db.friend_set_permission("-100888", "trusted").unwrap();
+        db.friend_set_permission("wx_bob", "trusted").unwrap();
+
+        // A normal member of a trusted group runs with normal tools
+        let reply = router.handle_message(&alice, &Message::plain("hello"), None).await.unwrap();
+        assert!(reply.starts_with("[mock]"), "{}", reply);
+        assert_eq!(backend.run_permission("-100888"), Some(Permission::Normal));
+
+        // A trusted member gets the group's level
+        router.handle_message(&bob, &Message::plain("hello"), None).await.unwrap();
+        assert_eq!(backend.run_permission("-100888"), Some(Permission::Trusted));
+    }
+
+    #[tokio::test]
+    async fn e2e_group_members_are_audited_and_put_files_separately() {
+        crate::config::init_test_config();
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(MockBackend::new());
+        let executor = Arc::new(ClaudeExecutor::new(backend.clone(), Arc::clone(&db), 60, 120));
+        let router = MessageRouter::new(Arc::clone(&db), executor, ADMIN.into());
+        let group = Group {
+            id: "-100999".into(),
+            name: "Infra".into(),
+        };
+        let member = |wxid: &str, nickname: &str| Contact {
+            group: Some(group.clone()),
+            ..contact(wxid, nickname)
+        };
+        let (alice, bob) = (member("wx_alice", "Alice"), member("wx_bob", "Bob"));
+        router.handle_message(&alice, &Message::plain("hi"), None).await;
+        router.handle_message(&bob, &Message::plain("hi"), None).await;
+        for wxid in ["-100999", "wx_alice", "wx_bob"] {
+            db.friend_set_permission(wxid, "trusted").unwrap();
+        }
+        let upload = |name: &str| Message {
+            attachments: vec![Attachment {
+                kind: AttachmentKind::File,
+                file_name: name.into(),
+                data: b"data".to_vec(),
+            }],
+            ..Message::plain("")
+        };
+
+        // Alice's /put waits for Alice's file, not the next one in the group
+        let reply = router.handle_message(&alice, &Message::plain("/put notes/"), None).await.unwrap();
+        assert!(reply.starts_with("📥"), "{}", reply);
+        let reply = router.handle_message(&bob, &upload("bob.txt"), None).await.unwrap();
+        assert!(reply.starts_with("[mock]"), "{}", reply);
+        assert!(backend.file("-100999", "notes/bob.txt").is_none());
+        let reply = router.handle_message(&alice, &upload("alice.txt"), None).await.unwrap();
+        assert_eq!(reply, "✅ 已保存到 notes/alice.txt (4B)");
+
+        // Audit entries name the member as well as the group
+        let alice_logs = db.audit_get_by_user("wx_alice", 50).unwrap();
+        assert!(alice_logs.iter().all(|l| l.wxid == "-100999" && l.sender.as_deref() == Some("wx_alice")));
+        assert!(alice_logs.iter().any(|l| l.message.as_deref() == Some("/put notes/")));
+        let (hits, _) = db.audit_search("bob.txt", Some("wx_bob"), None, 10, 0).unwrap();
+        assert!(!hits.is_empty());
+        assert!(hits.iter().all(|h| h.entry.sender.as_deref() == Some("wx_bob")));
+        let report = db.audit_verify().unwrap();
+        assert!(report.broken.is_none(), "{:?}", report.broken);
+    }
 }
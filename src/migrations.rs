@@ -0,0 +1,361 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use tracing::info;
+
+use crate::audit_chain;
+
+/// One schema change. Migrations run in order of `version`, each in its own
+/// transaction, and the database's `PRAGMA user_version` records the last
+/// one applied.
+///
+/// Never edit a migration that has shipped; add a new one instead.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+    /// Runs after `sql` in the same transaction, for data changes SQL
+    /// can't express.
+    pub after: Option<fn(&Connection) -> rusqlite::Result<()>>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        // Databases created before migrations existed already have these
+        // tables at user_version 0, hence IF NOT EXISTS
+        sql: "
+            -- Friends / authorization table
+            CREATE TABLE IF NOT EXISTS friends (
+                wxid           TEXT PRIMARY KEY,
+                nickname       TEXT,
+                remark_name    TEXT,
+                permission     TEXT NOT NULL DEFAULT 'normal'
+                               CHECK(permission IN ('admin','trusted','normal','blocked')),
+                added_at       DATETIME DEFAULT CURRENT_TIMESTAMP,
+                added_by       TEXT,
+                notes          TEXT
+            );
+
+            -- Sessions table (one active session per friend)
+            CREATE TABLE IF NOT EXISTS sessions (
+                id             TEXT PRIMARY KEY,
+                wxid           TEXT NOT NULL,
+                claude_session TEXT,
+                created_at     DATETIME DEFAULT CURRENT_TIMESTAMP,
+                last_active    DATETIME DEFAULT CURRENT_TIMESTAMP,
+                message_count  INTEGER DEFAULT 0,
+                FOREIGN KEY (wxid) REFERENCES friends(wxid)
+            );
+
+            -- Message audit log
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id             INTEGER PRIMARY KEY AUTOINCREMENT,
+                wxid           TEXT NOT NULL,
+                nickname       TEXT,
+                direction      TEXT NOT NULL CHECK(direction IN ('in','out')),
+                message        TEXT,
+                claude_session TEXT,
+                timestamp      DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+
+            -- Rate limit tracking
+            CREATE TABLE IF NOT EXISTS rate_limits (
+                wxid           TEXT NOT NULL,
+                window_start   DATETIME NOT NULL,
+                request_count  INTEGER DEFAULT 1,
+                PRIMARY KEY (wxid, window_start)
+            );
+
+            -- Token / cost accounting (one row per Claude run)
+            CREATE TABLE IF NOT EXISTS usage (
+                id                    INTEGER PRIMARY KEY AUTOINCREMENT,
+                wxid                  TEXT NOT NULL,
+                session_id            TEXT,
+                claude_session        TEXT,
+                input_tokens          INTEGER NOT NULL DEFAULT 0,
+                output_tokens         INTEGER NOT NULL DEFAULT 0,
+                cache_creation_tokens INTEGER NOT NULL DEFAULT 0,
+                cache_read_tokens     INTEGER NOT NULL DEFAULT 0,
+                cost_usd              REAL NOT NULL DEFAULT 0,
+                timestamp             DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+
+            -- Indexes
+            CREATE INDEX IF NOT EXISTS idx_audit_wxid ON audit_log(wxid);
+            CREATE INDEX IF NOT EXISTS idx_audit_ts   ON audit_log(timestamp);
+            CREATE INDEX IF NOT EXISTS idx_sessions_wxid ON sessions(wxid);
+            CREATE INDEX IF NOT EXISTS idx_rate_wxid  ON rate_limits(wxid);
+            CREATE INDEX IF NOT EXISTS idx_usage_wxid_ts ON usage(wxid, timestamp);
+            CREATE INDEX IF NOT EXISTS idx_usage_session ON usage(session_id);
+        ",
+        after: None,
+    },
+    Migration {
+        version: 2,
+        description: "friends.kind distinguishes group chats from users",
+        sql: "
+            ALTER TABLE friends ADD COLUMN kind TEXT NOT NULL DEFAULT 'user'
+                CHECK(kind IN ('user','group'));
+            -- Group rows were marked with a note before this column existed
+            UPDATE friends SET kind = 'group', notes = NULL WHERE notes = 'group';
+        ",
+        after: None,
+    },
+    Migration {
+        version: 3,
+        description: "full-text index over audit_log messages",
+        // Trigram tokens match any substring of 3+ characters, which also
+        // works for Chinese text that has no spaces between words
+        sql: "
+            CREATE VIRTUAL TABLE audit_fts USING fts5(
+                message, content='audit_log', content_rowid='id', tokenize='trigram'
+            );
+            CREATE TRIGGER audit_fts_insert AFTER INSERT ON audit_log BEGIN
+                INSERT INTO audit_fts(rowid, message) VALUES (new.id, new.message);
+            END;
+            CREATE TRIGGER audit_fts_delete AFTER DELETE ON audit_log BEGIN
+                INSERT INTO audit_fts(audit_fts, rowid, message) VALUES ('delete', old.id, old.message);
+            END;
+            CREATE TRIGGER audit_fts_update AFTER UPDATE OF message ON audit_log BEGIN
+                INSERT INTO audit_fts(audit_fts, rowid, message) VALUES ('delete', old.id, old.message);
+                INSERT INTO audit_fts(rowid, message) VALUES (new.id, new.message);
+            END;
+            INSERT INTO audit_fts(audit_fts) VALUES ('rebuild');
+        ",
+        after: None,
+    },
+    Migration {
+        version: 4,
+        description: "hash chain over audit_log",
+        sql: "
+            ALTER TABLE audit_log ADD COLUMN message_hash TEXT;
+            ALTER TABLE audit_log ADD COLUMN prev_hash TEXT;
+            ALTER TABLE audit_log ADD COLUMN hash TEXT;
+            -- Links of pruned entries, so the chain still verifies across them
+            CREATE TABLE audit_pruned (
+                id        INTEGER PRIMARY KEY,
+                prev_hash TEXT,
+                hash      TEXT
+            );
+        ",
+        after: Some(audit_chain::seal_unhashed),
+    },
+    Migration {
+        version: 5,
+        description: "audit_log.sender records the member who wrote in a group chat",
+        sql: "
+            ALTER TABLE audit_log ADD COLUMN sender TEXT;
+            CREATE INDEX IF NOT EXISTS idx_audit_sender ON audit_log(sender);
+        ",
+        after: None,
+    },
+];
+
+/// Schema version this build expects.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+/// Migrations not yet applied to the database, oldest first.
+fn pending(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let current = current_version(conn)?;
+    if current > latest_version() {
+        anyhow::bail!(
+            "Database schema version {} is newer than this build supports ({})",
+            current,
+            latest_version()
+        );
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+}
+
+fn apply(conn: &Connection, migration: &Migration) -> Result<()> {
+    (|| {
+        conn.execute_batch(migration.sql)?;
+        migration.after.map_or(Ok(()), |after| after(conn))
+    })()
+    .with_context(|| format!("Migration {} ({}) failed", migration.version, migration.description))
+}
+
+/// Apply pending migrations. Each runs in its own transaction together with
+/// the `user_version` bump, so a failed migration leaves the database at the
+/// previous version. Returns the versions applied.
+pub fn migrate(conn: &mut Connection) -> Result<Vec<u32>> {
+    let mut applied = Vec::new();
+    for migration in pending(conn)? {
+        let tx = conn.transaction()?;
+        apply(&tx, migration)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        info!("Applied database migration {}: {}", migration.version, migration.description);
+        applied.push(migration.version);
+    }
+    Ok(applied)
+}
+
+/// Run pending migrations and roll each back, to check they apply cleanly
+/// without changing the database. Returns the migrations that would run.
+pub fn dry_run(conn: &mut Connection) -> Result<Vec<&'static Migration>> {
+    let pending = pending(conn)?;
+    // Later migrations may depend on earlier ones, so roll back only at the end
+    let tx = conn.transaction()?;
+    for migration in &pending {
+        apply(&tx, migration)?;
+    }
+    tx.rollback()?;
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The schema as deployed before migrations existed (user_version 0).
+    const UNVERSIONED_SCHEMA: &str = "
+        CREATE TABLE friends (
+            wxid TEXT PRIMARY KEY, nickname TEXT, remark_name TEXT,
+            permission TEXT NOT NULL DEFAULT 'normal'
+                CHECK(permission IN ('admin','trusted','normal','blocked')),
+            added_at DATETIME DEFAULT CURRENT_TIMESTAMP, added_by TEXT, notes TEXT
+        );
+        CREATE TABLE sessions (
+            id TEXT PRIMARY KEY, wxid TEXT NOT NULL, claude_session TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            last_active DATETIME DEFAULT CURRENT_TIMESTAMP,
+            message_count INTEGER DEFAULT 0,
+            FOREIGN KEY (wxid) REFERENCES friends(wxid)
+        );
+        CREATE TABLE audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT, wxid TEXT NOT NULL, nickname TEXT,
+            direction TEXT NOT NULL CHECK(direction IN ('in','out')),
+            message TEXT, claude_session TEXT, timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE TABLE rate_limits (
+            wxid TEXT NOT NULL, window_start DATETIME NOT NULL,
+            request_count INTEGER DEFAULT 1, PRIMARY KEY (wxid, window_start)
+        );
+        CREATE TABLE usage (
+            id INTEGER PRIMARY KEY AUTOINCREMENT, wxid TEXT NOT NULL, session_id TEXT,
+            claude_session TEXT, input_tokens INTEGER NOT NULL DEFAULT 0,
+            output_tokens INTEGER NOT NULL DEFAULT 0,
+            cache_creation_tokens INTEGER NOT NULL DEFAULT 0,
+            cache_read_tokens INTEGER NOT NULL DEFAULT 0,
+            cost_usd REAL NOT NULL DEFAULT 0, timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX idx_audit_wxid ON audit_log(wxid);
+
+        INSERT INTO friends (wxid, nickname, permission) VALUES ('wx_alice', 'Alice', 'trusted');
+        INSERT INTO friends (wxid, nickname, permission, notes) VALUES ('-100777', 'Dev Team', 'normal', 'group');
+        INSERT INTO sessions (id, wxid, claude_session) VALUES ('s1', 'wx_alice', 'claude-1');
+        INSERT INTO audit_log (wxid, direction, message) VALUES ('wx_alice', 'in', 'hello');
+    ";
+
+    fn fixture() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(UNVERSIONED_SCHEMA).unwrap();
+        conn
+    }
+
+    fn kinds(conn: &Connection) -> Vec<(String, String)> {
+        let mut stmt = conn.prepare("SELECT wxid, kind FROM friends ORDER BY wxid").unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn versions_are_sequential() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, i + 1, "{}", migration.description);
+        }
+    }
+
+    #[test]
+    fn fresh_database_gets_every_migration() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let applied = migrate(&mut conn).unwrap();
+        assert_eq!(applied, (1..=latest_version()).collect::<Vec<_>>());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert!(migrate(&mut conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unversioned_database_is_upgraded_in_place() {
+        let mut conn = fixture();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert_eq!(
+            kinds(&conn),
+            vec![
+                ("-100777".to_string(), "group".to_string()),
+                ("wx_alice".to_string(), "user".to_string()),
+            ]
+        );
+        let notes: Option<String> = conn
+            .query_row("SELECT notes FROM friends WHERE wxid = '-100777'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(notes, None);
+
+        // Existing rows survive
+        let session: String = conn
+            .query_row("SELECT claude_session FROM sessions WHERE id = 's1'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(session, "claude-1");
+        let audit: i64 = conn.query_row("SELECT COUNT(*) FROM audit_log", [], |r| r.get(0)).unwrap();
+        assert_eq!(audit, 1);
+        // ... and existing messages are searchable
+        let hits: i64 = conn
+            .query_row("SELECT COUNT(*) FROM audit_fts WHERE audit_fts MATCH '\"ell\"'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(hits, 1);
+        // ... and chained
+        let (prev, hash): (String, String) = conn
+            .query_row("SELECT prev_hash, hash FROM audit_log", [], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap();
+        assert_eq!(prev, audit_chain::GENESIS);
+        assert_eq!(hash.len(), 64);
+    }
+
+    #[test]
+    fn dry_run_leaves_the_database_untouched() {
+        let mut conn = fixture();
+        let pending: Vec<u32> = dry_run(&mut conn).unwrap().iter().map(|m| m.version).collect();
+        assert_eq!(pending, (1..=latest_version()).collect::<Vec<_>>());
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        assert!(conn.prepare("SELECT kind FROM friends").is_err());
+
+        migrate(&mut conn).unwrap();
+        assert!(dry_run(&mut conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_migration_rolls_back() {
+        let mut conn = fixture();
+        // A column the migration is about to add makes it fail halfway
+        conn.execute_batch("ALTER TABLE friends ADD COLUMN kind TEXT").unwrap();
+
+        let err = migrate(&mut conn).unwrap_err();
+        assert!(format!("{:#}", err).contains("Migration 2"), "{:#}", err);
+        assert_eq!(current_version(&conn).unwrap(), 1);
+        let notes: Option<String> = conn
+            .query_row("SELECT notes FROM friends WHERE wxid = '-100777'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(notes.as_deref(), Some("group"));
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1).unwrap();
+        let err = migrate(&mut conn).unwrap_err();
+        assert!(err.to_string().contains("newer"), "{}", err);
+    }
+}
@@ -0,0 +1,201 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use tracing::{info, warn};
+
+use crate::claude_executor::ClaudeExecutor;
+use crate::database::Database;
+
+/// What one reaper pass did.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReapReport {
+    /// wxids whose containers were stopped.
+    pub stopped: Vec<String>,
+    /// wxids whose containers were removed (data dir kept).
+    pub removed: Vec<String>,
+}
+
+impl ReapReport {
+    pub fn is_empty(&self) -> bool {
+        self.stopped.is_empty() && self.removed.is_empty()
+    }
+
+    /// Summary for the admin, using friends' display names where known.
+    pub fn format(&self, db: &Database) -> String {
+        let names = |wxids: &[String]| {
+            wxids
+                .iter()
+                .map(|w| match db.friend_get(w) {
+                    Ok(Some(f)) => f
+                        .remark_name
+                        .filter(|r| !r.is_empty())
+                        .or(f.nickname)
+                        .unwrap_or_else(|| w.clone()),
+                    _ => w.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        let mut lines = vec!["🧹 闲置容器回收".to_string()];
+        if !self.stopped.is_empty() {
+            lines.push(format!("已停止 ({}): {}", self.stopped.len(), names(&self.stopped)));
+        }
+        if !self.removed.is_empty() {
+            lines.push(format!(
+                "已删除 ({}，数据已保留): {}",
+                self.removed.len(),
+                names(&self.removed)
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Stops containers of friends who have been idle for a while and removes
+/// them after a longer period. Removal keeps the data dir, and the next
+/// message recreates the container on demand.
+///
+/// Stopping wipes the container's tmpfs home (and with it Claude's session
+/// files), so the friend's session is cleared at the same time.
+pub struct Reaper {
+    executor: Arc<ClaudeExecutor>,
+    db: Arc<Database>,
+    stop_after_minutes: u64,
+    remove_after_minutes: u64,
+}
+
+impl Reaper {
+    pub fn new(
+        executor: Arc<ClaudeExecutor>,
+        db: Arc<Database>,
+        stop_after_minutes: u64,
+        remove_after_minutes: u64,
+    ) -> Self {
+        Self {
+            executor,
+            db,
+            stop_after_minutes,
+            remove_after_minutes,
+        }
+    }
+
+    /// Run one pass over all bridge containers.
+    pub async fn run_once(&self) -> Result<ReapReport> {
+        self.run_at(Utc::now().naive_utc()).await
+    }
+
+    async fn run_at(&self, now: NaiveDateTime) -> Result<ReapReport> {
+        let mut report = ReapReport::default();
+
+        for c in self.executor.list_containers().await? {
+            let Some(wxid) = c.wxid else { continue };
+            if self.executor.is_busy(&wxid).await {
+                continue;
+            }
+            let idle = match self.db.last_activity(&wxid) {
+                Ok(ts) => idle_minutes(ts.as_deref(), now),
+                Err(e) => {
+                    warn!("Reaper: failed to read activity for {}: {}", wxid, e);
+                    continue;
+                }
+            };
+            let running = c.status.starts_with("Up");
+
+            if idle >= self.remove_after_minutes {
+                if self.executor.destroy_container(&wxid).await? {
+                    info!("Reaper removed container of {} (idle {} min)", wxid, idle);
+                    report.removed.push(wxid);
+                }
+            } else if running && idle >= self.stop_after_minutes {
+                self.executor.clear_session(&wxid, false).await?;
+                if self.executor.stop_container(&wxid).await? {
+                    info!("Reaper stopped container of {} (idle {} min)", wxid, idle);
+                    report.stopped.push(wxid);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// Minutes between `last_active` (SQLite "%Y-%m-%d %H:%M:%S", UTC) and `now`.
+/// No or unparsable activity counts as idle forever.
+fn idle_minutes(last_active: Option<&str>, now: NaiveDateTime) -> u64 {
+    last_active
+        .and_then(|ts| NaiveDateTime::parse_from_str(ts, "%Y-%m-%d %H:%M:%S").ok())
+        .map(|ts| now.signed_duration_since(ts).num_minutes().max(0) as u64)
+        .unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_backend::{AgentBackend, MockBackend};
+    use crate::docker_manager::Permission;
+    use chrono::Duration;
+    use std::path::Path;
+
+    fn setup() -> (Reaper, Arc<MockBackend>, Arc<Database>) {
+        let db = Arc::new(Database::new(Some(Path::new(":memory:"))).unwrap());
+        let backend = Arc::new(MockBackend::new());
+        let executor = Arc::new(ClaudeExecutor::new(
+            backend.clone(),
+            Arc::clone(&db),
+            60,
+            120,
+        ));
+        (Reaper::new(executor, Arc::clone(&db), 120, 1440), backend, db)
+    }
+
+    #[test]
+    fn idle_minutes_parses_sqlite_timestamps() {
+        let now = NaiveDateTime::parse_from_str("2024-01-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(idle_minutes(Some("2024-01-01 10:30:00"), now), 90);
+        assert_eq!(idle_minutes(Some("2024-01-01 13:00:00"), now), 0);
+        assert_eq!(idle_minutes(Some("garbage"), now), u64::MAX);
+        assert_eq!(idle_minutes(None, now), u64::MAX);
+    }
+
+    #[tokio::test]
+    async fn reaper_stops_then_removes_idle_containers() {
+        let (reaper, backend, db) = setup();
+        for (wxid, name) in [("wx_a", "Alice"), ("wx_b", "Bob")] {
+            db.friend_upsert(wxid, Some(name), None, Some("trusted"), None, None).unwrap();
+            backend.prepare(wxid, Permission::Trusted).await.unwrap();
+            db.audit_log(wxid, None, "in", Some("hi"), None).unwrap();
+        }
+        db.session_create("s-a", "wx_a", None).unwrap();
+
+        // Fresh activity: nothing to do
+        let now = Utc::now().naive_utc();
+        assert!(reaper.run_at(now).await.unwrap().is_empty());
+
+        // Past the stop threshold: both stopped, sessions cleared
+        let report = reaper.run_at(now + Duration::minutes(180)).await.unwrap();
+        assert_eq!(report.stopped, vec!["wx_a", "wx_b"]);
+        assert!(report.removed.is_empty());
+        assert!(!backend.status("wx_a").await.running);
+        assert!(db.session_get_active("wx_a").unwrap().is_none());
+        assert_eq!(report.format(&db), "🧹 闲置容器回收\n已停止 (2): Alice, Bob");
+
+        // Already stopped: not reported again
+        assert!(reaper.run_at(now + Duration::minutes(200)).await.unwrap().is_empty());
+
+        // Past the remove threshold: removed from the listing
+        let report = reaper.run_at(now + Duration::minutes(1500)).await.unwrap();
+        assert_eq!(report.removed, vec!["wx_a", "wx_b"]);
+        assert!(backend.list().await.unwrap().is_empty());
+        assert_eq!(report.format(&db), "🧹 闲置容器回收\n已删除 (2，数据已保留): Alice, Bob");
+    }
+
+    #[tokio::test]
+    async fn reaper_treats_unknown_activity_as_idle() {
+        let (reaper, backend, _db) = setup();
+        backend.prepare("wx_ghost", Permission::Normal).await.unwrap();
+        let report = reaper.run_once().await.unwrap();
+        assert_eq!(report.removed, vec!["wx_ghost"]);
+    }
+}
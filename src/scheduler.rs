@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::docker_manager::Permission;
+
+/// Index into the per-level arrays: admin, trusted, normal.
+fn level_index(permission: Permission) -> usize {
+    match permission {
+        Permission::Admin => 0,
+        Permission::Trusted => 1,
+        Permission::Normal => 2,
+    }
+}
+
+/// A request waiting for a slot.
+struct Waiter {
+    ticket: u64,
+    wxid: String,
+    grant: oneshot::Sender<SchedulerPermit>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    /// FIFO of waiters per level. A user has at most one request in the
+    /// executor, so FIFO within a level is round-robin between users.
+    queues: [VecDeque<Waiter>; 3],
+    /// Smooth weighted round-robin counters per level.
+    current: [i64; 3],
+    next_ticket: u64,
+}
+
+/// Snapshot of the scheduler for `/status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueDepth {
+    pub running: usize,
+    pub capacity: usize,
+    pub waiting: usize,
+}
+
+/// Caps concurrent Claude runs across all users.
+///
+/// When all slots are taken, requests wait per permission level and levels
+/// are served by smooth weighted round-robin: with weights 4/2/1, admin gets
+/// 4 of every 7 freed slots while all levels are waiting, but normal users
+/// are never starved.
+pub struct Scheduler {
+    capacity: usize,
+    /// Admin, trusted, normal.
+    weights: [i64; 3],
+    state: Mutex<State>,
+}
+
+/// A running slot; freeing it (on drop) hands it to the next waiter.
+pub struct SchedulerPermit {
+    scheduler: Option<Arc<Scheduler>>,
+}
+
+impl Drop for SchedulerPermit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release();
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(4, [4, 2, 1])
+    }
+}
+
+impl Scheduler {
+    /// `weights` are for admin, trusted and normal, in that order.
+    pub fn new(capacity: usize, weights: [u32; 3]) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            weights: weights.map(|w| w.max(1) as i64),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Wait for a slot. If the request has to wait, `on_queued` is called
+    /// once with its 1-based position in line.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        wxid: &str,
+        permission: Permission,
+        on_queued: impl FnOnce(usize),
+    ) -> SchedulerPermit {
+        let (rx, position) = {
+            let mut state = self.state.lock().unwrap();
+            if state.running < self.capacity && state.queues.iter().all(VecDeque::is_empty) {
+                state.running += 1;
+                return self.permit();
+            }
+            let (tx, rx) = oneshot::channel();
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.queues[level_index(permission)].push_back(Waiter {
+                ticket,
+                wxid: wxid.to_string(),
+                grant: tx,
+            });
+            (rx, self.position_of(&state, |w| w.ticket == ticket))
+        };
+
+        on_queued(position.unwrap_or(1));
+        rx.await.expect("waiters are only dropped after a grant attempt")
+    }
+
+    /// Running / waiting counts.
+    pub fn depth(&self) -> QueueDepth {
+        let state = self.state.lock().unwrap();
+        QueueDepth {
+            running: state.running,
+            capacity: self.capacity,
+            waiting: state.queues.iter().map(VecDeque::len).sum(),
+        }
+    }
+
+    /// 1-based position of the user's waiting request, if they have one.
+    pub fn position(&self, wxid: &str) -> Option<usize> {
+        let state = self.state.lock().unwrap();
+        self.position_of(&state, |w| w.wxid == wxid)
+    }
+
+    fn permit(self: &Arc<Self>) -> SchedulerPermit {
+        SchedulerPermit {
+            scheduler: Some(Arc::clone(self)),
+        }
+    }
+
+    /// Pass a freed slot to the next live waiter, or give it back.
+    fn release(self: &Arc<Self>) {
+        loop {
+            let waiter = {
+                let mut state = self.state.lock().unwrap();
+                let State { queues, current, .. } = &mut *state;
+                let lens = queues.each_ref().map(VecDeque::len);
+                match pick_level(&lens, current, &self.weights) {
+                    Some(level) => queues[level].pop_front().expect("picked level is non-empty"),
+                    None => {
+                        state.running -= 1;
+                        return;
+                    }
+                }
+            };
+            // The slot moves to the waiter; if it gave up, try the next one
+            match waiter.grant.send(self.permit()) {
+                Ok(()) => return,
+                Err(mut unused) => {
+                    unused.scheduler = None;
+                }
+            }
+        }
+    }
+
+    /// Where the first waiter matching `pred` is in grant order.
+    fn position_of(&self, state: &State, pred: impl Fn(&Waiter) -> bool) -> Option<usize> {
+        let mut lens = state.queues.each_ref().map(VecDeque::len);
+        let mut heads = [0usize; 3];
+        let mut current = state.current;
+        let mut position = 0;
+        while let Some(level) = pick_level(&lens, &mut current, &self.weights) {
+            position += 1;
+            if pred(&state.queues[level][heads[level]]) {
+                return Some(position);
+            }
+            heads[level] += 1;
+            lens[level] -= 1;
+        }
+        None
+    }
+}
+
+/// Smooth weighted round-robin over the non-empty levels.
+fn pick_level(lens: &[usize; 3], current: &mut [i64; 3], weights: &[i64; 3]) -> Option<usize> {
+    let mut total = 0;
+    let mut best: Option<usize> = None;
+    for level in 0..lens.len() {
+        if lens[level] == 0 {
+            continue;
+        }
+        total += weights[level];
+        current[level] += weights[level];
+        if best.is_none_or(|b| current[level] > current[b]) {
+            best = Some(level);
+        }
+    }
+    let best = best?;
+    current[best] -= total;
+    Some(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Queue `wxid` behind the held slots and record the grant order.
+    fn spawn_waiter(
+        scheduler: &Arc<Scheduler>,
+        wxid: &'static str,
+        permission: Permission,
+        order: &Arc<Mutex<Vec<&'static str>>>,
+    ) -> tokio::task::JoinHandle<usize> {
+        let scheduler = Arc::clone(scheduler);
+        let order = Arc::clone(order);
+        tokio::spawn(async move {
+            let mut queued_at = 0;
+            let _permit = scheduler.acquire(wxid, permission, |p| queued_at = p).await;
+            order.lock().unwrap().push(wxid);
+            queued_at
+        })
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    #[tokio::test]
+    async fn runs_immediately_below_capacity() {
+        let s = Arc::new(Scheduler::new(2, [4, 2, 1]));
+        let mut queued = false;
+        let a = s.acquire("wx_a", Permission::Normal, |_| queued = true).await;
+        let _b = s.acquire("wx_b", Permission::Normal, |_| queued = true).await;
+        assert!(!queued);
+        assert_eq!(s.depth(), QueueDepth { running: 2, capacity: 2, waiting: 0 });
+        drop(a);
+        assert_eq!(s.depth().running, 1);
+    }
+
+    #[tokio::test]
+    async fn waiters_are_served_by_weight_without_starving_normal() {
+        let s = Arc::new(Scheduler::new(1, [2, 1, 1]));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let held = s.acquire("wx_busy", Permission::Normal, |_| {}).await;
+
+        let mut tasks = Vec::new();
+        for (wxid, permission) in [
+            ("n1", Permission::Normal),
+            ("n2", Permission::Normal),
+            ("a1", Permission::Admin),
+            ("a2", Permission::Admin),
+            ("a3", Permission::Admin),
+            ("t1", Permission::Trusted),
+        ] {
+            tasks.push(spawn_waiter(&s, wxid, permission, &order));
+            settle().await;
+        }
+        assert_eq!(s.depth().waiting, 6);
+        // Grant order is admin, trusted, normal, admin, admin, normal
+        assert_eq!(s.position("a1"), Some(1));
+        assert_eq!(s.position("t1"), Some(2));
+        assert_eq!(s.position("n1"), Some(3));
+        assert_eq!(s.position("n2"), Some(6));
+        assert_eq!(s.position("wx_busy"), None);
+
+        drop(held);
+        let queued_at: Vec<usize> = futures_util::future::join_all(tasks)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(*order.lock().unwrap(), vec!["a1", "t1", "n1", "a2", "a3", "n2"]);
+        // Each waiter was told its position at the time it queued
+        assert_eq!(queued_at, vec![1, 2, 1, 3, 4, 2]);
+        assert_eq!(s.depth(), QueueDepth { running: 0, capacity: 1, waiting: 0 });
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiters_are_skipped() {
+        let s = Arc::new(Scheduler::new(1, [4, 2, 1]));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let held = s.acquire("wx_busy", Permission::Normal, |_| {}).await;
+
+        let gone = spawn_waiter(&s, "gone", Permission::Admin, &order);
+        settle().await;
+        let next = spawn_waiter(&s, "next", Permission::Normal, &order);
+        settle().await;
+        gone.abort();
+        settle().await;
+
+        drop(held);
+        next.await.unwrap();
+        assert_eq!(*order.lock().unwrap(), vec!["next"]);
+        assert_eq!(s.depth().running, 0);
+    }
+}
@@ -0,0 +1,383 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::warn;
+
+use crate::docker_manager::Permission;
+
+/// Name prefix of snapshots taken automatically before a Claude run.
+pub const AUTO_PREFIX: &str = "auto-";
+
+/// A stored workspace snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    /// Archive size in bytes.
+    pub size: u64,
+    /// When the snapshot was taken (Unix seconds).
+    pub created: i64,
+}
+
+impl SnapshotInfo {
+    pub fn is_auto(&self) -> bool {
+        self.name.starts_with(AUTO_PREFIX)
+    }
+}
+
+/// Retention and automatic snapshot settings.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotPolicy {
+    /// Manual snapshots kept per user; the oldest are deleted first.
+    pub keep_manual: usize,
+    /// Automatic snapshots kept per user.
+    pub keep_auto: usize,
+    /// Snapshot the workspace before every Claude run, per level.
+    pub auto_admin: bool,
+    pub auto_trusted: bool,
+    pub auto_normal: bool,
+}
+
+impl SnapshotPolicy {
+    pub fn auto_for(&self, permission: Permission) -> bool {
+        match permission {
+            Permission::Admin => self.auto_admin,
+            Permission::Trusted => self.auto_trusted,
+            Permission::Normal => self.auto_normal,
+        }
+    }
+}
+
+/// Whether `name` can be used as a snapshot name: 1-64 ASCII letters,
+/// digits, `-` or `_`.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Snapshots of a user's workspace, kept as tar archives in a directory
+/// next to it (`<data_dir>/<wxid>/snapshots/`, outside the container).
+///
+/// All methods do blocking file I/O; call them from `spawn_blocking`.
+pub struct SnapshotDir {
+    workspace: PathBuf,
+    dir: PathBuf,
+}
+
+impl SnapshotDir {
+    /// Snapshots for the workspace at `workspace` on the host.
+    pub fn for_workspace(workspace: &Path) -> Self {
+        let parent = workspace.parent().unwrap_or(workspace);
+        Self {
+            workspace: workspace.to_path_buf(),
+            dir: parent.join("snapshots"),
+        }
+    }
+
+    fn archive_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.tar", name))
+    }
+
+    /// Archive the workspace as `name`, replacing an older snapshot of the
+    /// same name, then delete the oldest snapshots of the same kind beyond
+    /// `keep`. Files the bridge can't read are left out.
+    pub fn create(&self, name: &str, keep: usize) -> Result<SnapshotInfo> {
+        if !valid_name(name) {
+            anyhow::bail!("Invalid snapshot name: {}", name);
+        }
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {:?}", self.dir))?;
+
+        // Write to a temporary file so a failed snapshot never replaces a good one
+        let tmp = self.dir.join(format!(".{}.tar.tmp", name));
+        let result = (|| -> Result<()> {
+            let mut archive = tar::Builder::new(BufWriter::new(File::create(&tmp)?));
+            archive.follow_symlinks(false);
+            if self.workspace.is_dir() {
+                append_tree(&mut archive, &self.workspace, Path::new(""))?;
+            }
+            archive.into_inner()?.into_inner().map_err(|e| e.into_error())?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.context(format!("Failed to snapshot {:?}", self.workspace)));
+        }
+        let path = self.archive_path(name);
+        fs::rename(&tmp, &path)?;
+
+        let info = snapshot_info(name, &path)?;
+        self.prune(info.is_auto(), keep)?;
+        Ok(info)
+    }
+
+    /// All snapshots, newest first.
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut snapshots = Vec::new();
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let Some(name) = file_name.strip_suffix(".tar") else {
+                continue;
+            };
+            if valid_name(name) {
+                snapshots.push(snapshot_info(name, &entry.path())?);
+            }
+        }
+        snapshots.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| b.name.cmp(&a.name)));
+        Ok(snapshots)
+    }
+
+    /// Replace the workspace with the contents of snapshot `name`. Nothing
+    /// may be using the workspace meanwhile.
+    pub fn restore(&self, name: &str) -> Result<()> {
+        let path = self.archive_path(name);
+        if !valid_name(name) || !path.is_file() {
+            anyhow::bail!("No such snapshot: {}", name);
+        }
+
+        // Unpack next to the workspace first, then swap the directories
+        let staging = self.workspace.with_extension("restoring");
+        let old = self.workspace.with_extension("old");
+        let _ = fs::remove_dir_all(&staging);
+        fs::create_dir_all(&staging)?;
+        if let Err(e) = tar::Archive::new(File::open(&path)?).unpack(&staging) {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(anyhow::Error::new(e).context(format!("Failed to unpack snapshot {}", name)));
+        }
+
+        let _ = fs::remove_dir_all(&old);
+        if self.workspace.exists() {
+            fs::rename(&self.workspace, &old)?;
+        }
+        fs::rename(&staging, &self.workspace)?;
+        if let Err(e) = fs::remove_dir_all(&old) {
+            warn!("Failed to remove replaced workspace {:?}: {}", old, e);
+        }
+        Ok(())
+    }
+
+    /// Delete the oldest automatic (or manual) snapshots beyond `keep`.
+    fn prune(&self, auto: bool, keep: usize) -> Result<()> {
+        let stale = self
+            .list()?
+            .into_iter()
+            .filter(|s| s.is_auto() == auto)
+            .skip(keep.max(1));
+        for snapshot in stale {
+            fs::remove_file(self.archive_path(&snapshot.name))?;
+        }
+        Ok(())
+    }
+}
+
+fn snapshot_info(name: &str, path: &Path) -> Result<SnapshotInfo> {
+    let meta = fs::metadata(path)?;
+    let created = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64);
+    Ok(SnapshotInfo {
+        name: name.to_string(),
+        size: meta.len(),
+        created,
+    })
+}
+
+/// Add everything under `dir` to the archive as `prefix/...`.
+///
+/// Sandboxed code can swap any entry for a symlink to a host file while
+/// this runs, so entries are opened without following symlinks and their
+/// type is taken from the opened handle, not from the earlier listing.
+fn append_tree<W: std::io::Write>(
+    archive: &mut tar::Builder<W>,
+    dir: &Path,
+    prefix: &Path,
+) -> Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.flatten().collect();
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = prefix.join(entry.file_name());
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.file_type().is_symlink() {
+            // Stored as a link; its target is never read
+            let Ok(target) = fs::read_link(&path) else {
+                continue;
+            };
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&meta);
+            header.set_size(0);
+            archive.append_link(&mut header, &name, &target)?;
+            continue;
+        }
+        if !meta.is_dir() && !meta.is_file() {
+            continue;
+        }
+
+        let mut file = match open_nofollow(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Skipping {:?} in snapshot: {}", path, e);
+                continue;
+            }
+        };
+        let meta = file.metadata()?;
+        if meta.is_dir() {
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&meta);
+            header.set_size(0);
+            archive.append_data(&mut header, &name, std::io::empty())?;
+            if let Err(e) = append_tree(archive, &opened_dir_path(&file, &path), &name) {
+                warn!("Skipping {:?} in snapshot: {}", path, e);
+            }
+        } else if meta.is_file() {
+            archive.append_file(&name, &mut file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Open `path` for reading, failing if its last component is a symlink.
+/// FIFOs don't block the open; they are skipped after it.
+fn open_nofollow(path: &Path) -> std::io::Result<File> {
+    let mut options = File::options();
+    options.read(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+    }
+    options.open(path)
+}
+
+/// Path that reaches the directory `dir` was opened as, even if a parent
+/// is swapped for a symlink afterwards.
+#[cfg(target_os = "linux")]
+fn opened_dir_path(dir: &File, _path: &Path) -> PathBuf {
+    use std::os::fd::AsRawFd;
+    PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn opened_dir_path(_dir: &File, path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_workspace(tag: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("wcb-snap-{}-{}", tag, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let workspace = root.join("wx_s").join("workspace");
+        fs::create_dir_all(workspace.join("src")).unwrap();
+        workspace
+    }
+
+    #[test]
+    fn valid_names() {
+        assert!(valid_name("before-refactor_2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("../x"));
+        assert!(!valid_name("a b"));
+        assert!(!valid_name(&"x".repeat(65)));
+    }
+
+    #[test]
+    fn snapshot_and_restore_round_trip() {
+        let workspace = temp_workspace("roundtrip");
+        fs::write(workspace.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(workspace.join("notes.md"), "v1").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("notes.md", workspace.join("link")).unwrap();
+        let snapshots = SnapshotDir::for_workspace(&workspace);
+
+        let info = snapshots.create("good", 5).unwrap();
+        assert_eq!(info.name, "good");
+        assert!(info.size > 0);
+        assert!(workspace.parent().unwrap().join("snapshots/good.tar").is_file());
+
+        // Claude wrecks the workspace
+        fs::remove_dir_all(workspace.join("src")).unwrap();
+        fs::write(workspace.join("notes.md"), "garbage").unwrap();
+        fs::write(workspace.join("junk.txt"), "junk").unwrap();
+
+        snapshots.restore("good").unwrap();
+        assert_eq!(fs::read_to_string(workspace.join("src/main.rs")).unwrap(), "fn main() {}");
+        assert_eq!(fs::read_to_string(workspace.join("notes.md")).unwrap(), "v1");
+        assert!(!workspace.join("junk.txt").exists());
+        #[cfg(unix)]
+        assert_eq!(fs::read_link(workspace.join("link")).unwrap(), Path::new("notes.md"));
+        assert!(!workspace.with_extension("old").exists());
+        assert!(!workspace.with_extension("restoring").exists());
+
+        assert!(snapshots.restore("missing").is_err());
+        assert!(snapshots.restore("../wx_s/workspace").is_err());
+        assert!(snapshots.create("bad name", 5).is_err());
+        let _ = fs::remove_dir_all(workspace.parent().unwrap().parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn snapshot_never_follows_symlinks() {
+        let workspace = temp_workspace("nofollow");
+        let host = workspace.parent().unwrap().join("host");
+        fs::create_dir_all(&host).unwrap();
+        fs::write(host.join("secret.key"), "host secret").unwrap();
+        std::os::unix::fs::symlink(host.join("secret.key"), workspace.join("key")).unwrap();
+        std::os::unix::fs::symlink(&host, workspace.join("hostdir")).unwrap();
+        let fifo = std::ffi::CString::new(workspace.join("pipe").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        assert!(open_nofollow(&workspace.join("key")).is_err());
+
+        let snapshots = SnapshotDir::for_workspace(&workspace);
+        snapshots.create("s", 5).unwrap();
+        let archive = fs::read(snapshots.archive_path("s")).unwrap();
+        assert!(!archive.windows(11).any(|w| w == b"host secret"));
+        let mut tar = tar::Archive::new(archive.as_slice());
+        let names: Vec<(String, bool)> = tar
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                (e.path().unwrap().display().to_string(), e.header().entry_type().is_symlink())
+            })
+            .collect();
+        assert!(names.contains(&("key".into(), true)), "{:?}", names);
+        assert!(names.contains(&("hostdir".into(), true)), "{:?}", names);
+        assert!(!names.iter().any(|(n, _)| n == "pipe" || n.starts_with("hostdir/")), "{:?}", names);
+        let _ = fs::remove_dir_all(workspace.parent().unwrap().parent().unwrap());
+    }
+
+    #[test]
+    fn retention_is_per_kind() {
+        let workspace = temp_workspace("retention");
+        let snapshots = SnapshotDir::for_workspace(&workspace);
+        for (i, name) in ["m1", "m2", "auto-1", "auto-2", "auto-3", "m3"].iter().enumerate() {
+            snapshots.create(name, 2).unwrap();
+            // Backdate in creation order so "oldest" is well defined
+            let created = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000 + i as u64);
+            File::options()
+                .write(true)
+                .open(snapshots.archive_path(name))
+                .unwrap()
+                .set_modified(created)
+                .unwrap();
+        }
+        let mut names: Vec<String> = snapshots.list().unwrap().into_iter().map(|s| s.name).collect();
+        names.sort();
+        assert_eq!(names, vec!["auto-2", "auto-3", "m2", "m3"]);
+        let _ = fs::remove_dir_all(workspace.parent().unwrap().parent().unwrap());
+    }
+}
@@ -1,14 +1,41 @@
 use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
+use reqwest::multipart::{Form, Part};
 use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
 
-use crate::config::TelegramConfig;
-use crate::wechat_bot::{Contact, WeChatBot};
+use crate::config::{TelegramConfig, TelegramWebhook};
+use crate::wechat_bot::{
+    Attachment, AttachmentKind, Contact, Group, Message, Quote, ReplyStream, WeChatBot,
+};
+
+/// Telegram rejects messages longer than 4096 characters; stay well below.
+const TG_MAX_LEN: usize = 4000;
+
+/// Bots can't download files larger than 20 MB via `getFile`.
+const TG_MAX_DOWNLOAD: u64 = 20 * 1024 * 1024;
+
+/// Upload limits: 10 MB for photos, 50 MB for anything else.
+const TG_MAX_PHOTO: u64 = 10 * 1024 * 1024;
+const TG_MAX_UPLOAD: u64 = 50 * 1024 * 1024;
+
+/// Largest webhook request head and body accepted.
+const MAX_HEAD_LEN: usize = 8192;
+const MAX_WEBHOOK_BODY: usize = 1024 * 1024;
+
+/// Time allowed for Telegram to send a whole webhook request.
+const WEBHOOK_READ_TIMEOUT: Duration = Duration::from_secs(10);
 
 // ============================================
 // Telegram Bot API types
@@ -23,7 +50,6 @@ struct TgResponse<T> {
 
 #[derive(Deserialize, Debug)]
 struct TgUser {
-    #[allow(dead_code)]
     id: i64,
     first_name: String,
     last_name: Option<String>,
@@ -35,13 +61,36 @@ struct TgChat {
     id: i64,
     #[serde(rename = "type")]
     chat_type: String,
+    /// Group title.
+    title: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct TgMessage {
+    message_id: i64,
     from: Option<TgUser>,
     chat: TgChat,
     text: Option<String>,
+    caption: Option<String>,
+    /// Sizes of one photo, smallest first.
+    photo: Option<Vec<TgFileRef>>,
+    document: Option<TgFileRef>,
+    voice: Option<TgFileRef>,
+    reply_to_message: Option<Box<TgMessage>>,
+}
+
+/// The fields photos, documents and voice notes share.
+#[derive(Deserialize, Debug)]
+struct TgFileRef {
+    file_id: String,
+    file_unique_id: String,
+    file_name: Option<String>,
+    file_size: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TgFile {
+    file_path: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -50,154 +99,313 @@ struct TgUpdate {
     message: Option<TgMessage>,
 }
 
+#[derive(Serialize)]
+struct SetWebhookRequest<'a> {
+    url: &'a str,
+    secret_token: &'a str,
+    allowed_updates: &'a [&'a str],
+    /// One connection at a time keeps updates in order.
+    max_connections: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct TgSentMessage {
+    message_id: i64,
+}
+
 #[derive(Serialize)]
 struct SendMessageRequest {
     chat_id: String,
     text: String,
 }
 
+#[derive(Serialize)]
+struct EditMessageTextRequest {
+    chat_id: String,
+    message_id: i64,
+    text: String,
+}
+
 // ============================================
 // TelegramBot
 // ============================================
 
 pub struct TelegramBot {
     api_base: String,
+    /// Base URL for downloading files returned by `getFile`.
+    file_base: String,
     client: Client,
+    poll: Mutex<PollState>,
+    /// Webhook mode settings; long-polling when `None`.
+    webhook: Option<TelegramWebhook>,
+    /// The running webhook listener, once started.
+    webhook_listener: Option<JoinHandle<()>>,
+    /// The bot's own user ID and username, from `getMe` in `start`. Group
+    /// messages count as addressed to the bot when they mention it or reply
+    /// to it.
+    bot_id: i64,
+    username: String,
+}
+
+/// Next expected update ID plus messages received but not yet returned.
+#[derive(Default)]
+struct PollState {
     offset: i64,
-    buffer: VecDeque<(Contact, String)>,
+    buffer: VecDeque<(Contact, Message)>,
+    /// Updates from the webhook listener, in webhook mode.
+    webhook_updates: Option<mpsc::UnboundedReceiver<TgUpdate>>,
 }
 
 impl TelegramBot {
     pub fn new(cfg: &TelegramConfig) -> Self {
+        let base = cfg.api_base.trim_end_matches('/');
         Self {
-            api_base: format!("https://api.telegram.org/bot{}", cfg.bot_token),
+            api_base: format!("{}/bot{}", base, cfg.bot_token),
+            file_base: format!("{}/file/bot{}", base, cfg.bot_token),
             client: Client::new(),
-            offset: 0,
-            buffer: VecDeque::new(),
+            poll: Mutex::new(PollState::default()),
+            webhook: cfg.webhook.enabled.then(|| cfg.webhook.clone()),
+            webhook_listener: None,
+            bot_id: 0,
+            username: String::new(),
         }
     }
-}
 
-#[async_trait]
-impl WeChatBot for TelegramBot {
-    async fn start(&mut self) -> Result<()> {
-        let url = format!("{}/getMe", self.api_base);
-        let resp: TgResponse<TgUser> = self
+    /// POST a JSON body to a Bot API method and return its result.
+    async fn post_json<T: DeserializeOwned>(&self, method: &str, body: &impl Serialize) -> Result<T> {
+        let url = format!("{}/{}", self.api_base, method);
+        let resp: TgResponse<T> = self
+            .client
+            .post(&url)
+            .json(body)
+            .send()
+            .await
+            .with_context(|| format!("{} request failed", method))?
+            .json()
+            .await
+            .with_context(|| format!("{} parse failed", method))?;
+
+        if !resp.ok {
+            anyhow::bail!("{} failed: {}", method, resp.description.unwrap_or_default());
+        }
+        resp.result.with_context(|| format!("{} returned no result", method))
+    }
+
+    /// Long-poll `getUpdates` for updates from `offset` on.
+    async fn get_updates(&self, offset: i64) -> Result<Vec<TgUpdate>> {
+        let url = format!(
+            "{}/getUpdates?offset={}&timeout=30&allowed_updates=[\"message\"]",
+            self.api_base, offset
+        );
+
+        let resp: TgResponse<Vec<TgUpdate>> = self
             .client
             .get(&url)
+            .timeout(Duration::from_secs(35))
             .send()
             .await
-            .context("Telegram getMe failed. Check your bot_token and network.")?
+            .context("getUpdates request failed")?
             .json()
             .await
-            .context("Failed to parse getMe response")?;
+            .context("getUpdates parse failed")?;
 
         if !resp.ok {
             anyhow::bail!(
-                "Telegram bot token invalid: {}",
+                "getUpdates failed: {}",
                 resp.description.unwrap_or_default()
             );
         }
-
-        let me = resp.result.context("No user in getMe response")?;
-        info!(
-            "Telegram bot online: @{} ({})",
-            me.username.unwrap_or_default(),
-            me.first_name
-        );
-        Ok(())
+        Ok(resp.result.unwrap_or_default())
     }
 
-    async fn recv_message(&mut self) -> Result<Option<(Contact, String)>> {
-        // Drain buffer first
-        if let Some(msg) = self.buffer.pop_front() {
-            return Ok(Some(msg));
+    /// Turn a private-chat message, or a group message addressed to the bot,
+    /// into a contact and message, downloading its attachments. Other chats,
+    /// other group chatter and empty messages yield `None`.
+    async fn convert_message(&self, msg: TgMessage) -> Option<(Contact, Message)> {
+        let mut text = msg.text.clone().or_else(|| msg.caption.clone()).unwrap_or_default();
+        let group = match msg.chat.chat_type.as_str() {
+            "private" => None,
+            "group" | "supergroup" => {
+                let replied = msg
+                    .reply_to_message
+                    .as_ref()
+                    .and_then(|quoted| quoted.from.as_ref())
+                    .is_some_and(|from| from.id == self.bot_id);
+                match strip_mention(&text, &self.username) {
+                    Some(stripped) => text = stripped,
+                    None if replied => {}
+                    None => return None,
+                }
+                // Anonymous admins post as the group itself; there's no sender to check
+                msg.from.as_ref()?;
+                Some(Group {
+                    id: msg.chat.id.to_string(),
+                    name: msg.chat.title.clone().unwrap_or_default(),
+                })
+            }
+            _ => {
+                debug!("Skipping message from {} chat {}", msg.chat.chat_type, msg.chat.id);
+                return None;
+            }
+        };
+
+        let message = Message {
+            id: msg.message_id.to_string(),
+            text,
+            attachments: self.download_attachments(&msg).await,
+            reply_to: msg.reply_to_message.as_ref().map(|quoted| Quote {
+                id: quoted.message_id.to_string(),
+                text: quoted
+                    .text
+                    .clone()
+                    .or_else(|| quoted.caption.clone())
+                    .unwrap_or_default(),
+            }),
+        };
+        if message.is_empty() {
+            return None;
         }
 
-        // Long-poll getUpdates
-        loop {
-            let url = format!(
-                "{}/getUpdates?offset={}&timeout=30&allowed_updates=[\"message\"]",
-                self.api_base, self.offset
-            );
+        let user = msg.from.unwrap_or(TgUser {
+            id: msg.chat.id,
+            first_name: "Unknown".into(),
+            last_name: None,
+            username: None,
+        });
 
-            let resp: TgResponse<Vec<TgUpdate>> = self
-                .client
-                .get(&url)
-                .timeout(Duration::from_secs(35))
-                .send()
-                .await
-                .context("getUpdates request failed")?
-                .json()
-                .await
-                .context("getUpdates parse failed")?;
+        // Private chat IDs equal the user's ID
+        let user_id = if group.is_some() { user.id } else { msg.chat.id };
+        let nickname = match &user.last_name {
+            Some(last) => format!("{} {}", user.first_name, last),
+            None => user.first_name.clone(),
+        };
 
-            if !resp.ok {
-                anyhow::bail!(
-                    "getUpdates failed: {}",
-                    resp.description.unwrap_or_default()
-                );
-            }
+        let contact = Contact {
+            wxid: user_id.to_string(),
+            nickname,
+            remark_name: user.username.unwrap_or_default(),
+            group,
+        };
+        Some((contact, message))
+    }
 
-            let updates = resp.result.unwrap_or_default();
-            if updates.is_empty() {
-                continue; // timeout, poll again
-            }
+    /// Start the webhook listener and point Telegram at it.
+    async fn start_webhook(&mut self, cfg: TelegramWebhook) -> Result<()> {
+        if cfg.url.is_empty() {
+            anyhow::bail!("telegram.webhook.url is required in webhook mode");
+        }
+        let secret = if cfg.secret_token.is_empty() {
+            uuid::Uuid::new_v4().simple().to_string()
+        } else {
+            cfg.secret_token.clone()
+        };
+        let addr: SocketAddr = cfg
+            .listen
+            .parse()
+            .with_context(|| format!("Invalid telegram.webhook.listen: {}", cfg.listen))?;
 
-            for update in updates {
-                self.offset = update.update_id + 1;
+        let (local, updates, handle) = spawn_webhook_listener(addr, &cfg.path, &secret).await?;
+        self.poll.get_mut().webhook_updates = Some(updates);
+        self.webhook_listener = Some(handle);
 
-                let msg = match update.message {
-                    Some(m) => m,
-                    None => continue,
-                };
+        let body = SetWebhookRequest {
+            url: &cfg.url,
+            secret_token: &secret,
+            allowed_updates: &["message"],
+            max_connections: 1,
+        };
+        self.post_json::<bool>("setWebhook", &body).await?;
+        info!(
+            "Telegram webhook listening on {}{}, registered as {}",
+            local, cfg.path, cfg.url
+        );
+        Ok(())
+    }
 
-                // Private messages only
-                if msg.chat.chat_type != "private" {
-                    debug!("Skipping non-private message from chat {}", msg.chat.id);
-                    continue;
-                }
+    async fn delete_webhook(&self) -> Result<()> {
+        self.post_json::<bool>("deleteWebhook", &serde_json::json!({})).await?;
+        Ok(())
+    }
 
-                let text = match msg.text {
-                    Some(ref t) if !t.is_empty() => t.clone(),
-                    _ => continue,
-                };
+    /// Call `getFile` and download the file's contents.
+    async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        let url = format!("{}/getFile?file_id={}", self.api_base, file_id);
+        let resp: TgResponse<TgFile> = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .context("getFile request failed")?
+            .json()
+            .await
+            .context("getFile parse failed")?;
 
-                let user = msg.from.unwrap_or(TgUser {
-                    id: msg.chat.id,
-                    first_name: "Unknown".into(),
-                    last_name: None,
-                    username: None,
-                });
+        if !resp.ok {
+            anyhow::bail!("getFile failed: {}", resp.description.unwrap_or_default());
+        }
+        let path = resp
+            .result
+            .and_then(|f| f.file_path)
+            .context("getFile returned no file_path")?;
 
-                let nickname = match &user.last_name {
-                    Some(last) => format!("{} {}", user.first_name, last),
-                    None => user.first_name.clone(),
-                };
+        let bytes = self
+            .client
+            .get(format!("{}/{}", self.file_base, path))
+            .send()
+            .await
+            .context("file download failed")?
+            .error_for_status()
+            .context("file download failed")?
+            .bytes()
+            .await
+            .context("file download failed")?;
+        Ok(bytes.to_vec())
+    }
 
-                let contact = Contact {
-                    wxid: msg.chat.id.to_string(),
-                    nickname,
-                    remark_name: user.username.unwrap_or_default(),
-                };
+    /// Download the photo, document or voice note of a message. Files that
+    /// are too large or fail to download are skipped with a warning.
+    async fn download_attachments(&self, msg: &TgMessage) -> Vec<Attachment> {
+        let mut refs: Vec<(AttachmentKind, &TgFileRef, String)> = Vec::new();
+        if let Some(largest) = msg.photo.as_ref().and_then(|sizes| sizes.last()) {
+            let name = format!("photo_{}.jpg", largest.file_unique_id);
+            refs.push((AttachmentKind::Image, largest, name));
+        }
+        if let Some(ref doc) = msg.document {
+            let name = doc
+                .file_name
+                .clone()
+                .unwrap_or_else(|| format!("document_{}", doc.file_unique_id));
+            refs.push((AttachmentKind::File, doc, name));
+        }
+        if let Some(ref voice) = msg.voice {
+            let name = format!("voice_{}.ogg", voice.file_unique_id);
+            refs.push((AttachmentKind::Voice, voice, name));
+        }
 
-                self.buffer.push_back((contact, text));
+        let mut attachments = Vec::new();
+        for (kind, file, file_name) in refs {
+            let size = file.file_size.unwrap_or(0);
+            if size > TG_MAX_DOWNLOAD {
+                warn!("Skipping {} ({} bytes): too large to download", file_name, size);
+                continue;
             }
-
-            if let Some(msg) = self.buffer.pop_front() {
-                return Ok(Some(msg));
+            match self.download_file(&file.file_id).await {
+                Ok(data) => attachments.push(Attachment { kind, file_name, data }),
+                Err(e) => warn!("Failed to download {}: {}", file_name, e),
             }
         }
+        attachments
     }
 
-    async fn send_message(&self, contact: &Contact, message: &str) -> Result<()> {
+    /// Call `sendMessage` and return the new message's ID.
+    async fn send_text(&self, chat_id: &str, text: &str) -> Result<i64> {
         let url = format!("{}/sendMessage", self.api_base);
         let body = SendMessageRequest {
-            chat_id: contact.wxid.clone(),
-            text: message.to_string(),
+            chat_id: chat_id.to_string(),
+            text: text.to_string(),
         };
 
-        let resp: TgResponse<serde_json::Value> = self
+        let resp: TgResponse<TgSentMessage> = self
             .client
             .post(&url)
             .json(&body)
@@ -214,6 +422,972 @@ impl WeChatBot for TelegramBot {
                 resp.description.unwrap_or_default()
             );
         }
+        Ok(resp.result.map(|m| m.message_id).unwrap_or_default())
+    }
+
+    /// Upload a file with `sendPhoto` for images (falling back to
+    /// `sendDocument` if Telegram refuses it as a photo) or `sendDocument`.
+    async fn upload_file(&self, chat_id: &str, file: &Attachment) -> Result<()> {
+        if file.kind == AttachmentKind::Image && file.data.len() as u64 <= TG_MAX_PHOTO {
+            match self.upload("sendPhoto", "photo", chat_id, file).await {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!("sendPhoto failed, sending as document: {}", e),
+            }
+        }
+        self.upload("sendDocument", "document", chat_id, file).await
+    }
+
+    /// Call an upload method with the file as the multipart `field`.
+    async fn upload(
+        &self,
+        method: &str,
+        field: &str,
+        chat_id: &str,
+        file: &Attachment,
+    ) -> Result<()> {
+        let url = format!("{}/{}", self.api_base, method);
+        let part = Part::bytes(file.data.clone()).file_name(file.file_name.clone());
+        let form = Form::new()
+            .text("chat_id", chat_id.to_string())
+            .part(field.to_string(), part);
+
+        let resp: TgResponse<serde_json::Value> = self
+            .client
+            .post(&url)
+            .multipart(form)
+            .send()
+            .await
+            .with_context(|| format!("{} request failed", method))?
+            .json()
+            .await
+            .with_context(|| format!("{} parse failed", method))?;
+
+        if !resp.ok {
+            anyhow::bail!("{} failed: {}", method, resp.description.unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    /// Call `editMessageText` to replace the text of a previously sent message.
+    async fn edit_text(&self, chat_id: &str, message_id: i64, text: &str) -> Result<()> {
+        let url = format!("{}/editMessageText", self.api_base);
+        let body = EditMessageTextRequest {
+            chat_id: chat_id.to_string(),
+            message_id,
+            text: text.to_string(),
+        };
+
+        let resp: TgResponse<serde_json::Value> = self
+            .client
+            .post(&url)
+            .json(&body)
+            .send()
+            .await
+            .context("editMessageText request failed")?
+            .json()
+            .await
+            .context("editMessageText parse failed")?;
+
+        if !resp.ok {
+            anyhow::bail!(
+                "editMessageText failed: {}",
+                resp.description.unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl WeChatBot for TelegramBot {
+    async fn start(&mut self) -> Result<()> {
+        let url = format!("{}/getMe", self.api_base);
+        let resp: TgResponse<TgUser> = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .context("Telegram getMe failed. Check your bot_token and network.")?
+            .json()
+            .await
+            .context("Failed to parse getMe response")?;
+
+        if !resp.ok {
+            anyhow::bail!(
+                "Telegram bot token invalid: {}",
+                resp.description.unwrap_or_default()
+            );
+        }
+
+        let me = resp.result.context("No user in getMe response")?;
+        self.bot_id = me.id;
+        self.username = me.username.unwrap_or_default();
+        info!("Telegram bot online: @{} ({})", self.username, me.first_name);
+
+        match self.webhook.clone() {
+            Some(cfg) => self.start_webhook(cfg).await,
+            // getUpdates fails while a webhook is set, e.g. after a crash in webhook mode
+            None => self.delete_webhook().await,
+        }
+    }
+
+    async fn recv_message(&self) -> Result<Option<(Contact, Message)>> {
+        let mut poll = self.poll.lock().await;
+        loop {
+            if let Some(msg) = poll.buffer.pop_front() {
+                return Ok(Some(msg));
+            }
+
+            let updates = match poll.webhook_updates {
+                Some(ref mut rx) => match rx.recv().await {
+                    Some(update) => vec![update],
+                    None => return Ok(None),
+                },
+                None => self.get_updates(poll.offset).await?,
+            };
+
+            for update in updates {
+                // Telegram redelivers webhook updates it thinks weren't received
+                if update.update_id < poll.offset {
+                    continue;
+                }
+                poll.offset = update.update_id + 1;
+                if let Some(msg) = update.message {
+                    if let Some(received) = self.convert_message(msg).await {
+                        poll.buffer.push_back(received);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn stop(&self) -> Result<()> {
+        if let Some(listener) = self.webhook_listener.as_ref() {
+            listener.abort();
+            self.delete_webhook().await?;
+            info!("Telegram webhook removed");
+        }
+        Ok(())
+    }
+
+    async fn send_message(&self, contact: &Contact, message: &str) -> Result<()> {
+        self.send_text(contact.conversation_id(), message).await?;
+        Ok(())
+    }
+
+    async fn send_file(&self, contact: &Contact, file: &Attachment) -> Result<()> {
+        if file.data.len() as u64 > TG_MAX_UPLOAD {
+            anyhow::bail!("{} is larger than Telegram's 50 MB upload limit", file.file_name);
+        }
+        self.upload_file(contact.conversation_id(), file).await
+    }
+
+    /// Edit a single message in place while the reply grows. On the final
+    /// update, overflow beyond Telegram's length limit goes out as follow-ups.
+    async fn send_stream_update(
+        &self,
+        contact: &Contact,
+        stream: &mut ReplyStream,
+        text: &str,
+        done: bool,
+    ) -> Result<()> {
+        let chunks = crate::split_message(text, TG_MAX_LEN);
+        let (head, tail) = match chunks.split_first() {
+            Some((head, tail)) if !head.trim().is_empty() => (head, tail),
+            _ => return Ok(()),
+        };
+
+        match stream.message_id {
+            None => {
+                stream.message_id = Some(self.send_text(contact.conversation_id(), head).await?);
+            }
+            // Telegram errors on edits that don't change the text
+            Some(id) if stream.delivered != *head => {
+                self.edit_text(contact.conversation_id(), id, head).await?;
+            }
+            Some(_) => {}
+        }
+        stream.delivered = head.clone();
+
+        if done {
+            for chunk in tail {
+                self.send_text(contact.conversation_id(), chunk).await?;
+            }
+        }
         Ok(())
     }
 }
+
+/// Remove mentions of `@username` from group message text. Returns `None`
+/// if the bot isn't mentioned. Also handles commands addressed to the bot,
+/// like `/status@username`.
+fn strip_mention(text: &str, username: &str) -> Option<String> {
+    if username.is_empty() {
+        return None;
+    }
+    // ASCII lowercasing keeps byte offsets valid in `text`
+    let mention = format!("@{}", username).to_ascii_lowercase();
+    let lower = text.to_ascii_lowercase();
+    let mut stripped = String::with_capacity(text.len());
+    let mut last = 0;
+    let mut found = false;
+    for (i, _) in lower.match_indices(&mention) {
+        let end = i + mention.len();
+        // Not a prefix of a longer username
+        let boundary = lower[end..]
+            .chars()
+            .next()
+            .is_none_or(|c| !(c.is_ascii_alphanumeric() || c == '_'));
+        if i < last || !boundary {
+            continue;
+        }
+        stripped.push_str(&text[last..i]);
+        last = end;
+        found = true;
+    }
+    if !found {
+        return None;
+    }
+    stripped.push_str(&text[last..]);
+    Some(stripped.trim().to_string())
+}
+
+// ============================================
+// Webhook listener
+// ============================================
+
+/// Plain-HTTP endpoint Telegram posts updates to, via a TLS proxy.
+struct WebhookListener {
+    path: String,
+    secret: String,
+    updates: mpsc::UnboundedSender<TgUpdate>,
+}
+
+/// The parts of a webhook request the listener looks at.
+struct WebhookRequest {
+    method: String,
+    path: String,
+    secret: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Bind the webhook listener. Returns the bound address, the channel updates
+/// arrive on, and the task serving connections.
+async fn spawn_webhook_listener(
+    addr: SocketAddr,
+    path: &str,
+    secret: &str,
+) -> Result<(SocketAddr, mpsc::UnboundedReceiver<TgUpdate>, JoinHandle<()>)> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind Telegram webhook listener on {}", addr))?;
+    let local = listener.local_addr()?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let webhook = Arc::new(WebhookListener {
+        path: path.to_string(),
+        secret: secret.to_string(),
+        updates: tx,
+    });
+    Ok((local, rx, tokio::spawn(webhook.serve(listener))))
+}
+
+impl WebhookListener {
+    async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Webhook accept failed: {}", e);
+                    continue;
+                }
+            };
+            let webhook = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = webhook.handle(stream).await {
+                    debug!("Webhook request from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let request = tokio::time::timeout(WEBHOOK_READ_TIMEOUT, read_request(&mut stream))
+            .await
+            .context("Timed out reading webhook request")
+            .and_then(|r| r);
+        let status = match request {
+            Ok(ref request) => self.accept(request),
+            Err(_) => "400 Bad Request",
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        );
+        stream.write_all(response.as_bytes()).await?;
+        request.map(|_| ())
+    }
+
+    /// Check a request and queue its update. Returns the HTTP status.
+    fn accept(&self, request: &WebhookRequest) -> &'static str {
+        if request.path != self.path {
+            return "404 Not Found";
+        }
+        if request.method != "POST" {
+            return "405 Method Not Allowed";
+        }
+        if !secret_matches(request.secret.as_deref(), &self.secret) {
+            warn!("Rejected webhook request with a wrong secret token");
+            return "401 Unauthorized";
+        }
+        match serde_json::from_slice::<TgUpdate>(&request.body) {
+            Ok(update) => {
+                let _ = self.updates.send(update);
+                "200 OK"
+            }
+            Err(e) => {
+                warn!("Unparseable webhook update: {}", e);
+                "400 Bad Request"
+            }
+        }
+    }
+}
+
+/// Compare the secret token header without leaking where it differs.
+fn secret_matches(given: Option<&str>, expected: &str) -> bool {
+    let Some(given) = given else {
+        return false;
+    };
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<WebhookRequest> {
+    // Read up to the end of the head; body bytes read along with it are kept
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 4096];
+    let head_len = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if buf.len() >= MAX_HEAD_LEN {
+            anyhow::bail!("Request head too large");
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed before request head");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    let mut secret = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value.trim().parse().context("Invalid Content-Length")?
+            }
+            "x-telegram-bot-api-secret-token" => secret = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    if content_length > MAX_WEBHOOK_BODY {
+        anyhow::bail!("Request body too large ({} bytes)", content_length);
+    }
+
+    let mut body = buf[head_len..].to_vec();
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed before request body");
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(content_length);
+
+    Ok(WebhookRequest {
+        method,
+        path,
+        secret,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    const TOKEN: &str = "123:TEST";
+
+    // ============================================
+    // Fake Bot API server
+    // ============================================
+
+    /// A request the fake server received.
+    #[derive(Debug, Clone)]
+    struct ApiCall {
+        method: String,
+        query: String,
+        body: Value,
+    }
+
+    #[derive(Default)]
+    struct FakeState {
+        /// Scripted `getUpdates` responses, served in order; an empty
+        /// result once they run out.
+        updates: VecDeque<Value>,
+        /// Responses replacing the default for a method.
+        overrides: HashMap<String, Value>,
+        /// Contents served for `getFile` file IDs.
+        files: HashMap<String, Vec<u8>>,
+        calls: Vec<ApiCall>,
+        next_message_id: i64,
+    }
+
+    /// Minimal Bot API server: `getMe`, `getUpdates`, `sendMessage`,
+    /// `getFile` and file downloads; anything else answers `true`.
+    struct FakeBotApi {
+        addr: SocketAddr,
+        state: Arc<std::sync::Mutex<FakeState>>,
+        handle: JoinHandle<()>,
+    }
+
+    impl FakeBotApi {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let state = Arc::new(std::sync::Mutex::new(FakeState {
+                next_message_id: 100,
+                ..Default::default()
+            }));
+            let server_state = Arc::clone(&state);
+            let handle = tokio::spawn(async move {
+                loop {
+                    let Ok((stream, _)) = listener.accept().await else {
+                        continue;
+                    };
+                    tokio::spawn(fake_connection(stream, Arc::clone(&server_state)));
+                }
+            });
+            Self { addr, state, handle }
+        }
+
+        fn config(&self) -> TelegramConfig {
+            TelegramConfig {
+                enabled: true,
+                bot_token: TOKEN.into(),
+                api_base: format!("http://{}/", self.addr),
+                webhook: TelegramWebhook::default(),
+            }
+        }
+
+        /// Queue one `getUpdates` batch.
+        fn push_updates(&self, updates: Value) {
+            let response = json!({ "ok": true, "result": updates });
+            self.state.lock().unwrap().updates.push_back(response);
+        }
+
+        /// Queue a failed `getUpdates` response.
+        fn push_updates_error(&self, description: &str) {
+            let response = json!({ "ok": false, "error_code": 409, "description": description });
+            self.state.lock().unwrap().updates.push_back(response);
+        }
+
+        fn fail(&self, method: &str, description: &str) {
+            let response = json!({ "ok": false, "error_code": 400, "description": description });
+            self.state.lock().unwrap().overrides.insert(method.into(), response);
+        }
+
+        fn add_file(&self, file_id: &str, data: &[u8]) {
+            self.state.lock().unwrap().files.insert(file_id.into(), data.to_vec());
+        }
+
+        fn calls(&self, method: &str) -> Vec<ApiCall> {
+            let state = self.state.lock().unwrap();
+            state.calls.iter().filter(|c| c.method == method).cloned().collect()
+        }
+    }
+
+    impl Drop for FakeBotApi {
+        fn drop(&mut self) {
+            self.handle.abort();
+        }
+    }
+
+    async fn fake_connection(mut stream: TcpStream, state: Arc<std::sync::Mutex<FakeState>>) {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        let head_len = loop {
+            if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        };
+        let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+        let target = head.split_whitespace().nth(1).unwrap_or_default().to_string();
+        let content_length: usize = head
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, v)| v.trim().parse().ok())
+            .unwrap_or(0);
+        while buf.len() < head_len + content_length {
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+        let body = &buf[head_len..head_len + content_length];
+
+        let (status, payload) = fake_response(&target, body, &state).await;
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            payload.len()
+        );
+        let _ = stream.write_all(head.as_bytes()).await;
+        let _ = stream.write_all(&payload).await;
+    }
+
+    async fn fake_response(
+        target: &str,
+        body: &[u8],
+        state: &std::sync::Mutex<FakeState>,
+    ) -> (&'static str, Vec<u8>) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        if let Some(file_path) = path.strip_prefix(&format!("/file/bot{}/", TOKEN)) {
+            return match state.lock().unwrap().files.get(file_path) {
+                Some(data) => ("200 OK", data.clone()),
+                None => ("404 Not Found", Vec::new()),
+            };
+        }
+        let Some(method) = path.strip_prefix(&format!("/bot{}/", TOKEN)) else {
+            let response = json!({ "ok": false, "error_code": 401, "description": "Unauthorized" });
+            return ("401 Unauthorized", response.to_string().into_bytes());
+        };
+
+        let call = ApiCall {
+            method: method.to_string(),
+            query: query.to_string(),
+            body: serde_json::from_slice(body).unwrap_or(Value::Null),
+        };
+        let scripted = {
+            let mut state = state.lock().unwrap();
+            state.calls.push(call.clone());
+            match state.overrides.get(method) {
+                Some(response) => Some(response.clone()),
+                None if method == "getUpdates" => state.updates.pop_front(),
+                None if method == "sendMessage" => {
+                    state.next_message_id += 1;
+                    Some(json!({ "ok": true, "result": { "message_id": state.next_message_id } }))
+                }
+                None => None,
+            }
+        };
+
+        let response = match (scripted, method) {
+            (Some(response), _) => response,
+            (None, "getUpdates") => {
+                // Stand in for the long-poll timeout
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                json!({ "ok": true, "result": [] })
+            }
+            (None, "getMe") => json!({
+                "ok": true,
+                "result": { "id": 1, "is_bot": true, "first_name": "Bridge", "username": "bridge_bot" }
+            }),
+            (None, "getFile") => {
+                let file_id = query.strip_prefix("file_id=").unwrap_or_default();
+                json!({ "ok": true, "result": { "file_id": file_id, "file_path": file_id } })
+            }
+            (None, _) => json!({ "ok": true, "result": true }),
+        };
+        let status = if response["ok"] == json!(true) { "200 OK" } else { "400 Bad Request" };
+        (status, response.to_string().into_bytes())
+    }
+
+    fn private_message(update_id: i64, chat_id: i64, from: Value, text: &str) -> Value {
+        json!({
+            "update_id": update_id,
+            "message": {
+                "message_id": update_id * 10,
+                "from": from,
+                "chat": { "id": chat_id, "type": "private" },
+                "text": text
+            }
+        })
+    }
+
+    async fn recv(bot: &TelegramBot) -> (Contact, Message) {
+        tokio::time::timeout(Duration::from_secs(5), bot.recv_message())
+            .await
+            .expect("no message within 5s")
+            .unwrap()
+            .unwrap()
+    }
+
+    // ============================================
+    // Polling mode
+    // ============================================
+
+    #[tokio::test]
+    async fn start_checks_the_token_and_clears_any_webhook() {
+        let api = FakeBotApi::start().await;
+        let mut bot = TelegramBot::new(&api.config());
+        bot.start().await.unwrap();
+        assert_eq!(api.calls("getMe").len(), 1);
+        assert_eq!(api.calls("deleteWebhook").len(), 1);
+
+        let api = FakeBotApi::start().await;
+        api.fail("getMe", "Unauthorized");
+        let mut bot = TelegramBot::new(&api.config());
+        let err = bot.start().await.unwrap_err();
+        assert!(err.to_string().contains("Unauthorized"), "{}", err);
+        assert!(api.calls("deleteWebhook").is_empty());
+    }
+
+    #[tokio::test]
+    async fn private_chats_are_received_with_full_names() {
+        let api = FakeBotApi::start().await;
+        api.push_updates(json!([
+            {
+                "update_id": 1,
+                "message": {
+                    "message_id": 5,
+                    "from": { "id": 7, "first_name": "Grace" },
+                    "chat": { "id": -100200, "type": "supergroup" },
+                    "text": "group chatter"
+                }
+            },
+            private_message(2, 7, json!({ "id": 7, "first_name": "Ada", "last_name": "Lovelace", "username": "ada" }), "hello"),
+            private_message(3, 8, json!({ "id": 8, "first_name": "Alan" }), "hi"),
+            { "update_id": 4 },
+            private_message(5, 9, Value::Null, "anonymous")
+        ]));
+        let bot = TelegramBot::new(&api.config());
+
+        let (contact, message) = recv(&bot).await;
+        assert_eq!(contact.wxid, "7");
+        assert_eq!(contact.nickname, "Ada Lovelace");
+        assert_eq!(contact.remark_name, "ada");
+        assert_eq!(message.id, "20");
+        assert_eq!(message.text, "hello");
+
+        let (contact, message) = recv(&bot).await;
+        assert_eq!(contact.wxid, "8");
+        assert_eq!(contact.nickname, "Alan");
+        assert_eq!(contact.remark_name, "");
+        assert_eq!(message.text, "hi");
+
+        // No sender (e.g. a channel post) falls back to a placeholder name
+        let (contact, message) = recv(&bot).await;
+        assert_eq!(contact.wxid, "9");
+        assert_eq!(contact.nickname, "Unknown");
+        assert_eq!(message.text, "anonymous");
+
+        // All five came in one getUpdates call
+        assert_eq!(api.calls("getUpdates").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn offset_acknowledges_every_update_received() {
+        let api = FakeBotApi::start().await;
+        let ada = json!({ "id": 7, "first_name": "Ada" });
+        api.push_updates(json!([
+            private_message(41, 7, ada.clone(), "one"),
+            { "update_id": 42, "message": { "message_id": 1, "chat": { "id": -5, "type": "group" }, "text": "x" } }
+        ]));
+        api.push_updates(json!([]));
+        api.push_updates(json!([private_message(43, 7, ada.clone(), "two")]));
+        let bot = TelegramBot::new(&api.config());
+
+        assert_eq!(recv(&bot).await.1.text, "one");
+        assert_eq!(recv(&bot).await.1.text, "two");
+
+        let offsets: Vec<String> = api
+            .calls("getUpdates")
+            .iter()
+            .map(|c| c.query.split('&').next().unwrap().to_string())
+            .collect();
+        // The skipped group update still moves the offset past it
+        assert_eq!(offsets, vec!["offset=0", "offset=43", "offset=43"]);
+    }
+
+    #[tokio::test]
+    async fn api_errors_are_reported() {
+        let api = FakeBotApi::start().await;
+        api.push_updates_error("Conflict: terminated by other getUpdates request");
+        let bot = TelegramBot::new(&api.config());
+
+        let err = bot.recv_message().await.unwrap_err();
+        assert!(err.to_string().contains("Conflict"), "{}", err);
+
+        api.fail("sendMessage", "Bad Request: chat not found");
+        let contact = Contact {
+            wxid: "7".into(),
+            nickname: "Ada".into(),
+            remark_name: String::new(),
+            group: None,
+        };
+        let err = bot.send_message(&contact, "hello").await.unwrap_err();
+        assert!(err.to_string().contains("chat not found"), "{}", err);
+
+        // A wrong token gets Telegram's 401 body, which is still parsed
+        let mut cfg = api.config();
+        cfg.bot_token = "wrong".into();
+        let err = TelegramBot::new(&cfg).start().await.unwrap_err();
+        assert!(err.to_string().contains("Unauthorized"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn send_message_posts_to_the_chat() {
+        let api = FakeBotApi::start().await;
+        let bot = TelegramBot::new(&api.config());
+        let contact = Contact {
+            wxid: "7".into(),
+            nickname: "Ada".into(),
+            remark_name: String::new(),
+            group: None,
+        };
+        bot.send_message(&contact, "hello there").await.unwrap();
+
+        let calls = api.calls("sendMessage");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].body, json!({ "chat_id": "7", "text": "hello there" }));
+    }
+
+    #[tokio::test]
+    async fn attachments_are_downloaded_with_the_caption() {
+        let api = FakeBotApi::start().await;
+        api.add_file("doc-1", b"col1,col2\n");
+        api.push_updates(json!([{
+            "update_id": 1,
+            "message": {
+                "message_id": 3,
+                "from": { "id": 7, "first_name": "Ada" },
+                "chat": { "id": 7, "type": "private" },
+                "caption": "please summarise",
+                "document": { "file_id": "doc-1", "file_unique_id": "u1", "file_name": "data.csv", "file_size": 10 },
+                "reply_to_message": {
+                    "message_id": 2,
+                    "chat": { "id": 7, "type": "private" },
+                    "text": "earlier"
+                }
+            }
+        }]));
+        let bot = TelegramBot::new(&api.config());
+
+        let (_, message) = recv(&bot).await;
+        assert_eq!(message.text, "please summarise");
+        assert_eq!(message.attachments.len(), 1);
+        assert_eq!(message.attachments[0].file_name, "data.csv");
+        assert_eq!(message.attachments[0].data, b"col1,col2\n");
+        let quote = message.reply_to.unwrap();
+        assert_eq!((quote.id.as_str(), quote.text.as_str()), ("2", "earlier"));
+    }
+
+    #[test]
+    fn mentions_are_stripped() {
+        assert_eq!(strip_mention("@Bridge_Bot fix the build", "bridge_bot").as_deref(), Some("fix the build"));
+        assert_eq!(strip_mention("/status@bridge_bot", "bridge_bot").as_deref(), Some("/status"));
+        assert_eq!(strip_mention("你好 @bridge_bot，看一下", "bridge_bot").as_deref(), Some("你好 ，看一下"));
+        assert_eq!(strip_mention("ask @bridge_bot2", "bridge_bot"), None);
+        assert_eq!(strip_mention("no mention", "bridge_bot"), None);
+        assert_eq!(strip_mention("@bridge_bot", ""), None);
+    }
+
+    #[tokio::test]
+    async fn group_messages_need_a_mention_or_a_reply_to_the_bot() {
+        let api = FakeBotApi::start().await;
+        let group_message = |update_id: i64, from: i64, text: &str, reply_from: Option<i64>| {
+            let mut message = json!({
+                "message_id": update_id * 10,
+                "from": { "id": from, "first_name": "Ada" },
+                "chat": { "id": -100500, "type": "supergroup", "title": "Dev Team" },
+                "text": text
+            });
+            if let Some(reply_from) = reply_from {
+                message["reply_to_message"] = json!({
+                    "message_id": 1,
+                    "from": { "id": reply_from, "first_name": "someone" },
+                    "chat": { "id": -100500, "type": "supergroup" },
+                    "text": "earlier"
+                });
+            }
+            json!({ "update_id": update_id, "message": message })
+        };
+        api.push_updates(json!([
+            group_message(1, 7, "just chatting", None),
+            group_message(2, 7, "replying to a human", Some(8)),
+            group_message(3, 7, "@bridge_bot what's failing?", None),
+            group_message(4, 8, "and this?", Some(1)),
+            { "update_id": 5, "message": {
+                "message_id": 50,
+                "sender_chat": { "id": -100500, "type": "supergroup" },
+                "chat": { "id": -100500, "type": "supergroup", "title": "Dev Team" },
+                "text": "@bridge_bot anonymous admin"
+            } },
+            { "update_id": 6, "message": {
+                "message_id": 60,
+                "chat": { "id": -100600, "type": "channel", "title": "News" },
+                "text": "@bridge_bot channel post"
+            } },
+            private_message(7, 7, json!({ "id": 7, "first_name": "Ada" }), "done")
+        ]));
+        let mut bot = TelegramBot::new(&api.config());
+        bot.start().await.unwrap();
+
+        let (contact, message) = recv(&bot).await;
+        assert_eq!(message.text, "what's failing?");
+        assert_eq!(contact.wxid, "7");
+        assert_eq!(contact.conversation_id(), "-100500");
+        assert_eq!(contact.group.as_ref().unwrap().name, "Dev Team");
+
+        // A reply to one of the bot's messages needs no mention
+        let (contact, message) = recv(&bot).await;
+        assert_eq!((contact.wxid.as_str(), message.text.as_str()), ("8", "and this?"));
+        assert_eq!(message.reply_to.unwrap().text, "earlier");
+
+        let (contact, message) = recv(&bot).await;
+        assert!(contact.group.is_none());
+        assert_eq!(message.text, "done");
+
+        // Replies go to the group, not the sender
+        bot.send_message(&contact, "private").await.unwrap();
+        let in_group = Contact {
+            group: Some(Group {
+                id: "-100500".into(),
+                name: "Dev Team".into(),
+            }),
+            ..contact
+        };
+        bot.send_message(&in_group, "group").await.unwrap();
+        let chats: Vec<Value> = api.calls("sendMessage").iter().map(|c| c.body["chat_id"].clone()).collect();
+        assert_eq!(chats, vec![json!("7"), json!("-100500")]);
+    }
+
+    // ============================================
+    // Webhook mode
+    // ============================================
+
+    async fn post(addr: SocketAddr, path: &str, secret: Option<&str>, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let secret = secret
+            .map(|s| format!("X-Telegram-Bot-Api-Secret-Token: {}\r\n", s))
+            .unwrap_or_default();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: bot\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\n\r\n{}",
+            path,
+            secret,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn secret_comparison() {
+        assert!(secret_matches(Some("s3cret"), "s3cret"));
+        assert!(!secret_matches(Some("s3cres"), "s3cret"));
+        assert!(!secret_matches(Some("s3cret!"), "s3cret"));
+        assert!(!secret_matches(None, "s3cret"));
+    }
+
+    #[tokio::test]
+    async fn webhook_listener_checks_requests_and_queues_updates() {
+        let (addr, mut updates, handle) =
+            spawn_webhook_listener("127.0.0.1:0".parse().unwrap(), "/tg/hook", "s3cret")
+                .await
+                .unwrap();
+        let update = r#"{"update_id":7,"message":{"message_id":1,"chat":{"id":42,"type":"private"},"text":"hi"}}"#;
+
+        assert_eq!(post(addr, "/tg/hook", Some("s3cret"), update).await, "HTTP/1.1 200 OK");
+        let received = updates.recv().await.unwrap();
+        assert_eq!(received.update_id, 7);
+        assert_eq!(received.message.unwrap().text.as_deref(), Some("hi"));
+
+        assert_eq!(post(addr, "/tg/hook", Some("wrong"), update).await, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(post(addr, "/tg/hook", None, update).await, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(post(addr, "/other", Some("s3cret"), update).await, "HTTP/1.1 404 Not Found");
+        assert_eq!(post(addr, "/tg/hook", Some("s3cret"), "{nope").await, "HTTP/1.1 400 Bad Request");
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /tg/hook HTTP/1.1\r\nHost: bot\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"));
+
+        // Rejected requests queued nothing
+        assert!(updates.try_recv().is_err());
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn webhook_mode_registers_receives_and_unregisters() {
+        let api = FakeBotApi::start().await;
+        let listen = {
+            let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            probe.local_addr().unwrap()
+        };
+        let mut cfg = api.config();
+        cfg.webhook = TelegramWebhook {
+            enabled: true,
+            listen: listen.to_string(),
+            path: "/hook".into(),
+            url: "https://bot.example.com/hook".into(),
+            secret_token: String::new(),
+        };
+        let mut bot = TelegramBot::new(&cfg);
+        bot.start().await.unwrap();
+
+        let registered = api.calls("setWebhook");
+        assert_eq!(registered.len(), 1);
+        assert_eq!(registered[0].body["url"], "https://bot.example.com/hook");
+        assert_eq!(registered[0].body["allowed_updates"], json!(["message"]));
+        // An empty secret_token is replaced with a generated one
+        let secret = registered[0].body["secret_token"].as_str().unwrap().to_string();
+        assert_eq!(secret.len(), 32);
+        assert!(api.calls("deleteWebhook").is_empty());
+
+        let update = private_message(9, 7, json!({ "id": 7, "first_name": "Ada" }), "via webhook");
+        assert_eq!(post(listen, "/hook", Some(&secret), &update.to_string()).await, "HTTP/1.1 200 OK");
+        // Telegram redelivers when it misses our response; the repeat is dropped
+        assert_eq!(post(listen, "/hook", Some(&secret), &update.to_string()).await, "HTTP/1.1 200 OK");
+        let next = private_message(10, 7, json!({ "id": 7, "first_name": "Ada" }), "second");
+        assert_eq!(post(listen, "/hook", Some(&secret), &next.to_string()).await, "HTTP/1.1 200 OK");
+
+        let (contact, message) = recv(&bot).await;
+        assert_eq!((contact.wxid.as_str(), message.text.as_str()), ("7", "via webhook"));
+        assert_eq!(recv(&bot).await.1.text, "second");
+        assert!(api.calls("getUpdates").is_empty());
+
+        bot.stop().await.unwrap();
+        assert_eq!(api.calls("deleteWebhook").len(), 1);
+
+        cfg.webhook.url.clear();
+        let err = TelegramBot::new(&cfg).start().await.unwrap_err();
+        assert!(err.to_string().contains("webhook.url"), "{}", err);
+    }
+}
@@ -1,6 +1,9 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use anyhow::Result;
 use async_trait::async_trait;
 use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tokio::sync::Mutex;
 use tracing::{info, warn};
 
 /// A WeChat contact (sender of a message).
@@ -9,6 +12,130 @@ pub struct Contact {
     pub wxid: String,
     pub nickname: String,
     pub remark_name: String,
+    /// The group the message was posted in; `None` for private chats.
+    pub group: Option<Group>,
+}
+
+/// A group chat the bot is a member of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+}
+
+impl Contact {
+    /// The chat replies go to, and the key for per-chat state (container,
+    /// session, workspace): the group for group messages, else the sender.
+    pub fn conversation_id(&self) -> &str {
+        match self.group {
+            Some(ref group) => &group.id,
+            None => &self.wxid,
+        }
+    }
+}
+
+/// Kind of file attached to a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentKind {
+    Image,
+    File,
+    Voice,
+}
+
+impl AttachmentKind {
+    /// How to send a file: photos for common image formats, files otherwise.
+    pub fn for_file_name(file_name: &str) -> Self {
+        let ext = std::path::Path::new(file_name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        match ext.as_deref() {
+            Some("png" | "jpg" | "jpeg" | "webp") => AttachmentKind::Image,
+            _ => AttachmentKind::File,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttachmentKind::Image => "image",
+            AttachmentKind::File => "file",
+            AttachmentKind::Voice => "voice",
+        }
+    }
+}
+
+/// A file received with a message (already downloaded by the bot), or one
+/// to send to a contact.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub kind: AttachmentKind,
+    /// File name as sent (may be generated by the bot); not trusted as a path.
+    pub file_name: String,
+    pub data: Vec<u8>,
+}
+
+/// The earlier message a message replies to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quote {
+    pub id: String,
+    /// Text or caption of the quoted message; empty for bare attachments.
+    pub text: String,
+}
+
+/// An incoming message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message {
+    /// Platform message ID; empty if the platform has none.
+    pub id: String,
+    /// Message text, or the caption of its attachments.
+    pub text: String,
+    pub attachments: Vec<Attachment>,
+    pub reply_to: Option<Quote>,
+}
+
+impl Message {
+    /// A text-only message without an ID.
+    pub fn plain(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether there is nothing to handle (blank text, no attachments).
+    pub fn is_empty(&self) -> bool {
+        self.text.trim().is_empty() && self.attachments.is_empty()
+    }
+
+    /// Combine messages that queued up into one: texts joined by newlines,
+    /// attachments in order, the last ID and the first quote.
+    pub fn merge(messages: Vec<Message>) -> Option<Message> {
+        let mut iter = messages.into_iter();
+        let mut merged = iter.next()?;
+        for message in iter {
+            if !message.text.is_empty() {
+                if !merged.text.is_empty() {
+                    merged.text.push('\n');
+                }
+                merged.text.push_str(&message.text);
+            }
+            merged.attachments.extend(message.attachments);
+            merged.id = message.id;
+            if merged.reply_to.is_none() {
+                merged.reply_to = message.reply_to;
+            }
+        }
+        Some(merged)
+    }
+}
+
+/// State of a reply that is being streamed to a contact, threaded through
+/// successive `WeChatBot::send_stream_update` calls.
+#[derive(Debug, Default)]
+pub struct ReplyStream {
+    /// Text already shown to the user.
+    pub delivered: String,
+    /// Platform message being edited in place, if the bot supports editing.
+    pub message_id: Option<i64>,
 }
 
 /// Trait abstracting a WeChat bot. Implementations can be the real WeChat
@@ -18,12 +145,64 @@ pub trait WeChatBot: Send + Sync {
     /// Perform any startup/login sequence.
     async fn start(&mut self) -> Result<()>;
 
-    /// Wait for and return the next incoming message.
+    /// Wait for and return the next incoming message, with any attachments
+    /// already downloaded.
     /// Returns `None` when the input stream is exhausted (EOF / shutdown).
-    async fn recv_message(&mut self) -> Result<Option<(Contact, String)>>;
+    ///
+    /// Takes `&self` so a started bot can be shared (`Arc<dyn WeChatBot>`)
+    /// with background tasks that send messages while the loop is polling.
+    async fn recv_message(&self) -> Result<Option<(Contact, Message)>>;
+
+    /// Release anything `start` set up, at shutdown.
+    async fn stop(&self) -> Result<()> {
+        Ok(())
+    }
 
     /// Send a reply to the given contact.
     async fn send_message(&self, contact: &Contact, message: &str) -> Result<()>;
+
+    /// Send a file to the given contact. Bots that can't send files keep
+    /// this default, which fails.
+    async fn send_file(&self, _contact: &Contact, file: &Attachment) -> Result<()> {
+        anyhow::bail!("This bot cannot send files ({})", file.file_name)
+    }
+
+    /// Push the text accumulated so far for a streamed reply. `done` marks the
+    /// final update, whose `text` is the complete reply.
+    ///
+    /// The default implementation sends chunked follow-up messages: finished
+    /// paragraphs are sent as they appear and the remainder when `done`.
+    async fn send_stream_update(
+        &self,
+        contact: &Contact,
+        stream: &mut ReplyStream,
+        text: &str,
+        done: bool,
+    ) -> Result<()> {
+        // If the final text diverges from what was streamed (e.g. a timeout
+        // message), send it whole rather than a nonsensical suffix.
+        let pending = match text.strip_prefix(stream.delivered.as_str()) {
+            Some(rest) => rest,
+            None => {
+                stream.delivered.clear();
+                text
+            }
+        };
+
+        let cut = if done {
+            pending.len()
+        } else {
+            pending.rfind("\n\n").map(|i| i + 2).unwrap_or(0)
+        };
+        let chunk = pending[..cut].trim();
+        if !chunk.is_empty() {
+            for part in crate::split_message(chunk, 2000) {
+                self.send_message(contact, &part).await?;
+            }
+        }
+        stream.delivered.push_str(&pending[..cut]);
+        Ok(())
+    }
 }
 
 /// A testing bot that reads from stdin and writes to stdout.
@@ -33,13 +212,16 @@ pub trait WeChatBot: Send + Sync {
 ///
 /// If only one `|` is present the nickname defaults to the wxid.
 pub struct StdinBot {
-    reader: BufReader<io::Stdin>,
+    reader: Mutex<BufReader<io::Stdin>>,
+    /// Message IDs are line numbers.
+    next_id: AtomicU64,
 }
 
 impl StdinBot {
     pub fn new() -> Self {
         Self {
-            reader: BufReader::new(io::stdin()),
+            reader: Mutex::new(BufReader::new(io::stdin())),
+            next_id: AtomicU64::new(1),
         }
     }
 }
@@ -51,9 +233,9 @@ impl WeChatBot for StdinBot {
         Ok(())
     }
 
-    async fn recv_message(&mut self) -> Result<Option<(Contact, String)>> {
+    async fn recv_message(&self) -> Result<Option<(Contact, Message)>> {
         let mut line = String::new();
-        let n = self.reader.read_line(&mut line).await?;
+        let n = self.reader.lock().await.read_line(&mut line).await?;
         if n == 0 {
             // EOF
             return Ok(None);
@@ -87,13 +269,157 @@ impl WeChatBot for StdinBot {
             wxid,
             nickname,
             remark_name: String::new(),
+            group: None,
         };
 
-        Ok(Some((contact, message)))
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Ok(Some((
+            contact,
+            Message {
+                id: id.to_string(),
+                ..Message::plain(message)
+            },
+        )))
     }
 
     async fn send_message(&self, contact: &Contact, message: &str) -> Result<()> {
         println!("[{}] {}", contact.nickname, message);
         Ok(())
     }
+
+    async fn send_file(&self, contact: &Contact, file: &Attachment) -> Result<()> {
+        println!(
+            "[{}] 📎 {} ({}, {} bytes)",
+            contact.nickname,
+            file.file_name,
+            file.kind.as_str(),
+            file.data.len()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Bot that records every message it is asked to send.
+    struct RecordingBot {
+        sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl WeChatBot for RecordingBot {
+        async fn start(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn recv_message(&self) -> Result<Option<(Contact, Message)>> {
+            Ok(None)
+        }
+
+        async fn send_message(&self, _contact: &Contact, message: &str) -> Result<()> {
+            self.sent.lock().unwrap().push(message.to_string());
+            Ok(())
+        }
+    }
+
+    fn contact() -> Contact {
+        Contact {
+            wxid: "wx_stream".into(),
+            nickname: "Streamer".into(),
+            remark_name: String::new(),
+            group: None,
+        }
+    }
+
+    #[test]
+    fn merge_joins_texts_and_keeps_attachments() {
+        let photo = Attachment {
+            kind: AttachmentKind::Image,
+            file_name: "a.jpg".into(),
+            data: vec![1, 2, 3],
+        };
+        let quote = Quote { id: "7".into(), text: "earlier".into() };
+        let messages = vec![
+            Message { id: "1".into(), ..Message::plain("look") },
+            Message {
+                id: "2".into(),
+                text: String::new(),
+                attachments: vec![photo.clone()],
+                reply_to: Some(quote.clone()),
+            },
+            Message { id: "3".into(), ..Message::plain("what is it?") },
+        ];
+
+        let merged = Message::merge(messages).unwrap();
+        assert_eq!(merged.id, "3");
+        assert_eq!(merged.text, "look\nwhat is it?");
+        assert_eq!(merged.attachments, vec![photo]);
+        assert_eq!(merged.reply_to, Some(quote));
+        assert!(Message::merge(Vec::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn stream_update_sends_finished_paragraphs() {
+        let bot = RecordingBot { sent: Mutex::new(Vec::new()) };
+        let mut stream = ReplyStream::default();
+
+        bot.send_stream_update(&contact(), &mut stream, "First para", false).await.unwrap();
+        assert!(bot.sent.lock().unwrap().is_empty());
+
+        bot.send_stream_update(&contact(), &mut stream, "First para\n\nSecond", false)
+            .await
+            .unwrap();
+        assert_eq!(*bot.sent.lock().unwrap(), vec!["First para"]);
+
+        bot.send_stream_update(&contact(), &mut stream, "First para\n\nSecond para", true)
+            .await
+            .unwrap();
+        assert_eq!(*bot.sent.lock().unwrap(), vec!["First para", "Second para"]);
+    }
+
+    #[tokio::test]
+    async fn stream_update_resends_diverged_final_text() {
+        let bot = RecordingBot { sent: Mutex::new(Vec::new()) };
+        let mut stream = ReplyStream::default();
+
+        bot.send_stream_update(&contact(), &mut stream, "Working on it\n\n", false)
+            .await
+            .unwrap();
+        bot.send_stream_update(&contact(), &mut stream, "Request timed out", true)
+            .await
+            .unwrap();
+        assert_eq!(
+            *bot.sent.lock().unwrap(),
+            vec!["Working on it", "Request timed out"]
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_update_sends_each_turn_once_after_tool_use() {
+        let bot = RecordingBot { sent: Mutex::new(Vec::new()) };
+        let mut stream = ReplyStream::default();
+        let mut parser = crate::claude_cli::StreamJsonParser::new();
+        for text in ["Let me check.", "Found it.\n\nAll done."] {
+            let line = serde_json::json!({
+                "type": "assistant",
+                "message": {"content": [{"type": "text", "text": text}]},
+            });
+            parser.feed(format!("{}\n", line).as_bytes());
+            bot.send_stream_update(&contact(), &mut stream, parser.text(), false)
+                .await
+                .unwrap();
+        }
+        // The result only repeats the last turn
+        parser.feed(b"{\"type\":\"result\",\"result\":\"Found it.\\n\\nAll done.\"}\n");
+        bot.send_stream_update(&contact(), &mut stream, &parser.into_output(), true)
+            .await
+            .unwrap();
+        assert_eq!(
+            *bot.sent.lock().unwrap(),
+            vec!["Let me check.\n\nFound it.", "All done."]
+        );
+    }
 }